@@ -25,9 +25,10 @@ async fn db_sync(update_time: i64, first_sync: bool) -> Result<(), Box<dyn Error
         dynamo_db.all_last_peers(time_difference, PAGE_SIZE).await?
     };
 
-    // update sqliteDB from dynamoDB
-    for peer in peers {
-        sqlite_db.add_peer(peer).await?;
+    // update sqliteDB from dynamoDB, as is since dynamoDB already counted the crawls
+    sqlite_db.add_peers(peers).await?;
+    if let Some(latest_block) = dynamo_db.latest_block().await? {
+        sqlite_db.set_latest_block(latest_block).await?;
     }
 
     Ok(())
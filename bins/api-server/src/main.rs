@@ -5,16 +5,19 @@ use axum::http::Method;
 use axum::routing;
 use axum::Json;
 use axum::Router;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use db_sync::db_sync_handler;
-use peerdb::{rest_router, AppState};
+use peerdb::{admin_router, rest_router, AppState};
 use std::net::SocketAddr;
 use tokio::try_join;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::info;
 
 /// Update time for the recurrent `db_sync()` task. 5 minutes.
 const UPDATE_TIME: i64 = 300;
+/// Default max number of peers read by a scan of the table.
+const DEFAULT_MAX_SCAN_ITEMS: usize = 100_000;
 
 #[derive(Parser)]
 #[command(author, version)]
@@ -32,7 +35,19 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start crawling the network
-    StartApiServer,
+    StartApiServer(ApiServerOpts),
+}
+
+#[derive(Args)]
+struct ApiServerOpts {
+    /// Api key required by the `/admin` routes, passed in the `x-api-key` header. Admin routes are disabled if not set.
+    #[arg(long)]
+    admin_api_key: Option<String>,
+
+    /// Max number of peers read by the routes that scan the table, e.g. the stats or the filtered nodes. Responses
+    /// computed from a truncated scan carry an `x-truncated: true` header.
+    #[arg(long, default_value_t = DEFAULT_MAX_SCAN_ITEMS)]
+    max_scan_items: usize,
 }
 
 #[tokio::main]
@@ -41,7 +56,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let start_api_server_futures = {
         match cli.command {
-            Commands::StartApiServer => start_api_server(),
+            Commands::StartApiServer(opts) => start_api_server(opts),
         }
     };
 
@@ -52,7 +67,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn start_api_server() -> Result<(), Box<dyn std::error::Error>> {
+async fn start_api_server(opts: ApiServerOpts) -> Result<(), Box<dyn std::error::Error>> {
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
         .allow_origin(Any);
@@ -60,8 +75,11 @@ async fn start_api_server() -> Result<(), Box<dyn std::error::Error>> {
     let app = Router::new()
         .route("/", routing::get(handler))
         .merge(rest_router())
-        .with_state(AppState::new_sql().await)
-        .layer(cors);
+        .merge(admin_router())
+        .with_state(AppState::new_sql(opts.admin_api_key, opts.max_scan_items).await)
+        .layer(cors)
+        // compress according to `Accept-Encoding`, empty bodies such as upgrade responses are left untouched
+        .layer(CompressionLayer::new());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 3030));
     info!("Server started, listening on {addr}");
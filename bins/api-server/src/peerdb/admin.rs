@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{Duration, Utc};
+use reth_crawler_db::PeerDB;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::app_state::AppState;
+
+/// Header that must carry the admin api key.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// State shared by the admin routes.
+#[derive(Clone)]
+pub struct AdminState {
+    /// Key required to call the admin routes. Admin routes are disabled when it's not set.
+    api_key: Option<String>,
+    /// Stores that are pruned by the admin routes, in order.
+    stores: Vec<Arc<dyn PeerDB>>,
+    /// Prune jobs by job id.
+    jobs: Arc<RwLock<HashMap<u64, PruneJob>>>,
+    /// Id of the next prune job.
+    next_job_id: Arc<AtomicU64>,
+}
+
+impl AdminState {
+    pub fn new(api_key: Option<String>, stores: Vec<Arc<dyn PeerDB>>) -> Self {
+        Self {
+            api_key,
+            stores,
+            jobs: Arc::new(RwLock::new(HashMap::new())),
+            next_job_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Check that the request carries the admin api key.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let Some(api_key) = &self.api_key else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        match headers.get(API_KEY_HEADER) {
+            Some(key) if key.as_bytes() == api_key.as_bytes() => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PruneRequest {
+    /// Peers with a `last_seen` older than this are pruned, e.g. `30d`.
+    older_than: String,
+    /// Only count the peers that would be pruned.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+pub struct PruneResponse {
+    job_id: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneJobStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PruneJob {
+    status: PruneJobStatus,
+    older_than: String,
+    dry_run: bool,
+    /// Number of records deleted, or that would be deleted in dry-run mode.
+    deleted: Option<u64>,
+    elapsed_ms: Option<u128>,
+    error: Option<String>,
+}
+
+pub fn admin_router() -> Router<AppState> {
+    Router::new()
+        .route("/admin/prune", post(start_prune))
+        .route("/admin/prune/:job_id", get(get_prune_job))
+}
+
+async fn start_prune(
+    State(admin): State<AdminState>,
+    headers: HeaderMap,
+    Json(request): Json<PruneRequest>,
+) -> Result<Json<PruneResponse>, StatusCode> {
+    admin.authorize(&headers)?;
+
+    let older_than =
+        humantime::parse_duration(&request.older_than).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let older_than = Duration::from_std(older_than).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let last_seen = Utc::now()
+        .checked_sub_signed(older_than)
+        .ok_or(StatusCode::BAD_REQUEST)?
+        .to_string();
+
+    let job_id = admin.next_job_id.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut jobs = admin.jobs.write().expect("this should always work!");
+        jobs.insert(
+            job_id,
+            PruneJob {
+                status: PruneJobStatus::Running,
+                older_than: request.older_than,
+                dry_run: request.dry_run,
+                deleted: None,
+                elapsed_ms: None,
+                error: None,
+            },
+        );
+    }
+
+    let dry_run = request.dry_run;
+    tokio::spawn(async move {
+        let start = Instant::now();
+        // the reported count is the one of the first store, which is the source of truth
+        let mut result = Ok(None);
+        for store in &admin.stores {
+            match store
+                .delete_peers_older_than(last_seen.clone(), None, dry_run)
+                .await
+            {
+                Ok(deleted) => {
+                    result = result.map(|first: Option<u64>| first.or(Some(deleted)));
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        let result = result.map(Option::unwrap_or_default);
+
+        let mut jobs = admin.jobs.write().expect("this should always work!");
+        let job = jobs
+            .get_mut(&job_id)
+            .expect("job was inserted before spawning");
+        job.elapsed_ms = Some(start.elapsed().as_millis());
+        match result {
+            Ok(deleted) => {
+                info!("Prune job {job_id} completed: {deleted} peers (dry run: {dry_run})");
+                job.status = PruneJobStatus::Completed;
+                job.deleted = Some(deleted);
+            }
+            Err(e) => {
+                warn!("Prune job {job_id} failed: {e}");
+                job.status = PruneJobStatus::Failed;
+                job.error = Some(e.to_string());
+            }
+        }
+    });
+
+    Ok(Json(PruneResponse { job_id }))
+}
+
+async fn get_prune_job(
+    State(admin): State<AdminState>,
+    headers: HeaderMap,
+    Path(job_id): Path<u64>,
+) -> Result<Json<PruneJob>, StatusCode> {
+    admin.authorize(&headers)?;
+
+    let jobs = admin.jobs.read().expect("this should always work!");
+    jobs.get(&job_id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
@@ -1,23 +1,39 @@
 use std::sync::Arc;
 
 use axum::extract::FromRef;
-use reth_crawler_db::{db::SqlPeerDB, AwsPeerDB, PeerDB};
+use reth_crawler_db::{build_peer_db, Backend, PeerDB};
+
+use super::admin::AdminState;
+
+/// Max number of peers a scan of the table may read.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxScanItems(pub usize);
 
 #[derive(Clone, FromRef)]
 pub struct AppState {
     store: Arc<dyn PeerDB>,
+    admin: AdminState,
+    max_scan_items: MaxScanItems,
 }
 
 impl AppState {
-    pub async fn new_aws() -> Self {
+    pub async fn new_aws(admin_api_key: Option<String>, max_scan_items: usize) -> Self {
+        let store = build_peer_db(Backend::Aws).await;
         Self {
-            store: Arc::new(AwsPeerDB::new().await),
+            admin: AdminState::new(admin_api_key, vec![store.clone()]),
+            store,
+            max_scan_items: MaxScanItems(max_scan_items),
         }
     }
 
-    pub async fn new_sql() -> Self {
+    /// The sql store is synced from the AWS one, so admin operations are applied to both of them.
+    pub async fn new_sql(admin_api_key: Option<String>, max_scan_items: usize) -> Self {
+        let store = build_peer_db(Backend::Sqlite).await;
+        let upstream = build_peer_db(Backend::Aws).await;
         Self {
-            store: Arc::new(SqlPeerDB::new().await),
+            admin: AdminState::new(admin_api_key, vec![upstream, store.clone()]),
+            store,
+            max_scan_items: MaxScanItems(max_scan_items),
         }
     }
 }
@@ -1,5 +1,7 @@
+mod admin;
 mod app_state;
 mod routes;
 
+pub(crate) use admin::admin_router;
 pub(crate) use app_state::AppState;
 pub(crate) use routes::rest_router;
@@ -1,41 +1,535 @@
+use std::convert::Infallible;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use axum::{
-    extract::{Path, State},
+    body::StreamBody,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
-use reth_crawler_db::{types::ClientData, PeerDB, PeerData};
+use chrono::{DateTime, Duration, Utc};
+use reth_crawler_db::{
+    types::{
+        canonical_ip, decode_cursor, group_asns, group_cities, group_clients, group_eth_versions,
+        group_genesis, group_p2p_versions, group_sources, same_city, AsnCount, ChainHead,
+        CityCount, ClientCount, ClientData, ClientGrouping, EthVersionCount, GenesisCount,
+        NetworkSummary, P2pVersionCount, PeerFilter, ScanTableError, SourceCount, SyncedRatio,
+    },
+    PeerDB, PeerData,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use super::app_state::{AppState, MaxScanItems};
+
+/// Default number of peers returned by `/nodes/random`.
+const DEFAULT_RANDOM_PEERS: usize = 50;
+/// Max number of peers returned by `/nodes/random`.
+const MAX_RANDOM_PEERS: usize = 1000;
+/// Default number of peers of a `/nodes` page.
+const DEFAULT_PAGE_LIMIT: usize = 50;
+/// Max number of peers of a `/nodes` page.
+const MAX_PAGE_LIMIT: usize = 1000;
+/// Header carrying the cursor of the next `/nodes` page, missing on the last page.
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+/// Header set to `true` when a response was computed from a scan cut at `--max-scan-items`.
+const TRUNCATED_HEADER: &str = "x-truncated";
+/// Content type of `/nodes` streamed as one JSON peer per line.
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// Number of peers read from the db at a time while streaming `/nodes` as NDJSON.
+const NDJSON_PAGE_SIZE: usize = MAX_PAGE_LIMIT;
+
+#[derive(Deserialize)]
+struct RandomPeersQuery {
+    n: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ClientsQuery {
+    /// Either `name` (the default) or `name_version`.
+    group_by: Option<ClientGrouping>,
+}
+
+#[derive(Deserialize)]
+struct SyncedRatioQuery {
+    /// Only count the peers seen since this time, in RFC 3339, e.g. `2024-01-01T00:00:00Z`. Defaults to a day ago.
+    since: Option<String>,
+}
 
-use super::app_state::AppState;
+/// Filters of `/nodes`, the peers must meet all of them.
+#[derive(Deserialize)]
+struct NodesQuery {
+    /// Only return the peers with at least this availability, between 0 and 1.
+    min_availability: Option<f64>,
+    /// Only return the peers located in this city, compared case insensitively.
+    city: Option<String>,
+    /// Only return the peers whose advertised port was found reachable, or unreachable.
+    port_reachable: Option<bool>,
+    /// Only return the peers last surfaced by this discovery source, e.g. `dnsdisc`.
+    source: Option<String>,
+    /// Only return the peers whose best block is canonical, or on a side chain.
+    on_canonical: Option<bool>,
+    /// Only return the peers whose best block was at most this many blocks behind the crawler's head.
+    max_head_lag: Option<u64>,
+    /// Only return the peers with at least this reputation score, e.g. `0` to leave out the flaky ones.
+    min_score: Option<i64>,
+    /// Only return the peers whose handshakes failed since they were last crawled, or the other ones.
+    failed: Option<bool>,
+    /// Only return the peers running this client, e.g. `reth`, compared case insensitively.
+    client: Option<String>,
+    /// Only return the peers located in this country, compared case insensitively.
+    country: Option<String>,
+    /// Only return the synced peers, or the lagging ones.
+    synced: Option<bool>,
+    /// Only return the peers that negotiated this eth version.
+    eth_version: Option<u8>,
+    /// Only return the peers advertising this capability, e.g. `snap/1`.
+    capability: Option<String>,
+    /// Only return the peers serving snap sync, or the other ones.
+    snap: Option<bool>,
+    /// Only return the peers serving light clients, or the other ones.
+    les: Option<bool>,
+    /// Return a page of this many peers, before the other filters apply.
+    limit: Option<usize>,
+    /// Return the page after this one, as given by the `x-next-cursor` header of the previous page.
+    cursor: Option<String>,
+}
+
+/// A peer along with its availability score.
+#[derive(Serialize)]
+struct NodeData {
+    #[serde(flatten)]
+    peer: PeerData,
+    availability: f64,
+}
+
+impl From<PeerData> for NodeData {
+    fn from(peer: PeerData) -> Self {
+        Self {
+            availability: peer.availability(),
+            peer,
+        }
+    }
+}
+
+impl NodesQuery {
+    /// The filters the dbs can apply themselves.
+    fn peer_filter(&self) -> PeerFilter {
+        PeerFilter {
+            client: self.client.clone(),
+            country: self.country.clone(),
+            synced: self.synced,
+            eth_version: self.eth_version,
+            capability: self.capability.clone(),
+        }
+    }
+
+    /// Whether `node` meets all the filters, `filter` being [`NodesQuery::peer_filter`].
+    fn matches(&self, filter: &PeerFilter, node: &NodeData) -> bool {
+        node.availability >= self.min_availability.unwrap_or_default()
+            && filter.matches(&node.peer)
+            && (self.port_reachable.is_none() || node.peer.port_reachable == self.port_reachable)
+            && (self.source.is_none() || node.peer.discovery_source == self.source)
+            && (self.on_canonical.is_none() || node.peer.on_canonical == self.on_canonical)
+            && self.max_head_lag.map_or(true, |max_head_lag| {
+                node.peer
+                    .head_lag()
+                    .map_or(false, |lag| lag <= max_head_lag)
+            })
+            && self
+                .min_score
+                .map_or(true, |min_score| node.peer.score >= min_score)
+            && self
+                .failed
+                .map_or(true, |failed| (node.peer.handshake_failures > 0) == failed)
+            && self
+                .snap
+                .map_or(true, |snap| node.peer.snap_supported == snap)
+            && self.les.map_or(true, |les| node.peer.les_supported == les)
+            && self
+                .city
+                .as_ref()
+                .map_or(true, |city| same_city(&node.peer.city, city))
+    }
+}
 
 pub fn rest_router() -> Router<AppState> {
     Router::new()
         .route("/nodes", get(get_nodes))
+        .route("/nodes.geojson", get(get_nodes_geojson))
+        .route("/nodes/random", get(get_random_nodes))
         .route("/node/id/:id", get(get_node_by_id))
+        .route("/node/id/:id/history", get(get_node_history))
         .route("/node/ip/:ip", get(get_node_by_ip))
         .route("/clients", get(get_clients))
+        .route("/stats/summary", get(get_summary))
+        .route("/stats/clients", get(get_client_stats))
+        .route("/stats/p2p-versions", get(get_p2p_version_stats))
+        .route("/stats/eth-versions", get(get_eth_version_stats))
+        .route("/stats/cities", get(get_city_stats))
+        .route("/stats/sources", get(get_source_stats))
+        .route("/stats/genesis", get(get_genesis_stats))
+        .route("/stats/asn", get(get_asn_stats))
+        .route("/stats/synced-ratio", get(get_synced_ratio))
+        .route("/state/head", get(get_head))
 }
 
-async fn get_nodes(State(store): State<Arc<dyn PeerDB>>) -> Json<Vec<PeerData>> {
-    Json(store.all_peers(Some(50)).await.unwrap())
-}
-
-async fn get_clients(State(store): State<Arc<dyn PeerDB>>) -> Json<Vec<ClientData>> {
-    Json(
+/// The peers matching the `/nodes` filters, along with the headers of the response: the cursor of the next page when
+/// a page was asked for, and whether the scan of the peers was truncated.
+async fn filtered_nodes(
+    store: Arc<dyn PeerDB>,
+    max_scan_items: usize,
+    query: NodesQuery,
+) -> Result<(Vec<NodeData>, HeaderMap), (StatusCode, String)> {
+    let filter = query.peer_filter();
+    let mut truncated = false;
+    let (peers, next_cursor) = if query.limit.is_some() || query.cursor.is_some() {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT);
         store
-            .all_peers(Some(50))
+            .all_peers_cursor(limit, query.cursor.clone())
             .await
-            .unwrap()
-            .into_iter()
-            .map(|peer| {
-                let client_version = peer.client_version;
-                ClientData { client_version }
+            .map_err(scan_error)?
+    } else {
+        let max_items = Some(max_scan_items);
+        let scanned = match query.city.clone() {
+            Some(city) => store.peers_by_city(city, max_items).await,
+            None if !filter.is_empty() => store.query_peers(filter.clone(), max_items).await,
+            None => store.scan_peers(max_items).await,
+        }
+        .map_err(scan_error)?;
+        truncated = scanned.truncated;
+        (scanned.peers, None)
+    };
+    let nodes = peers
+        .into_iter()
+        .map(NodeData::from)
+        .filter(|node| query.matches(&filter, node))
+        .collect();
+    let mut headers = next_cursor_header(next_cursor);
+    headers.extend(truncated_header(truncated));
+    Ok((nodes, headers))
+}
+
+/// Reply with a bad request for an invalid cursor, and an internal error otherwise.
+fn scan_error(error: ScanTableError) -> (StatusCode, String) {
+    match error {
+        ScanTableError::InvalidCursor(_) => (StatusCode::BAD_REQUEST, error.to_string()),
+        error => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+    }
+}
+
+/// The header pointing to the next page, if there's one.
+fn next_cursor_header(next_cursor: Option<String>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(next_cursor) = next_cursor {
+        headers.insert(
+            NEXT_CURSOR_HEADER,
+            HeaderValue::from_str(&next_cursor).expect("cursors are base64"),
+        );
+    }
+    headers
+}
+
+/// The header flagging a response computed from a truncated scan, if it was.
+fn truncated_header(truncated: bool) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if truncated {
+        headers.insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    headers
+}
+
+/// The peers of `/nodes` as a JSON array, or streamed as NDJSON when the request accepts `application/x-ndjson`.
+async fn get_nodes(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+    request_headers: HeaderMap,
+    Query(query): Query<NodesQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    if accepts_ndjson(&request_headers) {
+        return stream_nodes(store, query);
+    }
+    let (nodes, headers) = filtered_nodes(store, max_scan_items, query).await?;
+    Ok((headers, Json(nodes)).into_response())
+}
+
+/// Whether the `Accept` header of a request lists NDJSON.
+fn accepts_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| {
+            accept.split(',').any(|media_type| {
+                let media_type = media_type.split(';').next().unwrap_or_default();
+                media_type.trim().eq_ignore_ascii_case(NDJSON_CONTENT_TYPE)
             })
-            .collect(),
+        })
+}
+
+/// Stream the peers of `/nodes` as NDJSON, walking the table page by page from the cursor of the query so that it's
+/// never held in memory at once. The page limit is ignored.
+fn stream_nodes(
+    store: Arc<dyn PeerDB>,
+    query: NodesQuery,
+) -> Result<Response, (StatusCode, String)> {
+    // a bad cursor can't be reported once the response started
+    if let Some(cursor) = &query.cursor {
+        decode_cursor(cursor).map_err(scan_error)?;
+    }
+    let (lines, receiver) = mpsc::channel::<Result<String, Infallible>>(NDJSON_PAGE_SIZE);
+    tokio::spawn(async move {
+        let filter = query.peer_filter();
+        let mut cursor = query.cursor.clone();
+        loop {
+            let (peers, next_cursor) = match store.all_peers_cursor(NDJSON_PAGE_SIZE, cursor).await
+            {
+                Ok(page) => page,
+                Err(e) => {
+                    // the response just ends early
+                    warn!("Failed to stream the nodes: {e}");
+                    return;
+                }
+            };
+            let nodes = peers
+                .into_iter()
+                .map(NodeData::from)
+                .filter(|node| query.matches(&filter, node));
+            for node in nodes {
+                let line = match serde_json::to_string(&node) {
+                    Ok(line) => line + "\n",
+                    Err(e) => {
+                        warn!("Failed to serialize peer {}: {e}", node.peer.id);
+                        continue;
+                    }
+                };
+                // the client went away
+                if lines.send(Ok(line)).await.is_err() {
+                    return;
+                }
+            }
+            let Some(next_cursor) = next_cursor else {
+                return;
+            };
+            cursor = Some(next_cursor);
+        }
+    });
+    Ok((
+        [(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        StreamBody::new(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+/// The peers of `/nodes` as a GeoJSON `FeatureCollection` of points, skipping the peers without coordinates.
+async fn get_nodes_geojson(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+    Query(query): Query<NodesQuery>,
+) -> Result<(HeaderMap, Json<Value>), (StatusCode, String)> {
+    let (nodes, headers) = filtered_nodes(store, max_scan_items, query).await?;
+    let features: Vec<Value> = nodes
+        .into_iter()
+        .filter_map(|node| {
+            let (Some(latitude), Some(longitude)) = (node.peer.latitude, node.peer.longitude)
+            else {
+                return None;
+            };
+            Some(json!({
+                "type": "Feature",
+                // GeoJSON positions are longitude first
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [longitude, latitude],
+                },
+                "properties": {
+                    "id": node.peer.id,
+                    "client_version": node.peer.client_version,
+                    "country": node.peer.country,
+                    "isp": node.peer.isp,
+                },
+            }))
+        })
+        .collect();
+    Ok((
+        headers,
+        Json(json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })),
+    ))
+}
+
+async fn get_random_nodes(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+    Query(query): Query<RandomPeersQuery>,
+) -> Result<(HeaderMap, Json<Vec<PeerData>>), (StatusCode, String)> {
+    let n = query
+        .n
+        .unwrap_or(DEFAULT_RANDOM_PEERS)
+        .min(MAX_RANDOM_PEERS);
+    let sampled = store
+        .random_peers(n, Some(max_scan_items))
+        .await
+        .map_err(scan_error)?;
+    Ok((truncated_header(sampled.truncated), Json(sampled.peers)))
+}
+
+async fn get_clients(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<ClientData>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(
+            scanned
+                .peers
+                .into_iter()
+                .map(|peer| {
+                    let client_version = peer.client_version;
+                    ClientData { client_version }
+                })
+                .collect(),
+        ),
     )
 }
 
+async fn get_summary(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> Result<(HeaderMap, Json<NetworkSummary>), (StatusCode, String)> {
+    let (summary, truncated) = store
+        .summary(Some(max_scan_items))
+        .await
+        .map_err(scan_error)?;
+    Ok((truncated_header(truncated), Json(summary)))
+}
+
+async fn get_client_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+    Query(query): Query<ClientsQuery>,
+) -> (HeaderMap, Json<Vec<ClientCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_clients(
+            &scanned.peers,
+            query.group_by.unwrap_or_default(),
+        )),
+    )
+}
+
+async fn get_p2p_version_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<P2pVersionCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_p2p_versions(&scanned.peers)),
+    )
+}
+
+/// The peers by negotiated and by advertised eth version, e.g. how many advertise eth/66 but negotiate eth/68.
+async fn get_eth_version_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<EthVersionCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_eth_versions(&scanned.peers)),
+    )
+}
+
+async fn get_city_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<CityCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_cities(&scanned.peers)),
+    )
+}
+
+async fn get_source_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<SourceCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_sources(&scanned.peers)),
+    )
+}
+
+async fn get_genesis_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<GenesisCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_genesis(&scanned.peers)),
+    )
+}
+
+/// The share of the peers seen since a time that are synced, a health indicator of the network.
+async fn get_synced_ratio(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+    Query(query): Query<SyncedRatioQuery>,
+) -> Result<(HeaderMap, Json<SyncedRatio>), (StatusCode, String)> {
+    let since = match &query.since {
+        Some(since) => DateTime::parse_from_rfc3339(since)
+            .map_err(|e| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid since `{since}`: {e}"),
+                )
+            })?
+            .with_timezone(&Utc),
+        None => Utc::now() - Duration::days(1),
+    };
+    // `last_seen` is saved in the display format of the times, which sorts like them
+    let scanned = store
+        .peers_seen_since(since.to_string(), Some(max_scan_items))
+        .await
+        .map_err(scan_error)?;
+    Ok((
+        truncated_header(scanned.truncated),
+        Json(SyncedRatio::from_peers(since.to_rfc3339(), &scanned.peers)),
+    ))
+}
+
+async fn get_asn_stats(
+    State(store): State<Arc<dyn PeerDB>>,
+    State(MaxScanItems(max_scan_items)): State<MaxScanItems>,
+) -> (HeaderMap, Json<Vec<AsnCount>>) {
+    let scanned = store.scan_peers(Some(max_scan_items)).await.unwrap();
+    (
+        truncated_header(scanned.truncated),
+        Json(group_asns(&scanned.peers)),
+    )
+}
+
+/// The latest block known by the crawler, what `synced` is relative to.
+async fn get_head(State(store): State<Arc<dyn PeerDB>>) -> Json<Option<ChainHead>> {
+    Json(store.latest_block().await.unwrap())
+}
+
 async fn get_node_by_id(
     State(store): State<Arc<dyn PeerDB>>,
     Path(id): Path<String>,
@@ -43,9 +537,71 @@ async fn get_node_by_id(
     Json(store.node_by_id(id).await.unwrap())
 }
 
+/// The snapshots the crawler recorded of a peer with `--record-history`, oldest first.
+async fn get_node_history(
+    State(store): State<Arc<dyn PeerDB>>,
+    Path(id): Path<String>,
+) -> Json<Vec<PeerData>> {
+    Json(store.peer_history(id).await.unwrap())
+}
+
 async fn get_node_by_ip(
     State(store): State<Arc<dyn PeerDB>>,
     Path(ip): Path<String>,
 ) -> Json<Option<Vec<PeerData>>> {
+    // ipv6 addresses have several spellings, the peers are stored with the canonical one
+    let ip = match ip.parse::<IpAddr>() {
+        Ok(parsed) => canonical_ip(parsed).to_string(),
+        Err(_) => ip,
+    };
     Json(store.node_by_ip(ip).await.unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use reth_crawler_db::InMemoryPeerDB;
+
+    use super::*;
+
+    /// A peer stored the way the crawler stores it, with the canonical form of `address`.
+    fn peer(id: &str, address: &str) -> PeerData {
+        serde_json::from_value(json!({
+            "enode_url": format!("enode://{id}@[{address}]:30303"),
+            "id": id,
+            "address": address,
+            "tcp_port": 30303,
+            "udp_port": 30303,
+            "client_version": "Geth/v1.13.5-stable/linux-amd64/go1.21.4",
+            "eth_version": 68,
+            "capabilities": ["eth/68"],
+            "chain": "mainnet",
+            "total_difficulty": "0",
+            "best_block": "0x00",
+            "genesis_block_hash": "0x00",
+            "last_seen": "2023-10-16 12:34:56.789 UTC",
+            "country": "",
+            "city": "",
+            "synced": null,
+            "isp": "",
+        }))
+        .expect("it's a valid peer")
+    }
+
+    #[tokio::test]
+    async fn finds_ipv6_peers_by_any_spelling_of_their_ip() {
+        let store: Arc<dyn PeerDB> = Arc::new(InMemoryPeerDB::new());
+        store.add_peer(peer("01", "2001:db8::7")).await.unwrap();
+        store.add_peer(peer("02", "203.0.113.7")).await.unwrap();
+
+        for spelling in ["2001:db8::7", "2001:0DB8:0000:0000:0000:0000:0000:0007"] {
+            let Json(found) =
+                get_node_by_ip(State(store.clone()), Path(spelling.to_string())).await;
+            let ids: Vec<_> = found.unwrap().into_iter().map(|peer| peer.id).collect();
+            assert_eq!(ids, vec!["01"]);
+        }
+        let Json(found) =
+            get_node_by_ip(State(store), Path("::ffff:203.0.113.7".to_string())).await;
+        let ids: Vec<_> = found.unwrap().into_iter().map(|peer| peer.id).collect();
+        assert_eq!(ids, vec!["02"]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use eyre::WrapErr;
+use reth_primitives::NodeRecord;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::crawler::pipeline::CrawlPipeline;
+
+/// Header that must carry the admin api key.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// State shared by the admin routes.
+#[derive(Clone)]
+struct AdminState {
+    /// Key required to call the admin routes.
+    api_key: String,
+    pipeline: Arc<CrawlPipeline>,
+}
+
+impl AdminState {
+    /// Check that the request carries the admin api key.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers.get(API_KEY_HEADER) {
+            Some(key) if key.as_bytes() == self.api_key.as_bytes() => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RecrawlRequest {
+    /// Enode url of the peer to crawl, e.g. `enode://<id>@192.0.2.1:30303`.
+    enode: String,
+}
+
+#[derive(Serialize)]
+struct RecrawlResponse {
+    /// Whether the peer was reached, its record is then updated.
+    reached: bool,
+}
+
+/// Bind the listener of the admin routes on `addr`.
+pub fn bind(addr: SocketAddr) -> eyre::Result<TcpListener> {
+    TcpListener::bind(addr).wrap_err_with(|| format!("failed to serve the admin routes on {addr}"))
+}
+
+/// Serve the admin routes of the crawler on `listener` until `shutdown` is cancelled.
+pub async fn serve(
+    listener: TcpListener,
+    api_key: String,
+    pipeline: Arc<CrawlPipeline>,
+    shutdown: CancellationToken,
+) -> eyre::Result<()> {
+    let app = Router::new()
+        .route("/admin/recrawl", post(recrawl))
+        .with_state(AdminState { api_key, pipeline });
+    info!("admin routes are served on {}", listener.local_addr()?);
+    axum::Server::from_tcp(listener)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+/// Crawl a peer right away, even if it was crawled recently.
+async fn recrawl(
+    State(state): State<AdminState>,
+    headers: HeaderMap,
+    Json(request): Json<RecrawlRequest>,
+) -> Result<Json<RecrawlResponse>, (StatusCode, String)> {
+    state
+        .authorize(&headers)
+        .map_err(|status| (status, "invalid api key".to_string()))?;
+    let peer = NodeRecord::from_str(&request.enode).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("invalid enode `{}`: {e}", request.enode),
+        )
+    })?;
+    info!("Recrawl of peer {} requested", peer.address);
+    let reached = state.pipeline.recrawl(peer).await;
+    Ok(Json(RecrawlResponse { reached }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn fails_to_bind_a_taken_address() {
+        let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let bound = bind(taken.local_addr().unwrap());
+
+        assert!(bound.is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ::metrics::counter;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::crawler::config::duration;
+use crate::crawler::metrics;
+
+/// Why an ip got banned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BanReason {
+    /// The peer failed the eth handshake, so it's not on our network.
+    WrongNetwork,
+    /// The peer failed too many p2p handshakes.
+    HandshakeFailures,
+    /// The peer didn't advertise a client version.
+    EmptyClientVersion,
+}
+
+impl BanReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanReason::WrongNetwork => "wrong_network",
+            BanReason::HandshakeFailures => "handshake_failures",
+            BanReason::EmptyClientVersion => "empty_client_version",
+        }
+    }
+}
+
+impl fmt::Display for BanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanReason::WrongNetwork => write!(f, "wrong network"),
+            BanReason::HandshakeFailures => write!(f, "handshake failures"),
+            BanReason::EmptyClientVersion => write!(f, "empty client version"),
+        }
+    }
+}
+
+/// How long an ip is banned for each [`BanReason`].
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BanDurations {
+    #[serde(with = "duration")]
+    pub wrong_network: Duration,
+    #[serde(with = "duration")]
+    pub handshake_failures: Duration,
+    #[serde(with = "duration")]
+    pub empty_client_version: Duration,
+}
+
+impl Default for BanDurations {
+    fn default() -> Self {
+        Self {
+            wrong_network: Duration::from_secs(24 * 60 * 60),
+            handshake_failures: Duration::from_secs(60 * 60),
+            empty_client_version: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl BanDurations {
+    fn duration(&self, reason: BanReason) -> Duration {
+        match reason {
+            BanReason::WrongNetwork => self.wrong_network,
+            BanReason::HandshakeFailures => self.handshake_failures,
+            BanReason::EmptyClientVersion => self.empty_client_version,
+        }
+    }
+}
+
+/// A ban as persisted on disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct BanRecord {
+    ip: IpAddr,
+    reason: BanReason,
+    /// Expiry of the ban, in seconds since the unix epoch.
+    expires_at: u64,
+}
+
+/// Ips we don't want to dial, each until its ban expires.
+///
+/// Bans issued through discv4 are permanent, so we keep our own list and check it before dialing a peer.
+/// When a file is set the list is written to it by [`BannedIps::persist`] after new bans, so that it survives restarts.
+#[derive(Debug, Default)]
+pub struct BannedIps {
+    bans: RwLock<HashMap<IpAddr, (Instant, BanReason)>>,
+    durations: BanDurations,
+    file: Option<PathBuf>,
+    /// Whether bans were issued since the list was last persisted.
+    dirty: AtomicBool,
+    /// How many bans were issued since the start, renewals included.
+    issued: AtomicU64,
+}
+
+impl BannedIps {
+    /// Create the list backed by `file`, loading the bans that didn't expire yet unless `clean` is set.
+    pub fn load(durations: BanDurations, file: PathBuf, clean: bool) -> Self {
+        let mut bans = HashMap::new();
+        if !clean {
+            match std::fs::read(&file) {
+                Ok(content) => match serde_json::from_slice::<Vec<BanRecord>>(&content) {
+                    Ok(records) => {
+                        let now = SystemTime::now();
+                        let instant_now = Instant::now();
+                        for record in records {
+                            let expires_at = UNIX_EPOCH + Duration::from_secs(record.expires_at);
+                            if let Ok(remaining) = expires_at.duration_since(now) {
+                                bans.insert(record.ip, (instant_now + remaining, record.reason));
+                            }
+                        }
+                        info!("Loaded {} bans from {}", bans.len(), file.display());
+                    }
+                    Err(e) => warn!("Failed to parse bans from {}: {e}", file.display()),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to read bans from {}: {e}", file.display()),
+            }
+        }
+        Self {
+            bans: RwLock::new(bans),
+            durations,
+            file: Some(file),
+            dirty: AtomicBool::new(false),
+            issued: AtomicU64::new(0),
+        }
+    }
+
+    /// Ban `ip` for the duration configured for `reason`.
+    pub fn ban(&self, ip: IpAddr, reason: BanReason) {
+        let until = Instant::now() + self.durations.duration(reason);
+        let mut bans = self.bans.write().expect("this should always work!");
+        bans.insert(ip, (until, reason));
+        self.dirty.store(true, Ordering::Relaxed);
+        counter!(metrics::BANS, 1, "reason" => reason.as_str());
+        self.issued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write the bans to the file, if any, unless none was issued since they were last written.
+    ///
+    /// It blocks on the file system, so it's meant to run on a blocking thread. The bans are written to a temporary
+    /// file first, which then replaces the file, so that a crash mid-write doesn't lose the previous list.
+    pub fn persist(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let now = SystemTime::now();
+        let instant_now = Instant::now();
+        let records: Vec<_> = {
+            let bans = self.bans.read().expect("this should always work!");
+            bans.iter()
+                .map(|(ip, (until, reason))| BanRecord {
+                    ip: *ip,
+                    reason: *reason,
+                    expires_at: (now + until.saturating_duration_since(instant_now))
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |expires_at| expires_at.as_secs()),
+                })
+                .collect()
+        };
+        let mut temp_file = file.clone().into_os_string();
+        temp_file.push(".tmp");
+        let result = serde_json::to_vec(&records)
+            .map_err(std::io::Error::from)
+            .and_then(|content| std::fs::write(&temp_file, content))
+            .and_then(|()| std::fs::rename(&temp_file, file));
+        if let Err(e) = result {
+            // try again on the next call
+            self.dirty.store(true, Ordering::Relaxed);
+            warn!("Failed to persist bans to {}: {e}", file.display());
+        }
+    }
+
+    /// Whether `ip` is currently banned.
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let bans = self.bans.read().expect("this should always work!");
+        bans.get(ip)
+            .is_some_and(|(until, _)| *until > Instant::now())
+    }
+
+    /// Drop the expired bans, returning how many were removed.
+    pub fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut bans = self.bans.write().expect("this should always work!");
+        let before = bans.len();
+        bans.retain(|_, (until, _)| *until > now);
+        before - bans.len()
+    }
+
+    /// Number of banned ips, including expired bans that weren't removed yet.
+    pub fn count(&self) -> usize {
+        self.bans.read().expect("this should always work!").len()
+    }
+
+    /// How many bans were issued so far.
+    pub fn issued(&self) -> u64 {
+        self.issued.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn persisted_bans_survive_a_restart() {
+        let file = std::env::temp_dir().join(format!(
+            "reth-crawler-persisted-bans-{}.json",
+            std::process::id()
+        ));
+        let ip = IpAddr::from(Ipv4Addr::new(203, 0, 113, 1));
+        let banned_ips = BannedIps::load(BanDurations::default(), file.clone(), true);
+
+        banned_ips.ban(ip, BanReason::WrongNetwork);
+        banned_ips.persist();
+        let reloaded = BannedIps::load(BanDurations::default(), file.clone(), false);
+
+        assert!(reloaded.is_banned(&ip));
+        assert!(!file.with_extension("json.tmp").exists());
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,520 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use discv5::Enr;
+use eyre::{ensure, WrapErr};
+use ipnetwork::IpNetwork;
+use reth_crawler_db::Backend;
+use reth_discv4::NatResolver;
+use reth_dns_discovery::tree::LinkEntry;
+use reth_primitives::NodeRecord;
+use secp256k1::SecretKey;
+use serde::{Deserialize, Serialize};
+
+use crate::crawler::geo::GeoProviderConfig;
+use crate::crawler::listener::eth_rpc::{is_http_url, is_ws_url};
+use crate::crawler::nodekey;
+use crate::crawler::savefilter::SaveFilter;
+use crate::crawler::{BanDurations, ScoreWeights};
+
+/// The DNS tree of all the mainnet nodes, published by the Ethereum Foundation.
+const MAINNET_DNS_TREE: &str =
+    "enrtree://AKA3AM6LPBYEUDMVNU3BSVQJ5AD45Y7YPOHJLEF6W26QOE4VTUDPE@all.mainnet.ethdisco.net";
+
+/// All the tunables of the crawler.
+///
+/// It can be loaded from a JSON file, any missing field takes its default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrawlerConfig {
+    /// Where the peers are stored: `aws`, `sqlite` or `memory`.
+    pub backend: Backend,
+    /// Comma separated eth RPC urls used to know the latest blocks, the crawler fails over from one to the next.
+    ///
+    /// New blocks are subscribed to with web socket urls and polled with http ones.
+    pub eth_rpc_url: String,
+    /// Interval between two polls of new blocks over http.
+    #[serde(with = "duration")]
+    pub block_poll_interval: Duration,
+    /// Client version the crawler advertises in its `Hello`, so that peers can tell it from a node.
+    pub client_id: String,
+    /// Max inbound connections.
+    pub max_inbound: usize,
+    /// Max outbound connections.
+    pub max_outbound: usize,
+    /// Interval between two discovery lookups.
+    #[serde(with = "duration")]
+    pub lookup_interval: Duration,
+    /// How long a peer found by discv4 waits for its ENR, and the fork id in it, before it's dialed anyway.
+    #[serde(with = "duration")]
+    pub enr_wait: Duration,
+    /// Timeout for each of the p2p and eth handshakes with a peer.
+    #[serde(with = "duration")]
+    pub handshake_timeout: Duration,
+    /// How many times handshakes failing with a transient error (timeout, reset connection...) are retried.
+    pub handshake_retries: u32,
+    /// Delay before the first handshake retry, doubled after each retry.
+    #[serde(with = "duration")]
+    pub handshake_retry_backoff: Duration,
+    /// Number of node identities the peers are dialed with in turn, the first being the node key.
+    pub identities: usize,
+    /// Delay before a peer that had too many peers is dialed again under the next identity, when there are several.
+    #[serde(with = "duration")]
+    pub too_many_peers_redial_delay: Duration,
+    /// How long a dialed peer has to send the header of its best block, used to measure how far behind it is.
+    /// Peers that don't answer in time are deemed synced if their best block is among the recent ones, `0s`
+    /// skips the request.
+    #[serde(with = "duration")]
+    pub head_request_timeout: Duration,
+    /// Enodes used to bootstrap discv4, e.g. `enode://<id>@192.0.2.1:30303`, along with those of `bootnodes_file`.
+    ///
+    /// They replace the mainnet boot nodes, unless `extend_bootnodes` is set.
+    pub bootnodes: Vec<String>,
+    /// File holding one enode per line used to bootstrap discv4, `#` starts a comment.
+    pub bootnodes_file: Option<PathBuf>,
+    /// Bootstrap discv4 from the mainnet boot nodes along with the custom ones, rather than replacing them.
+    pub extend_bootnodes: bool,
+    /// DNS trees the peers are discovered from, e.g. `enrtree://<public key>@all.mainnet.ethdisco.net`.
+    pub dns_trees: Vec<String>,
+    /// Address discv4, discv5 and the rlpx sessions listen on.
+    pub listen_addr: IpAddr,
+    /// UDP port discv4 listens on, 0 picks an ephemeral port.
+    pub discovery_port: u16,
+    /// TCP port the rlpx sessions are accepted on, 0 picks an ephemeral port.
+    pub rlpx_port: u16,
+    /// UDP port discv5 listens on.
+    pub discv5_port: u16,
+    /// ENRs used to bootstrap discv5, e.g. `enr:-IS4Q...`.
+    pub discv5_bootnodes: Vec<String>,
+    /// How the external ip advertised in the node records is found, like reth's `--nat`: `none`, `any`, `upnp`,
+    /// `publicip` or `extip:<ip>`.
+    pub nat: String,
+    /// External ip advertised in the node records, the same as setting `nat` to `extip:<ip>`.
+    pub external_ip: Option<IpAddr>,
+    /// File the secret key of the crawler is loaded from, or generated into when missing, so that its node identity
+    /// survives restarts. Defaults to `~/.reth-crawler/node.key`.
+    pub node_key_file: Option<PathBuf>,
+    /// Secret key of the crawler as hex, which takes precedence over `node_key_file`.
+    pub node_key: Option<String>,
+    /// How long ips are banned for each ban reason.
+    pub ban_durations: BanDurations,
+    /// File the bans are persisted to.
+    pub bans_file: PathBuf,
+    /// Ignore the bans persisted by a previous run.
+    pub clean_bans: bool,
+    /// Ip ranges that may be crawled, any ip may be if empty.
+    pub allow_cidrs: Vec<IpNetwork>,
+    /// Ip ranges that must never be crawled, even if they're allowed.
+    pub deny_cidrs: Vec<IpNetwork>,
+    /// When not empty, only the crawled peers matching one of these filters are saved.
+    pub save_filters: Vec<SaveFilter>,
+    /// Maximum number of peers being crawled at the same time.
+    pub max_concurrent_handshakes: usize,
+    /// How long after a successful crawl a peer isn't dialed again, only its `last_seen` is refreshed.
+    #[serde(with = "duration")]
+    pub recrawl_window: Duration,
+    /// How many recently crawled peers are remembered.
+    pub recent_peers_capacity: usize,
+    /// How many distinct peer ids an ip may show within `sybil_window` before its new ids are neither dialed nor saved,
+    /// unlimited if 0.
+    pub sybil_max_ids_per_ip: usize,
+    /// How long a peer id counts towards `sybil_max_ids_per_ip`.
+    #[serde(with = "duration")]
+    pub sybil_window: Duration,
+    /// Interval between two recrawls of stale peers.
+    #[serde(with = "duration")]
+    pub recrawl_interval: Duration,
+    /// Maximum number of stale peers recrawled at each interval.
+    pub recrawl_batch_size: usize,
+    /// How long after its `last_seen` a peer is considered stale.
+    #[serde(with = "duration")]
+    pub recrawl_stale_after: Duration,
+    /// How many failed recrawls in a row mark a peer as unreachable.
+    pub recrawl_max_failures: u32,
+    /// How much each crawl outcome moves the score of a peer, flaky peers are recrawled less often.
+    pub score_weights: ScoreWeights,
+    /// How long after its `last_seen` a peer is pruned from the db, peers are never pruned if 0.
+    #[serde(with = "duration")]
+    pub prune_older_than: Duration,
+    /// Interval between two prunes of the db.
+    #[serde(with = "duration")]
+    pub prune_interval: Duration,
+    /// How many peers are deleted at once while pruning.
+    pub prune_batch_size: usize,
+    /// Delay between two batches of deletes, so that a big prune doesn't take all the write capacity of the db.
+    #[serde(with = "duration")]
+    pub prune_batch_delay: Duration,
+    /// Only log how many peers would be pruned.
+    pub prune_dry_run: bool,
+    /// How long in-flight crawls are given to complete on shutdown.
+    #[serde(with = "duration")]
+    pub shutdown_grace_period: Duration,
+    /// How many blocks can a node be lagging and still be considered `synced`.
+    pub synced_threshold: u64,
+    /// How many failed p2p handshakes within `p2p_failure_window` get a peer banned.
+    pub p2p_failure_threshold: u32,
+    /// How long a failed p2p handshake is counted against a peer.
+    #[serde(with = "duration")]
+    pub p2p_failure_window: Duration,
+    /// How many times a peer can be removed from the network within `removed_peer_window` before its recrawls are
+    /// deferred.
+    pub removed_peer_threshold: u32,
+    /// How long the removal of a peer from the network is counted against it.
+    #[serde(with = "duration")]
+    pub removed_peer_window: Duration,
+    /// How many crawled peers can wait to be written to the db, the crawls wait for room beyond that.
+    pub writer_queue_capacity: usize,
+    /// How many surfaced peers can wait for a crawl worker, the listeners wait for room beyond that.
+    pub candidate_queue_capacity: usize,
+    /// How many ip locations are cached.
+    pub geolocation_cache_capacity: usize,
+    /// How long a cached ip location is fresh, a stale one is looked up again but still used if that fails.
+    #[serde(with = "duration")]
+    pub geolocation_cache_ttl: Duration,
+    /// Online geolocation providers, tried in order until one locates an ip, with their max requests per minute.
+    pub geolocation_providers: Vec<GeoProviderConfig>,
+    /// Key of the ip-api pro tier, `ip-api` is then asked through the pro endpoint and its rate limit can be raised.
+    pub geo_api_key: Option<String>,
+    /// MaxMind GeoLite2-City database to geolocate the ips offline, the online providers are only asked for the ips it lacks.
+    pub geoip_db: Option<PathBuf>,
+    /// MaxMind GeoLite2-ASN database telling the isp of the ips, along with `geoip_db`.
+    pub geoip_asn_db: Option<PathBuf>,
+    /// Never ask the online providers, the ips `geoip_db` lacks stay unlocated.
+    pub geoip_offline: bool,
+    /// Skip saving the network sessions whose ip was crawled recently under another peer id.
+    pub dedup_network_ip: bool,
+    /// Only record the discovered peers, without handshaking with them nor geolocating them.
+    pub discovery_only: bool,
+    /// Store the key/value pairs of the ENRs the peers are discovered with, as JSON.
+    pub store_enr: bool,
+    /// Also append a snapshot of each crawled peer to its history, on top of replacing its record.
+    pub record_history: bool,
+    /// Print the crawled peers as JSON lines instead of saving them, nothing reaches the db of `backend`.
+    pub dry_run: bool,
+    /// File the peers of a dry run are printed to, stdout if unset.
+    pub dry_run_output: Option<PathBuf>,
+    /// Address the Prometheus metrics are served on, they aren't served if unset.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Address the admin routes are served on, e.g. `POST /admin/recrawl`, they aren't served if unset.
+    pub admin_addr: Option<SocketAddr>,
+    /// Api key required by the admin routes, passed in the `x-api-key` header.
+    pub admin_api_key: Option<String>,
+    /// Check whether the peers that connected to us accept connections on their advertised port.
+    pub probe_ports: bool,
+    /// How long the port probes wait for the connection.
+    #[serde(with = "duration")]
+    pub port_probe_timeout: Duration,
+}
+
+impl Default for CrawlerConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Aws,
+            eth_rpc_url: "wss://localhost:8546".to_string(),
+            block_poll_interval: Duration::from_secs(12),
+            client_id: format!("reth-crawler/{}", env!("CARGO_PKG_VERSION")),
+            max_inbound: 10000,
+            max_outbound: 0,
+            lookup_interval: Duration::from_secs(3),
+            enr_wait: Duration::from_secs(1),
+            handshake_timeout: Duration::from_secs(10),
+            handshake_retries: 2,
+            handshake_retry_backoff: Duration::from_secs(1),
+            identities: 1,
+            too_many_peers_redial_delay: Duration::from_secs(10),
+            head_request_timeout: Duration::from_secs(5),
+            bootnodes: vec![],
+            bootnodes_file: None,
+            extend_bootnodes: false,
+            dns_trees: vec![MAINNET_DNS_TREE.to_string()],
+            listen_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            discovery_port: 30303,
+            rlpx_port: 30303,
+            discv5_port: 30304,
+            discv5_bootnodes: vec![],
+            nat: "none".to_string(),
+            external_ip: None,
+            node_key_file: None,
+            node_key: None,
+            ban_durations: BanDurations::default(),
+            bans_file: PathBuf::from("banned_ips.json"),
+            clean_bans: false,
+            allow_cidrs: vec![],
+            deny_cidrs: vec![],
+            save_filters: vec![],
+            max_concurrent_handshakes: 256,
+            recrawl_window: Duration::from_secs(6 * 60 * 60),
+            recent_peers_capacity: 50_000,
+            sybil_max_ids_per_ip: 5,
+            sybil_window: Duration::from_secs(60 * 60),
+            recrawl_interval: Duration::from_secs(10 * 60),
+            recrawl_batch_size: 100,
+            recrawl_stale_after: Duration::from_secs(24 * 60 * 60),
+            recrawl_max_failures: 3,
+            score_weights: ScoreWeights::default(),
+            prune_older_than: Duration::ZERO,
+            prune_interval: Duration::from_secs(24 * 60 * 60),
+            prune_batch_size: 500,
+            prune_batch_delay: Duration::from_secs(1),
+            prune_dry_run: false,
+            shutdown_grace_period: Duration::from_secs(15),
+            synced_threshold: 100,
+            p2p_failure_threshold: 5,
+            p2p_failure_window: Duration::from_secs(60 * 60),
+            removed_peer_threshold: 3,
+            removed_peer_window: Duration::from_secs(10 * 60),
+            writer_queue_capacity: 1000,
+            candidate_queue_capacity: 1000,
+            geolocation_cache_capacity: 50_000,
+            geolocation_cache_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            geolocation_providers: GeoProviderConfig::defaults(),
+            geo_api_key: None,
+            geoip_db: None,
+            geoip_asn_db: None,
+            geoip_offline: false,
+            dedup_network_ip: false,
+            discovery_only: false,
+            store_enr: false,
+            record_history: false,
+            dry_run: false,
+            dry_run_output: None,
+            metrics_addr: None,
+            admin_addr: None,
+            admin_api_key: None,
+            probe_ports: false,
+            port_probe_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl CrawlerConfig {
+    /// Load the config from a JSON file.
+    pub fn from_file(path: &Path) -> eyre::Result<Self> {
+        let content = std::fs::read(path)
+            .wrap_err_with(|| format!("failed to read config file {}", path.display()))?;
+        serde_json::from_slice(&content)
+            .wrap_err_with(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    /// Check that the config can be used to start the crawler.
+    pub fn validate(&self) -> eyre::Result<()> {
+        let eth_rpc_urls = self.eth_rpc_urls();
+        ensure!(!eth_rpc_urls.is_empty(), "eth_rpc_url must not be empty");
+        for url in eth_rpc_urls {
+            ensure!(
+                is_ws_url(&url) || is_http_url(&url),
+                "eth_rpc_url must be a web socket or http url, got `{url}`"
+            );
+        }
+        ensure!(!self.client_id.is_empty(), "client_id must not be empty");
+        ensure!(
+            self.max_concurrent_handshakes > 0,
+            "max_concurrent_handshakes must be greater than 0"
+        );
+        ensure!(self.identities > 0, "identities must be greater than 0");
+        ensure!(
+            self.recent_peers_capacity > 0,
+            "recent_peers_capacity must be greater than 0"
+        );
+        ensure!(
+            !self.prune_interval.is_zero(),
+            "prune_interval must be greater than 0"
+        );
+        ensure!(
+            self.prune_batch_size > 0,
+            "prune_batch_size must be greater than 0"
+        );
+        ensure!(
+            !self.sybil_window.is_zero(),
+            "sybil_window must be greater than 0"
+        );
+        ensure!(
+            self.writer_queue_capacity > 0,
+            "writer_queue_capacity must be greater than 0"
+        );
+        ensure!(
+            self.candidate_queue_capacity > 0,
+            "candidate_queue_capacity must be greater than 0"
+        );
+        ensure!(
+            !self.geolocation_providers.is_empty(),
+            "geolocation_providers must not be empty"
+        );
+        for (index, config) in self.geolocation_providers.iter().enumerate() {
+            ensure!(
+                config.requests_per_minute > 0,
+                "requests_per_minute of the geolocation provider {} must be greater than 0",
+                config.provider
+            );
+            ensure!(
+                !self.geolocation_providers[..index]
+                    .iter()
+                    .any(|previous| previous.provider == config.provider),
+                "geolocation provider {} is listed twice",
+                config.provider
+            );
+        }
+        ensure!(
+            self.geoip_asn_db.is_none() || self.geoip_db.is_some(),
+            "geoip_asn_db requires geoip_db"
+        );
+        ensure!(
+            self.dry_run_output.is_none() || self.dry_run,
+            "dry_run_output requires dry_run"
+        );
+        ensure!(
+            !self.geoip_offline || self.geoip_db.is_some(),
+            "geoip_offline requires geoip_db"
+        );
+        ensure!(
+            self.synced_threshold > 0,
+            "synced_threshold must be greater than 0"
+        );
+        ensure!(
+            self.p2p_failure_threshold > 0 && !self.p2p_failure_window.is_zero(),
+            "p2p_failure_threshold and p2p_failure_window must not be zero"
+        );
+        ensure!(
+            self.removed_peer_threshold > 0 && !self.removed_peer_window.is_zero(),
+            "removed_peer_threshold and removed_peer_window must not be zero"
+        );
+        ensure!(
+            self.score_weights.success >= 0
+                && self.score_weights.failure <= 0
+                && self.score_weights.protocol_breach <= 0
+                && self.score_weights.useless_peer <= 0,
+            "score_weights.success must not be negative, and the other weights not positive"
+        );
+        ensure!(
+            self.admin_addr.is_none() || self.admin_api_key.is_some(),
+            "admin_api_key must be set to serve the admin routes"
+        );
+        ensure!(
+            !self.lookup_interval.is_zero()
+                && !self.recrawl_interval.is_zero()
+                && !self.block_poll_interval.is_zero(),
+            "lookup_interval, recrawl_interval and block_poll_interval must not be zero"
+        );
+        self.bootnodes()?;
+        self.dns_trees()?;
+        self.discv5_bootnodes()?;
+        ensure!(
+            self.external_ip.is_none() || self.nat == "none",
+            "external_ip and nat must not both be set"
+        );
+        self.nat_resolver()?;
+        if let Some(node_key) = &self.node_key {
+            nodekey::parse_node_key(node_key)?;
+        }
+        Ok(())
+    }
+
+    /// The eth RPC urls, in failover order.
+    pub fn eth_rpc_urls(&self) -> Vec<String> {
+        self.eth_rpc_url
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// The custom discv4 boot nodes, from `bootnodes` then `bootnodes_file`.
+    pub fn bootnodes(&self) -> eyre::Result<Vec<NodeRecord>> {
+        let mut bootnodes = self
+            .bootnodes
+            .iter()
+            .map(|enode| {
+                NodeRecord::from_str(enode.trim())
+                    .map_err(|e| eyre::eyre!("invalid bootnode {enode}: {e}"))
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        if let Some(path) = &self.bootnodes_file {
+            let content = fs::read_to_string(path)
+                .wrap_err_with(|| format!("failed to read bootnodes file {}", path.display()))?;
+            for (index, line) in content.lines().enumerate() {
+                let enode = line.split('#').next().unwrap_or_default().trim();
+                if enode.is_empty() {
+                    continue;
+                }
+                let bootnode = NodeRecord::from_str(enode).map_err(|e| {
+                    eyre::eyre!(
+                        "invalid bootnode {enode} at line {} of {}: {e}",
+                        index + 1,
+                        path.display()
+                    )
+                })?;
+                bootnodes.push(bootnode);
+            }
+        }
+        Ok(bootnodes)
+    }
+
+    /// The parsed DNS trees.
+    pub fn dns_trees(&self) -> eyre::Result<Vec<LinkEntry>> {
+        self.dns_trees
+            .iter()
+            .map(|tree| {
+                LinkEntry::from_str(tree.trim())
+                    .map_err(|e| eyre::eyre!("invalid dns tree {tree}: {e}"))
+            })
+            .collect()
+    }
+
+    /// The secret key of the crawler, from `node_key` or else `node_key_file`, which is generated when missing.
+    pub fn node_key(&self) -> eyre::Result<SecretKey> {
+        match &self.node_key {
+            Some(node_key) => nodekey::parse_node_key(node_key),
+            None => nodekey::load_or_generate(
+                &self
+                    .node_key_file
+                    .clone()
+                    .unwrap_or_else(nodekey::default_node_key_file),
+            ),
+        }
+    }
+
+    /// How the external ip advertised in the node records is found, `external_ip` taking precedence over `nat`.
+    pub fn nat_resolver(&self) -> eyre::Result<NatResolver> {
+        if let Some(external_ip) = self.external_ip {
+            return Ok(NatResolver::ExternalIp(external_ip));
+        }
+        NatResolver::from_str(self.nat.trim())
+            .map_err(|e| eyre::eyre!("invalid nat {}: {e}", self.nat))
+    }
+
+    /// Domain of the `index`th DNS tree, e.g. `all.mainnet.ethdisco.net`.
+    pub fn dns_tree_domain(&self, index: usize) -> Option<String> {
+        let (_, domain) = self.dns_trees.get(index)?.trim().rsplit_once('@')?;
+        Some(domain.to_string())
+    }
+
+    /// The parsed discv5 boot nodes.
+    pub fn discv5_bootnodes(&self) -> eyre::Result<Vec<Enr>> {
+        self.discv5_bootnodes
+            .iter()
+            .map(|enr| {
+                Enr::from_str(enr).map_err(|e| eyre::eyre!("invalid discv5 bootnode {enr}: {e}"))
+            })
+            .collect()
+    }
+}
+
+/// (De)serialize durations as human readable strings, e.g. `10s` or `24h`.
+pub(crate) mod duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let duration = String::deserialize(deserializer)?;
+        humantime::parse_duration(&duration).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use eyre::WrapErr;
+use reth_crawler_db::types::{
+    AddItemError, ChainHead, DeleteItemError, NetworkSummary, PeerFilter, QueryItemError,
+    ScanTableError, ScannedPeers,
+};
+use reth_crawler_db::{InMemoryPeerDB, PeerDB, PeerData};
+use tracing::warn;
+
+/// Prints the saved peers as JSON lines instead of persisting them, for dry runs.
+///
+/// The peers are also kept in memory, so that the crawler reads back what it saved like it does with a real db.
+pub struct PrintPeerDB {
+    inner: InMemoryPeerDB,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl PrintPeerDB {
+    /// Print the peers to `output`, or to stdout if unset.
+    pub fn new(output: Option<&Path>) -> eyre::Result<Self> {
+        let output: Box<dyn Write + Send> = match output {
+            Some(path) => Box::new(BufWriter::new(File::create(path).wrap_err_with(|| {
+                format!("failed to create the output file {}", path.display())
+            })?)),
+            None => Box::new(io::stdout()),
+        };
+        Ok(Self {
+            inner: InMemoryPeerDB::new(),
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Print each of `peers` on its own line.
+    fn print<'a>(&self, peers: impl IntoIterator<Item = &'a PeerData>) {
+        let mut output = self.output.lock().expect("this should always work!");
+        for peer_data in peers {
+            let result = serde_json::to_string(peer_data)
+                .map_err(io::Error::from)
+                .and_then(|line| writeln!(output, "{line}"));
+            if let Err(e) = result {
+                warn!("Failed to print peer {}: {}", peer_data.id, e);
+            }
+        }
+        // a line is only useful once it's complete, and the crawler may be killed at any time
+        if let Err(e) = output.flush() {
+            warn!("Failed to flush the printed peers: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl PeerDB for PrintPeerDB {
+    async fn add_peer(&self, peer_data: PeerData) -> Result<(), AddItemError> {
+        self.print([&peer_data]);
+        self.inner.add_peer(peer_data).await
+    }
+
+    async fn add_peers(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        self.print(&peers);
+        self.inner.add_peers(peers).await
+    }
+
+    async fn upsert_peers(&self, peers: Vec<PeerData>) -> Result<Vec<PeerData>, AddItemError> {
+        self.print(&peers);
+        self.inner.upsert_peers(peers).await
+    }
+
+    async fn touch_peer(&self, id: String, last_seen: String) -> Result<(), AddItemError> {
+        self.inner.touch_peer(id, last_seen).await
+    }
+
+    async fn mark_unreachable(&self, id: String) -> Result<(), AddItemError> {
+        self.inner.mark_unreachable(id).await
+    }
+
+    async fn record_failure(
+        &self,
+        id: String,
+        failure: String,
+        error: String,
+        failed_at: String,
+    ) -> Result<(), AddItemError> {
+        self.inner
+            .record_failure(id, failure, error, failed_at)
+            .await
+    }
+
+    async fn record_disconnect(&self, id: String, reason: String) -> Result<(), AddItemError> {
+        self.inner.record_disconnect(id, reason).await
+    }
+
+    async fn adjust_score(&self, id: String, delta: i64) -> Result<(), AddItemError> {
+        self.inner.adjust_score(id, delta).await
+    }
+
+    async fn stale_peers(
+        &self,
+        last_seen: String,
+        limit: usize,
+    ) -> Result<Vec<PeerData>, ScanTableError> {
+        self.inner.stale_peers(last_seen, limit).await
+    }
+
+    async fn all_peers(&self, page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
+        self.inner.all_peers(page_size).await
+    }
+
+    async fn scan_peers(&self, max_items: Option<usize>) -> Result<ScannedPeers, ScanTableError> {
+        self.inner.scan_peers(max_items).await
+    }
+
+    async fn peers_seen_since(
+        &self,
+        last_seen: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.inner.peers_seen_since(last_seen, max_items).await
+    }
+
+    async fn all_peers_cursor(
+        &self,
+        limit: usize,
+        cursor: Option<String>,
+    ) -> Result<(Vec<PeerData>, Option<String>), ScanTableError> {
+        self.inner.all_peers_cursor(limit, cursor).await
+    }
+
+    async fn query_peers(
+        &self,
+        filter: PeerFilter,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.inner.query_peers(filter, max_items).await
+    }
+
+    async fn node_by_id(&self, id: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
+        self.inner.node_by_id(id).await
+    }
+
+    async fn node_by_ip(&self, ip: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
+        self.inner.node_by_ip(ip).await
+    }
+
+    async fn peers_by_city(
+        &self,
+        city: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.inner.peers_by_city(city, max_items).await
+    }
+
+    async fn random_peers(
+        &self,
+        n: usize,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.inner.random_peers(n, max_items).await
+    }
+
+    async fn delete_peers_older_than(
+        &self,
+        last_seen: String,
+        limit: Option<usize>,
+        dry_run: bool,
+    ) -> Result<u64, DeleteItemError> {
+        self.inner
+            .delete_peers_older_than(last_seen, limit, dry_run)
+            .await
+    }
+
+    async fn set_latest_block(&self, head: ChainHead) -> Result<(), AddItemError> {
+        self.inner.set_latest_block(head).await
+    }
+
+    async fn latest_block(&self) -> Result<Option<ChainHead>, QueryItemError> {
+        self.inner.latest_block().await
+    }
+
+    async fn append_history(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        self.inner.append_history(peers).await
+    }
+
+    async fn peer_history(&self, id: String) -> Result<Vec<PeerData>, QueryItemError> {
+        self.inner.peer_history(id).await
+    }
+
+    async fn summary(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<(NetworkSummary, bool), ScanTableError> {
+        self.inner.summary(max_items).await
+    }
+}
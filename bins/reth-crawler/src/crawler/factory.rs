@@ -1,112 +1,262 @@
+use discv5::enr::{CombinedKey, EnrBuilder};
+use discv5::{Discv5, Discv5ConfigBuilder};
+use eyre::WrapErr;
 use once_cell::sync::Lazy;
-use reth_discv4::{Discv4, Discv4ConfigBuilder, DEFAULT_DISCOVERY_ADDRESS};
+use reth_crawler_db::{build_peer_db, PeerDB};
+use reth_discv4::{Discv4, Discv4ConfigBuilder, NatResolver};
 use reth_dns_discovery::{DnsDiscoveryConfig, DnsDiscoveryService, DnsResolver};
+use reth_ecies::util::pk2id;
+use reth_eth_wire::HelloMessage;
 
-use reth_network::config::rng_secret_key;
 use reth_network::{NetworkConfig, NetworkManager, PeersConfig};
 use reth_primitives::{mainnet_nodes, NodeRecord};
 use reth_provider::test_utils::NoopProvider;
+use secp256k1::{SecretKey, SECP256K1};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::Duration;
 
+use tracing::{info, warn};
+
+use crate::crawler::admin;
+use crate::crawler::dryrun::PrintPeerDB;
+use crate::crawler::listener::Sources;
+use crate::crawler::CrawlerConfig;
 use crate::crawler::CrawlerService;
 
 pub static MAINNET_BOOT_NODES: Lazy<Vec<NodeRecord>> = Lazy::new(mainnet_nodes);
 
 /// Builder for a [`CrawlerService`]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct CrawlerBuilder {
-    /// Whether or not to use a local db
-    local_db: bool,
-    /// Eth RPC url
-    eth_rpc_url: Option<String>,
-    /// Max inbound connections for the crawler
-    max_inbound: usize,
-    /// Max outbound connections for the crawler
-    max_outbound: usize,
-    /// The lookup interval for the crawler
-    lookup_interval: Duration,
-}
-
-impl Default for CrawlerBuilder {
-    fn default() -> Self {
-        Self {
-            local_db: false,
-            eth_rpc_url: None,
-            max_inbound: 10000,
-            max_outbound: 0,
-            lookup_interval: Duration::from_secs(3),
-        }
-    }
+    /// Tunables of the crawler
+    config: CrawlerConfig,
 }
 
 impl CrawlerBuilder {
-    /// Enable the local db
-    pub fn with_local_db(mut self) -> Self {
-        self.local_db = true;
-        self
-    }
-
-    /// Disable the local db
-    pub fn without_local_db(mut self) -> Self {
-        self.local_db = false;
-        self
-    }
-
-    /// Set the eth rpc url
-    pub fn with_eth_rpc_url(mut self, eth_rpc_url: String) -> Self {
-        self.eth_rpc_url = Some(eth_rpc_url);
+    /// Set the config of the crawler, it should have been validated beforehand
+    pub fn with_config(mut self, config: CrawlerConfig) -> Self {
+        self.config = config;
         self
     }
 
-    /// Build the [`CrawlerService`]
-    pub async fn build(self) -> CrawlerService {
-        // Ensure the rpc url is set
-        let provider_url = self.eth_rpc_url.expect("eth rpc url must be provided");
-
-        // Setup configs related to this 'node' by creating a new random
-        let key = rng_secret_key();
-        let enr = NodeRecord::from_secret_key(DEFAULT_DISCOVERY_ADDRESS, &key);
+    /// Build the [`CrawlerService`], failing if one of its ports can't be bound
+    pub async fn build(self) -> eyre::Result<CrawlerService> {
+        // Setup configs related to this 'node' with its persisted key
+        let key = self.config.node_key()?;
+        info!(
+            "The crawler's peer id is {}",
+            pk2id(&key.public_key(SECP256K1))
+        );
+        let nat = self
+            .config
+            .nat_resolver()
+            .expect("nat is validated with the config");
+        let external_ip = self.external_ip(nat).await;
         // Setup discovery v4 protocol to find peers to talk to
+        let mut bootnodes = self
+            .config
+            .bootnodes()
+            .expect("bootnodes are validated with the config");
+        if bootnodes.is_empty() || self.config.extend_bootnodes {
+            bootnodes.extend(MAINNET_BOOT_NODES.iter().copied());
+        }
         let mut discv4_cfg = Discv4ConfigBuilder::default();
         discv4_cfg
-            .add_boot_nodes(MAINNET_BOOT_NODES.clone())
-            .lookup_interval(self.lookup_interval);
+            .add_boot_nodes(bootnodes)
+            .lookup_interval(self.config.lookup_interval)
+            // request the ENRs of the peers to learn their fork id before dialing them
+            .enable_eip868(true)
+            // keeps the advertised ip up to date, e.g. when the upnp mapping changes
+            .external_ip_resolver(Some(nat));
 
         let peer_config = PeersConfig::default()
-            .with_max_outbound(self.max_outbound)
-            .with_max_inbound(self.max_inbound);
+            .with_max_outbound(self.config.max_outbound)
+            .with_max_inbound(self.config.max_inbound);
 
         // disable discovery here since we already handle outbound connections (devp2p/eth handshakes in our case) for newly discovered peers "manually", and do not need Swarm/NetworkState to handle those outbound handshakes for us
         // we do however want inbound TCP (note: discv4 listens only for udp disc proto messages) connections to be handled
+        // inbound sessions see the same client version as the dialed peers
+        let hello_message = HelloMessage::builder(pk2id(&key.public_key(SECP256K1)))
+            .client_version(&self.config.client_id)
+            .build();
         let builder = NetworkConfig::<()>::builder(key)
+            .listener_addr(SocketAddr::new(
+                self.config.listen_addr,
+                self.config.rlpx_port,
+            ))
             .disable_discovery()
-            .peer_config(peer_config);
+            .peer_config(peer_config)
+            .hello_message(hello_message);
 
         let net_conf = builder.build(Arc::from(NoopProvider::default()));
-        let network = NetworkManager::new(net_conf).await.unwrap();
+        let network = NetworkManager::new(net_conf).await.wrap_err_with(|| {
+            format!(
+                "failed to listen for rlpx sessions on {}:{}",
+                self.config.listen_addr, self.config.rlpx_port
+            )
+        })?;
         let net_handle = network.handle().clone();
+        let rlpx_addr = network.local_addr();
 
-        let dnsdisc_cfg = DnsDiscoveryConfig::default();
-        // Start discovery protocol
-        let discv4 = Discv4::spawn(enr.udp_addr(), enr, key, discv4_cfg.build())
+        // Start discovery protocol, it listens on `listen_addr` but advertises the external ip
+        let discovery_addr = SocketAddr::new(self.config.listen_addr, self.config.discovery_port);
+        let mut enr = self.local_node_record(&key, external_ip, rlpx_addr.port());
+        let discv4 = Discv4::spawn(discovery_addr, enr, key, discv4_cfg.build())
             .await
-            .unwrap();
-        let (dns_disc_service, dnsdisc) = DnsDiscoveryService::new_pair(
-            Arc::new(DnsResolver::from_system_conf().unwrap()),
-            dnsdisc_cfg,
+            .wrap_err_with(|| format!("failed to listen for discv4 on {discovery_addr}"))?;
+        // a 0 port is only known once bound
+        enr.udp_port = discv4.local_addr().port();
+        info!(
+            "discv4 listens on {}, rlpx on {}, the crawler advertises {enr}",
+            discv4.local_addr(),
+            rlpx_addr
         );
-        dns_disc_service.spawn();
+        // one service per tree, so that the peers can be told apart by the tree they were found in
+        let dns_resolver = Arc::new(DnsResolver::from_system_conf().unwrap());
+        let dnsdisc = self
+            .config
+            .dns_trees()
+            .expect("dns trees are validated with the config")
+            .into_iter()
+            .map(|tree| {
+                let dnsdisc_cfg = DnsDiscoveryConfig {
+                    bootstrap_dns_networks: Some(HashSet::from([tree])),
+                    ..Default::default()
+                };
+                let (dns_disc_service, dnsdisc) =
+                    DnsDiscoveryService::new_pair(dns_resolver.clone(), dnsdisc_cfg);
+                dns_disc_service.spawn();
+                dnsdisc
+            })
+            .collect();
         tokio::spawn(network);
 
-        CrawlerService::new(
-            discv4,
-            dnsdisc,
-            net_handle,
+        // Start discovery v5 protocol with the same identity as discv4
+        let discv5_key = CombinedKey::secp256k1_from_bytes(&mut key.secret_bytes())
+            .expect("it's a valid secret key");
+        let mut discv5_enr = EnrBuilder::new("v4");
+        discv5_enr.udp4(self.config.discv5_port);
+        if let Some(IpAddr::V4(external_ip)) = external_ip {
+            discv5_enr.ip4(external_ip);
+        }
+        let discv5_enr = discv5_enr.build(&discv5_key).expect("it's a valid enr");
+        let mut discv5 =
+            Discv5::new(discv5_enr, discv5_key, Discv5ConfigBuilder::new().build()).unwrap();
+        let discv5_bootnodes = self
+            .config
+            .discv5_bootnodes()
+            .expect("bootnodes are validated with the config");
+        for bootnode in discv5_bootnodes {
+            if let Err(e) = discv5.add_enr(bootnode) {
+                warn!("Failed to add discv5 bootnode: {e}");
+            }
+        }
+        let discv5_addr = SocketAddr::new(self.config.listen_addr, self.config.discv5_port);
+        discv5
+            .start(discv5_addr)
+            .await
+            .map_err(|e| eyre::eyre!("failed to listen for discv5 on {discv5_addr}: {e:?}"))?;
+
+        let admin = self.config.admin_addr.map(admin::bind).transpose()?;
+
+        Ok(CrawlerService::new(
+            Sources {
+                discv4,
+                discv5: Arc::new(discv5),
+                dnsdisc,
+                network: net_handle,
+                admin,
+            },
             key,
-            self.local_db,
-            provider_url,
+            self.peer_db().await,
+            self.config,
         )
-        .await
+        .await)
+    }
+
+    /// The ip advertised in the node records, as resolved by `nat`.
+    async fn external_ip(&self, nat: NatResolver) -> Option<IpAddr> {
+        let external_ip = nat.external_addr().await;
+        if external_ip.is_none() && !matches!(nat, NatResolver::None) {
+            warn!("Failed to resolve the external ip with {nat:?}, no ip is advertised");
+        }
+        external_ip
+    }
+
+    /// The node record discv4 advertises, the listen address standing in for a missing external ip.
+    fn local_node_record(
+        &self,
+        key: &SecretKey,
+        external_ip: Option<IpAddr>,
+        rlpx_port: u16,
+    ) -> NodeRecord {
+        let mut enr = NodeRecord::from_secret_key(
+            SocketAddr::new(
+                external_ip.unwrap_or(self.config.listen_addr),
+                self.config.discovery_port,
+            ),
+            key,
+        );
+        enr.tcp_port = rlpx_port;
+        enr
+    }
+
+    /// The db the peers are saved to, one printing them for a dry run.
+    async fn peer_db(&self) -> Arc<dyn PeerDB> {
+        if self.config.dry_run {
+            return Arc::new(
+                PrintPeerDB::new(self.config.dry_run_output.as_deref())
+                    .expect("dry run output must open correctly!"),
+            );
+        }
+        build_peer_db(self.config.backend).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use reth_network::config::rng_secret_key;
+
+    use super::*;
+    use crate::crawler::test_utils::test_config;
+
+    #[tokio::test]
+    async fn advertises_the_extip_of_nat() {
+        let config = CrawlerConfig {
+            nat: "extip:203.0.113.7".to_string(),
+            discovery_port: 30304,
+            ..test_config()
+        };
+        let builder = CrawlerBuilder::default().with_config(config);
+        let key = rng_secret_key();
+
+        let nat = builder.config.nat_resolver().unwrap();
+        let external_ip = builder.external_ip(nat).await;
+        let enr = builder.local_node_record(&key, external_ip, 30305);
+
+        let extip = IpAddr::from(Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(external_ip, Some(extip));
+        assert_eq!(enr.address, extip);
+        assert_eq!(enr.udp_port, 30304);
+        assert_eq!(enr.tcp_port, 30305);
+        assert_eq!(enr.id, pk2id(&key.public_key(SECP256K1)));
+    }
+
+    #[tokio::test]
+    async fn advertises_the_listen_address_without_external_ip() {
+        let builder = CrawlerBuilder::default().with_config(CrawlerConfig {
+            nat: "none".to_string(),
+            ..test_config()
+        });
+
+        let nat = builder.config.nat_resolver().unwrap();
+        let external_ip = builder.external_ip(nat).await;
+        let enr = builder.local_node_record(&rng_secret_key(), external_ip, 30303);
+
+        assert_eq!(external_ip, None);
+        assert_eq!(enr.address, builder.config.listen_addr);
     }
 }
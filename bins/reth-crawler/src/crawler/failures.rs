@@ -0,0 +1,132 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use reth_primitives::PeerId;
+
+/// Recent p2p handshake failures of each peer, so that peers failing over and over get banned.
+///
+/// It also counts the removals of peers from the network, so that peers removed over and over aren't recrawled for a
+/// while.
+///
+/// Failures older than the window are forgotten, a peer is only banned after `threshold` failures within the window.
+#[derive(Debug)]
+pub struct P2pFailures {
+    /// When each peer failed, oldest first.
+    failures: RwLock<HashMap<PeerId, VecDeque<Instant>>>,
+    threshold: u32,
+    window: Duration,
+}
+
+impl P2pFailures {
+    pub fn new(threshold: u32, window: Duration) -> Self {
+        Self {
+            failures: RwLock::new(HashMap::new()),
+            threshold,
+            window,
+        }
+    }
+
+    /// Count a failure of `peer_id`.
+    ///
+    /// Returns the number of failures within the window once it reaches the threshold, the failures of the peer are
+    /// then reset since it's about to be banned.
+    pub fn record(&self, peer_id: PeerId) -> Option<usize> {
+        let now = Instant::now();
+        let mut failures = self.failures.write().expect("this should always work!");
+        let peer_failures = failures.entry(peer_id).or_default();
+        self.forget_expired(peer_failures, now);
+        peer_failures.push_back(now);
+        let count = peer_failures.len();
+        if count < self.threshold as usize {
+            return None;
+        }
+        failures.remove(&peer_id);
+        Some(count)
+    }
+
+    /// Forget the failures older than the window, returning how many peers have no failure left.
+    pub fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut failures = self.failures.write().expect("this should always work!");
+        let before = failures.len();
+        failures.retain(|_, peer_failures| {
+            self.forget_expired(peer_failures, now);
+            !peer_failures.is_empty()
+        });
+        before - failures.len()
+    }
+
+    fn forget_expired(&self, peer_failures: &mut VecDeque<Instant>, now: Instant) {
+        while peer_failures
+            .front()
+            .is_some_and(|failed_at| now.duration_since(*failed_at) >= self.window)
+        {
+            peer_failures.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn reports_the_failures_once_they_reach_the_threshold() {
+        let failures = P2pFailures::new(3, Duration::from_secs(60));
+        let peer_id = PeerId::from_low_u64_be(1);
+
+        assert_eq!(failures.record(peer_id), None);
+        assert_eq!(failures.record(peer_id), None);
+        assert_eq!(failures.record(peer_id), Some(3));
+    }
+
+    #[test]
+    fn counts_each_peer_on_its_own() {
+        let failures = P2pFailures::new(2, Duration::from_secs(60));
+        let peer_id = PeerId::from_low_u64_be(1);
+        let other_peer_id = PeerId::from_low_u64_be(2);
+
+        assert_eq!(failures.record(peer_id), None);
+        assert_eq!(failures.record(other_peer_id), None);
+        assert_eq!(failures.record(peer_id), Some(2));
+    }
+
+    #[test]
+    fn resets_the_failures_of_a_peer_reaching_the_threshold() {
+        let failures = P2pFailures::new(2, Duration::from_secs(60));
+        let peer_id = PeerId::from_low_u64_be(1);
+        failures.record(peer_id);
+        assert_eq!(failures.record(peer_id), Some(2));
+
+        assert_eq!(failures.record(peer_id), None);
+        assert_eq!(failures.record(peer_id), Some(2));
+    }
+
+    #[test]
+    fn forgets_the_failures_older_than_the_window() {
+        let failures = P2pFailures::new(2, WINDOW);
+        let peer_id = PeerId::from_low_u64_be(1);
+
+        assert_eq!(failures.record(peer_id), None);
+        thread::sleep(WINDOW);
+        assert_eq!(failures.record(peer_id), None);
+        assert_eq!(failures.record(peer_id), Some(2));
+    }
+
+    #[test]
+    fn removes_the_peers_without_failures_within_the_window() {
+        let failures = P2pFailures::new(3, WINDOW);
+        failures.record(PeerId::from_low_u64_be(1));
+        failures.record(PeerId::from_low_u64_be(2));
+        thread::sleep(WINDOW);
+        failures.record(PeerId::from_low_u64_be(2));
+
+        assert_eq!(failures.remove_expired(), 1);
+        assert_eq!(failures.remove_expired(), 0);
+    }
+}
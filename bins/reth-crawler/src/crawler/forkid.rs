@@ -0,0 +1,119 @@
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use lru::LruCache;
+use reth_primitives::{ForkFilter, ForkId, Hardfork, Head, PeerId, MAINNET};
+
+/// How many off-network peer ids are remembered.
+const OFF_NETWORK_CACHE_SIZE: usize = 10_000;
+
+/// Checks the fork ids advertised in the peers' ENRs against mainnet's current fork id, so that peers of other networks
+/// can be skipped without dialing them.
+#[derive(Debug)]
+pub struct ForkIdFilter {
+    /// Mainnet's fork filter, kept at the head of the chain.
+    filter: RwLock<ForkFilter>,
+    /// Peers whose ENR advertised another network.
+    off_network: RwLock<LruCache<PeerId, ForkId>>,
+    /// How many dials were avoided thanks to the filter.
+    avoided_dials: AtomicU64,
+}
+
+impl Default for ForkIdFilter {
+    fn default() -> Self {
+        // start from Shanghai, the head is updated as soon as we know about new blocks
+        let filter = MAINNET.fork_filter(Head {
+            timestamp: MAINNET.fork(Hardfork::Shanghai).as_timestamp().unwrap(),
+            ..Default::default()
+        });
+        Self {
+            filter: RwLock::new(filter),
+            off_network: RwLock::new(LruCache::new(
+                NonZeroUsize::new(OFF_NETWORK_CACHE_SIZE).expect("it's not zero!"),
+            )),
+            avoided_dials: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ForkIdFilter {
+    /// Update the head of the chain used to validate fork ids.
+    pub fn set_head(&self, number: u64, timestamp: u64) {
+        let mut filter = self.filter.write().expect("this should always work!");
+        filter.set_head(Head {
+            number,
+            timestamp,
+            ..Default::default()
+        });
+    }
+
+    /// Check the fork id advertised by `peer_id`, remembering it if it's not compatible with mainnet.
+    ///
+    /// Returns `true` if the peer belongs to another network.
+    pub fn check(&self, peer_id: PeerId, fork_id: ForkId) -> bool {
+        let off_network = {
+            let filter = self.filter.read().expect("this should always work!");
+            filter.validate(fork_id).is_err()
+        };
+        if off_network {
+            let mut peers = self.off_network.write().expect("this should always work!");
+            peers.put(peer_id, fork_id);
+        }
+        off_network
+    }
+
+    /// Whether `peer_id` previously advertised a fork id of another network.
+    pub fn is_off_network(&self, peer_id: &PeerId) -> bool {
+        let peers = self.off_network.read().expect("this should always work!");
+        peers.contains(peer_id)
+    }
+
+    /// Count a dial avoided thanks to the filter, returning the total so far.
+    pub fn avoid_dial(&self) -> u64 {
+        self.avoided_dials.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// How many dials were avoided so far.
+    pub fn avoided_dials(&self) -> u64 {
+        self.avoided_dials.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reth_primitives::ForkHash;
+
+    use super::*;
+    use crate::crawler::test_utils::node_record;
+
+    #[test]
+    fn mainnet_peers_pass() {
+        let filter = ForkIdFilter::default();
+        let peer_id = node_record([203, 0, 113, 1]).id;
+        let fork_id = filter
+            .filter
+            .read()
+            .expect("this should always work!")
+            .current();
+
+        assert!(!filter.check(peer_id, fork_id));
+        assert!(!filter.is_off_network(&peer_id));
+    }
+
+    #[test]
+    fn remembers_peers_of_another_network() {
+        let filter = ForkIdFilter::default();
+        let peer_id = node_record([203, 0, 113, 2]).id;
+        let fork_id = ForkId {
+            hash: ForkHash([0xde, 0xad, 0xbe, 0xef]),
+            next: 0,
+        };
+
+        assert!(filter.check(peer_id, fork_id));
+        assert!(filter.is_off_network(&peer_id));
+        assert!(!filter.is_off_network(&node_record([203, 0, 113, 3]).id));
+        assert_eq!(filter.avoid_dial(), 1);
+        assert_eq!(filter.avoided_dials(), 1);
+    }
+}
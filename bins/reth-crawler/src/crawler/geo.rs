@@ -0,0 +1,700 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::metrics::{counter, histogram};
+use async_trait::async_trait;
+use eyre::WrapErr;
+use lru::LruCache;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{debug, warn};
+
+use crate::crawler::config::CrawlerConfig;
+use crate::crawler::metrics;
+
+/// Fields asked to ip-api.
+const IP_API_FIELDS: &str = "status,message,country,city,isp,lat,lon,as";
+
+/// Timeout of a whole request to an online provider, so that a stalled one doesn't hold a crawl.
+const ONLINE_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Timeout of the connection to an online provider.
+const ONLINE_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Name of the offline provider, as a metric label.
+const MAXMIND: &str = "maxmind";
+
+/// Where an ip is located, and by whom it's hosted.
+#[derive(Debug, Clone, Default)]
+pub struct GeoInfo {
+    pub country: String,
+    pub city: String,
+    pub isp: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Number of the autonomous system announcing the ip, stable unlike the isp names.
+    pub asn: Option<u32>,
+    /// Organization owning the autonomous system.
+    pub as_org: Option<String>,
+    /// Provider that located the ip, e.g. `ip-api` or `maxmind`.
+    pub provider: String,
+}
+
+/// Online geolocation services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GeoProvider {
+    #[serde(rename = "ip-api")]
+    IpApi,
+    #[serde(rename = "ipapi.co")]
+    IpApiCo,
+    #[serde(rename = "ipwho.is")]
+    IpWhoIs,
+}
+
+impl GeoProvider {
+    pub const ALL: [GeoProvider; 3] = [
+        GeoProvider::IpApi,
+        GeoProvider::IpApiCo,
+        GeoProvider::IpWhoIs,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GeoProvider::IpApi => "ip-api",
+            GeoProvider::IpApiCo => "ipapi.co",
+            GeoProvider::IpWhoIs => "ipwho.is",
+        }
+    }
+
+    /// Url of the location of `ip`, the pro endpoint of ip-api takes a key.
+    fn url(&self, ip: IpAddr, pro: bool) -> String {
+        match self {
+            GeoProvider::IpApi if pro => format!("https://pro.ip-api.com/json/{ip}"),
+            GeoProvider::IpApi => format!("http://ip-api.com/json/{ip}"),
+            GeoProvider::IpApiCo => format!("https://ipapi.co/{ip}/json/"),
+            GeoProvider::IpWhoIs => format!("https://ipwho.is/{ip}"),
+        }
+    }
+}
+
+impl fmt::Display for GeoProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for GeoProvider {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GeoProvider::ALL
+            .into_iter()
+            .find(|provider| provider.as_str() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = GeoProvider::ALL.iter().map(GeoProvider::as_str).collect();
+                eyre::eyre!(
+                    "unknown geolocation provider `{s}`, expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// An online geolocation provider along with its rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GeoProviderConfig {
+    pub provider: GeoProvider,
+    pub requests_per_minute: u32,
+}
+
+impl GeoProviderConfig {
+    /// The providers tried by default, with the rate limits of their free tiers.
+    pub fn defaults() -> Vec<Self> {
+        vec![
+            Self {
+                provider: GeoProvider::IpApi,
+                requests_per_minute: 45,
+            },
+            // 1000 requests a day
+            Self {
+                provider: GeoProvider::IpApiCo,
+                requests_per_minute: 1,
+            },
+            Self {
+                provider: GeoProvider::IpWhoIs,
+                requests_per_minute: 1,
+            },
+        ]
+    }
+}
+
+/// Parses `provider:requests_per_minute`, e.g. `ip-api:45`.
+impl FromStr for GeoProviderConfig {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (provider, requests_per_minute) = s
+            .split_once(':')
+            .ok_or_else(|| eyre::eyre!("expected `provider:requests_per_minute`, got `{s}`"))?;
+        Ok(Self {
+            provider: provider.trim().parse()?,
+            requests_per_minute: requests_per_minute
+                .trim()
+                .parse()
+                .wrap_err_with(|| format!("invalid requests per minute in `{s}`"))?,
+        })
+    }
+}
+
+/// Answer of ip-api, only `status` and `message` are set when it fails.
+#[derive(Debug, Deserialize)]
+struct IpApiResponse {
+    status: String,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    country: String,
+    #[serde(default)]
+    city: String,
+    #[serde(default)]
+    isp: String,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Autonomous system, e.g. `AS24940 Hetzner Online GmbH`.
+    #[serde(default, rename = "as")]
+    autonomous_system: String,
+}
+
+impl IpApiResponse {
+    fn geo_info(self) -> eyre::Result<GeoInfo> {
+        eyre::ensure!(self.status == "success", "ip-api failed: {}", self.message);
+        let (asn, as_org) = parse_autonomous_system(&self.autonomous_system);
+        Ok(GeoInfo {
+            country: self.country,
+            city: self.city,
+            isp: self.isp,
+            latitude: self.lat,
+            longitude: self.lon,
+            asn,
+            as_org,
+            provider: GeoProvider::IpApi.to_string(),
+        })
+    }
+}
+
+/// Answer of ipapi.co, only `error` and `reason` are set when it fails.
+#[derive(Debug, Deserialize)]
+struct IpApiCoResponse {
+    #[serde(default)]
+    error: bool,
+    #[serde(default)]
+    reason: String,
+    #[serde(default)]
+    country_name: String,
+    #[serde(default)]
+    city: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    /// e.g. `AS24940`.
+    #[serde(default)]
+    asn: String,
+    /// Organization owning the autonomous system.
+    #[serde(default)]
+    org: String,
+}
+
+impl IpApiCoResponse {
+    fn geo_info(self) -> eyre::Result<GeoInfo> {
+        eyre::ensure!(!self.error, "ipapi.co failed: {}", self.reason);
+        let (asn, _) = parse_autonomous_system(&self.asn);
+        let as_org = Some(self.org).filter(|org| !org.is_empty());
+        Ok(GeoInfo {
+            country: self.country_name,
+            city: self.city,
+            // ipapi.co has no isp, the organization of the autonomous system is the closest
+            isp: as_org.clone().unwrap_or_default(),
+            latitude: self.latitude,
+            longitude: self.longitude,
+            asn,
+            as_org,
+            provider: GeoProvider::IpApiCo.to_string(),
+        })
+    }
+}
+
+/// Answer of ipwho.is, only `success` and `message` are set when it fails.
+#[derive(Debug, Deserialize)]
+struct IpWhoIsResponse {
+    success: bool,
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    country: String,
+    #[serde(default)]
+    city: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    #[serde(default)]
+    connection: IpWhoIsConnection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct IpWhoIsConnection {
+    asn: Option<u32>,
+    #[serde(default)]
+    org: String,
+    #[serde(default)]
+    isp: String,
+}
+
+impl IpWhoIsResponse {
+    fn geo_info(self) -> eyre::Result<GeoInfo> {
+        eyre::ensure!(self.success, "ipwho.is failed: {}", self.message);
+        Ok(GeoInfo {
+            country: self.country,
+            city: self.city,
+            isp: self.connection.isp,
+            latitude: self.latitude,
+            longitude: self.longitude,
+            asn: self.connection.asn,
+            as_org: Some(self.connection.org).filter(|org| !org.is_empty()),
+            provider: GeoProvider::IpWhoIs.to_string(),
+        })
+    }
+}
+
+/// Split an autonomous system like `AS24940 Hetzner Online GmbH` into its number and organization.
+fn parse_autonomous_system(autonomous_system: &str) -> (Option<u32>, Option<String>) {
+    let (number, org) = autonomous_system
+        .trim()
+        .split_once(' ')
+        .unwrap_or((autonomous_system.trim(), ""));
+    let asn = number
+        .strip_prefix("AS")
+        .and_then(|number| number.parse().ok());
+    let org = Some(org.trim().to_string()).filter(|org| !org.is_empty());
+    (asn, org)
+}
+
+/// Resolves where an ip is located.
+#[async_trait]
+pub trait GeoResolver: Send + Sync {
+    /// Locate `ip`, `None` if it can't be located.
+    async fn resolve(&self, ip: IpAddr) -> Option<GeoInfo>;
+}
+
+/// Geolocates ips through a [`GeoResolver`], caching the locations.
+///
+/// Peers often share their hosting and get crawled again, so most lookups are cache hits.
+pub struct Geolocator {
+    /// The location of each ip, and when it was looked up.
+    cache: Mutex<LruCache<IpAddr, (GeoInfo, Instant)>>,
+    /// How long a location is fresh, a stale one is looked up again but still served if that fails.
+    ttl: Duration,
+    resolver: Box<dyn GeoResolver>,
+    /// Locations served fresh from the cache.
+    hits: AtomicU64,
+    /// Locations missing from the cache, or expired.
+    misses: AtomicU64,
+}
+
+impl Geolocator {
+    /// The geolocator of `config`: the MaxMind databases if `geoip_db` is set, falling back to the online providers
+    /// unless `geoip_offline` is set.
+    pub fn from_config(config: &CrawlerConfig) -> eyre::Result<Self> {
+        let online: Box<dyn GeoResolver> = Box::new(OnlineResolver::new(
+            &config.geolocation_providers,
+            config.geo_api_key.clone(),
+        ));
+        let resolver: Box<dyn GeoResolver> = match &config.geoip_db {
+            Some(geoip_db) => Box::new(MaxMindResolver::open(
+                geoip_db,
+                config.geoip_asn_db.as_deref(),
+                (!config.geoip_offline).then_some(online),
+            )?),
+            None => online,
+        };
+        Ok(Self::new(
+            config.geolocation_cache_capacity,
+            config.geolocation_cache_ttl,
+            resolver,
+        ))
+    }
+
+    /// Cache up to `capacity` locations of `resolver` for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration, resolver: Box<dyn GeoResolver>) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("it's not zero!"),
+            )),
+            ttl,
+            resolver,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Share of the locations served fresh from the cache so far, `None` before the first one.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let lookups = hits + self.misses.load(Ordering::Relaxed);
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+
+    /// Locate `ip`, from the cache if it was looked up recently enough.
+    pub async fn locate(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let cached = self.cached(&ip);
+        if let Some((geo_info, true)) = cached {
+            counter!(metrics::GEOLOCATION_CACHE_HITS, 1);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(geo_info);
+        }
+        counter!(metrics::GEOLOCATION_CACHE_MISSES, 1);
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let Some(geo_info) = self.resolver.resolve(ip).await else {
+            // failures aren't cached, the ip is looked up again next time, and a stale location beats none
+            let (stale, _) = cached?;
+            counter!(metrics::GEOLOCATION_CACHE_STALE, 1);
+            return Some(stale);
+        };
+        let mut cache = self.cache.lock().expect("this should always work!");
+        cache.put(ip, (geo_info.clone(), Instant::now()));
+        Some(geo_info)
+    }
+
+    /// The cached location of `ip`, and whether it's still fresh.
+    fn cached(&self, ip: &IpAddr) -> Option<(GeoInfo, bool)> {
+        let mut cache = self.cache.lock().expect("this should always work!");
+        cache
+            .get(ip)
+            .map(|(geo_info, located_at)| (geo_info.clone(), located_at.elapsed() < self.ttl))
+    }
+}
+
+/// An online provider, and the requests it has left.
+#[derive(Debug)]
+struct OnlineProvider {
+    provider: GeoProvider,
+    limiter: TokenBucket,
+}
+
+/// Locates ips through a chain of online services, keeping under their rate limits.
+///
+/// The providers are tried in order until one locates the ip. Requests beyond the rate limit of the first provider
+/// wait for their turn, while the fallbacks out of requests are skipped so that a failing first provider doesn't stall
+/// the crawls.
+pub struct OnlineResolver {
+    client: reqwest::Client,
+    providers: Vec<OnlineProvider>,
+    /// Key of the ip-api pro tier, it must never be logged.
+    ip_api_key: Option<String>,
+}
+
+impl OnlineResolver {
+    pub fn new(providers: &[GeoProviderConfig], ip_api_key: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(ONLINE_REQUEST_TIMEOUT)
+                .connect_timeout(ONLINE_CONNECT_TIMEOUT)
+                .build()
+                .expect("the client config is valid"),
+            ip_api_key,
+            providers: providers
+                .iter()
+                .map(|config| OnlineProvider {
+                    provider: config.provider,
+                    limiter: TokenBucket::new(config.requests_per_minute),
+                })
+                .collect(),
+        }
+    }
+
+    async fn request(&self, provider: GeoProvider, ip: IpAddr) -> eyre::Result<GeoInfo> {
+        let mut request = self.client.get(provider.url(ip, self.ip_api_key.is_some()));
+        if provider == GeoProvider::IpApi {
+            request = request.query(&[("fields", IP_API_FIELDS)]);
+            if let Some(key) = &self.ip_api_key {
+                request = request.query(&[("key", key)]);
+            }
+        }
+        // the errors carry the url, which holds the key
+        let response = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url)?;
+        match provider {
+            GeoProvider::IpApi => response
+                .json::<IpApiResponse>()
+                .await
+                .map_err(reqwest::Error::without_url)?
+                .geo_info(),
+            GeoProvider::IpApiCo => response
+                .json::<IpApiCoResponse>()
+                .await
+                .map_err(reqwest::Error::without_url)?
+                .geo_info(),
+            GeoProvider::IpWhoIs => response
+                .json::<IpWhoIsResponse>()
+                .await
+                .map_err(reqwest::Error::without_url)?
+                .geo_info(),
+        }
+    }
+}
+
+#[async_trait]
+impl GeoResolver for OnlineResolver {
+    async fn resolve(&self, ip: IpAddr) -> Option<GeoInfo> {
+        for (index, OnlineProvider { provider, limiter }) in self.providers.iter().enumerate() {
+            let name = provider.as_str();
+            if index == 0 {
+                if limiter.acquire().await {
+                    counter!(metrics::GEOLOCATION_RATE_LIMIT_WAITS, 1, "provider" => name);
+                }
+            } else if !limiter.try_acquire() {
+                counter!(metrics::GEOLOCATION_RATE_LIMIT_SKIPS, 1, "provider" => name);
+                continue;
+            }
+            let start = Instant::now();
+            let result = self.request(*provider, ip).await;
+            histogram!(
+                metrics::GEOLOCATION_REQUEST_SECONDS,
+                start.elapsed().as_secs_f64(),
+                "provider" => name
+            );
+            match result {
+                Ok(geo_info) => return Some(geo_info),
+                Err(e) => {
+                    counter!(metrics::GEOLOCATION_FAILURES, 1, "provider" => name);
+                    debug!("Failed to geolocate {ip} with {name}: {e}");
+                }
+            }
+        }
+        warn!("Failed to geolocate {ip} with every provider");
+        None
+    }
+}
+
+/// Locates ips offline with the MaxMind GeoLite2 databases, falling back to another resolver for the ips they lack.
+pub struct MaxMindResolver {
+    city: Reader<Vec<u8>>,
+    /// Tells the autonomous system, which the city database doesn't.
+    asn: Option<Reader<Vec<u8>>>,
+    /// Asked for the ips the databases lack, they stay unlocated without it.
+    fallback: Option<Box<dyn GeoResolver>>,
+}
+
+impl MaxMindResolver {
+    /// Open the GeoLite2-City database at `city`, and the GeoLite2-ASN one at `asn` if any.
+    pub fn open(
+        city: &Path,
+        asn: Option<&Path>,
+        fallback: Option<Box<dyn GeoResolver>>,
+    ) -> eyre::Result<Self> {
+        let city = Reader::open_readfile(city)
+            .wrap_err_with(|| format!("failed to open the geoip db {}", city.display()))?;
+        let asn = asn
+            .map(|asn| {
+                Reader::open_readfile(asn)
+                    .wrap_err_with(|| format!("failed to open the geoip asn db {}", asn.display()))
+            })
+            .transpose()?;
+        Ok(Self {
+            city,
+            asn,
+            fallback,
+        })
+    }
+
+    /// Locate `ip` in the databases, `None` if the city database lacks it.
+    fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let start = Instant::now();
+        let city: Result<geoip2::City, _> = self.city.lookup(ip);
+        histogram!(
+            metrics::GEOLOCATION_REQUEST_SECONDS,
+            start.elapsed().as_secs_f64(),
+            "provider" => MAXMIND
+        );
+        let city = match city {
+            Ok(city) => city,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return None,
+            Err(e) => {
+                counter!(metrics::GEOLOCATION_FAILURES, 1, "provider" => MAXMIND);
+                warn!("Failed to look {ip} up in the geoip db: {e}");
+                return None;
+            }
+        };
+        let location = city.location.as_ref();
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|asn| asn.lookup::<geoip2::Asn>(ip).ok());
+        let as_org = asn
+            .as_ref()
+            .and_then(|asn| asn.autonomous_system_organization)
+            .map(|org| org.to_string());
+        Some(GeoInfo {
+            country: english_name(city.country.and_then(|country| country.names)),
+            city: english_name(city.city.and_then(|city| city.names)),
+            // GeoLite2 has no isp, the organization of the autonomous system is the closest
+            isp: as_org.clone().unwrap_or_default(),
+            latitude: location.and_then(|location| location.latitude),
+            longitude: location.and_then(|location| location.longitude),
+            asn: asn.and_then(|asn| asn.autonomous_system_number),
+            as_org,
+            provider: MAXMIND.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl GeoResolver for MaxMindResolver {
+    async fn resolve(&self, ip: IpAddr) -> Option<GeoInfo> {
+        match self.lookup(ip) {
+            Some(geo_info) => Some(geo_info),
+            None => match &self.fallback {
+                Some(fallback) => fallback.resolve(ip).await,
+                None => None,
+            },
+        }
+    }
+}
+
+/// The english name out of the localized names of a MaxMind record, like ip-api answers.
+fn english_name(names: Option<BTreeMap<&str, &str>>) -> String {
+    names
+        .and_then(|names| names.get("en").map(|name| name.to_string()))
+        .unwrap_or_default()
+}
+
+/// Lets through up to `capacity` requests per minute, refilled continuously.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Available tokens, and when they were last refilled.
+    state: Mutex<(f64, Instant)>,
+    capacity: f64,
+    tokens_per_second: f64,
+}
+
+impl TokenBucket {
+    fn new(requests_per_minute: u32) -> Self {
+        let capacity = f64::from(requests_per_minute.max(1));
+        Self {
+            state: Mutex::new((capacity, Instant::now())),
+            capacity,
+            tokens_per_second: capacity / 60.0,
+        }
+    }
+
+    /// Take a token, waiting for one if the bucket is empty. Returns whether it had to wait.
+    async fn acquire(&self) -> bool {
+        let mut waited = false;
+        loop {
+            let wait = match self.take() {
+                Ok(()) => return waited,
+                Err(wait) => wait,
+            };
+            waited = true;
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Take a token if one is available.
+    fn try_acquire(&self) -> bool {
+        self.take().is_ok()
+    }
+
+    /// Refill the bucket and take a token, or tell how long until the next one.
+    fn take(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("this should always work!");
+        let (tokens, refilled_at) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens
+            + now.duration_since(*refilled_at).as_secs_f64() * self.tokens_per_second)
+            .min(self.capacity);
+        *refilled_at = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - *tokens) / self.tokens_per_second,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::crawler::test_utils::StubResolver;
+
+    /// A city database locating 81.2.69.0/24 in London, and nothing else.
+    fn city_db() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/GeoLite2-City-Test.mmdb")
+    }
+
+    /// A resolver of the test database, falling back to a stub locating every ip in Paris.
+    fn resolver(lookups: Arc<AtomicUsize>) -> MaxMindResolver {
+        let fallback = StubResolver {
+            geo_info: Some(GeoInfo {
+                country: "France".to_string(),
+                city: "Paris".to_string(),
+                provider: "ip-api".to_string(),
+                ..Default::default()
+            }),
+            lookups,
+        };
+        MaxMindResolver::open(&city_db(), None, Some(Box::new(fallback))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn locates_the_ips_of_the_database_offline() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(lookups.clone());
+
+        let geo_info = resolver
+            .resolve([81, 2, 69, 142].into())
+            .await
+            .expect("the ip is in the database");
+
+        assert_eq!(geo_info.country, "United Kingdom");
+        assert_eq!(geo_info.city, "London");
+        assert_eq!(geo_info.provider, MAXMIND);
+        assert!(geo_info.latitude.is_some());
+        assert_eq!(lookups.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn falls_back_online_for_the_ips_the_database_lacks() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = resolver(lookups.clone());
+
+        let geo_info = resolver
+            .resolve([203, 0, 113, 1].into())
+            .await
+            .expect("the fallback locates the ip");
+
+        assert_eq!(geo_info.city, "Paris");
+        assert_eq!(geo_info.provider, "ip-api");
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn leaves_the_ips_the_database_lacks_unlocated_offline() {
+        let resolver = MaxMindResolver::open(&city_db(), None, None).unwrap();
+
+        assert!(resolver.resolve([203, 0, 113, 1].into()).await.is_none());
+    }
+}
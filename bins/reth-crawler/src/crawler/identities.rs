@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reth_network::config::rng_secret_key;
+use secp256k1::SecretKey;
+
+/// The node keys the crawler dials peers with, so that a peer full of connections can be retried under another
+/// identity.
+///
+/// The first one is the key of the crawler itself, the others are generated for the run. Discovery and the network
+/// only ever use the first one.
+#[derive(Debug)]
+pub struct Identities {
+    keys: Vec<SecretKey>,
+    /// Index of the identity dialing the next peer.
+    next: AtomicUsize,
+}
+
+impl Identities {
+    /// `count` identities, the first being `key`.
+    pub fn new(key: SecretKey, count: usize) -> Self {
+        let keys = std::iter::once(key)
+            .chain(std::iter::repeat_with(rng_secret_key))
+            .take(count.max(1))
+            .collect();
+        Self {
+            keys,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of identities.
+    pub fn count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The identity dialing the next peer, in turn, as its index and key.
+    pub fn pick(&self) -> (usize, SecretKey) {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.keys.len();
+        (index, self.keys[index])
+    }
+
+    /// The identity following the one at `index`, to retry a peer under another one.
+    pub fn after(&self, index: usize) -> (usize, SecretKey) {
+        let index = (index + 1) % self.keys.len();
+        (index, self.keys[index])
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ::metrics::{decrement_gauge, increment_gauge};
+use reth_primitives::PeerId;
+
+use crate::crawler::metrics;
+
+/// Peers being crawled, so that a peer surfaced by several listeners at once is only dialed once.
+#[derive(Debug, Default)]
+pub struct InFlightPeers {
+    peers: Mutex<HashSet<PeerId>>,
+    /// How many sightings were dropped because the peer was already being crawled.
+    deduped: AtomicU64,
+}
+
+impl InFlightPeers {
+    /// Add `peer_id` to the in-flight peers, unless it's already being crawled.
+    ///
+    /// The peer stays in flight until the returned guard is dropped.
+    pub fn claim(self: &Arc<Self>, peer_id: PeerId) -> Option<InFlight> {
+        let claimed = self
+            .peers
+            .lock()
+            .expect("this should always work!")
+            .insert(peer_id);
+        if !claimed {
+            self.deduped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        increment_gauge!(metrics::CRAWLS_IN_FLIGHT, 1.0);
+        Some(InFlight {
+            peer_id,
+            peers: self.clone(),
+        })
+    }
+
+    /// How many peers are being crawled.
+    pub fn count(&self) -> usize {
+        self.peers.lock().expect("this should always work!").len()
+    }
+
+    /// How many sightings of peers already being crawled were dropped so far.
+    pub fn deduped(&self) -> u64 {
+        self.deduped.load(Ordering::Relaxed)
+    }
+}
+
+/// A peer being crawled, removed from the in-flight peers when dropped.
+#[derive(Debug)]
+pub struct InFlight {
+    peer_id: PeerId,
+    peers: Arc<InFlightPeers>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.peers
+            .peers
+            .lock()
+            .expect("this should always work!")
+            .remove(&self.peer_id);
+        decrement_gauge!(metrics::CRAWLS_IN_FLIGHT, 1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn concurrent_claims_of_a_peer_let_only_one_through() {
+        let in_flight = Arc::new(InFlightPeers::default());
+        let peer_id = PeerId::from_low_u64_be(1);
+        let barrier = Arc::new(Barrier::new(2));
+
+        let claims: Vec<_> = (0..2)
+            .map(|_| {
+                let in_flight = in_flight.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    in_flight.claim(peer_id)
+                })
+            })
+            .collect();
+        let claims: Vec<_> = claims
+            .into_iter()
+            .map(|claim| claim.join().unwrap())
+            .collect();
+
+        assert_eq!(claims.iter().filter(|claim| claim.is_some()).count(), 1);
+        assert_eq!(in_flight.count(), 1);
+        assert_eq!(in_flight.deduped(), 1);
+        drop(claims);
+        assert_eq!(in_flight.count(), 0);
+        assert!(in_flight.claim(peer_id).is_some());
+    }
+
+    #[test]
+    fn other_peers_are_claimed_on_their_own() {
+        let in_flight = Arc::new(InFlightPeers::default());
+
+        let _first = in_flight.claim(PeerId::from_low_u64_be(1)).unwrap();
+        let second = in_flight.claim(PeerId::from_low_u64_be(2));
+
+        assert!(second.is_some());
+        assert_eq!(in_flight.count(), 2);
+    }
+}
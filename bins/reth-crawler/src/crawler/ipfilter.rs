@@ -0,0 +1,51 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ::metrics::counter;
+use ipnetwork::IpNetwork;
+
+use crate::crawler::metrics;
+
+/// Ip ranges the crawler must or must not reach, for compliance rather than misbehavior like the bans.
+///
+/// An ip is crawled unless it's in a denied range, and, when there are allowed ranges, only if it's in one of them.
+#[derive(Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+    /// How many peers were skipped because of their ip.
+    filtered: AtomicU64,
+}
+
+impl IpFilter {
+    pub fn new(allow: Vec<IpNetwork>, deny: Vec<IpNetwork>) -> Self {
+        Self {
+            allow,
+            deny,
+            filtered: AtomicU64::default(),
+        }
+    }
+
+    /// Whether `ip` may be crawled.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|network| network.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip))
+    }
+
+    /// Whether `ip` may be crawled, counting the peers skipped.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let permitted = self.permits(ip);
+        if !permitted {
+            self.filtered.fetch_add(1, Ordering::Relaxed);
+            counter!(metrics::IPS_FILTERED, 1);
+        }
+        permitted
+    }
+
+    /// How many peers were skipped because of their ip so far.
+    pub fn filtered(&self) -> u64 {
+        self.filtered.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use ethers::providers::{Http, Middleware, Provider, Ws};
+use ethers::types::{Block, H256};
+
+/// A connection to an eth rpc, new blocks are subscribed to over a web socket and polled over http.
+#[derive(Debug, Clone)]
+pub enum EthRpc {
+    Ws(Provider<Ws>),
+    Http(Provider<Http>),
+}
+
+impl EthRpc {
+    /// Connect to `url`, the transport is picked from its scheme.
+    pub async fn connect(url: &str) -> eyre::Result<Self> {
+        if is_ws_url(url) {
+            Ok(EthRpc::Ws(Provider::<Ws>::connect(url).await?))
+        } else {
+            Ok(EthRpc::Http(Provider::<Http>::try_from(url)?))
+        }
+    }
+}
+
+/// Where the blocks of the state come from, so that the state can be filled from a mock chain.
+#[async_trait]
+pub trait BlockSource: Send + Sync {
+    /// Number of the latest block.
+    async fn get_block_number(&self) -> eyre::Result<u64>;
+
+    /// The block `block_number`, `None` if it doesn't exist yet.
+    async fn get_block(&self, block_number: u64) -> eyre::Result<Option<Block<H256>>>;
+}
+
+#[async_trait]
+impl BlockSource for EthRpc {
+    async fn get_block_number(&self) -> eyre::Result<u64> {
+        let block_number = match self {
+            EthRpc::Ws(provider) => provider.get_block_number().await?,
+            EthRpc::Http(provider) => provider.get_block_number().await?,
+        };
+        Ok(block_number.as_u64())
+    }
+
+    async fn get_block(&self, block_number: u64) -> eyre::Result<Option<Block<H256>>> {
+        let block = match self {
+            EthRpc::Ws(provider) => provider.get_block(block_number).await?,
+            EthRpc::Http(provider) => provider.get_block(block_number).await?,
+        };
+        Ok(block)
+    }
+}
+
+pub fn is_ws_url(url: &str) -> bool {
+    url.starts_with("ws://") || url.starts_with("wss://")
+}
+
+pub fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
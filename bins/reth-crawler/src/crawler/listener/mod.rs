@@ -1,3 +1,4 @@
+pub(crate) mod eth_rpc;
 mod update_listener;
 
-pub(crate) use self::update_listener::UpdateListener;
+pub(crate) use self::update_listener::{fill_state, BlockHashNum, Sources, UpdateListener};
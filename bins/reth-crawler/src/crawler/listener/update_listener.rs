@@ -1,533 +1,1847 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::future::Future;
+use std::net::{IpAddr, TcpListener};
 use std::num::NonZeroUsize;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::p2p::{handshake_eth, handshake_p2p};
+use crate::crawler::admin;
+use crate::crawler::bans::BannedIps;
+use crate::crawler::config::CrawlerConfig;
+use crate::crawler::failures::P2pFailures;
+use crate::crawler::forkid::ForkIdFilter;
+use crate::crawler::geo::Geolocator;
+use crate::crawler::identities::Identities;
+use crate::crawler::inflight::InFlightPeers;
+use crate::crawler::ipfilter::IpFilter;
+use crate::crawler::listener::eth_rpc::{BlockSource, EthRpc};
+use crate::crawler::metrics;
+use crate::crawler::network::{PeerNetwork, RethNetwork};
+use crate::crawler::pipeline::{CrawlCandidate, CrawlPipeline, DiscoverySource, SessionInfo};
+use crate::crawler::progress::Progress;
+use crate::crawler::recent::RecentPeers;
+use crate::crawler::reputation::{recrawl_after, CrawlOutcome};
+use crate::crawler::sybil::SybilGuard;
+use crate::crawler::writer::PeerWriter;
+use ::metrics::{counter, gauge};
 use chrono::Utc;
+use discv5::enr::EnrPublicKey;
+use discv5::{Discv5, Discv5Event, Enr};
 use ethers::providers::{Middleware, Provider, Ws};
-use ethers::types::{H256, U64};
-use futures::StreamExt;
-use ipgeolocate::{Locator, Service};
+use ethers::types::{Block, H256, U64};
+use futures::future::join_all;
+use futures::{Stream, StreamExt};
 use lru::LruCache;
-use reth_crawler_db::{save_peer, AwsPeerDB, PeerDB, PeerData, SqlPeerDB};
+use reth_crawler_db::types::ChainHead;
+use reth_crawler_db::PeerDB;
 use reth_discv4::{DiscoveryUpdate, Discv4};
 use reth_dns_discovery::{DnsDiscoveryHandle, DnsNodeRecordUpdate};
+use reth_eth_wire::DisconnectReason;
 use reth_network::{NetworkEvent, NetworkHandle};
 use reth_primitives::{NodeRecord, PeerId};
 use secp256k1::SecretKey;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
 
-const P2P_FAILURE_THRESHOLD: u8 = 5;
-/// How many blocks can a node be lagging and still be considered `synced`.
+/// Default number of blocks a node can be lagging and still be considered `synced`.
 const SYNCED_THRESHOLD: u64 = 100;
 /// Stop the async tasks for this duration in seconds so that the state could be properly initialized!
 const SLEEP_TIME: u64 = 12;
+/// Interval in seconds between two stats logs.
+const STATS_INTERVAL: u64 = 60;
+/// First delay in seconds before reconnecting to the eth rpc or resubscribing to a discovery stream, doubled after each
+/// failed attempt.
+const RECONNECT_MIN_BACKOFF: u64 = 1;
+/// Maximum delay in seconds before reconnecting to the eth rpc or resubscribing to a discovery stream.
+const RECONNECT_MAX_BACKOFF: u64 = 60;
+/// How many times the state initialization is attempted before relying on new blocks only.
+const INITIALIZE_ATTEMPTS: u32 = 3;
+/// How many blocks are fetched at the same time while filling the state.
+const BLOCK_FETCH_CONCURRENCY: usize = 10;
+/// How many times a block is fetched before it's skipped.
+const BLOCK_FETCH_ATTEMPTS: u32 = 3;
+/// Delay in milliseconds before fetching a block again, doubled after each failed attempt.
+const BLOCK_FETCH_BACKOFF: u64 = 200;
+/// How many more stale peers than `recrawl_batch_size` each recrawl considers, since the poorly scored ones may not be
+/// due yet.
+const RECRAWL_CANDIDATES_FACTOR: usize = 4;
+
+/// Where the peers are discovered, along with the network of our node.
+pub struct Sources {
+    pub discv4: Discv4,
+    pub discv5: Arc<Discv5>,
+    /// One handle per DNS tree, in the order of `dns_trees`.
+    pub dnsdisc: Vec<DnsDiscoveryHandle>,
+    pub network: NetworkHandle,
+    /// Listener of the admin routes, bound along with the other services so that a taken address fails the startup.
+    pub admin: Option<TcpListener>,
+}
+
+/// The events of the sessions of our node, as far as the listener is concerned.
+pub enum SessionEvent {
+    Established(SessionInfo),
+    Closed {
+        peer_id: PeerId,
+        reason: Option<DisconnectReason>,
+    },
+    PeerAdded(PeerId),
+    PeerRemoved(PeerId),
+}
+
+impl From<NetworkEvent> for SessionEvent {
+    fn from(event: NetworkEvent) -> Self {
+        match event {
+            NetworkEvent::SessionEstablished {
+                peer_id,
+                remote_addr,
+                client_version,
+                capabilities,
+                status,
+                version,
+                ..
+            } => SessionEvent::Established(SessionInfo {
+                peer_id,
+                remote_addr,
+                client_version: client_version.to_string(),
+                capabilities: capabilities
+                    .as_ref()
+                    .capabilities()
+                    .iter()
+                    .map(|cap| cap.to_string())
+                    .collect(),
+                status,
+                eth_version: u8::from(version),
+            }),
+            NetworkEvent::SessionClosed { peer_id, reason } => {
+                SessionEvent::Closed { peer_id, reason }
+            }
+            NetworkEvent::PeerAdded(peer_id) => SessionEvent::PeerAdded(peer_id),
+            NetworkEvent::PeerRemoved(peer_id) => SessionEvent::PeerRemoved(peer_id),
+        }
+    }
+}
 
 pub struct UpdateListener {
     discv4: Discv4,
-    dnsdisc: DnsDiscoveryHandle,
+    discv5: Arc<Discv5>,
+    /// One handle per DNS tree, in the order of `dns_trees`.
+    dnsdisc: Vec<DnsDiscoveryHandle>,
     network: NetworkHandle,
-    key: SecretKey,
+    /// Taken when the admin routes are served.
+    admin: Mutex<Option<TcpListener>>,
     db: Arc<dyn PeerDB>,
-    p2p_failures: Arc<RwLock<HashMap<PeerId, u64>>>,
-    provider: Provider<Ws>,
+    writer: PeerWriter,
+    /// Cancelled once the crawls are over, the flusher then drains the queued peers and stops.
+    writer_closed: CancellationToken,
+    flusher: Mutex<Option<JoinHandle<()>>>,
+    p2p_failures: Arc<P2pFailures>,
+    /// Recent removals of each peer from the network.
+    removed_peers: P2pFailures,
+    /// The eth rpc the blocks are fetched from, `None` until one answers.
+    provider: RwLock<Option<EthRpc>>,
+    /// The eth rpc urls, the provider fails over from one to the next.
+    provider_urls: Vec<String>,
+    /// Index of the url of the current provider.
+    provider_index: AtomicUsize,
     state: BlockHashNum,
+    fork_filter: Arc<ForkIdFilter>,
+    banned_ips: Arc<BannedIps>,
+    ip_filter: Arc<IpFilter>,
+    handshakes: Arc<Semaphore>,
+    recent_peers: Arc<RecentPeers>,
+    sybil_guard: Arc<SybilGuard>,
+    in_flight: Arc<InFlightPeers>,
+    progress: Arc<Progress>,
+    /// Crawls the peers surfaced by the listeners.
+    pipeline: Arc<CrawlPipeline>,
+    /// Sends the peers surfaced by the listeners to the crawl workers.
+    candidates: mpsc::Sender<CrawlCandidate>,
+    shutdown: CancellationToken,
+    /// The crawl workers.
+    tasks: TaskTracker,
+    /// How many blocks the state was behind the head of the chain when the block subscription last resumed.
+    blocks_behind: AtomicU64,
+    config: CrawlerConfig,
 }
 
-/// This holds the mapping between block hash and block number of the latest `SYNCED_THRESHOLD` blocks.
+/// This holds the mapping between block hash and block number of the latest `synced_threshold` blocks.
 #[derive(Debug, Clone)]
 pub struct BlockHashNum {
     pub blocks_hash_to_number: Arc<RwLock<LruCache<H256, U64>>>,
+    /// Highest block number of the state, 0 until a block is known.
+    pub latest_block: Arc<AtomicU64>,
+    /// Hash of the highest block of the state.
+    pub latest_hash: Arc<RwLock<Option<H256>>>,
+    /// Hash of the canonical block at each height, replaced when a reorg brings another block at the same height.
+    pub canonical_hashes: Arc<RwLock<LruCache<U64, H256>>>,
 }
 
 impl Default for BlockHashNum {
     fn default() -> Self {
+        Self::new(SYNCED_THRESHOLD)
+    }
+}
+
+impl BlockHashNum {
+    /// Keep the latest `capacity` blocks, which must not be zero.
+    pub fn new(capacity: u64) -> Self {
         Self {
             blocks_hash_to_number: Arc::new(RwLock::new(LruCache::new(
-                NonZeroUsize::new(SYNCED_THRESHOLD as usize).expect("it's not zero!"),
+                NonZeroUsize::new(capacity as usize).expect("synced threshold must not be zero"),
+            ))),
+            latest_block: Arc::new(AtomicU64::new(0)),
+            latest_hash: Arc::new(RwLock::new(None)),
+            canonical_hashes: Arc::new(RwLock::new(LruCache::new(
+                NonZeroUsize::new(capacity as usize).expect("synced threshold must not be zero"),
             ))),
         }
     }
+
+    /// Add a block to the state.
+    pub fn put(&self, block_hash: H256, block_number: U64) {
+        let mut blocks_hash_to_number = self
+            .blocks_hash_to_number
+            .write()
+            .expect("this should always work!");
+        blocks_hash_to_number.put(block_hash, block_number);
+        self.canonical_hashes
+            .write()
+            .expect("this should always work!")
+            .put(block_number, block_hash);
+        let previous_block = self
+            .latest_block
+            .fetch_max(block_number.as_u64(), Ordering::Relaxed);
+        if block_number.as_u64() >= previous_block {
+            *self.latest_hash.write().expect("this should always work!") = Some(block_hash);
+        }
+        let latest_block = previous_block.max(block_number.as_u64());
+        gauge!(metrics::STATE_BLOCKS, blocks_hash_to_number.len() as f64);
+        gauge!(metrics::STATE_LATEST_BLOCK, latest_block as f64);
+        gauge!(
+            metrics::STATE_UPDATED_SECONDS,
+            Utc::now().timestamp() as f64
+        );
+    }
+
+    /// Whether `block_hash` is within the latest blocks of the state, `None` while the state holds no block.
+    pub fn is_synced(&self, block_hash: &H256) -> Option<bool> {
+        let blocks_hash_to_number = self
+            .blocks_hash_to_number
+            .read()
+            .expect("this should always work!");
+        // a state that couldn't be initialized can't tell a lagging peer from a synced one
+        if blocks_hash_to_number.is_empty() {
+            return None;
+        }
+        Some(blocks_hash_to_number.contains(block_hash))
+    }
+
+    /// Number of `block_hash`, `None` if the block isn't in the state.
+    pub fn block_number(&self, block_hash: &H256) -> Option<u64> {
+        self.blocks_hash_to_number
+            .read()
+            .expect("this should always work!")
+            .peek(block_hash)
+            .map(|block_number| block_number.as_u64())
+    }
+
+    /// Whether `block_hash` is the canonical block at its height, `None` if the block isn't in the state.
+    ///
+    /// A block of the state that was reorged out is on a side chain, unlike a block that is merely behind.
+    pub fn is_canonical(&self, block_hash: &H256) -> Option<bool> {
+        let block_number = *self
+            .blocks_hash_to_number
+            .read()
+            .expect("this should always work!")
+            .peek(block_hash)?;
+        let canonical_hashes = self
+            .canonical_hashes
+            .read()
+            .expect("this should always work!");
+        Some(canonical_hashes.peek(&block_number) == Some(block_hash))
+    }
 }
 
 impl UpdateListener {
     pub async fn new(
-        discv4: Discv4,
-        dnsdisc: DnsDiscoveryHandle,
-        network: NetworkHandle,
+        sources: Sources,
         key: SecretKey,
-        local_db: bool,
-        provider_url: String,
+        db: Arc<dyn PeerDB>,
+        config: CrawlerConfig,
     ) -> Self {
-        let p2p_failures = Arc::from(RwLock::from(HashMap::new()));
-        // initialize a new http provider
-        let provider = Provider::<Ws>::connect(provider_url)
-            .await
-            .expect("Provider must work correctly!");
-        if local_db {
-            UpdateListener {
-                discv4,
-                dnsdisc,
-                key,
-                db: Arc::new(SqlPeerDB::new().await),
-                network,
-                p2p_failures,
-                provider,
-                state: BlockHashNum::default(),
+        let peers = Arc::new(RethNetwork::new(
+            sources.discv4.clone(),
+            sources.network.clone(),
+            &config,
+        ));
+        Self::with_network(sources, peers, key, db, config).await
+    }
+
+    /// Listen to `sources`, the peers being dialed and disconnected through `peers`.
+    pub async fn with_network(
+        sources: Sources,
+        peers: Arc<dyn PeerNetwork>,
+        key: SecretKey,
+        db: Arc<dyn PeerDB>,
+        config: CrawlerConfig,
+    ) -> Self {
+        let Sources {
+            discv4,
+            discv5,
+            dnsdisc,
+            network,
+            admin,
+        } = sources;
+        metrics::describe();
+        let provider_urls = config.eth_rpc_urls();
+        let p2p_failures = Arc::new(P2pFailures::new(
+            config.p2p_failure_threshold,
+            config.p2p_failure_window,
+        ));
+        let banned_ips = Arc::new(BannedIps::load(
+            config.ban_durations,
+            config.bans_file.clone(),
+            config.clean_bans,
+        ));
+        let ip_filter = Arc::new(IpFilter::new(
+            config.allow_cidrs.clone(),
+            config.deny_cidrs.clone(),
+        ));
+        let handshakes = Arc::new(Semaphore::new(config.max_concurrent_handshakes));
+        let recent_peers = Arc::new(RecentPeers::new(
+            config.recent_peers_capacity,
+            config.recrawl_window,
+        ));
+        let sybil_guard = Arc::new(SybilGuard::new(
+            config.recent_peers_capacity,
+            config.sybil_max_ids_per_ip,
+            config.sybil_window,
+        ));
+        // connect to the first eth rpc that answers
+        let mut connection = None;
+        for (index, url) in provider_urls.iter().enumerate() {
+            match EthRpc::connect(url).await {
+                Ok(provider) => {
+                    connection = Some((index, provider));
+                    break;
+                }
+                Err(e) => warn!("Failed to connect to the eth rpc {url}: {e}"),
             }
-        } else {
-            UpdateListener {
-                discv4,
-                dnsdisc,
-                key,
-                db: Arc::new(AwsPeerDB::new().await),
-                network,
-                p2p_failures,
-                provider,
-                state: BlockHashNum::default(),
+        }
+        // without an eth rpc the peers are crawled all the same, their synced status stays unknown until
+        // `start_state` reconnects
+        let (provider_index, provider) = match connection {
+            Some((index, provider)) => (index, Some(provider)),
+            None => {
+                warn!(
+                    "No eth rpc answered, the synced status of the peers is unknown until one does"
+                );
+                // the first reconnect tries the first url
+                (provider_urls.len().saturating_sub(1), None)
             }
+        };
+        let provider = RwLock::new(provider);
+        let (writer, flusher) = PeerWriter::new(
+            db.clone(),
+            config.writer_queue_capacity,
+            config.record_history,
+        );
+        let writer_closed = CancellationToken::new();
+        let flusher = tokio::spawn(flusher.run(writer_closed.clone()));
+        let state = BlockHashNum::new(config.synced_threshold);
+        let fork_filter = Arc::new(ForkIdFilter::default());
+        let in_flight = Arc::new(InFlightPeers::default());
+        let progress = Arc::new(Progress::default());
+        let pipeline = Arc::new(CrawlPipeline {
+            identities: Arc::new(Identities::new(key, config.identities)),
+            db: db.clone(),
+            writer: writer.clone(),
+            network: peers,
+            p2p_failures: p2p_failures.clone(),
+            state: state.clone(),
+            fork_filter: fork_filter.clone(),
+            geolocator: Arc::new(
+                Geolocator::from_config(&config).expect("GeoIP db must open correctly!"),
+            ),
+            banned_ips: banned_ips.clone(),
+            ip_filter: ip_filter.clone(),
+            handshakes: handshakes.clone(),
+            recent_peers: recent_peers.clone(),
+            sybil_guard: sybil_guard.clone(),
+            in_flight: in_flight.clone(),
+            progress: progress.clone(),
+            config: config.clone(),
+        });
+        let (candidates, receiver) = mpsc::channel(config.candidate_queue_capacity);
+        let shutdown = CancellationToken::new();
+        let tasks = TaskTracker::new();
+        pipeline.spawn_workers(receiver, &tasks, &shutdown);
+        UpdateListener {
+            discv4,
+            discv5,
+            dnsdisc,
+            db,
+            writer,
+            writer_closed,
+            flusher: Mutex::new(Some(flusher)),
+            network,
+            admin: Mutex::new(admin),
+            p2p_failures,
+            removed_peers: P2pFailures::new(
+                config.removed_peer_threshold,
+                config.removed_peer_window,
+            ),
+            provider,
+            provider_urls,
+            provider_index: AtomicUsize::new(provider_index),
+            state,
+            fork_filter,
+            banned_ips,
+            ip_filter,
+            handshakes,
+            recent_peers,
+            sybil_guard,
+            in_flight,
+            progress,
+            pipeline,
+            candidates,
+            shutdown,
+            tasks,
+            blocks_behind: AtomicU64::new(0),
+            config,
         }
     }
 
-    pub async fn start_discv4(&self) -> eyre::Result<()> {
-        time::sleep(Duration::from_secs(SLEEP_TIME)).await;
-        let mut discv4_stream = self.discv4.update_stream().await?;
-        let key = self.key;
-        info!("discv4 is starting...");
-        while let Some(update) = discv4_stream.next().await {
-            let state = self.state.clone();
-            let db = self.db.clone();
-            let captured_discv4 = self.discv4.clone();
-            let p2p_failures = self.p2p_failures.clone();
-            if let DiscoveryUpdate::Added(peer) | DiscoveryUpdate::DiscoveredAtCapacity(peer) =
-                update
-            {
-                tokio::spawn(async move {
-                    // kick a forced lookup
-                    captured_discv4.send_lookup(peer.id);
-                    let mut p2p_failure_count: u64;
-                    {
-                        let rlock = p2p_failures.read().unwrap();
-                        p2p_failure_count = *rlock.get(&peer.id).unwrap_or(&0);
-                    }
-                    let (p2p_stream, their_hello) = match handshake_p2p(peer, key).await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            info!("Failed P2P handshake with peer {}, {}", peer.address, e);
-                            if e.to_string().contains("Too many peers") {
-                                info!("Skip counting p2p_failure for peer: {}", peer.address);
-                                return;
-                            }
-                            p2p_failure_count += 1;
-                            if p2p_failure_count >= P2P_FAILURE_THRESHOLD as u64 {
-                                // ban this peer - TODO: we probably want Discv4Service::ban_until() semantics here, but that isn't exposed to us
-                                // for now - permaban
-                                info!(
-                                    "PeerId {} has failed p2p handshake {} times, banning",
-                                    peer.id, p2p_failure_count
-                                );
-                                captured_discv4.ban_ip(peer.address);
-                                // scope guard to drop wlock
-                                {
-                                    // reset count to 0 since we've now banned
-                                    let mut wlock = p2p_failures.write().unwrap();
-                                    wlock.insert(peer.id, 0);
-                                }
-                                return;
-                            }
-                            // scope guard to drop wlock
-                            {
-                                // increment failure count
-                                let mut wlock = p2p_failures.write().unwrap();
-                                wlock.insert(peer.id, p2p_failure_count);
-                            }
-                            return;
-                        }
-                    };
-
-                    let (_, their_status) = match handshake_eth(p2p_stream).await {
-                        Ok(s) => s,
-                        Err(e) => {
-                            info!("Failed ETH handshake with peer {}, {}", peer.address, e);
-                            // ban the peer permanently - we never want to process another disc packet for this again since we know its not on the same network
-                            captured_discv4.ban_ip(peer.address);
-                            return;
-                        }
-                    };
-                    if their_hello.client_version.is_empty() {
-                        info!(
-                            "Peer {} with empty client_version - returning",
-                            peer.address
-                        );
-                        // ban their IP - since our results show that we have multiple PeerIDs with the same IPs and no ClientVersion
-                        captured_discv4.ban_ip(peer.address);
-                        return;
-                    }
+    /// Token cancelled to stop all the listeners.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
 
-                    let last_seen = Utc::now().to_string();
+    /// Await `future`, or return `None` as soon as the shutdown is requested.
+    async fn until_shutdown<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            _ = self.shutdown.cancelled() => None,
+            output = future => Some(output),
+        }
+    }
 
-                    info!(
-                        "Successfully connected to a peer at {}:{} ({}) using eth-wire version eth/{:#?}",
-                        peer.address, peer.tcp_port, their_hello.client_version, their_hello.protocol_version
-                    );
+    /// Give the in-flight crawls the shutdown grace period to complete, then log how many didn't make it.
+    pub async fn wait_for_crawls(&self) {
+        self.tasks.close();
+        let in_flight = self.in_flight.count();
+        info!(
+            "Waiting up to {:?} for {} in-flight crawls...",
+            self.config.shutdown_grace_period, in_flight
+        );
+        let cancelled =
+            match time::timeout(self.config.shutdown_grace_period, self.tasks.wait()).await {
+                Ok(()) => 0,
+                Err(_) => self.in_flight.count(),
+            };
+        info!(
+            "Shutdown: {} in-flight crawls completed, {} cancelled",
+            in_flight.saturating_sub(cancelled),
+            cancelled
+        );
+    }
 
-                    // get peer location
-                    let service = Service::IpApi;
-                    let ip_addr = peer.address.to_string();
+    /// Write the peers still queued once the crawls are over.
+    pub async fn flush_writes(&self) {
+        self.writer_closed.cancel();
+        let flusher = self
+            .flusher
+            .lock()
+            .expect("this should always work!")
+            .take();
+        if let Some(flusher) = flusher {
+            if let Err(e) = flusher.await {
+                warn!("Peer flusher failed: {e}");
+            }
+        }
+        info!(
+            "Shutdown: {} peers written in {} batches",
+            self.writer.stats().flushed.load(Ordering::Relaxed),
+            self.writer.stats().batches.load(Ordering::Relaxed)
+        );
+    }
 
-                    let mut country = String::default();
-                    let mut city = String::default();
-                    let mut isp = String::default();
+    /// Hand `candidate` to the crawl workers, waiting for room in their queue unless the shutdown is requested.
+    async fn enqueue(&self, candidate: CrawlCandidate) {
+        if let Some(Err(_)) = self.until_shutdown(self.candidates.send(candidate)).await {
+            warn!("Crawl workers stopped, dropping a candidate");
+        }
+    }
 
-                    if let Ok(loc) = Locator::get(&ip_addr, service).await {
-                        country = loc.country;
-                        city = loc.city;
-                        isp = loc.isp;
+    /// Feed the streams `subscribe` returns to `handle`, subscribing again with backoff whenever a stream ends or the
+    /// subscription fails, until the shutdown is requested.
+    async fn resubscribing<S, E, F, H>(
+        &self,
+        listener: &str,
+        mut subscribe: impl FnMut() -> F,
+        mut handle: impl FnMut(S) -> H,
+    ) where
+        F: Future<Output = Result<S, E>>,
+        E: Display,
+        H: Future<Output = ()>,
+    {
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        loop {
+            match self.until_shutdown(subscribe()).await {
+                None => return,
+                Some(Ok(stream)) => {
+                    let subscribed_at = Instant::now();
+                    handle(stream).await;
+                    if self.shutdown.is_cancelled() {
+                        return;
                     }
-                    let capabilities: Vec<String> = their_hello
-                        .capabilities
-                        .iter()
-                        .map(|cap| cap.to_string())
-                        .collect();
-
-                    let chain = their_status.chain.to_string();
-
-                    let total_difficulty = their_status.total_difficulty.to_string();
-                    let best_block = their_status.blockhash.to_string();
-                    let genesis_block_hash = their_status.genesis.to_string();
-
-                    // check if peer is synced with the latest chain's blocks
-                    let synced: Option<bool>;
-                    {
-                        let block_hash_to_num = state
-                            .blocks_hash_to_number
-                            .read()
-                            .expect("this should always work!");
-                        if block_hash_to_num.contains(&their_status.blockhash.0.into()) {
-                            synced = Some(true);
-                        } else {
-                            synced = Some(false);
-                        }
+                    // a stream that lasted was healthy, only the streams ending right away back off further
+                    if subscribed_at.elapsed() >= Duration::from_secs(RECONNECT_MAX_BACKOFF) {
+                        backoff = RECONNECT_MIN_BACKOFF;
                     }
-
-                    // collect data into `PeerData`
-                    let peer_data = PeerData {
-                        enode_url: peer.to_string(),
-                        id: peer.id.to_string(),
-                        address: ip_addr,
-                        tcp_port: peer.tcp_port,
-                        client_version: their_hello.client_version.clone(),
-                        eth_version: their_status.version,
-                        capabilities,
-                        total_difficulty,
-                        chain,
-                        best_block,
-                        genesis_block_hash,
-                        last_seen,
-                        country,
-                        city,
-                        synced,
-                        isp,
-                    };
-                    save_peer(peer_data, db).await;
-                });
+                    warn!("The {listener} stream ended, resubscribing in {backoff}s...");
+                }
+                Some(Err(e)) => {
+                    warn!("Failed to subscribe to {listener}: {e}, retrying in {backoff}s...");
+                }
             }
+            if self
+                .until_shutdown(time::sleep(Duration::from_secs(backoff)))
+                .await
+                .is_none()
+            {
+                return;
+            }
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
         }
-        Ok(())
     }
 
-    pub async fn start_dnsdisc(&self) -> eyre::Result<()> {
+    pub async fn start_discv4(&self) -> eyre::Result<()> {
         time::sleep(Duration::from_secs(SLEEP_TIME)).await;
-        let mut dnsdisc_update_stream = self.dnsdisc.node_record_stream().await?;
-        let key = self.key;
-        info!("dnsdisc is starting...");
-        while let Some(update) = dnsdisc_update_stream.next().await {
-            let state = self.state.clone();
-            let db = self.db.clone();
-            let p2p_failures = self.p2p_failures.clone();
-            let captured_discv4 = self.discv4.clone();
-            let DnsNodeRecordUpdate {
-                node_record: peer, ..
-            } = update;
-            tokio::spawn(async move {
-                // kick a forced lookup
-                captured_discv4.send_lookup(peer.id);
-                let mut p2p_failure_count: u64;
+        info!("discv4 is starting...");
+        self.resubscribing(
+            "discv4",
+            move || self.discv4.update_stream(),
+            move |updates| self.handle_discv4_updates(updates),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Enqueue the peers added to the discv4 table once their ENR arrived or `enr_wait` passed, until `updates` ends
+    /// and the waiting peers are enqueued, or the shutdown is requested.
+    ///
+    /// discv4 requests the ENR of a peer right after adding it, the fork id in it tells the pipeline not to dial a
+    /// peer of another network.
+    pub async fn handle_discv4_updates(
+        &self,
+        mut updates: impl Stream<Item = DiscoveryUpdate> + Unpin,
+    ) {
+        // the peers waiting for their ENR, and when each one stops waiting, in the order they were added
+        let mut waiting: HashMap<PeerId, NodeRecord> = HashMap::new();
+        let mut deadlines: VecDeque<(time::Instant, PeerId)> = VecDeque::new();
+        let mut ended = false;
+        loop {
+            let next_deadline = deadlines.front().map(|(deadline, _)| *deadline);
+            if ended && next_deadline.is_none() {
+                break;
+            }
+            let update = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = time::sleep_until(next_deadline.unwrap_or_else(time::Instant::now)),
+                    if next_deadline.is_some() =>
                 {
-                    let rlock = p2p_failures.read().unwrap();
-                    p2p_failure_count = *rlock.get(&peer.id).unwrap_or(&0);
-                }
-                let (p2p_stream, their_hello) = match handshake_p2p(peer, key).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        info!("Failed P2P handshake with peer {}, {}", peer.address, e);
-                        if e.to_string().contains("Too many peers") {
-                            info!("Skip counting p2p_failure for peer: {}", peer.address);
-                            return;
-                        }
-                        p2p_failure_count += 1;
-                        if p2p_failure_count >= P2P_FAILURE_THRESHOLD as u64 {
-                            // ban this peer - TODO: we probably want Discv4Service::ban_until() semantics here, but that isn't exposed to us
-                            // for now - permaban
-                            info!(
-                                "PeerId {} has failed p2p handshake {} times, banning",
-                                peer.id, p2p_failure_count
-                            );
-                            captured_discv4.ban_ip(peer.address);
-                            // scope guard to drop wlock
-                            {
-                                // reset count to 0 since we've now banned
-                                let mut wlock = p2p_failures.write().unwrap();
-                                wlock.insert(peer.id, 0);
-                            }
-                            return;
-                        }
-                        // scope guard to drop wlock
-                        {
-                            // increment failure count
-                            let mut wlock = p2p_failures.write().unwrap();
-                            wlock.insert(peer.id, p2p_failure_count);
+                    let now = time::Instant::now();
+                    while deadlines.front().is_some_and(|(deadline, _)| *deadline <= now) {
+                        let (_, peer_id) = deadlines.pop_front().expect("it's not empty");
+                        if let Some(peer) = waiting.remove(&peer_id) {
+                            self.enqueue(CrawlCandidate::Dial(peer, DiscoverySource::Discv4, None))
+                                .await;
                         }
-                        return;
                     }
-                };
-
-                let (_eth_stream, their_status) = match handshake_eth(p2p_stream).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        info!("Failed ETH handshake with peer {}, {}", peer.address, e);
-                        // ban the peer permanently - we never want to process another disc packet for this again since we know its not on the same network
-                        captured_discv4.ban_ip(peer.address);
-                        return;
+                    continue;
+                }
+                update = updates.next(), if !ended => update,
+            };
+            let Some(update) = update else {
+                ended = true;
+                continue;
+            };
+            counter!(metrics::DISCOVERY_UPDATES, 1, "source" => DiscoverySource::Discv4.as_str());
+            self.progress.discovery_update(DiscoverySource::Discv4);
+            match update {
+                DiscoveryUpdate::Added(peer) | DiscoveryUpdate::DiscoveredAtCapacity(peer) => {
+                    if waiting.insert(peer.id, peer).is_none() {
+                        deadlines.push_back((time::Instant::now() + self.config.enr_wait, peer.id));
                     }
-                };
-                if their_hello.client_version.is_empty() {
-                    info!(
-                        "Peer {} with empty client_version - returning",
-                        peer.address
-                    );
-                    // ban their IP - since our results show that we have multiple PeerIDs with the same IP and no ClientVersion
-                    captured_discv4.ban_ip(peer.address);
-                    return;
                 }
-                let last_seen = Utc::now().to_string();
-
-                info!(
-                        "Successfully connected to a peer at {}:{} ({}) using eth-wire version eth/{:#?}",
-                        peer.address, peer.tcp_port, their_hello.client_version, their_hello.protocol_version
-                    );
-                // get peer location
-                let service = Service::IpApi;
-                let ip_addr = peer.address.to_string();
-
-                let mut country = String::default();
-                let mut city = String::default();
-                let mut isp = String::default();
-
-                if let Ok(loc) = Locator::get(&ip_addr, service).await {
-                    country = loc.country;
-                    city = loc.city;
-                    isp = loc.isp;
+                DiscoveryUpdate::EnrForkId(peer_id, fork_id) => {
+                    if self.fork_filter.check(peer_id, fork_id) {
+                        info!(
+                            "Peer {} advertised off-network fork id {:?}",
+                            peer_id, fork_id
+                        );
+                    }
+                    // the pipeline skips the peer if it's off-network
+                    if let Some(peer) = waiting.remove(&peer_id) {
+                        self.enqueue(CrawlCandidate::Dial(peer, DiscoverySource::Discv4, None))
+                            .await;
+                    }
                 }
+                _ => {}
+            }
+        }
+    }
 
-                let capabilities: Vec<String> = their_hello
-                    .capabilities
-                    .iter()
-                    .map(|cap| cap.to_string())
-                    .collect();
-
-                let chain = their_status.chain.to_string();
+    /// Follow every dns tree, each tree is subscribed to again on its own when its stream ends.
+    pub async fn start_dnsdisc(&self) -> eyre::Result<()> {
+        time::sleep(Duration::from_secs(SLEEP_TIME)).await;
+        info!("dnsdisc is starting with {} trees...", self.dnsdisc.len());
+        join_all(self.dnsdisc.iter().enumerate().map(|(tree, dnsdisc)| {
+            self.resubscribing(
+                "dnsdisc",
+                move || dnsdisc.node_record_stream(),
+                move |records| self.handle_dns_records(records.map(move |update| (tree, update))),
+            )
+        }))
+        .await;
+        Ok(())
+    }
 
-                let total_difficulty = their_status.total_difficulty.to_string();
-                let best_block = their_status.blockhash.to_string();
-                let genesis_block_hash = their_status.genesis.to_string();
+    /// Enqueue the peers found in the dns trees, along with the index of their tree, until `records` ends or the
+    /// shutdown is requested.
+    ///
+    /// A peer published in several trees is only crawled once, like a peer surfaced by several listeners.
+    pub async fn handle_dns_records(
+        &self,
+        mut records: impl Stream<Item = (usize, DnsNodeRecordUpdate)> + Unpin,
+    ) {
+        while let Some(Some((tree, update))) = self.until_shutdown(records.next()).await {
+            let source = DiscoverySource::Dnsdisc(tree);
+            counter!(metrics::DISCOVERY_UPDATES, 1, "source" => source.as_str());
+            self.progress.discovery_update(source);
+            let DnsNodeRecordUpdate {
+                node_record: peer,
+                fork_id,
+                ..
+            } = update;
+            if let Some(fork_id) = fork_id {
+                self.fork_filter.check(peer.id, fork_id);
+            }
+            self.enqueue(CrawlCandidate::Dial(peer, source, None)).await;
+        }
+    }
 
-                // check if peer is synced with the latest chain's blocks
-                let synced: Option<bool>;
-                {
-                    let block_hash_to_num = state
-                        .blocks_hash_to_number
-                        .read()
-                        .expect("this should always work!");
-                    if block_hash_to_num.contains(&their_status.blockhash.0.into()) {
-                        synced = Some(true);
-                    } else {
-                        synced = Some(false);
-                    }
+    pub async fn start_discv5(&self) -> eyre::Result<()> {
+        time::sleep(Duration::from_secs(SLEEP_TIME)).await;
+        let discv5_events = self
+            .discv5
+            .event_stream()
+            .await
+            .map_err(|e| eyre::eyre!("failed to start discv5 event stream: {e:?}"))?;
+        info!("discv5 is starting...");
+        // discv5 only discovers nodes through lookups, so keep querying random node ids
+        let discv5 = self.discv5.clone();
+        let lookup_interval = self.config.lookup_interval;
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(lookup_interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                if let Err(e) = discv5.find_node(discv5::enr::NodeId::random()).await {
+                    warn!("discv5 lookup failed: {e:?}");
                 }
+            }
+        });
+        self.handle_discv5_events(discv5_events).await;
+        Ok(())
+    }
 
-                // collect data into `PeerData`
-                let peer_data = PeerData {
-                    enode_url: peer.to_string(),
-                    id: peer.id.to_string(),
-                    address: ip_addr,
-                    tcp_port: peer.tcp_port,
-                    client_version: their_hello.client_version.clone(),
-                    eth_version: their_status.version,
-                    capabilities,
-                    total_difficulty,
-                    chain,
-                    best_block,
-                    genesis_block_hash,
-                    last_seen,
-                    country,
-                    city,
-                    synced,
-                    isp,
-                };
-                save_peer(peer_data, db).await;
-            });
+    /// Enqueue the peers discovered by discv5, until `events` closes or the shutdown is requested.
+    pub async fn handle_discv5_events(&self, mut events: mpsc::Receiver<Discv5Event>) {
+        while let Some(Some(event)) = self.until_shutdown(events.recv()).await {
+            counter!(metrics::DISCOVERY_UPDATES, 1, "source" => DiscoverySource::Discv5.as_str());
+            self.progress.discovery_update(DiscoverySource::Discv5);
+            let enr = match event {
+                Discv5Event::Discovered(enr) | Discv5Event::SessionEstablished(enr, _) => enr,
+                _ => continue,
+            };
+            if let Some(peer) = enr_to_node_record(&enr) {
+                let enr = self.config.store_enr.then(|| enr_to_json(&enr));
+                self.enqueue(CrawlCandidate::Dial(peer, DiscoverySource::Discv5, enr))
+                    .await;
+            }
         }
-        Ok(())
     }
 
     pub async fn start_network(&self) {
         time::sleep(Duration::from_secs(SLEEP_TIME)).await;
-        let mut net_events = self.network.event_listener();
+        let net_events = self.network.event_listener().map(SessionEvent::from);
         info!("network is starting...");
-        while let Some(event) = net_events.next().await {
+        self.handle_network_events(net_events).await;
+    }
+
+    /// Enqueue the peers of the established sessions, until `events` ends or the shutdown is requested.
+    pub async fn handle_network_events(
+        &self,
+        mut events: impl Stream<Item = SessionEvent> + Unpin,
+    ) {
+        while let Some(Some(event)) = self.until_shutdown(events.next()).await {
             match event {
-                NetworkEvent::SessionEstablished {
-                    peer_id,
-                    remote_addr,
-                    client_version,
-                    capabilities,
-                    status,
-                    version,
-                    ..
-                } => {
+                SessionEvent::Established(session) => {
+                    // only the sessions surface peers, the other events are about peers we already know
+                    counter!(metrics::DISCOVERY_UPDATES, 1, "source" => DiscoverySource::Network.as_str());
+                    self.progress.discovery_update(DiscoverySource::Network);
                     info!(
-                        "Session Established with peer {}",
-                        remote_addr.ip().to_string()
+                        "Session Established with peer {} using eth-wire version eth/{}",
+                        session.remote_addr.ip().to_string(),
+                        session.eth_version
                     );
-                    let state = self.state.clone();
-                    let db = self.db.clone();
-                    let peer_handle = self.network.peers_handle().clone();
-                    tokio::spawn(async move {
-                        // immediately disconnect the peer since we don't need any data from it
-                        peer_handle.remove_peer(peer_id);
-                        let enode_url = NodeRecord::new(remote_addr, peer_id);
-                        let capabilities = capabilities
-                            .as_ref()
-                            .capabilities()
-                            .to_vec()
-                            .iter()
-                            .map(|cap| cap.to_string())
-                            .collect();
-                        let chain = status.chain.to_string();
-                        let total_difficulty = status.total_difficulty.to_string();
-                        let best_block = status.blockhash.to_string();
-                        let genesis_block_hash = status.genesis.to_string();
-                        let last_seen = Utc::now().to_string();
-                        let mut country = String::default();
-                        let mut city = String::default();
-                        let mut isp = String::default();
-                        let service = Service::IpApi;
-                        let ip_addr = remote_addr.ip().to_string();
-
-                        if let Ok(loc) = Locator::get(&ip_addr, service).await {
-                            country = loc.country;
-                            city = loc.city;
-                            isp = loc.isp;
-                        }
-                        // these peers inflate our numbers, same IP multiple generated ID
-                        // TODO: ban them, but this isn't controlled by disc, and ban_ip semantics don't seem public to peers/network handles (?) - maybe peer_handle::reputation_change
-                        if client_version.is_empty() {
-                            info!("Peer {} with empty client_version - returning", ip_addr);
-                            return;
-                        }
-
-                        // check if peer is synced with the latest chain's blocks
-                        let synced: Option<bool>;
-                        {
-                            let block_hash_to_num = state
-                                .blocks_hash_to_number
-                                .read()
-                                .expect("this should always work!");
-                            if block_hash_to_num.contains(&status.blockhash.0.into()) {
-                                synced = Some(true);
-                            } else {
-                                synced = Some(false);
-                            }
-                        }
-
-                        let peer_data = PeerData {
-                            enode_url: enode_url.to_string(),
-                            id: peer_id.to_string(),
-                            tcp_port: remote_addr.port(),
-                            address: remote_addr.ip().to_string(),
-                            client_version: client_version.to_string(),
-                            capabilities,
-                            eth_version: u8::from(version),
-                            chain,
-                            total_difficulty,
-                            best_block,
-                            genesis_block_hash,
-                            last_seen,
-                            country,
-                            city,
-                            synced,
-                            isp,
-                        };
-                        save_peer(peer_data, db).await;
-                    });
+                    self.enqueue(CrawlCandidate::Session(session)).await;
+                }
+                SessionEvent::PeerAdded(_) => {}
+                SessionEvent::PeerRemoved(peer_id) => {
+                    if let Some(removals) = self.removed_peers.record(peer_id) {
+                        info!(
+                            "Peer {} was removed {} times, deferring its recrawls",
+                            peer_id, removals
+                        );
+                        self.recent_peers.defer(peer_id);
+                    }
                 }
-                NetworkEvent::PeerAdded(_) | NetworkEvent::PeerRemoved(_) => {}
-                NetworkEvent::SessionClosed { peer_id, reason } => {
+                SessionEvent::Closed { peer_id, reason } => {
                     if let Some(reason) = reason {
                         info!(
                             "Session closed with peer {} for {}",
                             peer_id.to_string(),
                             reason
-                        )
+                        );
+                        let useless_peer = matches!(reason, DisconnectReason::UselessPeer);
+                        let reason = reason.to_string();
+                        counter!(metrics::DISCONNECTS, 1, "reason" => reason.clone());
+                        let delta = self.config.score_weights.weight(CrawlOutcome::UselessPeer);
+                        // don't hold the events back for a db roundtrip
+                        let db = self.db.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = db.record_disconnect(peer_id.to_string(), reason).await
+                            {
+                                warn!("Failed to record the disconnect of peer {}: {}", peer_id, e);
+                            }
+                            if !useless_peer {
+                                return;
+                            }
+                            if let Err(e) = db.adjust_score(peer_id.to_string(), delta).await {
+                                warn!("Failed to adjust the score of peer {}: {}", peer_id, e);
+                            }
+                        });
                     }
                 }
             }
         }
     }
 
-    pub async fn start_state(&self) -> eyre::Result<()> {
-        let mut stream = self.provider.subscribe_blocks().await?;
+    /// Periodically crawl again the peers that weren't seen for a while, so that their records don't go stale just
+    /// because they stopped showing up in discovery.
+    ///
+    /// Peers with a negative score wait longer before they're recrawled, and the best scored peers go first.
+    pub async fn start_recrawler(&self) -> eyre::Result<()> {
+        time::sleep(Duration::from_secs(SLEEP_TIME)).await;
+        let stale_after = chrono::Duration::from_std(self.config.recrawl_stale_after)?;
+        let mut failures: HashMap<PeerId, u32> = HashMap::new();
+        let mut interval = time::interval(self.config.recrawl_interval);
+        info!("recrawler is starting...");
+        while self.until_shutdown(interval.tick()).await.is_some() {
+            let now = Utc::now();
+            let last_seen = (now - stale_after).to_string();
+            let mut peers = match self
+                .db
+                .stale_peers(
+                    last_seen,
+                    self.config.recrawl_batch_size * RECRAWL_CANDIDATES_FACTOR,
+                )
+                .await
+            {
+                Ok(peers) => peers,
+                Err(e) => {
+                    warn!("Failed to get stale peers: {}", e);
+                    continue;
+                }
+            };
+            peers.retain(|peer| {
+                let wait = recrawl_after(self.config.recrawl_stale_after, peer.score);
+                let due_before = chrono::Duration::from_std(wait).unwrap_or(stale_after);
+                peer.last_seen < (now - due_before).to_string()
+            });
+            // the sort is stable, so equally scored peers stay oldest first
+            peers.sort_by_key(|peer| Reverse(peer.score));
+            peers.truncate(self.config.recrawl_batch_size);
+            let peers: Vec<NodeRecord> = peers
+                .iter()
+                .filter_map(|peer| NodeRecord::from_str(&peer.enode_url).ok())
+                // denied peers aren't failing, they just must not be reached
+                .filter(|peer| self.ip_filter.permits(peer.address))
+                .collect();
+            let results = join_all(peers.into_iter().map(|peer| async move {
+                (
+                    peer.id,
+                    self.pipeline
+                        .crawl(CrawlCandidate::Dial(peer, DiscoverySource::Recrawler, None))
+                        .await,
+                )
+            }))
+            .await;
+
+            let recrawled = results.len();
+            let mut reached = 0;
+            for (peer_id, reachable) in results {
+                if reachable {
+                    reached += 1;
+                    failures.remove(&peer_id);
+                    continue;
+                }
+                let peer_failures = failures.entry(peer_id).or_insert(0);
+                *peer_failures += 1;
+                if *peer_failures >= self.config.recrawl_max_failures {
+                    info!(
+                        "Peer {} failed {} recrawls, marking it unreachable",
+                        peer_id, peer_failures
+                    );
+                    failures.remove(&peer_id);
+                    if let Err(e) = self.db.mark_unreachable(peer_id.to_string()).await {
+                        warn!("Failed to mark peer {} unreachable: {}", peer_id, e);
+                    }
+                }
+            }
+            info!("Recrawled {} stale peers, {} reached", recrawled, reached);
+        }
+        Ok(())
+    }
+
+    /// Periodically delete the peers that weren't seen for `prune_older_than`, if it's set, so that vanished nodes
+    /// don't inflate the statistics.
+    pub async fn start_pruner(&self) -> eyre::Result<()> {
+        if self.config.prune_older_than.is_zero() {
+            return Ok(());
+        }
+        let older_than = chrono::Duration::from_std(self.config.prune_older_than)?;
+        let batch_size = self.config.prune_batch_size;
+        let mut interval = time::interval(self.config.prune_interval);
+        info!("pruner is starting...");
+        while self.until_shutdown(interval.tick()).await.is_some() {
+            let last_seen = (Utc::now() - older_than).to_string();
+            if self.config.prune_dry_run {
+                match self
+                    .db
+                    .delete_peers_older_than(last_seen.clone(), None, true)
+                    .await
+                {
+                    Ok(stale) => info!("{} peers seen before {} would be pruned", stale, last_seen),
+                    Err(e) => warn!("Failed to count the peers to prune: {}", e),
+                }
+                continue;
+            }
+            let mut pruned = 0;
+            loop {
+                match self
+                    .db
+                    .delete_peers_older_than(last_seen.clone(), Some(batch_size), false)
+                    .await
+                {
+                    Ok(deleted) => {
+                        pruned += deleted;
+                        if deleted < batch_size as u64 {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to prune peers: {}", e);
+                        break;
+                    }
+                }
+                // throttle the deletes, a first prune can remove most of the table
+                if self
+                    .until_shutdown(time::sleep(self.config.prune_batch_delay))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+            }
+            info!("Pruned {} peers seen before {}", pruned, last_seen);
+        }
+        Ok(())
+    }
+
+    /// Serve the admin routes until the shutdown, if an admin address is configured.
+    pub async fn start_admin(&self) -> eyre::Result<()> {
+        let Some(listener) = self.admin.lock().expect("this should always work!").take() else {
+            return Ok(());
+        };
+        let api_key = self
+            .config
+            .admin_api_key
+            .clone()
+            .expect("it's validated along with the admin address");
+        admin::serve(
+            listener,
+            api_key,
+            self.pipeline.clone(),
+            self.shutdown.clone(),
+        )
+        .await
+    }
+
+    /// Write the bans issued since they were last written to the bans file, on a blocking thread.
+    pub async fn persist_bans(&self) {
+        let banned_ips = self.banned_ips.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || banned_ips.persist()).await {
+            warn!("Failed to persist the bans: {e}");
+        }
+    }
+
+    /// Periodically log the crawler stats, also dropping the expired bans, p2p failures and peer removals, and
+    /// persisting the new bans.
+    pub async fn start_stats(&self) {
+        let mut interval = time::interval(Duration::from_secs(STATS_INTERVAL));
+        info!(
+            "Stats: peers lagging up to {} blocks are considered synced",
+            self.config.synced_threshold
+        );
+        while self.until_shutdown(interval.tick()).await.is_some() {
+            let unbanned = self.banned_ips.remove_expired();
+            self.persist_bans().await;
+            self.p2p_failures.remove_expired();
+            self.removed_peers.remove_expired();
+            let in_flight =
+                self.config.max_concurrent_handshakes - self.handshakes.available_permits();
+            gauge!(metrics::HANDSHAKES_IN_FLIGHT, in_flight as f64);
+            let writes = self.writer.stats();
+            info!(
+                "Stats: {} ips banned ({} bans expired), {} peers skipped by the ip filter, {} handshakes in flight, {} peers being crawled ({} duplicate sightings dropped), {} dials of recently crawled peers skipped, {} sessions skipped as ip duplicates, {} peers suppressed as sybils, state was {} blocks behind head at last resubscription, {} peers written in {} batches ({} retried one by one, {} waited for room in the queue)",
+                self.banned_ips.count(),
+                unbanned,
+                self.ip_filter.filtered(),
+                in_flight,
+                self.in_flight.count(),
+                self.in_flight.deduped(),
+                self.recent_peers.skipped_dials(),
+                self.recent_peers.skipped_ip_duplicates(),
+                self.sybil_guard.suppressed(),
+                self.blocks_behind.load(Ordering::Relaxed),
+                writes.flushed.load(Ordering::Relaxed),
+                writes.batches.load(Ordering::Relaxed),
+                writes.retries.load(Ordering::Relaxed),
+                writes.backpressured.load(Ordering::Relaxed)
+            );
+            let geo_hit_rate = match self.pipeline.geolocator.hit_rate() {
+                Some(rate) => format!("{:.1}%", rate * 100.0),
+                None => "n/a".to_string(),
+            };
+            info!(
+                "Progress: discovery updates {}, {} handshakes attempted ({} succeeded, {} failed), {} new peers saved, {} db writes ({} failed), {} bans issued, {} geolocation cache hit rate, head block {}",
+                self.progress.discovery_updates(),
+                self.progress.handshake_attempts.load(Ordering::Relaxed),
+                self.progress.handshake_successes.load(Ordering::Relaxed),
+                self.progress.handshake_failures.load(Ordering::Relaxed),
+                self.progress.new_peers.load(Ordering::Relaxed),
+                writes.flushed.load(Ordering::Relaxed),
+                writes.failures.load(Ordering::Relaxed),
+                self.banned_ips.issued(),
+                geo_hit_rate,
+                self.state.latest_block.load(Ordering::Relaxed)
+            );
+        }
+    }
 
-        while let Some(block) = stream.next().await {
-            let block_hash = block.hash.expect("it's not a pending block");
-            let block_number = block.number.expect("it's not a pending block");
+    /// The current eth rpc provider, if any answered yet.
+    fn provider(&self) -> eyre::Result<EthRpc> {
+        self.provider
+            .read()
+            .expect("this should always work!")
+            .clone()
+            .ok_or_else(|| eyre::eyre!("not connected to any eth rpc"))
+    }
+
+    /// Replace the eth rpc provider with a new connection, failing over to the next url if several were given.
+    async fn reconnect(&self) {
+        if self.provider_urls.is_empty() {
+            return;
+        }
+        let index =
+            (self.provider_index.fetch_add(1, Ordering::Relaxed) + 1) % self.provider_urls.len();
+        let url = &self.provider_urls[index];
+        match EthRpc::connect(url).await {
+            Ok(provider) => {
+                info!("Reconnected to the eth rpc {url}");
+                *self.provider.write().expect("this should always work!") = Some(provider);
+            }
+            Err(e) => warn!("Failed to reconnect to the eth rpc {url}: {e}"),
+        }
+    }
+
+    /// Keep the state up to date with new blocks, reconnecting to the eth rpc whenever the subscription drops or
+    /// polling fails.
+    ///
+    /// The state keeps serving the last known blocks while disconnected.
+    pub async fn start_state(&self) -> eyre::Result<()> {
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        loop {
+            let result = match self.provider() {
+                Ok(EthRpc::Ws(provider)) => self.subscribe_blocks(&provider, &mut backoff).await,
+                Ok(EthRpc::Http(_)) => self.poll_blocks(&mut backoff).await,
+                Err(e) => Err(e),
+            };
+            if self.shutdown.is_cancelled() {
+                info!("Block source closed");
+                return Ok(());
+            }
+            match result {
+                Ok(()) => warn!("Block subscription ended, reconnecting in {backoff}s..."),
+                Err(e) => warn!("Failed to follow new blocks: {e}, reconnecting in {backoff}s..."),
+            }
+            if self
+                .until_shutdown(time::sleep(Duration::from_secs(backoff)))
+                .await
+                .is_none()
             {
-                let mut blocks_hash_to_number = self
-                    .state
-                    .blocks_hash_to_number
-                    .write()
-                    .expect("this should always work!");
-                blocks_hash_to_number.put(block_hash, block_number);
+                return Ok(());
             }
+            backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+            self.reconnect().await;
+        }
+    }
+
+    /// Follow new blocks through a web socket subscription, until it ends or the shutdown is requested.
+    async fn subscribe_blocks(
+        &self,
+        provider: &Provider<Ws>,
+        backoff: &mut u64,
+    ) -> eyre::Result<()> {
+        // dropping the stream unsubscribes from new blocks
+        let mut stream = provider.subscribe_blocks().await?;
+        *backoff = RECONNECT_MIN_BACKOFF;
+        if let Err(e) = self.backfill_state().await {
+            warn!("Failed to backfill the state: {e}");
+        }
+        while let Some(Some(block)) = self.until_shutdown(stream.next()).await {
+            let (Some(block_hash), Some(block_number)) = (block.hash, block.number) else {
+                warn!("Skipping a new block without hash or number");
+                continue;
+            };
+            self.state.put(block_hash, block_number);
+            self.fork_filter
+                .set_head(block_number.as_u64(), block.timestamp.as_u64());
+            self.save_latest_block().await;
         }
         Ok(())
     }
 
+    /// Poll new blocks over http, until a request fails or the shutdown is requested.
+    async fn poll_blocks(&self, backoff: &mut u64) -> eyre::Result<()> {
+        self.backfill_state().await?;
+        *backoff = RECONNECT_MIN_BACKOFF;
+        let mut interval = time::interval(self.config.block_poll_interval);
+        while self.until_shutdown(interval.tick()).await.is_some() {
+            // fetching every block since the last poll keeps the state without gaps
+            self.fetch_new_blocks().await?;
+            self.save_latest_block().await;
+        }
+        Ok(())
+    }
+
+    /// Save the latest block of the state, it's shared with the api through the db.
+    async fn save_latest_block(&self) {
+        let latest_block = self.state.latest_block.load(Ordering::Relaxed);
+        if latest_block == 0 {
+            return;
+        }
+        let Some(latest_hash) = *self
+            .state
+            .latest_hash
+            .read()
+            .expect("this should always work!")
+        else {
+            return;
+        };
+        let head = ChainHead {
+            number: latest_block,
+            hash: format!("{latest_hash:?}"),
+            updated_at: Utc::now().to_string(),
+        };
+        if let Err(e) = self.db.set_latest_block(head).await {
+            warn!("Failed to save the latest block: {}", e);
+        }
+    }
+
+    /// Fill the state with the latest `synced_threshold` blocks, reconnecting to the eth rpc between failed attempts.
     pub async fn initialize_state(&self) -> eyre::Result<()> {
-        let last_block_number = self.provider.get_block_number().await?;
-        for block_number in
-            (last_block_number.as_u64() - SYNCED_THRESHOLD)..=last_block_number.as_u64()
-        {
-            let block = self
-                .provider
-                .get_block(block_number)
-                .await?
-                .expect("it's not a pending block");
-            let block_hash = block.hash.expect("it's not a pending block");
-            let block_number = block.number.expect("it's not a pending block");
-            {
-                let mut blocks_hash_to_number = self
-                    .state
-                    .blocks_hash_to_number
-                    .write()
-                    .expect("this should always work!");
-                blocks_hash_to_number.put(block_hash, block_number);
+        let mut backoff = RECONNECT_MIN_BACKOFF;
+        let start = Instant::now();
+        for attempt in 1..=INITIALIZE_ATTEMPTS {
+            match self.fetch_new_blocks().await {
+                Ok(_) => {
+                    info!("State initialized in {:?}", start.elapsed());
+                    return Ok(());
+                }
+                Err(e) if attempt < INITIALIZE_ATTEMPTS => {
+                    warn!("Failed to initialize the state: {e}, retrying in {backoff}s...");
+                    time::sleep(Duration::from_secs(backoff)).await;
+                    backoff = (backoff * 2).min(RECONNECT_MAX_BACKOFF);
+                    self.reconnect().await;
+                }
+                Err(e) => {
+                    warn!("Failed to initialize the state: {e}, relying on new blocks only");
+                    return Err(e);
+                }
             }
         }
+        Ok(())
+    }
 
+    /// Fetch the blocks missed since the latest one of the state, so that it has no gap after the block subscription
+    /// resumes.
+    async fn backfill_state(&self) -> eyre::Result<()> {
+        if self.state.latest_block.load(Ordering::Relaxed) == 0 {
+            // the state wasn't initialized, so there's no gap to measure
+            return self.fetch_new_blocks().await.map(|_| ());
+        }
+        let blocks_behind = self.fetch_new_blocks().await?;
+        self.blocks_behind.store(blocks_behind, Ordering::Relaxed);
+        if blocks_behind > 0 {
+            warn!("State was {blocks_behind} blocks behind head, backfilled");
+        }
         Ok(())
     }
+
+    /// Add the blocks from the latest one of the state up to the head of the chain, returning how many blocks the
+    /// state was behind.
+    async fn fetch_new_blocks(&self) -> eyre::Result<u64> {
+        fill_state(
+            &self.provider()?,
+            &self.state,
+            &self.fork_filter,
+            self.config.synced_threshold,
+        )
+        .await
+    }
+}
+
+/// Add the blocks of `blocks` from the latest one of `state` up to the head of the chain, keeping the head of the fork
+/// filter along, and return how many blocks the state was behind.
+pub(crate) async fn fill_state(
+    blocks: &impl BlockSource,
+    state: &BlockHashNum,
+    fork_filter: &ForkIdFilter,
+    synced_threshold: u64,
+) -> eyre::Result<u64> {
+    let head = blocks.get_block_number().await?;
+    let latest_block = state.latest_block.load(Ordering::Relaxed);
+    // older blocks would be evicted from the state right away, and a young chain may not have `synced_threshold`
+    // blocks yet, its genesis is then part of the state
+    let oldest_block_number = head.saturating_sub(synced_threshold);
+    let first_block_number = if latest_block == 0 {
+        oldest_block_number
+    } else {
+        (latest_block + 1).max(oldest_block_number)
+    };
+    // `buffered` yields the blocks in order, so the latest block of the state and the fork filter head only move
+    // forward
+    let mut fetched = futures::stream::iter(first_block_number..=head)
+        .map(|block_number| fetch_block(blocks, block_number))
+        .buffered(BLOCK_FETCH_CONCURRENCY);
+    while let Some(block) = fetched.next().await {
+        let Some(block) = block else {
+            continue;
+        };
+        let (Some(block_hash), Some(block_number)) = (block.hash, block.number) else {
+            warn!("Skipping a block without hash or number while filling the state");
+            continue;
+        };
+        state.put(block_hash, block_number);
+        fork_filter.set_head(block_number.as_u64(), block.timestamp.as_u64());
+    }
+
+    Ok(head.saturating_sub(latest_block))
+}
+
+/// Fetch a block while filling the state, retrying a few times before skipping it rather than failing the whole fill.
+async fn fetch_block(blocks: &impl BlockSource, block_number: u64) -> Option<Block<H256>> {
+    let mut backoff = BLOCK_FETCH_BACKOFF;
+    for attempt in 1..=BLOCK_FETCH_ATTEMPTS {
+        match blocks.get_block(block_number).await {
+            Ok(Some(block)) => return Some(block),
+            Ok(None) => {
+                warn!("Block {block_number} not found while filling the state, skipping");
+                return None;
+            }
+            Err(e) if attempt < BLOCK_FETCH_ATTEMPTS => {
+                warn!("Failed to fetch block {block_number}: {e}, retrying in {backoff}ms...");
+                time::sleep(Duration::from_millis(backoff)).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                warn!("Failed to fetch block {block_number}: {e}, skipping");
+            }
+        }
+    }
+    None
+}
+
+/// Convert a discv5 [`Enr`] of an execution layer node into a dialable [`NodeRecord`].
+///
+/// Returns `None` for records without an `eth` entry (e.g. consensus layer nodes) or without an ip and tcp port. The
+/// ipv4 address is preferred, ipv6-only peers are dialed on their ipv6 address.
+fn enr_to_node_record(enr: &Enr) -> Option<NodeRecord> {
+    enr.get("eth")?;
+    let (address, tcp_port, udp_port) = match (enr.ip4(), enr.tcp4()) {
+        (Some(ip), Some(tcp_port)) => (IpAddr::from(ip), tcp_port, enr.udp4().unwrap_or(tcp_port)),
+        _ => {
+            // the ipv6 ports may be left out when they're the same as the ipv4 ones
+            let tcp_port = enr.tcp6().or(enr.tcp4())?;
+            let udp_port = enr.udp6().or(enr.udp4()).unwrap_or(tcp_port);
+            (IpAddr::from(enr.ip6()?), tcp_port, udp_port)
+        }
+    };
+    let id = PeerId::from_slice(&enr.public_key().encode_uncompressed());
+    Some(NodeRecord {
+        address,
+        tcp_port,
+        udp_port,
+        id,
+    })
+}
+
+/// The key/value pairs of a discv5 [`Enr`] as a JSON object, along with its `seq` and `node_id`.
+///
+/// The ip, ports and identity scheme are decoded, the other values are kept as hex of their RLP encoding.
+fn enr_to_json(enr: &Enr) -> serde_json::Value {
+    let mut entries = serde_json::Map::new();
+    entries.insert("seq".to_string(), enr.seq().into());
+    entries.insert("node_id".to_string(), to_hex(&enr.node_id().raw()).into());
+    for (key, value) in enr.iter() {
+        let key = String::from_utf8_lossy(key).into_owned();
+        let decoded = match key.as_str() {
+            "id" => enr.id().map(serde_json::Value::from),
+            "ip" => enr.ip4().map(|ip| ip.to_string().into()),
+            "ip6" => enr.ip6().map(|ip| ip.to_string().into()),
+            "tcp" => enr.tcp4().map(serde_json::Value::from),
+            "udp" => enr.udp4().map(serde_json::Value::from),
+            "tcp6" => enr.tcp6().map(serde_json::Value::from),
+            "udp6" => enr.udp6().map(serde_json::Value::from),
+            _ => None,
+        };
+        entries.insert(key, decoded.unwrap_or_else(|| to_hex(value).into()));
+    }
+    serde_json::Value::Object(entries)
+}
+
+/// `bytes` as a `0x` prefixed hex string.
+fn to_hex(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("0x{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use discv5::enr::{CombinedKey, EnrBuilder};
+    use discv5::Discv5ConfigBuilder;
+    use reth_crawler_db::InMemoryPeerDB;
+    use reth_discv4::Discv4ConfigBuilder;
+    use reth_eth_wire::Status;
+    use reth_network::config::rng_secret_key;
+    use reth_network::{NetworkConfig, NetworkManager};
+    use reth_network_api::ReputationChangeKind;
+    use reth_primitives::{ForkHash, ForkId};
+    use reth_provider::test_utils::NoopProvider;
+
+    use super::*;
+    use crate::crawler::network::{HandshakeError, PeerHandshake};
+    use crate::crawler::test_utils::{
+        node_record, peer_handshake, saved_peer, test_config, wait_until, MockNetwork,
+    };
+    use crate::p2p::{FailureCause, HandshakeFailure};
+
+    /// Discovery and network services bound to the loopback, the tests feed their own updates to the listener.
+    async fn sources(key: SecretKey) -> Sources {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let net_conf = NetworkConfig::<()>::builder(key)
+            .listener_addr(localhost)
+            .disable_discovery()
+            .build(Arc::from(NoopProvider::default()));
+        let network = NetworkManager::new(net_conf)
+            .await
+            .expect("the loopback can be bound");
+        let net_handle = network.handle().clone();
+        tokio::spawn(network);
+        let discv4 = Discv4::spawn(
+            localhost,
+            NodeRecord::from_secret_key(localhost, &key),
+            key,
+            Discv4ConfigBuilder::default().build(),
+        )
+        .await
+        .expect("the loopback can be bound");
+        let discv5_key = CombinedKey::secp256k1_from_bytes(&mut key.secret_bytes())
+            .expect("it's a valid secret key");
+        let discv5_enr = EnrBuilder::new("v4")
+            .build(&discv5_key)
+            .expect("it's a valid enr");
+        let discv5 = Discv5::new(discv5_enr, discv5_key, Discv5ConfigBuilder::new().build())
+            .expect("discv5 is created");
+        Sources {
+            discv4,
+            discv5: Arc::new(discv5),
+            dnsdisc: vec![],
+            network: net_handle,
+            admin: None,
+        }
+    }
+
+    /// A listener dialing and disconnecting the peers through `network`.
+    async fn listener(
+        network: Arc<MockNetwork>,
+        db: Arc<dyn PeerDB>,
+        config: CrawlerConfig,
+    ) -> UpdateListener {
+        let key = rng_secret_key();
+        UpdateListener::with_network(sources(key).await, network, key, db, config).await
+    }
+
+    /// Surface `peer` through discv4, then wait until its crawl is over.
+    async fn discover(listener: &UpdateListener, network: &MockNetwork, peer: NodeRecord) {
+        let dials = network.dials(peer.id);
+        listener
+            .handle_discv4_updates(futures::stream::iter([DiscoveryUpdate::Added(peer)]))
+            .await;
+        wait_until(|| network.dials(peer.id) > dials && listener.in_flight.count() == 0).await;
+    }
+
+    /// The handshakes of a synced peer whose best block is `blockhash`.
+    fn handshake_at(blockhash: reth_primitives::H256) -> PeerHandshake {
+        PeerHandshake {
+            status: Status {
+                blockhash,
+                ..Default::default()
+            },
+            ..peer_handshake("Geth/v1.13.5-stable/linux-amd64/go1.21.4")
+        }
+    }
+
+    #[tokio::test]
+    async fn bans_peers_failing_the_threshold_of_handshakes() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            p2p_failure_threshold: 2,
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db.clone(), config).await;
+        let peer = node_record([203, 0, 113, 1]);
+        for _ in 0..2 {
+            network.script(
+                peer.id,
+                Err(HandshakeError::Failed(
+                    HandshakeFailure::Permanent,
+                    FailureCause::Other,
+                )),
+            );
+        }
+
+        discover(&listener, &network, peer).await;
+        assert_eq!(network.lookups(peer.id), 1);
+        assert!(!listener.banned_ips.is_banned(&peer.address));
+        discover(&listener, &network, peer).await;
+        assert!(listener.banned_ips.is_banned(&peer.address));
+        listener.flush_writes().await;
+        assert!(saved_peer(&*db, &peer).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn too_many_peers_doesnt_count_toward_a_ban() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            p2p_failure_threshold: 2,
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db, config).await;
+        let peer = node_record([203, 0, 113, 2]);
+        for _ in 0..3 {
+            network.script(peer.id, Err(HandshakeError::TooManyPeers));
+        }
+
+        for _ in 0..3 {
+            discover(&listener, &network, peer).await;
+        }
+        assert!(!listener.banned_ips.is_banned(&peer.address));
+    }
+
+    #[tokio::test]
+    async fn bans_peers_on_another_network_at_once() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let peer = node_record([203, 0, 113, 3]);
+        network.script(peer.id, Err(HandshakeError::WrongNetwork));
+
+        discover(&listener, &network, peer).await;
+        assert!(listener.banned_ips.is_banned(&peer.address));
+        listener.flush_writes().await;
+        assert!(saved_peer(&*db, &peer).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn bans_dialed_peers_without_client_version() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let peer = node_record([203, 0, 113, 4]);
+        network.script(peer.id, Ok(peer_handshake("")));
+
+        discover(&listener, &network, peer).await;
+        assert!(listener.banned_ips.is_banned(&peer.address));
+        listener.flush_writes().await;
+        assert!(saved_peer(&*db, &peer).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disconnects_sessions_without_client_version() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let peer = node_record([203, 0, 113, 5]);
+
+        listener
+            .handle_network_events(futures::stream::iter([SessionEvent::Established(
+                SessionInfo {
+                    peer_id: peer.id,
+                    remote_addr: SocketAddr::new(peer.address, 41234),
+                    client_version: String::new(),
+                    capabilities: vec!["eth/68".to_string()],
+                    status: Status::default(),
+                    eth_version: 68,
+                },
+            )]))
+            .await;
+        wait_until(|| network.removals(peer.id) == 1).await;
+        assert!(matches!(
+            network.reputation_changes(peer.id)[..],
+            [ReputationChangeKind::BadProtocol]
+        ));
+        listener.flush_writes().await;
+        assert!(saved_peer(&*db, &peer).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_sessions_count_as_network_updates() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db, test_config()).await;
+        let peer = node_record([203, 0, 113, 9]);
+
+        listener
+            .handle_network_events(futures::stream::iter([
+                SessionEvent::PeerAdded(peer.id),
+                SessionEvent::Established(SessionInfo {
+                    peer_id: peer.id,
+                    remote_addr: SocketAddr::new(peer.address, 41234),
+                    client_version: String::new(),
+                    capabilities: vec!["eth/68".to_string()],
+                    status: Status::default(),
+                    eth_version: 68,
+                }),
+                SessionEvent::Closed {
+                    peer_id: peer.id,
+                    reason: None,
+                },
+                SessionEvent::PeerRemoved(peer.id),
+            ]))
+            .await;
+        assert_eq!(
+            listener.progress.discovery_updates(),
+            "discv4=0 dnsdisc=0 discv5=0 network=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn saves_then_disconnects_established_sessions() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let peer = node_record([203, 0, 113, 10]);
+        let (events, receiver) = futures::channel::mpsc::unbounded();
+
+        events
+            .unbounded_send(SessionEvent::Established(SessionInfo {
+                peer_id: peer.id,
+                remote_addr: SocketAddr::new(peer.address, 41234),
+                client_version: "Geth/v1.13.5-stable/linux-amd64/go1.21.4".to_string(),
+                capabilities: vec!["eth/67".to_string(), "eth/68".to_string()],
+                status: Status::default(),
+                eth_version: 68,
+            }))
+            .unwrap();
+        drop(events);
+        listener.handle_network_events(receiver).await;
+        wait_until(|| network.removals(peer.id) > 0 && listener.in_flight.count() == 0).await;
+        listener.flush_writes().await;
+
+        assert_eq!(network.removals(peer.id), 1);
+        assert!(network.reputation_changes(peer.id).is_empty());
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert_eq!(
+            saved.client_version,
+            "Geth/v1.13.5-stable/linux-amd64/go1.21.4"
+        );
+        assert_eq!(saved.capabilities, vec!["eth/67", "eth/68"]);
+        assert_eq!(saved.eth_version, 68);
+        assert_eq!(saved.address, "203.0.113.10");
+        assert_eq!(saved.discovery_source.as_deref(), Some("network"));
+        assert_eq!(saved.handshake_completed, Some(true));
+    }
+
+    #[tokio::test]
+    async fn synced_is_checked_against_the_state() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let head = reth_primitives::H256::from_low_u64_be(1);
+        let behind = reth_primitives::H256::from_low_u64_be(2);
+        let before_state = node_record([203, 0, 113, 6]);
+        let synced = node_record([203, 0, 113, 7]);
+        let lagging = node_record([203, 0, 113, 8]);
+        network.script(before_state.id, Ok(handshake_at(head)));
+        network.script(synced.id, Ok(handshake_at(head)));
+        network.script(lagging.id, Ok(handshake_at(behind)));
+
+        // the state holds no block yet
+        discover(&listener, &network, before_state).await;
+        listener.state.put(H256::from_low_u64_be(1), U64::from(100));
+        discover(&listener, &network, synced).await;
+        discover(&listener, &network, lagging).await;
+        listener.flush_writes().await;
+
+        let before_state = saved_peer(&*db, &before_state).await.unwrap();
+        assert_eq!(before_state.synced, None);
+        let synced = saved_peer(&*db, &synced).await.unwrap();
+        assert_eq!(synced.synced, Some(true));
+        assert_eq!(synced.head_block_number, Some(100));
+        assert_eq!(synced.crawler_head_number, Some(100));
+        let lagging = saved_peer(&*db, &lagging).await.unwrap();
+        assert_eq!(lagging.synced, Some(false));
+        assert_eq!(lagging.head_block_number, None);
+    }
+
+    /// An ENR of an execution layer node, with `ip` and `ip6` if any.
+    fn enr(ip: Option<Ipv4Addr>, ip6: Option<Ipv6Addr>) -> Enr {
+        let mut builder = EnrBuilder::new("v4");
+        builder.add_value("eth", &0u64);
+        if let Some(ip) = ip {
+            builder.ip4(ip).tcp4(30303).udp4(30301);
+        }
+        if let Some(ip6) = ip6 {
+            builder.ip6(ip6).tcp6(30304);
+        }
+        builder
+            .build(&CombinedKey::generate_secp256k1())
+            .expect("it's a valid enr")
+    }
+
+    #[test]
+    fn ipv6_only_enrs_are_dialed_on_their_ipv6_address() {
+        let ip6 = "2001:db8::1".parse().unwrap();
+        let enr = enr(None, Some(ip6));
+
+        let peer = enr_to_node_record(&enr).expect("the peer is dialable");
+        assert_eq!(peer.address, IpAddr::V6(ip6));
+        assert_eq!(peer.tcp_port, 30304);
+        assert_eq!(peer.udp_port, 30304);
+        let json = enr_to_json(&enr);
+        assert_eq!(json["ip6"], "2001:db8::1");
+        assert_eq!(json["tcp6"], 30304);
+    }
+
+    #[test]
+    fn dual_stack_enrs_are_dialed_on_their_ipv4_address() {
+        let enr = enr(
+            Some(Ipv4Addr::new(203, 0, 113, 1)),
+            Some("2001:db8::1".parse().unwrap()),
+        );
+
+        let peer = enr_to_node_record(&enr).expect("the peer is dialable");
+        assert_eq!(peer.address, IpAddr::from([203, 0, 113, 1]));
+        assert_eq!(peer.tcp_port, 30303);
+        assert_eq!(peer.udp_port, 30301);
+        let json = enr_to_json(&enr);
+        assert_eq!(json["ip"], "203.0.113.1");
+        assert_eq!(json["ip6"], "2001:db8::1");
+    }
+
+    #[tokio::test]
+    async fn ipv6_peers_are_stored_with_their_canonical_address() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network.clone(), db.clone(), test_config()).await;
+        let peer = node_record("2001:db8:0:0::7".parse::<Ipv6Addr>().unwrap());
+        let mapped = node_record(Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped());
+
+        discover(&listener, &network, peer).await;
+        discover(&listener, &network, mapped).await;
+        listener.flush_writes().await;
+
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert_eq!(saved.address, "2001:db8::7");
+        let saved = saved_peer(&*db, &mapped).await.expect("the peer was saved");
+        assert_eq!(saved.address, "203.0.113.7");
+    }
+
+    #[tokio::test]
+    async fn crawls_without_an_eth_rpc() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            // nothing listens there, so the web socket can't connect
+            eth_rpc_url: "ws://127.0.0.1:1".to_string(),
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db.clone(), config).await;
+        let peer = node_record([203, 0, 113, 8]);
+
+        assert!(listener.fetch_new_blocks().await.is_err());
+        discover(&listener, &network, peer).await;
+        listener.flush_writes().await;
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert_eq!(saved.handshake_completed, Some(true));
+        assert_eq!(saved.synced, None);
+    }
+
+    #[tokio::test]
+    async fn saves_the_crawls_completing_within_the_grace_period() {
+        let network = Arc::new(MockNetwork::with_delay(Duration::from_millis(200)));
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            shutdown_grace_period: Duration::from_secs(5),
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db.clone(), config).await;
+        let peers = [
+            node_record([203, 0, 113, 9]),
+            node_record([203, 0, 113, 10]),
+            node_record([203, 0, 113, 11]),
+        ];
+
+        listener
+            .handle_discv4_updates(futures::stream::iter(peers.map(DiscoveryUpdate::Added)))
+            .await;
+        // every handshake is under way when the shutdown is requested
+        wait_until(|| peers.iter().all(|peer| network.dials(peer.id) == 1)).await;
+        listener.shutdown_token().cancel();
+        listener.wait_for_crawls().await;
+        listener.flush_writes().await;
+        for peer in &peers {
+            let saved = saved_peer(&*db, peer).await.expect("the peer was saved");
+            assert_eq!(saved.handshake_completed, Some(true));
+        }
+    }
+
+    #[tokio::test]
+    async fn records_peers_advertising_another_network_without_dialing_them() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            // the ENR releases the peer, not the wait
+            enr_wait: Duration::from_secs(60),
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db.clone(), config).await;
+        let peer = node_record([203, 0, 113, 15]);
+        let fork_id = ForkId {
+            hash: ForkHash([0xde, 0xad, 0xbe, 0xef]),
+            next: 0,
+        };
+
+        time::timeout(
+            Duration::from_secs(5),
+            listener.handle_discv4_updates(futures::stream::iter([
+                DiscoveryUpdate::Added(peer),
+                DiscoveryUpdate::EnrForkId(peer.id, fork_id),
+            ])),
+        )
+        .await
+        .expect("the ENR released the peer");
+        wait_until(|| listener.fork_filter.avoided_dials() == 1 && listener.in_flight.count() == 0)
+            .await;
+        listener.flush_writes().await;
+        assert_eq!(network.dials(peer.id), 0);
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert!(saved.off_network);
+        assert_eq!(saved.handshake_completed, Some(false));
+    }
+
+    #[tokio::test]
+    async fn waits_for_room_in_the_candidate_queue() {
+        let network = Arc::new(MockNetwork::with_delay(Duration::from_millis(500)));
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            max_concurrent_handshakes: 1,
+            candidate_queue_capacity: 1,
+            ..test_config()
+        };
+        let listener = listener(network.clone(), db, config).await;
+        let peers = [
+            node_record([203, 0, 113, 12]),
+            node_record([203, 0, 113, 13]),
+            node_record([203, 0, 113, 14]),
+        ];
+
+        // the single worker crawls the first peer and the queue holds the second, so the third has to wait
+        let enqueued = time::timeout(
+            Duration::from_millis(100),
+            listener
+                .handle_discv4_updates(futures::stream::iter(peers.map(DiscoveryUpdate::Added))),
+        )
+        .await;
+
+        assert!(enqueued.is_err());
+        wait_until(|| network.dials(peers[1].id) == 1 && listener.in_flight.count() == 0).await;
+        assert_eq!(network.dials(peers[0].id), 1);
+        assert_eq!(network.dials(peers[2].id), 0);
+    }
+
+    #[tokio::test]
+    async fn resubscribes_after_the_stream_ends() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let listener = listener(network, db, test_config()).await;
+        let shutdown = listener.shutdown_token();
+        let subscriptions = AtomicUsize::new(0);
+        let handled = &Mutex::new(vec![]);
+
+        listener
+            .resubscribing(
+                "test",
+                || {
+                    let subscription = subscriptions.fetch_add(1, Ordering::SeqCst);
+                    // stop once the finite stream was subscribed to twice
+                    if subscription == 2 {
+                        shutdown.cancel();
+                    }
+                    futures::future::ready(Ok::<_, String>(futures::stream::iter([subscription])))
+                },
+                move |stream| async move {
+                    let items: Vec<_> = stream.collect().await;
+                    handled.lock().unwrap().extend(items);
+                },
+            )
+            .await;
+
+        assert_eq!(subscriptions.load(Ordering::SeqCst), 3);
+        assert_eq!(handled.lock().unwrap()[..2], [0, 1]);
+    }
+
+    /// A chain whose block `n` has the hash `n + 1`, up to its head.
+    #[derive(Default)]
+    struct MockChain {
+        head: AtomicU64,
+    }
+
+    impl MockChain {
+        fn at(head: u64) -> Self {
+            Self {
+                head: AtomicU64::new(head),
+            }
+        }
+
+        fn hash(block_number: u64) -> H256 {
+            H256::from_low_u64_be(block_number + 1)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BlockSource for MockChain {
+        async fn get_block_number(&self) -> eyre::Result<u64> {
+            Ok(self.head.load(Ordering::SeqCst))
+        }
+
+        async fn get_block(&self, block_number: u64) -> eyre::Result<Option<Block<H256>>> {
+            if block_number > self.head.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            Ok(Some(Block {
+                hash: Some(MockChain::hash(block_number)),
+                number: Some(block_number.into()),
+                timestamp: block_number.into(),
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn fills_the_state_of_a_chain_shorter_than_the_threshold() {
+        let chain = MockChain::at(42);
+        let state = BlockHashNum::new(100);
+
+        let behind = fill_state(&chain, &state, &ForkIdFilter::default(), 100)
+            .await
+            .unwrap();
+
+        assert_eq!(behind, 42);
+        assert_eq!(state.latest_block.load(Ordering::SeqCst), 42);
+        for block_number in 0..=42 {
+            assert_eq!(
+                state.block_number(&MockChain::hash(block_number)),
+                Some(block_number)
+            );
+        }
+        assert_eq!(state.is_synced(&MockChain::hash(42)), Some(true));
+    }
+
+    #[tokio::test]
+    async fn fills_the_state_of_a_chain_at_genesis() {
+        let chain = MockChain::default();
+        let state = BlockHashNum::new(100);
+
+        fill_state(&chain, &state, &ForkIdFilter::default(), 100)
+            .await
+            .unwrap();
+
+        assert_eq!(state.block_number(&MockChain::hash(0)), Some(0));
+    }
+
+    #[tokio::test]
+    async fn backfills_the_blocks_missed_while_disconnected() {
+        let chain = MockChain::at(1000);
+        let state = BlockHashNum::new(100);
+        fill_state(&chain, &state, &ForkIdFilter::default(), 100)
+            .await
+            .unwrap();
+
+        // the chain moves on while the rpc is unreachable
+        chain.head.store(1040, Ordering::SeqCst);
+        let behind = fill_state(&chain, &state, &ForkIdFilter::default(), 100)
+            .await
+            .unwrap();
+
+        assert_eq!(behind, 40);
+        assert_eq!(state.latest_block.load(Ordering::SeqCst), 1040);
+        for block_number in 941..=1040 {
+            assert_eq!(
+                state.block_number(&MockChain::hash(block_number)),
+                Some(block_number),
+                "block {block_number} is missing"
+            );
+        }
+    }
 }
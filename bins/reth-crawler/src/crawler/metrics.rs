@@ -0,0 +1,171 @@
+//! Names of the crawler metrics, recorded through the [`metrics`] facade.
+
+use std::net::SocketAddr;
+
+use eyre::WrapErr;
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+/// Updates received from the listeners, labelled by `source`.
+pub const DISCOVERY_UPDATES: &str = "discovery_updates_total";
+/// Handshakes attempted with dialed peers, retries included, labelled by `identity`.
+pub const HANDSHAKE_ATTEMPTS: &str = "handshake_attempts_total";
+/// Successful p2p and eth handshakes with dialed peers, labelled by `identity`.
+pub const HANDSHAKE_SUCCESSES: &str = "handshake_successes_total";
+/// Failed handshakes with dialed peers, labelled by error `class`, `cause` and `identity`.
+pub const HANDSHAKE_FAILURES: &str = "handshake_failures_total";
+/// Peers with too many peers dialed again under another identity.
+pub const TOO_MANY_PEERS_REDIALS: &str = "too_many_peers_redials_total";
+/// Peers with too many peers crawled once dialed under another identity.
+pub const TOO_MANY_PEERS_REDIAL_SUCCESSES: &str = "too_many_peers_redial_successes_total";
+/// Peers skipped because their ip is denied, or not allowed.
+pub const IPS_FILTERED: &str = "ips_filtered_total";
+/// Ips banned, labelled by ban `reason`.
+pub const BANS: &str = "bans_total";
+/// Sessions closed by a disconnect, labelled by disconnect `reason`.
+pub const DISCONNECTS: &str = "disconnects_total";
+/// Crawled peers not saved because they matched none of the save filters.
+pub const PEERS_FILTERED: &str = "peers_filtered_total";
+/// Peers neither dialed nor saved because their ip showed too many peer ids.
+pub const SYBIL_SUPPRESSED: &str = "sybil_suppressed_total";
+/// Peers written to the db.
+pub const PEERS_SAVED: &str = "peers_saved_total";
+/// Peers saved that the crawler didn't remember crawling before.
+pub const NEW_PEERS: &str = "new_peers_total";
+/// Peers that couldn't be written to the db, even one by one.
+pub const DB_WRITE_FAILURES: &str = "db_write_failures_total";
+/// Peers that waited for room in the writer queue.
+pub const WRITER_BACKPRESSURE: &str = "writer_backpressure_total";
+/// Duration of the peer writes, labelled by `op`: `batch` or `single`.
+pub const DB_WRITE_SECONDS: &str = "db_write_seconds";
+/// Duration of the geolocation requests, labelled by `provider`.
+pub const GEOLOCATION_REQUEST_SECONDS: &str = "geolocation_request_seconds";
+/// Failed geolocation requests, labelled by `provider`.
+pub const GEOLOCATION_FAILURES: &str = "geolocation_failures_total";
+/// Geolocations served from the cache.
+pub const GEOLOCATION_CACHE_HITS: &str = "geolocation_cache_hits_total";
+/// Geolocations missing from the cache, or expired.
+pub const GEOLOCATION_CACHE_MISSES: &str = "geolocation_cache_misses_total";
+/// Stale geolocations served because they couldn't be looked up again.
+pub const GEOLOCATION_CACHE_STALE: &str = "geolocation_cache_stale_total";
+/// Geolocation requests that waited for the rate limit, labelled by `provider`.
+pub const GEOLOCATION_RATE_LIMIT_WAITS: &str = "geolocation_rate_limit_waits_total";
+/// Fallback geolocation providers skipped because they were out of requests, labelled by `provider`.
+pub const GEOLOCATION_RATE_LIMIT_SKIPS: &str = "geolocation_rate_limit_skips_total";
+/// Peers being crawled.
+pub const CRAWLS_IN_FLIGHT: &str = "crawls_in_flight";
+/// Handshakes holding a permit.
+pub const HANDSHAKES_IN_FLIGHT: &str = "handshakes_in_flight";
+/// Blocks held by the state used to tell whether peers are synced.
+pub const STATE_BLOCKS: &str = "state_blocks";
+/// Latest block number of the state.
+pub const STATE_LATEST_BLOCK: &str = "state_latest_block";
+/// Unix timestamp of the last block added to the state, its age tells whether new blocks still come in.
+pub const STATE_UPDATED_SECONDS: &str = "state_updated_timestamp_seconds";
+
+/// Buckets of the histograms, in seconds.
+const HISTOGRAM_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Serve the metrics in the Prometheus format on `addr`.
+pub fn install_exporter(addr: SocketAddr) -> eyre::Result<()> {
+    // histograms are rendered as summaries unless they have buckets
+    PrometheusBuilder::new()
+        .set_buckets(HISTOGRAM_BUCKETS)?
+        .with_http_listener(addr)
+        .install()
+        .wrap_err_with(|| format!("failed to serve metrics on {addr}"))
+}
+
+/// Describe the crawler metrics to the installed recorder.
+pub fn describe() {
+    describe_counter!(
+        DISCOVERY_UPDATES,
+        "Updates received from the listeners, by source"
+    );
+    describe_counter!(
+        HANDSHAKE_ATTEMPTS,
+        "Handshakes attempted with dialed peers, retries included, by identity"
+    );
+    describe_counter!(
+        HANDSHAKE_SUCCESSES,
+        "Successful handshakes with dialed peers, by identity"
+    );
+    describe_counter!(
+        HANDSHAKE_FAILURES,
+        "Failed handshakes with dialed peers, by error class, cause and identity"
+    );
+    describe_counter!(
+        TOO_MANY_PEERS_REDIALS,
+        "Peers with too many peers dialed again under another identity"
+    );
+    describe_counter!(
+        TOO_MANY_PEERS_REDIAL_SUCCESSES,
+        "Peers with too many peers crawled once dialed under another identity"
+    );
+    describe_counter!(
+        IPS_FILTERED,
+        "Peers skipped because their ip is denied, or not allowed"
+    );
+    describe_counter!(BANS, "Ips banned, by reason");
+    describe_counter!(DISCONNECTS, "Sessions closed by a disconnect, by reason");
+    describe_counter!(
+        PEERS_FILTERED,
+        "Crawled peers not saved because they matched none of the save filters"
+    );
+    describe_counter!(
+        SYBIL_SUPPRESSED,
+        "Peers neither dialed nor saved because their ip showed too many peer ids"
+    );
+    describe_counter!(PEERS_SAVED, "Peers written to the db");
+    describe_counter!(
+        NEW_PEERS,
+        "Peers saved that the crawler didn't remember crawling before"
+    );
+    describe_counter!(
+        DB_WRITE_FAILURES,
+        "Peers that couldn't be written to the db, even one by one"
+    );
+    describe_counter!(
+        WRITER_BACKPRESSURE,
+        "Peers that waited for room in the writer queue"
+    );
+    describe_histogram!(
+        DB_WRITE_SECONDS,
+        Unit::Seconds,
+        "Duration of the peer writes"
+    );
+    describe_histogram!(
+        GEOLOCATION_REQUEST_SECONDS,
+        Unit::Seconds,
+        "Duration of the geolocation requests"
+    );
+    describe_counter!(GEOLOCATION_FAILURES, "Failed geolocation requests");
+    describe_counter!(GEOLOCATION_CACHE_HITS, "Geolocations served from the cache");
+    describe_counter!(
+        GEOLOCATION_CACHE_MISSES,
+        "Geolocations missing from the cache, or expired"
+    );
+    describe_counter!(
+        GEOLOCATION_CACHE_STALE,
+        "Stale geolocations served because they couldn't be looked up again"
+    );
+    describe_counter!(
+        GEOLOCATION_RATE_LIMIT_WAITS,
+        "Geolocation requests that waited for the rate limit"
+    );
+    describe_counter!(
+        GEOLOCATION_RATE_LIMIT_SKIPS,
+        "Fallback geolocation providers skipped because they were out of requests"
+    );
+    describe_gauge!(CRAWLS_IN_FLIGHT, "Peers being crawled");
+    describe_gauge!(HANDSHAKES_IN_FLIGHT, "Handshakes holding a permit");
+    describe_gauge!(STATE_BLOCKS, "Blocks held by the state");
+    describe_gauge!(STATE_LATEST_BLOCK, "Latest block number of the state");
+    describe_gauge!(
+        STATE_UPDATED_SECONDS,
+        Unit::Seconds,
+        "Unix timestamp of the last block added to the state"
+    );
+}
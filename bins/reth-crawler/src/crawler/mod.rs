@@ -1,6 +1,35 @@
+mod admin;
+mod bans;
+mod config;
+mod dryrun;
 mod factory;
+mod failures;
+mod forkid;
+mod geo;
+mod identities;
+mod inflight;
+mod ipfilter;
 mod listener;
+mod metrics;
+mod network;
+mod nodekey;
+mod pipeline;
+mod progress;
+mod recent;
+mod reputation;
+mod savefilter;
 mod service;
+mod sybil;
+#[cfg(test)]
+mod test_utils;
+mod writer;
 
+pub use self::bans::BanDurations;
+pub use self::config::CrawlerConfig;
 pub use self::factory::CrawlerBuilder;
+pub use self::geo::GeoProviderConfig;
+pub use self::metrics::install_exporter as install_metrics_exporter;
+pub use self::pipeline::{crawl_one, CrawlOneError};
+pub use self::reputation::ScoreWeights;
+pub use self::savefilter::SaveFilter;
 pub use self::service::CrawlerService;
@@ -0,0 +1,222 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reth_discv4::Discv4;
+use reth_eth_wire::{HelloMessage, Status};
+use reth_network::NetworkHandle;
+use reth_network_api::ReputationChangeKind;
+use reth_primitives::{NodeRecord, PeerId, H256};
+use secp256k1::SecretKey;
+use tokio::time;
+use tracing::info;
+
+use crate::crawler::config::CrawlerConfig;
+use crate::p2p::{
+    handshake_eth, handshake_p2p, request_header, AuthedEthStream, FailureCause, HandshakeFailure,
+};
+
+/// What a dialed peer told about itself in its handshakes.
+pub struct PeerHandshake {
+    pub client_version: String,
+    pub capabilities: Vec<String>,
+    pub p2p_version: u8,
+    pub status: Status,
+    /// Time taken by the tcp connection and both handshakes.
+    pub latency_ms: u64,
+    /// Number and timestamp of the peer's best block, when the peer sent its header.
+    pub best_header: Option<(u64, u64)>,
+}
+
+/// Why the handshakes with a peer failed.
+pub enum HandshakeError {
+    /// The peer refused the p2p handshake because it has too many peers.
+    TooManyPeers,
+    /// The status of the peer doesn't match ours, so the peer isn't on our network.
+    WrongNetwork,
+    /// Any other failure of either handshake.
+    Failed(HandshakeFailure, FailureCause),
+}
+
+impl HandshakeError {
+    /// Class of the error, as a metric label.
+    pub fn class(&self) -> &'static str {
+        match self {
+            HandshakeError::TooManyPeers => "too_many_peers",
+            HandshakeError::WrongNetwork => "wrong_network",
+            HandshakeError::Failed(failure, _) => failure.as_str(),
+        }
+    }
+
+    pub fn cause(&self) -> FailureCause {
+        match self {
+            HandshakeError::TooManyPeers => FailureCause::TooManyPeers,
+            HandshakeError::WrongNetwork => FailureCause::WrongNetwork,
+            HandshakeError::Failed(_, cause) => *cause,
+        }
+    }
+}
+
+/// Everything the crawl pipeline does on the wire.
+///
+/// The pipeline only talks to the peers through this trait, so that it can be driven by a mock network.
+#[async_trait]
+pub trait PeerNetwork: Send + Sync {
+    /// Perform the p2p and eth handshakes with `peer` as the identity of `key`, then ask for its best header.
+    async fn handshake(
+        &self,
+        peer: NodeRecord,
+        key: SecretKey,
+    ) -> Result<PeerHandshake, HandshakeError>;
+
+    /// Kick a discovery lookup of `peer_id`.
+    fn lookup(&self, peer_id: PeerId);
+
+    /// Change the reputation of a peer connected to our node.
+    fn reputation_change(&self, peer_id: PeerId, kind: ReputationChangeKind);
+
+    /// Disconnect a peer connected to our node.
+    fn remove_peer(&self, peer_id: PeerId);
+}
+
+/// Dials the peers with our own handshakes, and manages the sessions of our node through reth's network.
+pub struct RethNetwork {
+    discv4: Discv4,
+    network: NetworkHandle,
+    client_id: String,
+    handshake_timeout: Duration,
+    head_request_timeout: Duration,
+}
+
+impl RethNetwork {
+    pub fn new(discv4: Discv4, network: NetworkHandle, config: &CrawlerConfig) -> Self {
+        Self {
+            discv4,
+            network,
+            client_id: config.client_id.clone(),
+            handshake_timeout: config.handshake_timeout,
+            head_request_timeout: config.head_request_timeout,
+        }
+    }
+
+    /// Ask a dialed peer for the header of its best block, `None` if it doesn't send it in time.
+    async fn best_header(
+        &self,
+        peer: &NodeRecord,
+        eth_stream: &mut AuthedEthStream,
+        hash: H256,
+    ) -> Option<(u64, u64)> {
+        let timeout = self.head_request_timeout;
+        if timeout.is_zero() {
+            return None;
+        }
+        match time::timeout(timeout, request_header(eth_stream, hash)).await {
+            Ok(Ok(header)) => header,
+            Ok(Err(e)) => {
+                info!(
+                    "Failed to get the best header of peer {}: {e}",
+                    peer.address
+                );
+                None
+            }
+            Err(_) => {
+                info!("Peer {} didn't send its best header in time", peer.address);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl PeerNetwork for RethNetwork {
+    async fn handshake(
+        &self,
+        peer: NodeRecord,
+        key: SecretKey,
+    ) -> Result<PeerHandshake, HandshakeError> {
+        // the latency covers the tcp connection and both handshakes, but neither geolocation nor db writes
+        let handshake_start = Instant::now();
+        let (mut eth_stream, their_hello, their_status) =
+            handshake(peer, key, &self.client_id, self.handshake_timeout).await?;
+        let latency_ms = handshake_start.elapsed().as_millis() as u64;
+        // the pipeline drops the peers without client version, their header isn't worth asking
+        let best_header = if their_hello.client_version.is_empty() {
+            None
+        } else {
+            self.best_header(&peer, &mut eth_stream, their_status.blockhash)
+                .await
+        };
+        Ok(PeerHandshake {
+            capabilities: their_hello
+                .capabilities
+                .iter()
+                .map(|cap| cap.to_string())
+                .collect(),
+            client_version: their_hello.client_version,
+            p2p_version: their_hello.protocol_version as u8,
+            status: their_status,
+            latency_ms,
+            best_header,
+        })
+    }
+
+    fn lookup(&self, peer_id: PeerId) {
+        self.discv4.send_lookup(peer_id);
+    }
+
+    fn reputation_change(&self, peer_id: PeerId, kind: ReputationChangeKind) {
+        self.network.peers_handle().reputation_change(peer_id, kind);
+    }
+
+    fn remove_peer(&self, peer_id: PeerId) {
+        self.network.peers_handle().remove_peer(peer_id);
+    }
+}
+
+/// Perform the p2p then the eth handshake with `peer`, each within `timeout`.
+async fn handshake(
+    peer: NodeRecord,
+    key: SecretKey,
+    client_id: &str,
+    timeout: Duration,
+) -> Result<(AuthedEthStream, HelloMessage, Status), HandshakeError> {
+    let (p2p_stream, their_hello) =
+        match time::timeout(timeout, handshake_p2p(peer, key, client_id)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => {
+                info!("Failed P2P handshake with peer {}, {}", peer.address, e);
+                return Err(match e.cause() {
+                    FailureCause::TooManyPeers => HandshakeError::TooManyPeers,
+                    cause => HandshakeError::Failed(HandshakeFailure::classify(&e), cause),
+                });
+            }
+            Err(_) => {
+                info!("Timed out P2P handshake with peer {}", peer.address);
+                return Err(HandshakeError::Failed(
+                    HandshakeFailure::Transient,
+                    FailureCause::Timeout,
+                ));
+            }
+        };
+
+    match time::timeout(timeout, handshake_eth(p2p_stream)).await {
+        Ok(Ok((eth_stream, their_status))) => Ok((eth_stream, their_hello, their_status)),
+        Ok(Err(e)) => {
+            info!("Failed ETH handshake with peer {}, {}", peer.address, e);
+            // only a mismatching status tells the peer is on another network, not a dropped connection nor a
+            // disconnect
+            Err(match e.cause() {
+                FailureCause::WrongNetwork => HandshakeError::WrongNetwork,
+                FailureCause::TooManyPeers => HandshakeError::TooManyPeers,
+                cause => HandshakeError::Failed(HandshakeFailure::classify(&e), cause),
+            })
+        }
+        Err(_) => {
+            // a stalled peer isn't necessarily on another network
+            info!("Timed out ETH handshake with peer {}", peer.address);
+            Err(HandshakeError::Failed(
+                HandshakeFailure::Transient,
+                FailureCause::Timeout,
+            ))
+        }
+    }
+}
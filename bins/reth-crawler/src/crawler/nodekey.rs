@@ -0,0 +1,51 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use eyre::WrapErr;
+use reth_network::config::rng_secret_key;
+use secp256k1::SecretKey;
+
+/// Default file of the node key, in the home directory.
+const NODE_KEY_FILE: &str = ".reth-crawler/node.key";
+
+/// `~/.reth-crawler/node.key`, or relative to the working directory when there's no home.
+pub fn default_node_key_file() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(NODE_KEY_FILE),
+        None => PathBuf::from(NODE_KEY_FILE),
+    }
+}
+
+/// Parse a node key given as hex, with or without a `0x` prefix.
+pub fn parse_node_key(hex: &str) -> eyre::Result<SecretKey> {
+    let hex = hex.trim();
+    SecretKey::from_str(hex.strip_prefix("0x").unwrap_or(hex))
+        .map_err(|e| eyre::eyre!("invalid node key: {e}"))
+}
+
+/// Load the node key of `path`, or generate one and write it there, so that the crawler keeps its identity across
+/// restarts.
+pub fn load_or_generate(path: &Path) -> eyre::Result<SecretKey> {
+    if path.exists() {
+        let hex = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read the node key file {}", path.display()))?;
+        return parse_node_key(&hex).wrap_err_with(|| format!("in {}", path.display()));
+    }
+    let key = rng_secret_key();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("failed to create the directory {}", dir.display()))?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // the key is the identity of the crawler, only its owner may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", key.display_secret()))
+        .wrap_err_with(|| format!("failed to write the node key file {}", path.display()))?;
+    Ok(key)
+}
@@ -0,0 +1,1343 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::crawler::bans::{BanReason, BannedIps};
+use crate::crawler::config::CrawlerConfig;
+use crate::crawler::failures::P2pFailures;
+use crate::crawler::forkid::ForkIdFilter;
+use crate::crawler::geo::{GeoInfo, Geolocator};
+use crate::crawler::identities::Identities;
+use crate::crawler::inflight::InFlightPeers;
+use crate::crawler::ipfilter::IpFilter;
+use crate::crawler::listener::eth_rpc::EthRpc;
+use crate::crawler::listener::{fill_state, BlockHashNum};
+use crate::crawler::metrics;
+use crate::crawler::network::{HandshakeError, PeerHandshake, PeerNetwork};
+use crate::crawler::progress::Progress;
+use crate::crawler::recent::RecentPeers;
+use crate::crawler::reputation::CrawlOutcome;
+use crate::crawler::sybil::SybilGuard;
+use crate::crawler::writer::PeerWriter;
+use crate::p2p::{
+    handshake_eth, handshake_p2p_over, request_header, FailureCause, HandshakeFailure,
+};
+use ::metrics::counter;
+use chrono::{DateTime, Utc};
+use reth_crawler_db::types::canonical_ip;
+use reth_crawler_db::{build_peer_db, PeerDB, PeerData};
+use reth_eth_wire::Status;
+use reth_network::config::rng_secret_key;
+use reth_network_api::ReputationChangeKind;
+use reth_primitives::{ForkId, NodeRecord, PeerId};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+/// How a peer was surfaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoverySource {
+    Discv4,
+    /// The peer was found in the DNS tree at this index of `dns_trees`.
+    Dnsdisc(usize),
+    Discv5,
+    /// The peer established a session with our node.
+    Network,
+    /// The peer was stale in the db and crawled again.
+    Recrawler,
+    /// The crawl of the peer was requested through the admin routes.
+    Admin,
+    /// The peer was crawled on its own with `crawl-one`.
+    CrawlOne,
+}
+
+impl DiscoverySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiscoverySource::Discv4 => "discv4",
+            DiscoverySource::Dnsdisc(_) => "dnsdisc",
+            DiscoverySource::Discv5 => "discv5",
+            DiscoverySource::Network => "network",
+            DiscoverySource::Recrawler => "recrawler",
+            DiscoverySource::Admin => "admin",
+            DiscoverySource::CrawlOne => "crawl_one",
+        }
+    }
+}
+
+/// A peer surfaced by one of the listeners.
+pub enum CrawlCandidate {
+    /// A peer found through discovery, it's dialed to handshake with it. The ENR it was found with is kept as JSON
+    /// when `store_enr` is set.
+    Dial(NodeRecord, DiscoverySource, Option<serde_json::Value>),
+    /// A peer that established a session with our node, the session already holds its data.
+    Session(SessionInfo),
+}
+
+impl CrawlCandidate {
+    /// Ip of the peer.
+    pub fn ip(&self) -> IpAddr {
+        match self {
+            CrawlCandidate::Dial(peer, ..) => peer.address,
+            CrawlCandidate::Session(session) => session.remote_addr.ip(),
+        }
+    }
+
+    /// The candidate with its ip in canonical form, so that ipv4 peers reached as `::ffff:a.b.c.d` are filtered,
+    /// banned, geolocated and stored like the others.
+    fn with_canonical_ip(mut self) -> Self {
+        match &mut self {
+            CrawlCandidate::Dial(peer, ..) => peer.address = canonical_ip(peer.address),
+            CrawlCandidate::Session(session) => session
+                .remote_addr
+                .set_ip(canonical_ip(session.remote_addr.ip())),
+        }
+        self
+    }
+}
+
+/// What the network tells about an established session.
+pub struct SessionInfo {
+    pub peer_id: PeerId,
+    pub remote_addr: SocketAddr,
+    pub client_version: String,
+    pub capabilities: Vec<String>,
+    pub status: Status,
+    pub eth_version: u8,
+}
+
+/// What a peer told about itself in its handshakes, however they were performed.
+struct Handshaked {
+    peer: NodeRecord,
+    client_version: String,
+    capabilities: Vec<String>,
+    status: Status,
+    eth_version: u8,
+    latency_ms: Option<u64>,
+    p2p_version: Option<u8>,
+    port_reachable: Option<bool>,
+    source: DiscoverySource,
+    /// Number and timestamp of the peer's best block, when the peer sent its header.
+    best_header: Option<(u64, u64)>,
+    /// Domain of the DNS tree the peer was found in.
+    dns_tree: Option<String>,
+    /// The ENR the peer was found with, as JSON.
+    enr: Option<serde_json::Value>,
+}
+
+impl Handshaked {
+    /// What a peer we dialed told in `handshake`, its `eth_version` being the one negotiated in its status rather than
+    /// the highest of its capabilities.
+    fn dialed(
+        peer: NodeRecord,
+        handshake: PeerHandshake,
+        source: DiscoverySource,
+        dns_tree: Option<String>,
+        enr: Option<serde_json::Value>,
+    ) -> Self {
+        let PeerHandshake {
+            client_version,
+            capabilities,
+            p2p_version,
+            status,
+            latency_ms,
+            best_header,
+        } = handshake;
+        Handshaked {
+            peer,
+            client_version,
+            capabilities,
+            eth_version: status.version,
+            status,
+            latency_ms: Some(latency_ms),
+            p2p_version: Some(p2p_version),
+            // we just connected to it
+            port_reachable: Some(true),
+            source,
+            best_header,
+            dns_tree,
+            enr,
+        }
+    }
+}
+
+/// Whether a peer is synced, as told by the state and the header of its best block.
+struct SyncCheck {
+    synced: Option<bool>,
+    on_canonical: Option<bool>,
+    sync_lag_blocks: Option<u64>,
+    sync_lag_seconds: Option<u64>,
+    head_block_number: Option<u64>,
+    crawler_head_number: Option<u64>,
+}
+
+/// Crawls the candidates of every listener, the handshakes, geolocation, synced check and save only happen here.
+#[derive(Clone)]
+pub struct CrawlPipeline {
+    /// The node identities the peers are dialed with.
+    pub identities: Arc<Identities>,
+    pub db: Arc<dyn PeerDB>,
+    pub writer: PeerWriter,
+    /// Dials the peers and manages the sessions of our node.
+    pub network: Arc<dyn PeerNetwork>,
+    pub p2p_failures: Arc<P2pFailures>,
+    pub state: BlockHashNum,
+    pub fork_filter: Arc<ForkIdFilter>,
+    pub geolocator: Arc<Geolocator>,
+    pub banned_ips: Arc<BannedIps>,
+    pub ip_filter: Arc<IpFilter>,
+    pub handshakes: Arc<Semaphore>,
+    pub recent_peers: Arc<RecentPeers>,
+    pub sybil_guard: Arc<SybilGuard>,
+    pub progress: Arc<Progress>,
+    pub in_flight: Arc<InFlightPeers>,
+    pub config: CrawlerConfig,
+}
+
+impl CrawlPipeline {
+    /// Spawn `max_concurrent_handshakes` workers crawling the candidates sent by the listeners.
+    ///
+    /// The workers stop taking candidates once `shutdown` is cancelled, the crawls they already started go on.
+    pub fn spawn_workers(
+        self: &Arc<Self>,
+        candidates: mpsc::Receiver<CrawlCandidate>,
+        tasks: &TaskTracker,
+        shutdown: &CancellationToken,
+    ) {
+        let candidates = Arc::new(Mutex::new(candidates));
+        for _ in 0..self.config.max_concurrent_handshakes {
+            let pipeline = self.clone();
+            let candidates = candidates.clone();
+            let shutdown = shutdown.clone();
+            tasks.spawn(async move {
+                loop {
+                    let candidate = tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled() => break,
+                        candidate = async { candidates.lock().await.recv().await } => candidate,
+                    };
+                    let Some(candidate) = candidate else {
+                        break;
+                    };
+                    pipeline.crawl(candidate).await;
+                }
+            });
+        }
+    }
+
+    /// Crawl a candidate.
+    ///
+    /// Returns whether the peer was reached, or was crawled recently enough not to be dialed.
+    pub async fn crawl(&self, candidate: CrawlCandidate) -> bool {
+        let candidate = candidate.with_canonical_ip();
+        // nothing, not even a geolocation request, may reach a filtered ip
+        if !self.ip_filter.check(candidate.ip()) {
+            if let CrawlCandidate::Session(session) = &candidate {
+                self.network.remove_peer(session.peer_id);
+            }
+            return false;
+        }
+        match candidate {
+            CrawlCandidate::Dial(peer, source, enr) => self.dial(peer, source, enr).await,
+            CrawlCandidate::Session(session) => self.record_session(session).await,
+        }
+    }
+
+    /// Crawl `peer` right away, even if it was crawled recently.
+    pub async fn recrawl(&self, peer: NodeRecord) -> bool {
+        self.recent_peers.forget(&peer.id);
+        self.crawl(CrawlCandidate::Dial(peer, DiscoverySource::Admin, None))
+            .await
+    }
+
+    /// Handshake with a discovered peer and save its data.
+    async fn dial(
+        &self,
+        peer: NodeRecord,
+        source: DiscoverySource,
+        enr: Option<serde_json::Value>,
+    ) -> bool {
+        if self.banned_ips.is_banned(&peer.address) {
+            return false;
+        }
+        // another listener is already crawling this peer
+        let Some(_in_flight) = self.in_flight.claim(peer.id) else {
+            return true;
+        };
+        if self.recent_peers.skip(&peer.id) {
+            touch_peer(peer.id, &self.db).await;
+            return true;
+        }
+        if !self.sybil_guard.admit(peer.address, peer.id) {
+            counter!(metrics::SYBIL_SUPPRESSED, 1);
+            info!(
+                "Skipping peer {} whose ip {} showed too many peer ids",
+                peer.id, peer.address
+            );
+            return true;
+        }
+        if self.fork_filter.is_off_network(&peer.id) {
+            let avoided_dials = self.fork_filter.avoid_dial();
+            info!(
+                "Skipping off-network peer {} ({} dials avoided so far)",
+                peer.address, avoided_dials
+            );
+            let dns_tree = self.dns_tree(source);
+            let peer_data = PeerData {
+                off_network: true,
+                ..discovered_peer_data(peer, source, dns_tree, enr, Utc::now())
+            };
+            self.save(&peer, peer_data).await;
+            return false;
+        }
+        if self.config.discovery_only {
+            let dns_tree = self.dns_tree(source);
+            self.save(
+                &peer,
+                discovered_peer_data(peer, source, dns_tree, enr, Utc::now()),
+            )
+            .await;
+            return true;
+        }
+        // wait for a permit rather than dropping the peer, it's held until the peer is saved
+        let _permit = self
+            .handshakes
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        let Some(peer_data) = self.crawl_peer(peer, source, enr).await else {
+            return false;
+        };
+        self.save(&peer, peer_data).await;
+        true
+    }
+
+    /// The domain of the DNS tree `source` comes from, if it's one.
+    fn dns_tree(&self, source: DiscoverySource) -> Option<String> {
+        match source {
+            DiscoverySource::Dnsdisc(tree) => self.config.dns_tree_domain(tree),
+            _ => None,
+        }
+    }
+
+    /// Handshake with `peer`, retrying transient failures, and collect its data.
+    ///
+    /// The failures are recorded and the misbehaving ips banned here, `None` means the peer couldn't be crawled.
+    async fn crawl_peer(
+        &self,
+        peer: NodeRecord,
+        source: DiscoverySource,
+        enr: Option<serde_json::Value>,
+    ) -> Option<PeerData> {
+        // kick a forced lookup
+        self.network.lookup(peer.id);
+        let mut attempt = 1;
+        let mut backoff = self.config.handshake_retry_backoff;
+        let (mut identity, mut key) = self.identities.pick();
+        let mut redials = 0;
+        let handshake = loop {
+            counter!(metrics::HANDSHAKE_ATTEMPTS, 1, "identity" => identity.to_string());
+            self.progress
+                .handshake_attempts
+                .fetch_add(1, Ordering::Relaxed);
+            let result = self.network.handshake(peer, key).await;
+            if let Err(e) = &result {
+                self.progress
+                    .handshake_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                counter!(
+                    metrics::HANDSHAKE_FAILURES,
+                    1,
+                    "class" => e.class(),
+                    "cause" => e.cause().as_str(),
+                    "identity" => identity.to_string()
+                );
+            }
+            let (failure, cause) = match result {
+                Ok(handshake) => {
+                    counter!(metrics::HANDSHAKE_SUCCESSES, 1, "identity" => identity.to_string());
+                    if redials > 0 {
+                        counter!(metrics::TOO_MANY_PEERS_REDIAL_SUCCESSES, 1);
+                    }
+                    self.progress
+                        .handshake_successes
+                        .fetch_add(1, Ordering::Relaxed);
+                    break handshake;
+                }
+                // the peer only refused this identity, each of the others gets a chance
+                Err(HandshakeError::TooManyPeers) if redials + 1 < self.identities.count() => {
+                    redials += 1;
+                    (identity, key) = self.identities.after(identity);
+                    counter!(metrics::TOO_MANY_PEERS_REDIALS, 1);
+                    info!(
+                        "Peer {} has too many peers, dialing it as identity {} in {:?}",
+                        peer.address, identity, self.config.too_many_peers_redial_delay
+                    );
+                    time::sleep(self.config.too_many_peers_redial_delay).await;
+                    continue;
+                }
+                Err(HandshakeError::TooManyPeers) => {
+                    info!("Skip counting p2p_failure for peer: {}", peer.address);
+                    record_failure(
+                        peer.id,
+                        HandshakeFailure::Transient,
+                        FailureCause::TooManyPeers,
+                        &self.db,
+                    )
+                    .await;
+                    return None;
+                }
+                Err(HandshakeError::WrongNetwork) => {
+                    // ban the peer - we don't want to dial it again for a while since we know its not on the same network
+                    self.banned_ips.ban(peer.address, BanReason::WrongNetwork);
+                    record_failure(
+                        peer.id,
+                        HandshakeFailure::Permanent,
+                        FailureCause::WrongNetwork,
+                        &self.db,
+                    )
+                    .await;
+                    self.score(peer.id, CrawlOutcome::ProtocolBreach).await;
+                    return None;
+                }
+                Err(HandshakeError::Failed(failure, cause)) => (failure, cause),
+            };
+            if failure == HandshakeFailure::Transient && attempt <= self.config.handshake_retries {
+                info!(
+                    "Retrying handshake with peer {} in {:?} (attempt {})",
+                    peer.address, backoff, attempt
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+                continue;
+            }
+            // only permanent failures and peers that kept failing transiently count toward a ban
+            self.count_p2p_failure(&peer);
+            record_failure(peer.id, failure, cause, &self.db).await;
+            if let Some(outcome) = CrawlOutcome::from_cause(cause) {
+                self.score(peer.id, outcome).await;
+            }
+            return None;
+        };
+        if handshake.client_version.is_empty() {
+            info!(
+                "Peer {} with empty client_version - returning",
+                peer.address
+            );
+            // ban their IP - since our results show that we have multiple PeerIDs with the same IPs and no ClientVersion
+            self.banned_ips
+                .ban(peer.address, BanReason::EmptyClientVersion);
+            self.score(peer.id, CrawlOutcome::ProtocolBreach).await;
+            return None;
+        }
+
+        info!(
+            "Successfully connected to a peer at {} ({}) using eth-wire version eth/{} (p2p version {:?}, advertised capabilities: {:?})",
+            // brackets ipv6 addresses
+            SocketAddr::new(peer.address, peer.tcp_port), handshake.client_version, handshake.status.version, handshake.p2p_version, handshake.capabilities
+        );
+
+        let dns_tree = self.dns_tree(source);
+        let peer_data = self
+            .peer_data(Handshaked::dialed(peer, handshake, source, dns_tree, enr))
+            .await;
+        Some(peer_data)
+    }
+
+    /// Save the data of a peer that established a session with our node, then disconnect it.
+    async fn record_session(&self, session: SessionInfo) -> bool {
+        let SessionInfo {
+            peer_id,
+            remote_addr,
+            client_version,
+            capabilities,
+            status,
+            eth_version,
+        } = session;
+        // these peers inflate our numbers, same IP multiple generated ID
+        if client_version.is_empty() {
+            info!(
+                "Peer {} with empty client_version - lowering its reputation",
+                remote_addr.ip()
+            );
+            // the network can't ban ips like discv4 does, but a bad reputation gets the peer dropped
+            // and avoided by the session layer, it must be applied before the peer is removed
+            self.network
+                .reputation_change(peer_id, ReputationChangeKind::BadProtocol);
+            self.network.remove_peer(peer_id);
+            return false;
+        }
+        // everything we need is in the session, so the peer is disconnected as soon as its data is
+        // assembled, removing it earlier races with the session still being set up
+        let network = self.network.clone();
+        let disconnect = move || network.remove_peer(peer_id);
+        let Some(_in_flight) = self.in_flight.claim(peer_id) else {
+            disconnect();
+            return true;
+        };
+        if self.recent_peers.skip(&peer_id) {
+            disconnect();
+            touch_peer(peer_id, &self.db).await;
+            return true;
+        }
+        if self.config.dedup_network_ip
+            && self
+                .recent_peers
+                .skip_ip_duplicate(&remote_addr.ip(), &peer_id)
+        {
+            info!(
+                "Skipping peer {} whose ip {} was crawled recently under another id",
+                peer_id,
+                remote_addr.ip()
+            );
+            disconnect();
+            return true;
+        }
+        if !self.sybil_guard.admit(remote_addr.ip(), peer_id) {
+            counter!(metrics::SYBIL_SUPPRESSED, 1);
+            info!(
+                "Skipping peer {} whose ip {} showed too many peer ids",
+                peer_id,
+                remote_addr.ip()
+            );
+            disconnect();
+            return true;
+        }
+        // only the socket address of the session is known here
+        let peer = NodeRecord::new(remote_addr, peer_id);
+        // the sessions are mostly inbound, their address carries the source port of the peer rather than the port it
+        // listens on, which is only known when the peer was discovered
+        let advertised_port = if self.config.probe_ports {
+            self.advertised_tcp_port(peer_id).await
+        } else {
+            None
+        };
+        let port_reachable = match advertised_port {
+            Some(port) => Some(
+                probe_port(
+                    SocketAddr::new(remote_addr.ip(), port),
+                    self.config.port_probe_timeout,
+                )
+                .await,
+            ),
+            None => None,
+        };
+        let peer_data = self
+            .peer_data(Handshaked {
+                peer,
+                client_version,
+                capabilities,
+                status,
+                eth_version,
+                // reth doesn't expose the timing of inbound sessions
+                latency_ms: None,
+                // reth doesn't expose the hello of inbound sessions either
+                p2p_version: None,
+                port_reachable,
+                source: DiscoverySource::Network,
+                // the session's stream is owned by the network
+                best_header: None,
+                dns_tree: None,
+                enr: None,
+            })
+            .await;
+        disconnect();
+        self.save(&peer, peer_data).await;
+        true
+    }
+
+    /// The tcp port `peer_id` advertises, as saved when it was discovered, if it ever was.
+    async fn advertised_tcp_port(&self, peer_id: PeerId) -> Option<u16> {
+        let peers = match self.db.node_by_id(peer_id.to_string()).await {
+            Ok(peers) => peers?,
+            Err(e) => {
+                warn!(
+                    "Failed to look up the saved port of peer {}: {}",
+                    peer_id, e
+                );
+                return None;
+            }
+        };
+        peers
+            .into_iter()
+            // the port of a peer only seen in sessions is a session's source port
+            .find(|peer| {
+                peer.discovery_source.as_deref() != Some(DiscoverySource::Network.as_str())
+            })
+            .map(|peer| peer.tcp_port)
+    }
+
+    /// Geolocate a peer, check whether it's synced and collect its data into [`PeerData`].
+    async fn peer_data(&self, handshaked: Handshaked) -> PeerData {
+        let geo = self
+            .geolocator
+            .locate(handshaked.peer.address)
+            .await
+            .unwrap_or_default();
+        let sync = sync_check(
+            &self.state,
+            self.config.synced_threshold,
+            &handshaked.status,
+            handshaked.best_header,
+        );
+        build_peer_data(
+            handshaked,
+            geo,
+            sync,
+            self.config.score_weights.success,
+            Utc::now(),
+        )
+    }
+
+    /// Queue the write of a crawled peer, unless the save filters leave it out, and remember it was crawled.
+    async fn save(&self, peer: &NodeRecord, peer_data: PeerData) {
+        let filters = &self.config.save_filters;
+        let saved = filters.is_empty() || filters.iter().any(|filter| filter.matches(&peer_data));
+        if saved {
+            self.writer.save(peer_data).await;
+        } else {
+            counter!(metrics::PEERS_FILTERED, 1);
+        }
+        if self.recent_peers.crawled(peer.id, peer.address) && saved {
+            self.progress.new_peers.fetch_add(1, Ordering::Relaxed);
+            counter!(metrics::NEW_PEERS, 1);
+        }
+    }
+
+    /// Move the saved score of `peer_id` by the weight of `outcome`.
+    async fn score(&self, peer_id: PeerId, outcome: CrawlOutcome) {
+        let delta = self.config.score_weights.weight(outcome);
+        if let Err(e) = self.db.adjust_score(peer_id.to_string(), delta).await {
+            warn!("Failed to adjust the score of peer {}: {}", peer_id, e);
+        }
+    }
+
+    /// Count a failed handshake with `peer`, banning its ip once it failed too many times within the failure window.
+    fn count_p2p_failure(&self, peer: &NodeRecord) {
+        if let Some(failures) = self.p2p_failures.record(peer.id) {
+            info!(
+                "PeerId {} has failed p2p handshake {} times, banning",
+                peer.id, failures
+            );
+            self.banned_ips
+                .ban(peer.address, BanReason::HandshakeFailures);
+        }
+    }
+}
+
+/// Why [`crawl_one`] failed, each step exits with its own code so that scripts can tell them apart.
+#[derive(Debug, Error)]
+pub enum CrawlOneError {
+    #[error("failed to dial the peer: {0}")]
+    Dial(String),
+    #[error("failed P2P handshake: {0}")]
+    P2p(String),
+    #[error("failed ETH handshake: {0}")]
+    Eth(String),
+    #[error(transparent)]
+    Other(#[from] eyre::Report),
+}
+
+impl CrawlOneError {
+    /// Exit code of the process.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CrawlOneError::Other(_) => 1,
+            CrawlOneError::Dial(_) => 2,
+            CrawlOneError::P2p(_) => 3,
+            CrawlOneError::Eth(_) => 4,
+        }
+    }
+}
+
+/// Crawl `peer` on its own, without discovery nor retries, and save it if `save` is set.
+///
+/// The peer is geolocated and checked against the eth rpc like the crawled ones, so that a wrong record can be
+/// reproduced.
+pub async fn crawl_one(
+    config: &CrawlerConfig,
+    peer: NodeRecord,
+    save: bool,
+) -> Result<PeerData, CrawlOneError> {
+    let key = rng_secret_key();
+    let timeout = config.handshake_timeout;
+    let handshake_start = Instant::now();
+    // a host dropping our packets times out while connecting, it's a failed dial rather than a failed handshake
+    let outgoing =
+        match time::timeout(timeout, TcpStream::connect((peer.address, peer.tcp_port))).await {
+            Ok(Ok(outgoing)) => outgoing,
+            Ok(Err(e)) => return Err(CrawlOneError::Dial(e.to_string())),
+            Err(_) => return Err(CrawlOneError::Dial("timed out".to_string())),
+        };
+    let (p2p_stream, their_hello) = match time::timeout(
+        timeout,
+        handshake_p2p_over(outgoing, peer, key, &config.client_id),
+    )
+    .await
+    {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => return Err(CrawlOneError::P2p(e.to_string())),
+        Err(_) => return Err(CrawlOneError::P2p("timed out".to_string())),
+    };
+    let (mut eth_stream, their_status) =
+        match time::timeout(timeout, handshake_eth(p2p_stream)).await {
+            Ok(Ok(s)) => s,
+            Ok(Err(e)) => return Err(CrawlOneError::Eth(e.to_string())),
+            Err(_) => return Err(CrawlOneError::Eth("timed out".to_string())),
+        };
+    let latency_ms = handshake_start.elapsed().as_millis() as u64;
+    let best_header = if config.head_request_timeout.is_zero() {
+        None
+    } else {
+        match time::timeout(
+            config.head_request_timeout,
+            request_header(&mut eth_stream, their_status.blockhash),
+        )
+        .await
+        {
+            Ok(Ok(header)) => header,
+            Ok(Err(e)) => {
+                warn!("Failed to get the best header of the peer: {e}");
+                None
+            }
+            Err(_) => {
+                warn!("The peer didn't send its best header in time");
+                None
+            }
+        }
+    };
+    drop(eth_stream);
+
+    let state = load_state(config).await?;
+    let geo = Geolocator::from_config(config)?
+        .locate(peer.address)
+        .await
+        .unwrap_or_default();
+    let sync = sync_check(&state, config.synced_threshold, &their_status, best_header);
+    let handshake = PeerHandshake {
+        capabilities: their_hello
+            .capabilities
+            .iter()
+            .map(|cap| cap.to_string())
+            .collect(),
+        client_version: their_hello.client_version,
+        p2p_version: their_hello.protocol_version as u8,
+        status: their_status,
+        latency_ms,
+        best_header,
+    };
+    let peer_data = build_peer_data(
+        Handshaked::dialed(peer, handshake, DiscoverySource::CrawlOne, None, None),
+        geo,
+        sync,
+        config.score_weights.success,
+        Utc::now(),
+    );
+    if save {
+        build_peer_db(config.backend)
+            .await
+            .add_peer(peer_data.clone())
+            .await
+            .map_err(|e| eyre::eyre!("failed to save the peer: {e}"))?;
+    }
+    Ok(peer_data)
+}
+
+/// The latest `synced_threshold` blocks, fetched from the first eth rpc that answers.
+async fn load_state(config: &CrawlerConfig) -> eyre::Result<BlockHashNum> {
+    let mut provider = None;
+    for url in config.eth_rpc_urls() {
+        match EthRpc::connect(&url).await {
+            Ok(connected) => {
+                provider = Some(connected);
+                break;
+            }
+            Err(e) => warn!("Failed to connect to the eth rpc {url}: {e}"),
+        }
+    }
+    let provider = provider.ok_or_else(|| eyre::eyre!("no eth rpc answered"))?;
+    let state = BlockHashNum::new(config.synced_threshold);
+    fill_state(
+        &provider,
+        &state,
+        &ForkIdFilter::default(),
+        config.synced_threshold,
+    )
+    .await?;
+    Ok(state)
+}
+
+/// Check whether a peer is synced from its best block, against the latest `synced_threshold` blocks of `state`.
+fn sync_check(
+    state: &BlockHashNum,
+    synced_threshold: u64,
+    status: &Status,
+    best_header: Option<(u64, u64)>,
+) -> SyncCheck {
+    // how far behind the peer is, the block lag is unknown until the state holds blocks
+    let latest_block = state.latest_block.load(Ordering::Relaxed);
+    let (sync_lag_blocks, sync_lag_seconds) = match best_header {
+        Some((number, timestamp)) => (
+            (latest_block > 0).then(|| latest_block.saturating_sub(number)),
+            // the crawler's head is about as old as the clock
+            Some((Utc::now().timestamp() as u64).saturating_sub(timestamp)),
+        ),
+        None => (None, None),
+    };
+    // otherwise check if peer is synced with the latest chain's blocks, unknown until the state holds blocks
+    let synced = match sync_lag_blocks {
+        Some(lag) => Some(lag <= synced_threshold),
+        None => state.is_synced(&status.blockhash.0.into()),
+    };
+    SyncCheck {
+        synced,
+        // stronger than `synced`, a recent block may have been reorged out
+        on_canonical: state.is_canonical(&status.blockhash.0.into()),
+        sync_lag_blocks,
+        sync_lag_seconds,
+        head_block_number: best_header
+            .map(|(number, _)| number)
+            .or_else(|| state.block_number(&status.blockhash.0.into())),
+        crawler_head_number: (latest_block > 0).then_some(latest_block),
+    }
+}
+
+/// Assemble the data of a peer seen at `seen_at` from its handshakes, location and sync check, `score` being what the
+/// successful crawl adds to its saved score.
+fn build_peer_data(
+    handshaked: Handshaked,
+    geo: GeoInfo,
+    sync: SyncCheck,
+    score: i64,
+    seen_at: DateTime<Utc>,
+) -> PeerData {
+    let Handshaked {
+        peer,
+        client_version,
+        capabilities,
+        status,
+        eth_version,
+        latency_ms,
+        p2p_version,
+        port_reachable,
+        source,
+        best_header: _,
+        dns_tree,
+        enr,
+    } = handshaked;
+    let GeoInfo {
+        country,
+        city,
+        isp,
+        latitude,
+        longitude,
+        asn,
+        as_org,
+        provider,
+    } = geo;
+    let SyncCheck {
+        synced,
+        on_canonical,
+        sync_lag_blocks,
+        sync_lag_seconds,
+        head_block_number,
+        crawler_head_number,
+    } = sync;
+    let last_seen = seen_at.to_string();
+    let first_seen = last_seen.clone();
+    let advertised_eth_versions = advertised_eth_versions(&capabilities);
+    let snap_supported = supports_protocol(&capabilities, "snap");
+    let les_supported = supports_protocol(&capabilities, "les");
+
+    PeerData {
+        enode_url: peer.to_string(),
+        id: peer.id.to_string(),
+        address: peer.address.to_string(),
+        tcp_port: peer.tcp_port,
+        udp_port: peer.udp_port,
+        client_version,
+        eth_version,
+        capabilities,
+        total_difficulty: status.total_difficulty.to_string(),
+        chain: status.chain.to_string(),
+        // the display of the hashes is abbreviated
+        best_block: format!("{:#x}", status.blockhash),
+        genesis_block_hash: format!("{:#x}", status.genesis),
+        last_seen,
+        country,
+        city,
+        synced,
+        isp,
+        first_seen,
+        seen_count: 1,
+        unreachable: false,
+        latency_ms,
+        fork_hash: fork_hash_hex(&status.forkid),
+        fork_next: status.forkid.next,
+        latitude,
+        longitude,
+        last_failure: None,
+        p2p_version,
+        asn,
+        as_org,
+        // the defaults of an ip that couldn't be located have no provider
+        geo_provider: Some(provider).filter(|provider| !provider.is_empty()),
+        port_reachable,
+        discovery_source: Some(source.as_str().to_string()),
+        last_failure_at: None,
+        total_difficulty_num: u128::try_from(status.total_difficulty).ok(),
+        last_disconnect_reason: None,
+        on_canonical,
+        sync_lag_blocks,
+        sync_lag_seconds,
+        head_block_number,
+        crawler_head_number,
+        dns_tree,
+        handshake_completed: Some(true),
+        score,
+        // the failures only count since the last successful crawl
+        last_handshake_error: None,
+        handshake_failures: 0,
+        advertised_eth_versions,
+        enr,
+        snap_supported,
+        les_supported,
+        off_network: false,
+    }
+}
+
+/// The data of a peer only known from discovery at `seen_at`, its client, status and location are left empty.
+fn discovered_peer_data(
+    peer: NodeRecord,
+    source: DiscoverySource,
+    dns_tree: Option<String>,
+    enr: Option<serde_json::Value>,
+    seen_at: DateTime<Utc>,
+) -> PeerData {
+    let last_seen = seen_at.to_string();
+    PeerData {
+        enode_url: peer.to_string(),
+        id: peer.id.to_string(),
+        address: peer.address.to_string(),
+        tcp_port: peer.tcp_port,
+        udp_port: peer.udp_port,
+        client_version: String::new(),
+        eth_version: 0,
+        capabilities: vec![],
+        total_difficulty: String::new(),
+        chain: String::new(),
+        best_block: String::new(),
+        genesis_block_hash: String::new(),
+        first_seen: last_seen.clone(),
+        last_seen,
+        country: String::new(),
+        city: String::new(),
+        synced: None,
+        isp: String::new(),
+        // only a successful crawl counts as a sighting
+        seen_count: 0,
+        unreachable: false,
+        latency_ms: None,
+        fork_hash: String::new(),
+        fork_next: 0,
+        latitude: None,
+        longitude: None,
+        last_failure: None,
+        p2p_version: None,
+        asn: None,
+        as_org: None,
+        geo_provider: None,
+        port_reachable: None,
+        discovery_source: Some(source.as_str().to_string()),
+        last_failure_at: None,
+        total_difficulty_num: None,
+        last_disconnect_reason: None,
+        on_canonical: None,
+        sync_lag_blocks: None,
+        sync_lag_seconds: None,
+        head_block_number: None,
+        crawler_head_number: None,
+        dns_tree,
+        handshake_completed: Some(false),
+        // being discovered says nothing about the peer
+        score: 0,
+        last_handshake_error: None,
+        handshake_failures: 0,
+        advertised_eth_versions: vec![],
+        enr,
+        snap_supported: false,
+        les_supported: false,
+        off_network: false,
+    }
+}
+
+/// The eth protocol versions among `capabilities`, e.g. `[66, 67, 68]`, in increasing order.
+fn advertised_eth_versions(capabilities: &[String]) -> Vec<u8> {
+    let mut eth_versions: Vec<u8> = capabilities
+        .iter()
+        .filter_map(|capability| capability.strip_prefix("eth/")?.parse().ok())
+        .collect();
+    eth_versions.sort_unstable();
+    eth_versions.dedup();
+    eth_versions
+}
+
+/// Whether `capabilities` hold any version of `protocol`, e.g. `snap/1` for `snap`.
+fn supports_protocol(capabilities: &[String], protocol: &str) -> bool {
+    capabilities
+        .iter()
+        // names are lowercase by convention, not by the spec
+        .any(|capability| {
+            capability
+                .split_once('/')
+                .is_some_and(|(name, _)| name.eq_ignore_ascii_case(protocol))
+        })
+}
+
+/// Format the hash of a fork id as a `0x` prefixed hex string, e.g. `0xdce96c2d`.
+fn fork_hash_hex(fork_id: &ForkId) -> String {
+    format!("{:#010x}", u32::from_be_bytes(fork_id.hash.0))
+}
+
+/// Record how and why the crawl of `peer_id` failed, if it's a saved peer.
+async fn record_failure(
+    peer_id: PeerId,
+    failure: HandshakeFailure,
+    cause: FailureCause,
+    db: &Arc<dyn PeerDB>,
+) {
+    if let Err(e) = db
+        .record_failure(
+            peer_id.to_string(),
+            failure.as_str().to_string(),
+            cause.as_str().to_string(),
+            Utc::now().to_string(),
+        )
+        .await
+    {
+        warn!("Failed to record the failure of peer {}: {}", peer_id, e);
+    }
+}
+
+/// Whether a plain tcp connection to `addr` succeeds within `timeout`.
+async fn probe_port(addr: SocketAddr, timeout: Duration) -> bool {
+    matches!(
+        time::timeout(timeout, TcpStream::connect(addr)).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Refresh the `last_seen` of a recently crawled peer instead of crawling it again, it's online again if it was marked unreachable.
+async fn touch_peer(peer_id: PeerId, db: &Arc<dyn PeerDB>) {
+    if let Err(e) = db
+        .touch_peer(peer_id.to_string(), Utc::now().to_string())
+        .await
+    {
+        warn!("Failed to refresh last_seen of peer {}: {}", peer_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+    use reth_crawler_db::InMemoryPeerDB;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::crawler::test_utils::{
+        node_record, peer_handshake, saved_peer, test_config, test_pipeline, MockNetwork,
+    };
+
+    /// What a peer advertising `capabilities` told when dialed, having negotiated `eth_version` in its status.
+    fn dialed(capabilities: &[&str], eth_version: u8) -> Handshaked {
+        let handshake = PeerHandshake {
+            capabilities: capabilities.iter().map(|cap| cap.to_string()).collect(),
+            status: Status {
+                version: eth_version,
+                ..Default::default()
+            },
+            ..peer_handshake("Geth/v1.13.5-stable/linux-amd64/go1.21.4")
+        };
+        Handshaked::dialed(
+            node_record([203, 0, 113, 1]),
+            handshake,
+            DiscoverySource::Discv4,
+            None,
+            None,
+        )
+    }
+
+    /// The sync check of a crawler whose state holds no block.
+    fn no_sync_check() -> SyncCheck {
+        SyncCheck {
+            synced: None,
+            on_canonical: None,
+            sync_lag_blocks: None,
+            sync_lag_seconds: None,
+            head_block_number: None,
+            crawler_head_number: None,
+        }
+    }
+
+    fn peer_data(handshaked: Handshaked) -> PeerData {
+        build_peer_data(
+            handshaked,
+            GeoInfo::default(),
+            no_sync_check(),
+            1,
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn stores_the_advertised_capabilities() {
+        let peer_data = peer_data(dialed(&["eth/66", "eth/67", "eth/68", "snap/1"], 68));
+
+        assert_eq!(
+            peer_data.capabilities,
+            vec!["eth/66", "eth/67", "eth/68", "snap/1"]
+        );
+        assert_eq!(peer_data.advertised_eth_versions, vec![66, 67, 68]);
+        assert!(peer_data.snap_supported);
+        assert!(!peer_data.les_supported);
+    }
+
+    #[test]
+    fn eth_version_is_the_status_version() {
+        let peer_data = peer_data(dialed(&["eth/66", "eth/67", "eth/68", "snap/1"], 68));
+
+        assert_eq!(peer_data.eth_version, 68);
+        assert_eq!(peer_data.handshake_completed, Some(true));
+    }
+
+    #[test]
+    fn eth_version_isnt_the_highest_advertised_one() {
+        // the peer advertises eth/68 but negotiated eth/67
+        let peer_data = peer_data(dialed(&["eth/66", "eth/67", "eth/68"], 67));
+
+        assert_eq!(peer_data.eth_version, 67);
+        assert_eq!(peer_data.advertised_eth_versions, vec![66, 67, 68]);
+    }
+
+    /// A session of `peer_id` from `remote_addr`.
+    fn session(peer_id: PeerId, remote_addr: SocketAddr) -> SessionInfo {
+        SessionInfo {
+            peer_id,
+            remote_addr,
+            client_version: "Geth/v1.13.5-stable/linux-amd64/go1.21.4".to_string(),
+            capabilities: vec!["eth/68".to_string()],
+            status: Status::default(),
+            eth_version: 68,
+        }
+    }
+
+    /// A loopback port nothing listens on.
+    async fn closed_port() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    fn probing_config() -> CrawlerConfig {
+        CrawlerConfig {
+            probe_ports: true,
+            port_probe_timeout: Duration::from_secs(1),
+            ..test_config()
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_the_advertised_port_of_session_peers() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let (pipeline, flusher) = test_pipeline(network.clone(), db.clone(), probing_config());
+        let listening = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = NodeRecord::from_secret_key(listening.local_addr().unwrap(), &rng_secret_key());
+        let discovered =
+            discovered_peer_data(peer, DiscoverySource::Discv4, None, None, Utc::now());
+        db.add_peer(discovered).await.unwrap();
+
+        let source_addr = closed_port().await;
+        assert!(
+            pipeline
+                .crawl(CrawlCandidate::Session(session(peer.id, source_addr)))
+                .await
+        );
+        flusher.flush().await;
+
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert_eq!(saved.port_reachable, Some(true));
+        assert_eq!(network.removals(peer.id), 1);
+    }
+
+    #[tokio::test]
+    async fn session_peers_never_discovered_arent_probed() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let (pipeline, flusher) = test_pipeline(network.clone(), db.clone(), probing_config());
+        let peer = NodeRecord::from_secret_key(closed_port().await, &rng_secret_key());
+
+        assert!(
+            pipeline
+                .crawl(CrawlCandidate::Session(session(peer.id, peer.tcp_addr())))
+                .await
+        );
+        flusher.flush().await;
+
+        let saved = saved_peer(&*db, &peer).await.expect("the peer was saved");
+        assert_eq!(saved.port_reachable, None);
+    }
+
+    fn capabilities(capabilities: &[&str]) -> Vec<String> {
+        capabilities.iter().map(|cap| cap.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_the_protocols_among_real_capabilities() {
+        // geth serving snap, and an old light server
+        let geth = capabilities(&["eth/66", "eth/67", "eth/68", "snap/1"]);
+        let les_server = capabilities(&["eth/66", "les/2", "les/3", "les/4"]);
+
+        assert!(supports_protocol(&geth, "snap"));
+        assert!(!supports_protocol(&geth, "les"));
+        assert!(supports_protocol(&les_server, "les"));
+        assert!(!supports_protocol(&les_server, "snap"));
+    }
+
+    #[test]
+    fn only_matches_the_whole_protocol_name() {
+        let near_misses = capabilities(&["snapx/1", "sna/1", "xsnap/1", "snap"]);
+
+        assert!(!supports_protocol(&near_misses, "snap"));
+    }
+
+    #[test]
+    fn matches_protocol_names_in_any_case() {
+        assert!(supports_protocol(&capabilities(&["Snap/1"]), "snap"));
+        assert!(supports_protocol(&capabilities(&["LES/4"]), "les"));
+    }
+
+    #[tokio::test]
+    async fn handshakes_never_exceed_the_limit() {
+        let network = Arc::new(MockNetwork::with_delay(Duration::from_millis(20)));
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            max_concurrent_handshakes: 2,
+            ..test_config()
+        };
+        let (pipeline, flusher) = test_pipeline(network.clone(), db, config);
+        let peers: Vec<_> = (1..=10).map(|i| node_record([203, 0, 113, i])).collect();
+
+        // the crawls are all started at once, those without a permit wait for one
+        let crawled = join_all(peers.iter().map(|peer| {
+            pipeline.crawl(CrawlCandidate::Dial(*peer, DiscoverySource::Discv4, None))
+        }))
+        .await;
+        flusher.flush().await;
+
+        assert!(crawled.into_iter().all(|crawled| crawled));
+        assert_eq!(network.max_in_progress(), 2);
+        for peer in &peers {
+            assert_eq!(network.dials(peer.id), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn workers_crawl_both_kinds_of_candidates() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let (pipeline, flusher) = test_pipeline(network.clone(), db.clone(), test_config());
+        let (candidates, receiver) = mpsc::channel(2);
+        let tasks = TaskTracker::new();
+        Arc::new(pipeline).spawn_workers(receiver, &tasks, &CancellationToken::new());
+        let dialed = node_record([203, 0, 113, 21]);
+        let session_peer = node_record([203, 0, 113, 22]);
+
+        candidates
+            .send(CrawlCandidate::Dial(dialed, DiscoverySource::Discv4, None))
+            .await
+            .unwrap();
+        candidates
+            .send(CrawlCandidate::Session(session(
+                session_peer.id,
+                SocketAddr::new(session_peer.address, 30303),
+            )))
+            .await
+            .unwrap();
+        // the workers stop once the candidates are drained
+        drop(candidates);
+        tasks.close();
+        tasks.wait().await;
+        flusher.flush().await;
+
+        let saved = saved_peer(&*db, &dialed).await.expect("the peer was saved");
+        assert_eq!(saved.discovery_source.as_deref(), Some("discv4"));
+        assert_eq!(network.dials(dialed.id), 1);
+        let saved = saved_peer(&*db, &session_peer)
+            .await
+            .expect("the session peer was saved");
+        assert_eq!(saved.discovery_source.as_deref(), Some("network"));
+        // the session peer is already connected, it's disconnected rather than dialed
+        assert_eq!(network.dials(session_peer.id), 0);
+        assert_eq!(network.removals(session_peer.id), 1);
+    }
+
+    #[tokio::test]
+    async fn crawl_one_times_out_dialing_as_a_failed_dial() {
+        let config = CrawlerConfig {
+            handshake_timeout: Duration::from_millis(200),
+            ..test_config()
+        };
+        // a non-routable address, the connection hangs or fails right away depending on the host
+        let peer = node_record([10, 255, 255, 1]);
+
+        let error = crawl_one(&config, peer, false).await.unwrap_err();
+
+        assert!(matches!(error, CrawlOneError::Dial(_)), "{error}");
+        assert_eq!(error.exit_code(), 2);
+    }
+
+    #[tokio::test]
+    async fn crawl_one_times_out_a_silent_peer_as_a_failed_handshake() {
+        let config = CrawlerConfig {
+            handshake_timeout: Duration::from_millis(200),
+            ..test_config()
+        };
+        // accepts the connection, then never answers
+        let listening = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = NodeRecord::from_secret_key(listening.local_addr().unwrap(), &rng_secret_key());
+
+        let error = crawl_one(&config, peer, false).await.unwrap_err();
+
+        assert!(matches!(error, CrawlOneError::P2p(_)), "{error}");
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[tokio::test]
+    async fn records_the_history_through_the_writer() {
+        let network = Arc::new(MockNetwork::default());
+        let db = Arc::new(InMemoryPeerDB::new());
+        let config = CrawlerConfig {
+            record_history: true,
+            ..test_config()
+        };
+        let (pipeline, flusher) = test_pipeline(network, db.clone(), config);
+        let peer = node_record([203, 0, 113, 23]);
+
+        assert!(
+            pipeline
+                .crawl(CrawlCandidate::Dial(peer, DiscoverySource::Discv4, None))
+                .await
+        );
+        flusher.flush().await;
+
+        let history = db.peer_history(peer.id.to_string()).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].handshake_completed, Some(true));
+    }
+}
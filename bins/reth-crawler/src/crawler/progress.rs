@@ -0,0 +1,51 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::crawler::pipeline::DiscoverySource;
+
+/// Running totals of the crawl, logged as a single progress line at each stats interval.
+///
+/// They're bumped along with the matching metrics, which can't be read back.
+#[derive(Debug, Default)]
+pub struct Progress {
+    discv4_updates: AtomicU64,
+    dnsdisc_updates: AtomicU64,
+    discv5_updates: AtomicU64,
+    network_updates: AtomicU64,
+    /// Handshakes attempted with dialed peers, retries included.
+    pub handshake_attempts: AtomicU64,
+    pub handshake_successes: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    /// Peers saved that the crawler didn't remember crawling before.
+    pub new_peers: AtomicU64,
+}
+
+impl Progress {
+    /// Count an update received from the listener of `source`.
+    pub fn discovery_update(&self, source: DiscoverySource) {
+        let updates = match source {
+            DiscoverySource::Discv4 => &self.discv4_updates,
+            DiscoverySource::Dnsdisc(_) => &self.dnsdisc_updates,
+            DiscoverySource::Discv5 => &self.discv5_updates,
+            DiscoverySource::Network => &self.network_updates,
+            // not a listener
+            DiscoverySource::Recrawler | DiscoverySource::Admin | DiscoverySource::CrawlOne => {
+                return
+            }
+        };
+        updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The updates received from each listener, as `source=count` pairs.
+    pub fn discovery_updates(&self) -> String {
+        [
+            (DiscoverySource::Discv4, &self.discv4_updates),
+            (DiscoverySource::Dnsdisc(0), &self.dnsdisc_updates),
+            (DiscoverySource::Discv5, &self.discv5_updates),
+            (DiscoverySource::Network, &self.network_updates),
+        ]
+        .iter()
+        .map(|(source, updates)| format!("{}={}", source.as_str(), updates.load(Ordering::Relaxed)))
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use reth_primitives::PeerId;
+
+/// Peers that were successfully crawled recently, so that they aren't dialed again for a while.
+#[derive(Debug)]
+pub struct RecentPeers {
+    /// When each peer was last crawled.
+    peers: RwLock<LruCache<PeerId, Instant>>,
+    /// The last peer crawled from each ip, and when.
+    ips: RwLock<LruCache<IpAddr, (PeerId, Instant)>>,
+    /// How long a crawl is considered recent.
+    window: Duration,
+    /// How many dials were skipped because the peer was crawled recently.
+    skipped_dials: AtomicU64,
+    /// How many sessions were skipped because their ip was crawled recently under another id.
+    skipped_ip_duplicates: AtomicU64,
+}
+
+impl RecentPeers {
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            peers: RwLock::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("it's not zero!"),
+            )),
+            ips: RwLock::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("it's not zero!"),
+            )),
+            window,
+            skipped_dials: AtomicU64::new(0),
+            skipped_ip_duplicates: AtomicU64::new(0),
+        }
+    }
+
+    /// Remember that `peer_id` was just crawled at `ip`, returning whether it wasn't remembered yet.
+    pub fn crawled(&self, peer_id: PeerId, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut peers = self.peers.write().expect("this should always work!");
+        let new = peers.put(peer_id, now).is_none();
+        let mut ips = self.ips.write().expect("this should always work!");
+        ips.put(ip, (peer_id, now));
+        new
+    }
+
+    /// Don't crawl `peer_id` again until the window is over, as if it was just crawled.
+    pub fn defer(&self, peer_id: PeerId) {
+        let mut peers = self.peers.write().expect("this should always work!");
+        peers.put(peer_id, Instant::now());
+    }
+
+    /// Forget that `peer_id` was crawled, so that it's dialed again.
+    pub fn forget(&self, peer_id: &PeerId) {
+        let mut peers = self.peers.write().expect("this should always work!");
+        peers.pop(peer_id);
+    }
+
+    /// Whether another peer than `peer_id` was crawled at `ip` within the window, counting a skipped duplicate if so.
+    pub fn skip_ip_duplicate(&self, ip: &IpAddr, peer_id: &PeerId) -> bool {
+        let duplicate = {
+            let ips = self.ips.read().expect("this should always work!");
+            ips.peek(ip).is_some_and(|(crawled_id, crawled_at)| {
+                crawled_id != peer_id && crawled_at.elapsed() < self.window
+            })
+        };
+        if duplicate {
+            self.skipped_ip_duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+        duplicate
+    }
+
+    /// Whether `peer_id` was crawled within the window, counting a skipped dial if so.
+    pub fn skip(&self, peer_id: &PeerId) -> bool {
+        let recent = {
+            let peers = self.peers.read().expect("this should always work!");
+            peers
+                .peek(peer_id)
+                .is_some_and(|crawled_at| crawled_at.elapsed() < self.window)
+        };
+        if recent {
+            self.skipped_dials.fetch_add(1, Ordering::Relaxed);
+        }
+        recent
+    }
+
+    /// How many dials were skipped so far.
+    pub fn skipped_dials(&self) -> u64 {
+        self.skipped_dials.load(Ordering::Relaxed)
+    }
+
+    /// How many sessions were skipped as ip duplicates so far.
+    pub fn skipped_ip_duplicates(&self) -> u64 {
+        self.skipped_ip_duplicates.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::p2p::FailureCause;
+
+/// How many more times than `recrawl_stale_after` the worst scored peers wait before they're recrawled.
+const MAX_RECRAWL_BACKOFF: i64 = 7;
+
+/// What a crawl tells about a peer, each outcome moves its score by its [`ScoreWeights`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrawlOutcome {
+    /// The p2p and eth handshakes succeeded.
+    Success,
+    /// The handshake failed, e.g. the connection was refused or timed out.
+    Failure,
+    /// The peer broke the protocol, is on another network or hides its client version.
+    ProtocolBreach,
+    /// The peer disconnected us as a useless peer.
+    UselessPeer,
+}
+
+impl CrawlOutcome {
+    /// The outcome of a handshake that failed for `cause`, `None` when it says nothing about the peer.
+    pub fn from_cause(cause: FailureCause) -> Option<Self> {
+        match cause {
+            // a full peer isn't a bad one
+            FailureCause::TooManyPeers => None,
+            FailureCause::UselessPeer => Some(CrawlOutcome::UselessPeer),
+            FailureCause::ProtocolBreach | FailureCause::WrongNetwork => {
+                Some(CrawlOutcome::ProtocolBreach)
+            }
+            FailureCause::Io | FailureCause::Timeout | FailureCause::Other => {
+                Some(CrawlOutcome::Failure)
+            }
+        }
+    }
+}
+
+/// How much each [`CrawlOutcome`] moves the score of a peer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreWeights {
+    pub success: i64,
+    pub failure: i64,
+    pub protocol_breach: i64,
+    pub useless_peer: i64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            success: 1,
+            failure: -1,
+            protocol_breach: -5,
+            useless_peer: -3,
+        }
+    }
+}
+
+impl ScoreWeights {
+    /// How much `outcome` moves the score of a peer.
+    pub fn weight(&self, outcome: CrawlOutcome) -> i64 {
+        match outcome {
+            CrawlOutcome::Success => self.success,
+            CrawlOutcome::Failure => self.failure,
+            CrawlOutcome::ProtocolBreach => self.protocol_breach,
+            CrawlOutcome::UselessPeer => self.useless_peer,
+        }
+    }
+}
+
+/// How long a peer with `score` must go unseen before it's recrawled.
+///
+/// Each point below zero adds another `stale_after`, up to [`MAX_RECRAWL_BACKOFF`] times, so that flaky peers are
+/// recrawled less often.
+pub fn recrawl_after(stale_after: Duration, score: i64) -> Duration {
+    stale_after * (1 + score.saturating_neg().clamp(0, MAX_RECRAWL_BACKOFF) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    /// The score of a peer after `outcomes`, with the default weights.
+    fn score(outcomes: &[CrawlOutcome]) -> i64 {
+        let weights = ScoreWeights::default();
+        outcomes
+            .iter()
+            .map(|outcome| weights.weight(*outcome))
+            .sum()
+    }
+
+    #[test]
+    fn successful_peers_are_recrawled_as_soon_as_stale() {
+        let score = score(&[CrawlOutcome::Success; 3]);
+
+        assert_eq!(score, 3);
+        assert_eq!(recrawl_after(HOUR, score), HOUR);
+    }
+
+    #[test]
+    fn each_failure_delays_the_recrawl() {
+        let score = score(&[
+            CrawlOutcome::Success,
+            CrawlOutcome::Failure,
+            CrawlOutcome::Failure,
+            CrawlOutcome::Failure,
+        ]);
+
+        assert_eq!(score, -2);
+        assert_eq!(recrawl_after(HOUR, score), 3 * HOUR);
+    }
+
+    #[test]
+    fn breaches_and_useless_peers_weigh_more_than_failures() {
+        let breach = score(&[CrawlOutcome::Success, CrawlOutcome::ProtocolBreach]);
+        let useless = score(&[CrawlOutcome::Success, CrawlOutcome::UselessPeer]);
+
+        assert_eq!(breach, -4);
+        assert_eq!(recrawl_after(HOUR, breach), 5 * HOUR);
+        assert_eq!(useless, -2);
+        assert_eq!(recrawl_after(HOUR, useless), 3 * HOUR);
+    }
+
+    #[test]
+    fn the_recrawl_delay_is_clamped() {
+        let score = score(&[CrawlOutcome::ProtocolBreach; 4]);
+
+        assert_eq!(score, -20);
+        assert_eq!(recrawl_after(HOUR, score), 8 * HOUR);
+        assert_eq!(recrawl_after(HOUR, i64::MIN), 8 * HOUR);
+        assert_eq!(recrawl_after(HOUR, i64::MAX), HOUR);
+    }
+
+    #[test]
+    fn failure_causes_map_to_outcomes() {
+        assert_eq!(CrawlOutcome::from_cause(FailureCause::TooManyPeers), None);
+        assert_eq!(
+            CrawlOutcome::from_cause(FailureCause::WrongNetwork),
+            Some(CrawlOutcome::ProtocolBreach)
+        );
+        assert_eq!(
+            CrawlOutcome::from_cause(FailureCause::UselessPeer),
+            Some(CrawlOutcome::UselessPeer)
+        );
+        assert_eq!(
+            CrawlOutcome::from_cause(FailureCause::Timeout),
+            Some(CrawlOutcome::Failure)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::str::FromStr;
+
+use reth_crawler_db::types::client_name;
+use reth_crawler_db::PeerData;
+use serde::{Deserialize, Serialize};
+
+/// A condition a crawled peer must meet to be saved, so that the db only holds the peers of interest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SaveFilter {
+    /// The peer advertises this capability in its `Hello`, e.g. `snap/1`.
+    Capability(String),
+    /// The peer runs this client, e.g. `reth`, compared case insensitively.
+    Client(String),
+}
+
+impl SaveFilter {
+    /// Whether `peer_data` meets the condition.
+    pub fn matches(&self, peer_data: &PeerData) -> bool {
+        match self {
+            SaveFilter::Capability(capability) => peer_data
+                .capabilities
+                .iter()
+                .any(|advertised| advertised.eq_ignore_ascii_case(capability)),
+            SaveFilter::Client(client) => {
+                client_name(&peer_data.client_version).eq_ignore_ascii_case(client)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SaveFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveFilter::Capability(capability) => write!(f, "capability={capability}"),
+            SaveFilter::Client(client) => write!(f, "client={client}"),
+        }
+    }
+}
+
+/// Parses `capability=<name/version>` or `client=<name>`, e.g. `capability=snap/1`.
+impl FromStr for SaveFilter {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| eyre::eyre!("expected `capability=...` or `client=...`, got `{s}`"))?;
+        let value = value.trim();
+        eyre::ensure!(!value.is_empty(), "empty save filter value in `{s}`");
+        match key.trim() {
+            "capability" => Ok(SaveFilter::Capability(value.to_string())),
+            "client" => Ok(SaveFilter::Client(value.to_string())),
+            key => Err(eyre::eyre!(
+                "unknown save filter `{key}`, expected `capability` or `client`"
+            )),
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use futures::join;
-use reth_discv4::Discv4;
-use reth_dns_discovery::DnsDiscoveryHandle;
-use reth_network::NetworkHandle;
+use reth_crawler_db::PeerDB;
 use secp256k1::SecretKey;
-use tracing::info;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
 
-use crate::crawler::listener::UpdateListener;
+use crate::crawler::config::CrawlerConfig;
+use crate::crawler::listener::{Sources, UpdateListener};
 
 pub struct CrawlerService {
     updates: UpdateListener,
@@ -13,29 +14,74 @@ pub struct CrawlerService {
 
 impl CrawlerService {
     pub async fn new(
-        discv4: Discv4,
-        dnsdisc: DnsDiscoveryHandle,
-        network: NetworkHandle,
+        sources: Sources,
         key: SecretKey,
-        local_db: bool,
-        provider_url: String,
+        db: Arc<dyn PeerDB>,
+        config: CrawlerConfig,
     ) -> Self {
-        let updates =
-            UpdateListener::new(discv4, dnsdisc, network, key, local_db, provider_url).await;
+        let updates = UpdateListener::new(sources, key, db, config).await;
         Self { updates }
     }
 
-    pub async fn run(self) -> (eyre::Result<()>, eyre::Result<()>, (), eyre::Result<()>) {
+    /// Run the listeners until the shutdown is requested, logging each one that failed.
+    ///
+    /// Fails if any of them did, once the in-flight crawls are over and the queued peers written.
+    pub async fn run(self) -> eyre::Result<()> {
         // first initialize the state
         info!("start initializing the state...");
         let _ = self.updates.initialize_state().await;
+        // stop the listeners on SIGINT/SIGTERM
+        let shutdown = self.updates.shutdown_token();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            info!("shutdown requested, stopping the listeners...");
+            shutdown.cancel();
+        });
         // then start crawling
         info!("start crawling...");
-        join!(
+        let (discv4, discv5, dnsdisc, (), state, recrawler, pruner, (), admin) = join!(
             self.updates.start_discv4(),
+            self.updates.start_discv5(),
             self.updates.start_dnsdisc(),
             self.updates.start_network(),
             self.updates.start_state(),
-        )
+            self.updates.start_recrawler(),
+            self.updates.start_pruner(),
+            self.updates.start_stats(),
+            self.updates.start_admin(),
+        );
+        self.updates.wait_for_crawls().await;
+        self.updates.flush_writes().await;
+        // the crawls may have banned ips since the last stats
+        self.updates.persist_bans().await;
+        let results = [
+            ("discv4", discv4),
+            ("discv5", discv5),
+            ("dnsdisc", dnsdisc),
+            ("state", state),
+            ("recrawler", recrawler),
+            ("pruner", pruner),
+            ("admin", admin),
+        ];
+        let mut failed = vec![];
+        for (listener, result) in results {
+            if let Err(e) = result {
+                error!("The {listener} listener failed: {e:?}");
+                failed.push(listener);
+            }
+        }
+        if !failed.is_empty() {
+            eyre::bail!("the {} listeners failed", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Resolve when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
     }
 }
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use reth_primitives::PeerId;
+
+/// Distinct peer ids seen at each ip, so that an ip generating many ids doesn't inflate the dataset.
+#[derive(Debug)]
+pub struct SybilGuard {
+    /// The distinct ids seen at each ip within the window, and when each was first seen.
+    ips: Mutex<LruCache<IpAddr, VecDeque<(PeerId, Instant)>>>,
+    /// How many distinct ids an ip may show within the window, unlimited if 0.
+    max_ids: usize,
+    /// How long an id counts towards the limit of its ip.
+    window: Duration,
+    /// How many peers were suppressed because their ip showed too many ids.
+    suppressed: AtomicU64,
+}
+
+impl SybilGuard {
+    pub fn new(capacity: usize, max_ids: usize, window: Duration) -> Self {
+        Self {
+            ips: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity.max(1)).expect("it's not zero!"),
+            )),
+            max_ids,
+            window,
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether `peer_id` may be crawled at `ip`, counting a suppressed peer if not.
+    ///
+    /// Ids already seen at `ip` within the window are always admitted, new ones only while the ip is under the limit.
+    pub fn admit(&self, ip: IpAddr, peer_id: PeerId) -> bool {
+        if self.max_ids == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut ips = self.ips.lock().expect("this should always work!");
+        let ids = ips.get_or_insert_mut(ip, VecDeque::new);
+        while ids
+            .front()
+            .is_some_and(|(_, seen_at)| now.duration_since(*seen_at) >= self.window)
+        {
+            ids.pop_front();
+        }
+        if ids.iter().any(|(id, _)| *id == peer_id) {
+            return true;
+        }
+        if ids.len() >= self.max_ids {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        ids.push_back((peer_id, now));
+        true
+    }
+
+    /// How many peers were suppressed so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reth_crawler_db::{PeerDB, PeerData};
+use reth_eth_wire::Status;
+use reth_network::config::rng_secret_key;
+use reth_network_api::ReputationChangeKind;
+use reth_primitives::{NodeRecord, PeerId};
+use secp256k1::SecretKey;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::crawler::bans::BannedIps;
+use crate::crawler::config::CrawlerConfig;
+use crate::crawler::failures::P2pFailures;
+use crate::crawler::forkid::ForkIdFilter;
+use crate::crawler::geo::{GeoInfo, GeoResolver, Geolocator};
+use crate::crawler::identities::Identities;
+use crate::crawler::inflight::InFlightPeers;
+use crate::crawler::ipfilter::IpFilter;
+use crate::crawler::listener::BlockHashNum;
+use crate::crawler::network::{HandshakeError, PeerHandshake, PeerNetwork};
+use crate::crawler::pipeline::CrawlPipeline;
+use crate::crawler::progress::Progress;
+use crate::crawler::recent::RecentPeers;
+use crate::crawler::sybil::SybilGuard;
+use crate::crawler::writer::PeerWriter;
+
+/// How long [`wait_until`] waits before failing the test.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tells apart the bans files of the tests running at the same time.
+static BANS_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A config keeping the tests off the network, except for the loopback, and off the working directory.
+pub fn test_config() -> CrawlerConfig {
+    let bans_file = std::env::temp_dir().join(format!(
+        "reth-crawler-bans-{}-{}.json",
+        std::process::id(),
+        BANS_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    CrawlerConfig {
+        // nothing listens there
+        eth_rpc_url: "http://127.0.0.1:1".to_string(),
+        bans_file,
+        clean_bans: true,
+        // the peers are located by the geolocator of each test, if any
+        geolocation_providers: vec![],
+        handshake_retries: 0,
+        max_concurrent_handshakes: 4,
+        // the tests send the ENRs they want along with the discovered peers
+        enr_wait: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+/// A peer listening on `ip`.
+pub fn node_record(ip: impl Into<IpAddr>) -> NodeRecord {
+    NodeRecord::from_secret_key(SocketAddr::new(ip.into(), 30303), &rng_secret_key())
+}
+
+/// The handshakes of a mainnet peer running `client_version`.
+pub fn peer_handshake(client_version: &str) -> PeerHandshake {
+    PeerHandshake {
+        client_version: client_version.to_string(),
+        capabilities: vec!["eth/67".to_string(), "eth/68".to_string()],
+        p2p_version: 5,
+        status: Status::default(),
+        latency_ms: 10,
+        best_header: None,
+    }
+}
+
+/// A network whose handshakes answer as scripted for each peer, recording what the pipeline asks of it.
+///
+/// The peers without a scripted answer complete their handshakes as [`peer_handshake`] of a geth node.
+#[derive(Default)]
+pub struct MockNetwork {
+    handshakes: Mutex<HashMap<PeerId, VecDeque<Result<PeerHandshake, HandshakeError>>>>,
+    /// How long each handshake takes.
+    delay: Duration,
+    dials: Mutex<Vec<PeerId>>,
+    lookups: Mutex<Vec<PeerId>>,
+    reputation_changes: Mutex<Vec<(PeerId, ReputationChangeKind)>>,
+    removals: Mutex<Vec<PeerId>>,
+    /// Handshakes in progress, and the most there were at once.
+    in_progress: AtomicUsize,
+    max_in_progress: AtomicUsize,
+}
+
+impl MockNetwork {
+    /// A network whose handshakes take `delay`.
+    pub fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Default::default()
+        }
+    }
+
+    /// Answer the next handshake with `peer_id` with `result`.
+    pub fn script(&self, peer_id: PeerId, result: Result<PeerHandshake, HandshakeError>) {
+        self.handshakes
+            .lock()
+            .expect("this should always work!")
+            .entry(peer_id)
+            .or_default()
+            .push_back(result);
+    }
+
+    /// How many times `peer_id` was dialed.
+    pub fn dials(&self, peer_id: PeerId) -> usize {
+        count(&self.dials, peer_id)
+    }
+
+    /// How many times `peer_id` was looked up.
+    pub fn lookups(&self, peer_id: PeerId) -> usize {
+        count(&self.lookups, peer_id)
+    }
+
+    /// How many times `peer_id` was disconnected.
+    pub fn removals(&self, peer_id: PeerId) -> usize {
+        count(&self.removals, peer_id)
+    }
+
+    /// The reputation changes of `peer_id`.
+    pub fn reputation_changes(&self, peer_id: PeerId) -> Vec<ReputationChangeKind> {
+        self.reputation_changes
+            .lock()
+            .expect("this should always work!")
+            .iter()
+            .filter(|(id, _)| *id == peer_id)
+            .map(|(_, kind)| *kind)
+            .collect()
+    }
+
+    /// The most handshakes that were in progress at once.
+    pub fn max_in_progress(&self) -> usize {
+        self.max_in_progress.load(Ordering::SeqCst)
+    }
+}
+
+fn count(peer_ids: &Mutex<Vec<PeerId>>, peer_id: PeerId) -> usize {
+    peer_ids
+        .lock()
+        .expect("this should always work!")
+        .iter()
+        .filter(|id| **id == peer_id)
+        .count()
+}
+
+#[async_trait]
+impl PeerNetwork for MockNetwork {
+    async fn handshake(
+        &self,
+        peer: NodeRecord,
+        _key: SecretKey,
+    ) -> Result<PeerHandshake, HandshakeError> {
+        self.dials
+            .lock()
+            .expect("this should always work!")
+            .push(peer.id);
+        let in_progress = self.in_progress.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_progress
+            .fetch_max(in_progress, Ordering::SeqCst);
+        time::sleep(self.delay).await;
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+        self.handshakes
+            .lock()
+            .expect("this should always work!")
+            .get_mut(&peer.id)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_else(|| Ok(peer_handshake("Geth/v1.13.5-stable/linux-amd64/go1.21.4")))
+    }
+
+    fn lookup(&self, peer_id: PeerId) {
+        self.lookups
+            .lock()
+            .expect("this should always work!")
+            .push(peer_id);
+    }
+
+    fn reputation_change(&self, peer_id: PeerId, kind: ReputationChangeKind) {
+        self.reputation_changes
+            .lock()
+            .expect("this should always work!")
+            .push((peer_id, kind));
+    }
+
+    fn remove_peer(&self, peer_id: PeerId) {
+        self.removals
+            .lock()
+            .expect("this should always work!")
+            .push(peer_id);
+    }
+}
+
+/// Poll `condition` until it holds, failing the test if it doesn't soon enough.
+pub async fn wait_until(mut condition: impl FnMut() -> bool) {
+    time::timeout(WAIT_TIMEOUT, async {
+        while !condition() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("timed out waiting for the condition");
+}
+
+/// The saved record of `peer`, if any.
+pub async fn saved_peer(db: &dyn PeerDB, peer: &NodeRecord) -> Option<PeerData> {
+    db.node_by_id(peer.id.to_string())
+        .await
+        .expect("the in-memory db doesn't fail")?
+        .into_iter()
+        .next()
+}
+
+/// Answers every ip with the same location, counting the lookups.
+#[derive(Default)]
+pub struct StubResolver {
+    pub geo_info: Option<GeoInfo>,
+    pub lookups: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl GeoResolver for StubResolver {
+    async fn resolve(&self, _ip: IpAddr) -> Option<GeoInfo> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        self.geo_info.clone()
+    }
+}
+
+/// Runs the flusher of a test pipeline's writer.
+pub struct Flusher {
+    closed: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl Flusher {
+    /// Write the queued peers, then stop.
+    pub async fn flush(self) {
+        self.closed.cancel();
+        self.task.await.expect("the flusher doesn't panic");
+    }
+}
+
+/// A pipeline crawling through `network` into `db`, along with the flusher of its writer.
+///
+/// The peers aren't located, and the state holds no block.
+pub fn test_pipeline(
+    network: Arc<MockNetwork>,
+    db: Arc<dyn PeerDB>,
+    config: CrawlerConfig,
+) -> (CrawlPipeline, Flusher) {
+    let (writer, flusher) = PeerWriter::new(
+        db.clone(),
+        config.writer_queue_capacity,
+        config.record_history,
+    );
+    let closed = CancellationToken::new();
+    let task = tokio::spawn(flusher.run(closed.clone()));
+    let pipeline = CrawlPipeline {
+        identities: Arc::new(Identities::new(rng_secret_key(), config.identities)),
+        db,
+        writer,
+        network,
+        p2p_failures: Arc::new(P2pFailures::new(
+            config.p2p_failure_threshold,
+            config.p2p_failure_window,
+        )),
+        state: BlockHashNum::new(config.synced_threshold),
+        fork_filter: Arc::new(ForkIdFilter::default()),
+        geolocator: Arc::new(Geolocator::new(
+            1,
+            Duration::ZERO,
+            Box::<StubResolver>::default(),
+        )),
+        banned_ips: Arc::new(BannedIps::default()),
+        ip_filter: Arc::new(IpFilter::default()),
+        handshakes: Arc::new(tokio::sync::Semaphore::new(
+            config.max_concurrent_handshakes,
+        )),
+        recent_peers: Arc::new(RecentPeers::new(
+            config.recent_peers_capacity,
+            config.recrawl_window,
+        )),
+        sybil_guard: Arc::new(SybilGuard::new(
+            config.recent_peers_capacity,
+            config.sybil_max_ids_per_ip,
+            config.sybil_window,
+        )),
+        progress: Arc::new(Progress::default()),
+        in_flight: Arc::new(InFlightPeers::default()),
+        config,
+    };
+    (pipeline, Flusher { closed, task })
+}
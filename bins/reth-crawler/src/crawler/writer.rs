@@ -0,0 +1,183 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ::metrics::{counter, histogram};
+use reth_crawler_db::{PeerDB, PeerData};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::crawler::metrics;
+
+/// Max number of peers written in a single batch, it's the most a DynamoDB `BatchWriteItem` accepts.
+const BATCH_SIZE: usize = 25;
+/// Max time a peer waits in a batch before it's written.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Counters of the peer writes.
+#[derive(Debug, Default)]
+pub struct WriterStats {
+    /// Peers written to the db.
+    pub flushed: AtomicU64,
+    /// Batches sent to the db.
+    pub batches: AtomicU64,
+    /// Peers retried one by one after their batch failed.
+    pub retries: AtomicU64,
+    /// Peers that waited for room in the queue.
+    pub backpressured: AtomicU64,
+    /// Peers that couldn't be written, even one by one.
+    pub failures: AtomicU64,
+}
+
+/// Queues crawled peers so that they're written to the db in batches rather than one by one.
+#[derive(Debug, Clone)]
+pub struct PeerWriter {
+    sender: mpsc::Sender<PeerData>,
+    stats: Arc<WriterStats>,
+}
+
+impl PeerWriter {
+    /// Create the writer along with the flusher that must be run to actually write the peers.
+    ///
+    /// Up to `queue_capacity` peers can be queued, the crawls wait for room beyond that. With `record_history`, the
+    /// snapshots of the handshaked peers are also appended to their history.
+    pub fn new(
+        db: Arc<dyn PeerDB>,
+        queue_capacity: usize,
+        record_history: bool,
+    ) -> (Self, PeerFlusher) {
+        let (sender, receiver) = mpsc::channel(queue_capacity);
+        let stats = Arc::new(WriterStats::default());
+        let flusher = PeerFlusher {
+            receiver,
+            db,
+            record_history,
+            stats: stats.clone(),
+        };
+        (Self { sender, stats }, flusher)
+    }
+
+    /// Queue `peer_data`, waiting for room if the queue is full.
+    pub async fn save(&self, peer_data: PeerData) {
+        let peer_data = match self.sender.try_send(peer_data) {
+            Ok(()) => return,
+            Err(TrySendError::Full(peer_data)) => {
+                self.stats.backpressured.fetch_add(1, Ordering::Relaxed);
+                counter!(metrics::WRITER_BACKPRESSURE, 1);
+                peer_data
+            }
+            Err(TrySendError::Closed(peer_data)) => peer_data,
+        };
+        if let Err(e) = self.sender.send(peer_data).await {
+            warn!("Peer writer closed, dropping peer {}", e.0.id);
+        }
+    }
+
+    pub fn stats(&self) -> &WriterStats {
+        &self.stats
+    }
+}
+
+/// Writes the peers queued by the [`PeerWriter`]s in batches of up to [`BATCH_SIZE`] peers, or every
+/// [`FLUSH_INTERVAL`], whichever comes first.
+pub struct PeerFlusher {
+    receiver: mpsc::Receiver<PeerData>,
+    db: Arc<dyn PeerDB>,
+    record_history: bool,
+    stats: Arc<WriterStats>,
+}
+
+impl PeerFlusher {
+    /// Write the queued peers until `close` is cancelled and the queue is drained.
+    pub async fn run(mut self, close: CancellationToken) {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        let mut closed = false;
+        loop {
+            // the batch starts with its first peer, so that an idle queue doesn't wake the flusher up
+            let peer_data = tokio::select! {
+                _ = close.cancelled(), if !closed => {
+                    // new peers are refused, but the queued ones are still written
+                    self.receiver.close();
+                    closed = true;
+                    continue;
+                }
+                peer_data = self.receiver.recv() => peer_data,
+            };
+            let Some(peer_data) = peer_data else {
+                break;
+            };
+            batch.push(peer_data);
+            let deadline = time::sleep(FLUSH_INTERVAL);
+            tokio::pin!(deadline);
+            while batch.len() < BATCH_SIZE {
+                tokio::select! {
+                    _ = &mut deadline => break,
+                    peer_data = self.receiver.recv() => match peer_data {
+                        Some(peer_data) => batch.push(peer_data),
+                        None => break,
+                    },
+                }
+            }
+            self.flush(std::mem::take(&mut batch)).await;
+        }
+    }
+
+    async fn flush(&self, batch: Vec<PeerData>) {
+        self.stats.batches.fetch_add(1, Ordering::Relaxed);
+        // only the handshaked peers have a state worth keeping a history of
+        let history: Vec<_> = if self.record_history {
+            batch
+                .iter()
+                .filter(|peer_data| peer_data.handshake_completed == Some(true))
+                .cloned()
+                .collect()
+        } else {
+            vec![]
+        };
+        self.write(batch).await;
+        if !history.is_empty() {
+            if let Err(e) = self.db.append_history(history).await {
+                warn!("Failed to append a batch of peers to their history: {e}");
+            }
+        }
+    }
+
+    async fn write(&self, batch: Vec<PeerData>) {
+        let batch_len = batch.len();
+        let start = Instant::now();
+        let result = self.db.upsert_peers(batch.clone()).await;
+        histogram!(metrics::DB_WRITE_SECONDS, start.elapsed().as_secs_f64(), "op" => "batch");
+        let failed = match result {
+            Ok(failed) => failed,
+            Err(e) => {
+                warn!(
+                    "Failed to write a batch of {batch_len} peers: {e}, retrying them one by one"
+                );
+                batch
+            }
+        };
+        let flushed = (batch_len - failed.len()) as u64;
+        self.stats.flushed.fetch_add(flushed, Ordering::Relaxed);
+        counter!(metrics::PEERS_SAVED, flushed);
+        for peer_data in failed {
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            let id = peer_data.id.clone();
+            let start = Instant::now();
+            let result = self.db.add_peer(peer_data).await;
+            histogram!(metrics::DB_WRITE_SECONDS, start.elapsed().as_secs_f64(), "op" => "single");
+            match result {
+                Ok(()) => {
+                    self.stats.flushed.fetch_add(1, Ordering::Relaxed);
+                    counter!(metrics::PEERS_SAVED, 1);
+                }
+                Err(e) => {
+                    warn!("Failed to write peer {id}: {e}");
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    counter!(metrics::DB_WRITE_FAILURES, 1);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,15 @@
 mod crawler;
 mod p2p;
 use clap::{Args, Parser, Subcommand};
-use crawler::CrawlerBuilder;
+use crawler::{crawl_one, CrawlerBuilder, CrawlerConfig, GeoProviderConfig, SaveFilter};
+use ipnetwork::IpNetwork;
+use reth_crawler_db::Backend;
+use reth_primitives::NodeRecord;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(author, version)]
@@ -20,39 +28,605 @@ struct Cli {
 enum Commands {
     /// Start crawling the network
     Crawl(CrawlOpts),
+    /// Crawl a single peer without discovery and print its data as JSON, to debug its record. Exits with 2 if the
+    /// peer can't be dialed, 3 if the P2P handshake fails and 4 if the ETH handshake fails
+    CrawlOne(CrawlOneOpts),
+}
+
+#[derive(Args)]
+struct CrawlOneOpts {
+    /// Enode url of the peer, e.g. `enode://<id>@192.0.2.1:30303`.
+    enode: String,
+
+    /// Also write the peer to the db.
+    #[arg(long)]
+    save: bool,
+
+    #[command(flatten)]
+    crawl: CrawlOpts,
 }
 
 #[derive(Args)]
 struct CrawlOpts {
+    /// JSON config file with the tunables of the crawler, the flags below override its values.
     #[arg(long)]
-    /// Use a sqlite db for local testing.
+    config: Option<PathBuf>,
+
+    /// Where the peers are stored: `aws`, `sqlite` or `memory`. Defaults to `aws`.
+    #[arg(long)]
+    backend: Option<Backend>,
+
+    /// Use a sqlite db for local testing, shorthand for `--backend sqlite`.
+    #[arg(long, conflicts_with = "backend")]
     local_db: bool,
 
-    /// Eth RPC url to use for getting full blocks and determining whether or not a node is synced. It **MUST** be a web socket url.
-    #[arg(long, default_value = "wss://localhost:8546")]
-    eth_rpc_url: String,
+    /// Eth RPC url to use for getting full blocks and determining whether or not a node is synced. Web socket urls are subscribed to, http ones are polled, and a comma separated list fails over from one url to the next. Defaults to `wss://localhost:8546`.
+    #[arg(long, visible_alias = "provider-url")]
+    eth_rpc_url: Option<String>,
+
+    /// Interval between two polls of new blocks when the eth RPC is an http url, e.g. `12s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    block_poll_interval: Option<Duration>,
+
+    /// Client version advertised to the peers, e.g. `"reth-crawler/0.1.0 (+https://example.com)"`. Defaults to
+    /// `reth-crawler/<version>`.
+    #[arg(long, visible_alias = "client-version")]
+    client_id: Option<String>,
+
+    /// How long a peer found by discv4 waits for its ENR, and the fork id in it, before it's dialed anyway, e.g. `1s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    enr_wait: Option<Duration>,
+
+    /// Timeout in seconds for each of the p2p and eth handshakes with a peer. Defaults to 10.
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+
+    /// How many times handshakes failing with a transient error are retried. Defaults to 2.
+    #[arg(long)]
+    handshake_retries: Option<u32>,
+
+    /// Delay before the first handshake retry, doubled after each retry, e.g. `1s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    handshake_retry_backoff: Option<Duration>,
+
+    /// Number of node identities the peers are dialed with in turn, so that a peer with too many peers is retried
+    /// under another one. Defaults to 1.
+    #[arg(long)]
+    identities: Option<usize>,
+
+    /// Delay before a peer with too many peers is dialed again under the next identity, e.g. `10s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    too_many_peers_redial_delay: Option<Duration>,
+
+    /// How long a dialed peer has to send the header of its best block, e.g. `5s`. `0s` skips the request.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    head_request_timeout: Option<Duration>,
+
+    /// Maximum number of peers being handshaked at the same time, the others wait for their turn. Defaults to 256.
+    #[arg(long)]
+    max_concurrent_handshakes: Option<usize>,
+
+    /// How long after a successful crawl a peer isn't dialed again, only its `last_seen` is refreshed, e.g. `6h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    recrawl_window: Option<Duration>,
+
+    /// How many recently crawled peers are remembered. Defaults to 50000.
+    #[arg(long)]
+    recent_peers_capacity: Option<usize>,
+
+    /// How many distinct peer ids an ip may show within the sybil window before its new ids are neither dialed nor
+    /// saved, 0 for no limit. Defaults to 5.
+    #[arg(long)]
+    sybil_max_ids_per_ip: Option<usize>,
+
+    /// How long a peer id counts towards the limit of its ip, e.g. `1h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    sybil_window: Option<Duration>,
+
+    /// Interval between two recrawls of the stale peers of the database, e.g. `10m`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    recrawl_interval: Option<Duration>,
+
+    /// Maximum number of stale peers recrawled at each interval. Defaults to 100.
+    #[arg(long)]
+    recrawl_batch_size: Option<usize>,
+
+    /// How long after its `last_seen` a peer is considered stale and recrawled, e.g. `24h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    recrawl_stale_after: Option<Duration>,
+
+    /// How many failed recrawls in a row mark a peer as unreachable. Defaults to 3.
+    #[arg(long)]
+    recrawl_max_failures: Option<u32>,
+
+    /// How much a successful crawl raises the score of a peer. Defaults to 1.
+    #[arg(long)]
+    score_success: Option<i64>,
+
+    /// How much a failed handshake lowers the score of a peer, as a negative number. Defaults to -1.
+    #[arg(long, allow_negative_numbers = true)]
+    score_failure: Option<i64>,
+
+    /// How much a protocol breach lowers the score of a peer, as a negative number. Defaults to -5.
+    #[arg(long, allow_negative_numbers = true)]
+    score_protocol_breach: Option<i64>,
+
+    /// How much a useless peer disconnect lowers the score of a peer, as a negative number. Defaults to -3.
+    #[arg(long, allow_negative_numbers = true)]
+    score_useless_peer: Option<i64>,
+
+    /// Prune the peers whose `last_seen` is older than this from the db, e.g. `30d`. Peers are never pruned by default.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    prune_older_than: Option<Duration>,
+
+    /// Interval between two prunes of the db, e.g. `24h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    prune_interval: Option<Duration>,
+
+    /// How many peers are deleted at once while pruning. Defaults to 500.
+    #[arg(long)]
+    prune_batch_size: Option<usize>,
+
+    /// Delay between two batches of deletes while pruning, e.g. `1s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    prune_batch_delay: Option<Duration>,
+
+    /// Only log how many peers would be pruned.
+    #[arg(long)]
+    prune_dry_run: bool,
+
+    /// Comma separated list of enodes used to bootstrap discv4, they replace the mainnet boot nodes unless
+    /// `--extend-bootnodes` is set.
+    #[arg(long, value_delimiter = ',')]
+    bootnodes: Option<Vec<String>>,
+
+    /// File holding one enode per line used to bootstrap discv4, `#` starts a comment.
+    #[arg(long)]
+    bootnodes_file: Option<PathBuf>,
+
+    /// Bootstrap discv4 from the mainnet boot nodes along with the custom ones.
+    #[arg(long)]
+    extend_bootnodes: bool,
+
+    /// DNS trees the peers are discovered from, repeated or comma separated, e.g.
+    /// `enrtree://<public key>@all.mainnet.ethdisco.net`. Defaults to the mainnet tree of all the nodes.
+    #[arg(long = "dns-tree", value_delimiter = ',')]
+    dns_trees: Option<Vec<String>>,
+
+    /// Address discv4, discv5 and the rlpx sessions listen on. Defaults to `0.0.0.0`.
+    #[arg(long)]
+    listen_addr: Option<IpAddr>,
+
+    /// UDP port used by discv4, 0 picks an ephemeral port. Defaults to 30303.
+    #[arg(long)]
+    discovery_port: Option<u16>,
+
+    /// TCP port the rlpx sessions are accepted on, 0 picks an ephemeral port. Defaults to 30303.
+    #[arg(long)]
+    rlpx_port: Option<u16>,
+
+    /// UDP port used by discv5. Defaults to 30304.
+    #[arg(long)]
+    discv5_port: Option<u16>,
+
+    /// How the external ip advertised in the node records is found: `none`, `any`, `upnp`, `publicip` or
+    /// `extip:<ip>`. Defaults to `none`, which advertises no ip.
+    #[arg(long)]
+    nat: Option<String>,
+
+    /// External ip advertised in the node records, the same as `--nat extip:<ip>`.
+    #[arg(long)]
+    external_ip: Option<IpAddr>,
+
+    /// Comma separated list of ENRs used to bootstrap discv5.
+    #[arg(long, value_delimiter = ',')]
+    discv5_bootnodes: Option<Vec<String>>,
+
+    /// How long to ban the ip of a peer on another network, e.g. `24h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    wrong_network_ban: Option<Duration>,
+
+    /// How long to ban the ip of a peer that failed too many handshakes, e.g. `1h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    handshake_failures_ban: Option<Duration>,
+
+    /// How long to ban the ip of a peer without a client version, e.g. `24h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    empty_client_version_ban: Option<Duration>,
+
+    /// File the secret key of the crawler is loaded from, or generated into with 0600 permissions when missing, so
+    /// that it keeps its node identity across restarts. Defaults to `~/.reth-crawler/node.key`.
+    #[arg(long)]
+    node_key_file: Option<PathBuf>,
+
+    /// Secret key of the crawler as hex, e.g. injected as a secret in a container. Takes precedence over
+    /// `--node-key-file`.
+    #[arg(long)]
+    node_key: Option<String>,
+
+    /// File the banned ips are persisted to, so that they survive restarts. Defaults to `banned_ips.json`.
+    #[arg(long)]
+    bans_file: Option<PathBuf>,
+
+    /// Start with a clean slate, ignoring the bans persisted by a previous run.
+    #[arg(long)]
+    clean_bans: bool,
+
+    /// Comma separated ip ranges that may be crawled, e.g. `10.0.0.0/8,2001:db8::/32`. Any ip may be crawled if unset.
+    #[arg(long, value_delimiter = ',')]
+    allow_cidr: Option<Vec<IpNetwork>>,
+
+    /// Comma separated ip ranges that must never be crawled, not even geolocated, e.g. `192.0.2.0/24`.
+    #[arg(long, value_delimiter = ',')]
+    deny_cidr: Option<Vec<IpNetwork>>,
+
+    /// Comma separated filters of the crawled peers to save, a peer matching any of them is saved, e.g. `capability=snap/1,client=reth`.
+    #[arg(long, value_delimiter = ',')]
+    save_filter: Option<Vec<SaveFilter>>,
+
+    /// How long in-flight crawls are given to complete on shutdown, e.g. `15s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    shutdown_grace_period: Option<Duration>,
+
+    /// How many blocks can a node be lagging and still be considered synced. Defaults to 100.
+    #[arg(long)]
+    synced_threshold: Option<u64>,
+
+    /// How many failed p2p handshakes within the failure window get a peer banned. Defaults to 5.
+    #[arg(long)]
+    p2p_failure_threshold: Option<u32>,
+
+    /// How long a failed p2p handshake is counted against a peer, e.g. `1h`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    p2p_failure_window: Option<Duration>,
+
+    /// How many removals of a peer from the network within the removal window defer its recrawls. Defaults to 3.
+    #[arg(long)]
+    removed_peer_threshold: Option<u32>,
+
+    /// How long the removal of a peer from the network is counted against it, e.g. `10m`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    removed_peer_window: Option<Duration>,
+
+    /// How many crawled peers can wait to be written to the db, the crawls wait for room beyond that. Defaults to 1000.
+    #[arg(long)]
+    writer_queue_capacity: Option<usize>,
+
+    /// How many surfaced peers can wait for a crawl worker, the listeners wait for room beyond that. Defaults to 1000.
+    #[arg(long)]
+    candidate_queue_capacity: Option<usize>,
+
+    /// How many ip locations are cached. Defaults to 50000.
+    #[arg(long)]
+    geolocation_cache_capacity: Option<usize>,
+
+    /// How long a cached ip location is fresh, e.g. `7days`. A stale one is looked up again but still used if that fails. Defaults to 7 days.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    geolocation_cache_ttl: Option<Duration>,
+
+    /// Comma separated online geolocation providers with their max requests per minute, tried in order until one locates an ip, e.g. `ip-api:45,ipapi.co:1,ipwho.is:1`. The providers are `ip-api`, `ipapi.co` and `ipwho.is`.
+    #[arg(long, value_delimiter = ',')]
+    geolocation_providers: Option<Vec<GeoProviderConfig>>,
+
+    /// Key of the ip-api pro tier, `ip-api` is then asked through the pro endpoint. Raise its rate limit with
+    /// `--geolocation-providers` to make use of it.
+    #[arg(long)]
+    geo_api_key: Option<String>,
+
+    /// MaxMind GeoLite2-City database to geolocate the peers offline, the online providers are only asked for the ips it lacks.
+    #[arg(long)]
+    geoip_db: Option<PathBuf>,
+
+    /// MaxMind GeoLite2-ASN database telling the isp of the peers, requires `--geoip-db`.
+    #[arg(long)]
+    geoip_asn_db: Option<PathBuf>,
+
+    /// Never ask the online providers, the peers missing from `--geoip-db` stay unlocated. Requires `--geoip-db`.
+    #[arg(long)]
+    geoip_offline: bool,
+
+    /// Skip saving the inbound network sessions whose ip was crawled recently under another peer id.
+    #[arg(long)]
+    dedup_network_ip: bool,
+
+    /// Only record the discovered peers, without handshaking with them nor geolocating them. Their client and status
+    /// fields are empty and `handshake_completed` is false.
+    #[arg(long)]
+    discovery_only: bool,
+
+    /// Store the key/value pairs of the ENRs the peers are discovered with as JSON in their `enr` field. Only discv5
+    /// hands over the ENRs.
+    #[arg(long)]
+    store_enr: bool,
+
+    /// Append a snapshot of each crawled peer to its history, served by `/node/id/:id/history`, on top of replacing
+    /// its record.
+    #[arg(long)]
+    record_history: bool,
+
+    /// Print each crawled peer as a JSON line instead of saving it, nothing is written to the db.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// File the peers of a dry run are printed to instead of stdout, e.g. `peers.ndjson`. Requires `--dry-run`.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
+    /// Check whether the peers that connected to us accept connections on their advertised port, with a plain tcp connect. Off by default, some operators consider unsolicited connects impolite.
+    #[arg(long)]
+    probe_ports: bool,
+
+    /// How long the port probes wait for the connection, e.g. `3s`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    port_probe_timeout: Option<Duration>,
+
+    /// Serve Prometheus metrics on this address, e.g. `0.0.0.0:9001`. Metrics aren't served by default.
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve the admin routes on this address, e.g. `127.0.0.1:9002`. Admin routes aren't served by default.
+    #[arg(long)]
+    admin_addr: Option<SocketAddr>,
+
+    /// Api key required by the admin routes, passed in the `x-api-key` header.
+    #[arg(long)]
+    admin_api_key: Option<String>,
+}
+
+impl CrawlOpts {
+    /// Resolve the config of the crawler: the config file if any, overridden by the flags that were set.
+    fn config(&self) -> eyre::Result<CrawlerConfig> {
+        let mut config = match &self.config {
+            Some(path) => CrawlerConfig::from_file(path)?,
+            None => CrawlerConfig::default(),
+        };
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+        if self.local_db {
+            config.backend = Backend::Sqlite;
+        }
+        config.clean_bans |= self.clean_bans;
+        config.dedup_network_ip |= self.dedup_network_ip;
+        config.geoip_offline |= self.geoip_offline;
+        config.discovery_only |= self.discovery_only;
+        config.store_enr |= self.store_enr;
+        config.record_history |= self.record_history;
+        config.dry_run |= self.dry_run;
+        if let Some(output) = &self.output {
+            config.dry_run_output = Some(output.clone());
+        }
+        config.probe_ports |= self.probe_ports;
+        config.extend_bootnodes |= self.extend_bootnodes;
+        if let Some(eth_rpc_url) = &self.eth_rpc_url {
+            config.eth_rpc_url = eth_rpc_url.clone();
+        }
+        if let Some(block_poll_interval) = self.block_poll_interval {
+            config.block_poll_interval = block_poll_interval;
+        }
+        if let Some(client_id) = &self.client_id {
+            config.client_id = client_id.clone();
+        }
+        if let Some(enr_wait) = self.enr_wait {
+            config.enr_wait = enr_wait;
+        }
+        if let Some(handshake_timeout) = self.handshake_timeout {
+            config.handshake_timeout = Duration::from_secs(handshake_timeout);
+        }
+        if let Some(handshake_retries) = self.handshake_retries {
+            config.handshake_retries = handshake_retries;
+        }
+        if let Some(handshake_retry_backoff) = self.handshake_retry_backoff {
+            config.handshake_retry_backoff = handshake_retry_backoff;
+        }
+        if let Some(identities) = self.identities {
+            config.identities = identities;
+        }
+        if let Some(too_many_peers_redial_delay) = self.too_many_peers_redial_delay {
+            config.too_many_peers_redial_delay = too_many_peers_redial_delay;
+        }
+        if let Some(head_request_timeout) = self.head_request_timeout {
+            config.head_request_timeout = head_request_timeout;
+        }
+        if let Some(max_concurrent_handshakes) = self.max_concurrent_handshakes {
+            config.max_concurrent_handshakes = max_concurrent_handshakes;
+        }
+        if let Some(recrawl_window) = self.recrawl_window {
+            config.recrawl_window = recrawl_window;
+        }
+        if let Some(recent_peers_capacity) = self.recent_peers_capacity {
+            config.recent_peers_capacity = recent_peers_capacity;
+        }
+        if let Some(sybil_max_ids_per_ip) = self.sybil_max_ids_per_ip {
+            config.sybil_max_ids_per_ip = sybil_max_ids_per_ip;
+        }
+        if let Some(sybil_window) = self.sybil_window {
+            config.sybil_window = sybil_window;
+        }
+        if let Some(recrawl_interval) = self.recrawl_interval {
+            config.recrawl_interval = recrawl_interval;
+        }
+        if let Some(recrawl_batch_size) = self.recrawl_batch_size {
+            config.recrawl_batch_size = recrawl_batch_size;
+        }
+        if let Some(recrawl_stale_after) = self.recrawl_stale_after {
+            config.recrawl_stale_after = recrawl_stale_after;
+        }
+        if let Some(recrawl_max_failures) = self.recrawl_max_failures {
+            config.recrawl_max_failures = recrawl_max_failures;
+        }
+        if let Some(score_success) = self.score_success {
+            config.score_weights.success = score_success;
+        }
+        if let Some(score_failure) = self.score_failure {
+            config.score_weights.failure = score_failure;
+        }
+        if let Some(score_protocol_breach) = self.score_protocol_breach {
+            config.score_weights.protocol_breach = score_protocol_breach;
+        }
+        if let Some(score_useless_peer) = self.score_useless_peer {
+            config.score_weights.useless_peer = score_useless_peer;
+        }
+        if let Some(prune_older_than) = self.prune_older_than {
+            config.prune_older_than = prune_older_than;
+        }
+        if let Some(prune_interval) = self.prune_interval {
+            config.prune_interval = prune_interval;
+        }
+        if let Some(prune_batch_size) = self.prune_batch_size {
+            config.prune_batch_size = prune_batch_size;
+        }
+        if let Some(prune_batch_delay) = self.prune_batch_delay {
+            config.prune_batch_delay = prune_batch_delay;
+        }
+        config.prune_dry_run |= self.prune_dry_run;
+        if let Some(listen_addr) = self.listen_addr {
+            config.listen_addr = listen_addr;
+        }
+        if let Some(discovery_port) = self.discovery_port {
+            config.discovery_port = discovery_port;
+        }
+        if let Some(rlpx_port) = self.rlpx_port {
+            config.rlpx_port = rlpx_port;
+        }
+        if let Some(discv5_port) = self.discv5_port {
+            config.discv5_port = discv5_port;
+        }
+        if let Some(nat) = &self.nat {
+            config.nat = nat.clone();
+        }
+        if let Some(external_ip) = self.external_ip {
+            config.external_ip = Some(external_ip);
+        }
+        if let Some(bootnodes) = &self.bootnodes {
+            config.bootnodes = bootnodes.clone();
+        }
+        if let Some(bootnodes_file) = &self.bootnodes_file {
+            config.bootnodes_file = Some(bootnodes_file.clone());
+        }
+        if let Some(dns_trees) = &self.dns_trees {
+            config.dns_trees = dns_trees.clone();
+        }
+        if let Some(discv5_bootnodes) = &self.discv5_bootnodes {
+            config.discv5_bootnodes = discv5_bootnodes.clone();
+        }
+        if let Some(wrong_network_ban) = self.wrong_network_ban {
+            config.ban_durations.wrong_network = wrong_network_ban;
+        }
+        if let Some(handshake_failures_ban) = self.handshake_failures_ban {
+            config.ban_durations.handshake_failures = handshake_failures_ban;
+        }
+        if let Some(empty_client_version_ban) = self.empty_client_version_ban {
+            config.ban_durations.empty_client_version = empty_client_version_ban;
+        }
+        if let Some(node_key_file) = &self.node_key_file {
+            config.node_key_file = Some(node_key_file.clone());
+        }
+        if let Some(node_key) = &self.node_key {
+            config.node_key = Some(node_key.clone());
+        }
+        if let Some(bans_file) = &self.bans_file {
+            config.bans_file = bans_file.clone();
+        }
+        if let Some(allow_cidr) = &self.allow_cidr {
+            config.allow_cidrs = allow_cidr.clone();
+        }
+        if let Some(deny_cidr) = &self.deny_cidr {
+            config.deny_cidrs = deny_cidr.clone();
+        }
+        if let Some(save_filter) = &self.save_filter {
+            config.save_filters = save_filter.clone();
+        }
+        if let Some(shutdown_grace_period) = self.shutdown_grace_period {
+            config.shutdown_grace_period = shutdown_grace_period;
+        }
+        if let Some(synced_threshold) = self.synced_threshold {
+            config.synced_threshold = synced_threshold;
+        }
+        if let Some(p2p_failure_threshold) = self.p2p_failure_threshold {
+            config.p2p_failure_threshold = p2p_failure_threshold;
+        }
+        if let Some(p2p_failure_window) = self.p2p_failure_window {
+            config.p2p_failure_window = p2p_failure_window;
+        }
+        if let Some(removed_peer_threshold) = self.removed_peer_threshold {
+            config.removed_peer_threshold = removed_peer_threshold;
+        }
+        if let Some(removed_peer_window) = self.removed_peer_window {
+            config.removed_peer_window = removed_peer_window;
+        }
+        if let Some(writer_queue_capacity) = self.writer_queue_capacity {
+            config.writer_queue_capacity = writer_queue_capacity;
+        }
+        if let Some(candidate_queue_capacity) = self.candidate_queue_capacity {
+            config.candidate_queue_capacity = candidate_queue_capacity;
+        }
+        if let Some(geolocation_cache_capacity) = self.geolocation_cache_capacity {
+            config.geolocation_cache_capacity = geolocation_cache_capacity;
+        }
+        if let Some(geolocation_cache_ttl) = self.geolocation_cache_ttl {
+            config.geolocation_cache_ttl = geolocation_cache_ttl;
+        }
+        if let Some(geolocation_providers) = &self.geolocation_providers {
+            config.geolocation_providers = geolocation_providers.clone();
+        }
+        if let Some(geo_api_key) = &self.geo_api_key {
+            config.geo_api_key = Some(geo_api_key.clone());
+        }
+        if let Some(geoip_db) = &self.geoip_db {
+            config.geoip_db = Some(geoip_db.clone());
+        }
+        if let Some(geoip_asn_db) = &self.geoip_asn_db {
+            config.geoip_asn_db = Some(geoip_asn_db.clone());
+        }
+        if let Some(port_probe_timeout) = self.port_probe_timeout {
+            config.port_probe_timeout = port_probe_timeout;
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            config.metrics_addr = Some(metrics_addr);
+        }
+        if let Some(admin_addr) = self.admin_addr {
+            config.admin_addr = Some(admin_addr);
+        }
+        if let Some(admin_api_key) = &self.admin_api_key {
+            config.admin_api_key = Some(admin_api_key.clone());
+        }
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> eyre::Result<()> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
 
     match &cli.command {
         Commands::Crawl(opts) => {
-            let builder = if opts.local_db {
-                CrawlerBuilder::default().with_local_db()
-            } else {
-                CrawlerBuilder::default().without_local_db()
-            };
-
-            let (_, _, _, _) = builder
-                .with_eth_rpc_url(opts.eth_rpc_url.clone())
+            let config = opts.config()?;
+            if let Some(metrics_addr) = config.metrics_addr {
+                crawler::install_metrics_exporter(metrics_addr)?;
+            }
+            CrawlerBuilder::default()
+                .with_config(config)
                 .build()
-                .await
+                .await?
                 .run()
-                .await;
+                .await?;
+        }
+        Commands::CrawlOne(opts) => {
+            let config = opts.crawl.config()?;
+            let peer = NodeRecord::from_str(&opts.enode)
+                .map_err(|e| eyre::eyre!("invalid enode `{}`: {e}", opts.enode))?;
+            match crawl_one(&config, peer, opts.save).await {
+                Ok(peer_data) => println!("{}", serde_json::to_string_pretty(&peer_data)?),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    process::exit(e.exit_code());
+                }
+            }
         }
     }
+    Ok(())
 }
@@ -1,33 +1,217 @@
-use futures::StreamExt;
-use reth_ecies::{stream::ECIESStream, util::pk2id};
+use std::io::{self, ErrorKind};
+
+use futures::{SinkExt, StreamExt};
+use reth_ecies::{
+    error::{ECIESError, ECIESErrorImpl},
+    stream::ECIESStream,
+    util::pk2id,
+};
 use reth_eth_wire::{
-    EthMessage, EthStream, HelloMessage, P2PStream, Status, UnauthedEthStream, UnauthedP2PStream,
+    errors::{EthHandshakeError, EthStreamError, P2PHandshakeError, P2PStreamError},
+    BlockHeaders, DisconnectReason, EthMessage, EthStream, GetBlockHeaders, HelloMessage,
+    P2PStream, RequestPair, Status, UnauthedEthStream, UnauthedP2PStream,
+};
+use reth_primitives::{
+    BlockHashOrNumber, Chain, Hardfork, Head, HeadersDirection, NodeRecord, H256, MAINNET,
+    MAINNET_GENESIS,
 };
-use reth_primitives::{Chain, Hardfork, Head, NodeRecord, MAINNET, MAINNET_GENESIS};
 use secp256k1::{SecretKey, SECP256K1};
+use thiserror::Error;
 use tokio::net::TcpStream;
 
 type AuthedP2PStream = P2PStream<ECIESStream<TcpStream>>;
-type AuthedEthStream = EthStream<P2PStream<ECIESStream<TcpStream>>>;
+pub type AuthedEthStream = EthStream<P2PStream<ECIESStream<TcpStream>>>;
+
+/// Error of [`handshake_p2p`] or [`handshake_eth`].
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Ecies(#[from] ECIESError),
+    #[error(transparent)]
+    P2p(#[from] P2PStreamError),
+    #[error(transparent)]
+    Eth(#[from] EthStreamError),
+}
+
+impl WireError {
+    /// Why the peer disconnected us during the handshake, if it did.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        let p2p = match self {
+            WireError::P2p(e) | WireError::Eth(EthStreamError::P2PStreamError(e)) => e,
+            _ => return None,
+        };
+        match p2p {
+            P2PStreamError::HandshakeError(P2PHandshakeError::Disconnected(reason))
+            | P2PStreamError::Disconnected(reason) => Some(*reason),
+            _ => None,
+        }
+    }
+
+    /// What the error says about the peer.
+    pub fn cause(&self) -> FailureCause {
+        match self.disconnect_reason() {
+            Some(DisconnectReason::TooManyPeers) => return FailureCause::TooManyPeers,
+            Some(DisconnectReason::UselessPeer) => return FailureCause::UselessPeer,
+            Some(DisconnectReason::ProtocolBreach) => return FailureCause::ProtocolBreach,
+            _ => {}
+        }
+        match self {
+            WireError::Io(_)
+            | WireError::P2p(P2PStreamError::Io(_))
+            | WireError::Eth(EthStreamError::P2PStreamError(P2PStreamError::Io(_))) => {
+                FailureCause::Io
+            }
+            WireError::Ecies(e) if matches!(e.inner(), ECIESErrorImpl::IO(_)) => FailureCause::Io,
+            // only a status that doesn't match ours tells the peer is on another network
+            WireError::Eth(EthStreamError::EthHandshakeError(
+                EthHandshakeError::MismatchedGenesis { .. }
+                | EthHandshakeError::MismatchedChain { .. }
+                | EthHandshakeError::MismatchedProtocolVersion { .. }
+                | EthHandshakeError::InvalidFork(_),
+            )) => FailureCause::WrongNetwork,
+            WireError::Eth(EthStreamError::EthHandshakeError(EthHandshakeError::NoResponse)) => {
+                FailureCause::Timeout
+            }
+            _ => FailureCause::Other,
+        }
+    }
+}
+
+/// Cause of a failed handshake, the failure count and ban policy key off it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCause {
+    /// The peer has too many peers, which doesn't make it a faulty peer.
+    TooManyPeers,
+    /// The peer has no use for us.
+    UselessPeer,
+    /// The peer disconnected us for breaching the protocol.
+    ProtocolBreach,
+    /// The status of the peer doesn't match ours: another genesis, chain or fork.
+    WrongNetwork,
+    /// The connection failed, e.g. it was refused or reset.
+    Io,
+    /// The handshake didn't complete in time.
+    Timeout,
+    /// Any other failure, e.g. a message that couldn't be decoded.
+    Other,
+}
 
-// Perform a P2P handshake with a peer
+impl FailureCause {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureCause::TooManyPeers => "too_many_peers",
+            FailureCause::UselessPeer => "useless_peer",
+            FailureCause::ProtocolBreach => "protocol_breach",
+            FailureCause::WrongNetwork => "wrong_network",
+            FailureCause::Io => "io",
+            FailureCause::Timeout => "timeout",
+            FailureCause::Other => "other",
+        }
+    }
+}
+
+// Perform a P2P handshake with a peer, advertising `client_id` as our client version
 pub async fn handshake_p2p(
     peer: NodeRecord,
     key: SecretKey,
-) -> eyre::Result<(AuthedP2PStream, HelloMessage)> {
+    client_id: &str,
+) -> Result<(AuthedP2PStream, HelloMessage), WireError> {
     let outgoing = TcpStream::connect((peer.address, peer.tcp_port)).await?;
+    handshake_p2p_over(outgoing, peer, key, client_id).await
+}
+
+// Perform a P2P handshake with a peer over the tcp connection `outgoing` already made to it
+pub async fn handshake_p2p_over(
+    outgoing: TcpStream,
+    peer: NodeRecord,
+    key: SecretKey,
+    client_id: &str,
+) -> Result<(AuthedP2PStream, HelloMessage), WireError> {
     let ecies_stream = ECIESStream::connect(outgoing, key, peer.id).await?;
 
     let our_peer_id = pk2id(&key.public_key(SECP256K1));
-    let our_hello = HelloMessage::builder(our_peer_id).build();
+    let our_hello = HelloMessage::builder(our_peer_id)
+        .client_version(client_id)
+        .build();
 
     Ok(UnauthedP2PStream::new(ecies_stream)
         .handshake(our_hello)
         .await?)
 }
 
+/// How a handshake failed, to tell apart the peers worth dialing again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The connection timed out, was reset or dropped, the peer may answer a later dial.
+    Transient,
+    /// The peer rejected us or violated the protocol, e.g. another genesis or a useless peer disconnect.
+    Permanent,
+}
+
+impl HandshakeFailure {
+    /// Classify the error returned by [`handshake_p2p`] or [`handshake_eth`], anything not known to be transient is
+    /// permanent.
+    pub fn classify(error: &WireError) -> Self {
+        let transient = match error {
+            WireError::Io(e) => is_transient_io(e),
+            WireError::Ecies(e) => match e.inner() {
+                ECIESErrorImpl::IO(e) => is_transient_io(e),
+                ECIESErrorImpl::UnreadableStream | ECIESErrorImpl::StreamTimeout => true,
+                _ => false,
+            },
+            WireError::P2p(e) | WireError::Eth(EthStreamError::P2PStreamError(e)) => {
+                is_transient_p2p(e)
+            }
+            // the peer didn't send its status, it may send it on a later dial
+            WireError::Eth(EthStreamError::EthHandshakeError(EthHandshakeError::NoResponse)) => {
+                true
+            }
+            WireError::Eth(_) => false,
+        };
+        if transient {
+            HandshakeFailure::Transient
+        } else {
+            HandshakeFailure::Permanent
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HandshakeFailure::Transient => "transient",
+            HandshakeFailure::Permanent => "permanent",
+        }
+    }
+}
+
+fn is_transient_io(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::Interrupted
+    )
+}
+
+fn is_transient_p2p(error: &P2PStreamError) -> bool {
+    match error {
+        P2PStreamError::Io(e) => is_transient_io(e),
+        P2PStreamError::HandshakeError(P2PHandshakeError::NoResponse)
+        | P2PStreamError::PingTimeout => true,
+        _ => false,
+    }
+}
+
 // Perform a ETH Wire handshake with a peer
-pub async fn handshake_eth(p2p_stream: AuthedP2PStream) -> eyre::Result<(AuthedEthStream, Status)> {
+pub async fn handshake_eth(
+    p2p_stream: AuthedP2PStream,
+) -> Result<(AuthedEthStream, Status), WireError> {
     let fork_filter = MAINNET.fork_filter(Head {
         timestamp: MAINNET.fork(Hardfork::Shanghai).as_timestamp().unwrap(),
         ..Default::default()
@@ -47,6 +231,42 @@ pub async fn handshake_eth(p2p_stream: AuthedP2PStream) -> eyre::Result<(AuthedE
     Ok(eth_unauthed.handshake(status, fork_filter).await?)
 }
 
+/// Number and timestamp of the header of block `hash`, asked to the peer over its eth stream.
+///
+/// Returns `None` if the peer doesn't have the block, the broadcasts received meanwhile are ignored.
+pub async fn request_header(
+    eth_stream: &mut AuthedEthStream,
+    hash: H256,
+) -> Result<Option<(u64, u64)>, WireError> {
+    // a single request is sent on this stream
+    let request_id = 0;
+    eth_stream
+        .send(EthMessage::GetBlockHeaders(RequestPair {
+            request_id,
+            message: GetBlockHeaders {
+                start_block: BlockHashOrNumber::Hash(hash),
+                limit: 1,
+                skip: 0,
+                direction: HeadersDirection::Rising,
+            },
+        }))
+        .await?;
+    while let Some(message) = eth_stream.next().await {
+        if let EthMessage::BlockHeaders(RequestPair {
+            request_id: id,
+            message: BlockHeaders(headers),
+        }) = message?
+        {
+            if id == request_id {
+                return Ok(headers
+                    .first()
+                    .map(|header| (header.number, header.timestamp)));
+            }
+        }
+    }
+    Err(P2PStreamError::Disconnected(DisconnectReason::DisconnectRequested).into())
+}
+
 // Snoop by greedily capturing all broadcasts that the peer emits
 // note: this node cannot handle request so will be disconnected by peer when challenged
 pub async fn _snoop(peer: NodeRecord, mut eth_stream: AuthedEthStream) {
@@ -110,3 +330,122 @@ pub async fn _snoop(peer: NodeRecord, mut eth_stream: AuthedEthStream) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eth_handshake_error(error: EthHandshakeError) -> WireError {
+        WireError::Eth(EthStreamError::EthHandshakeError(error))
+    }
+
+    #[test]
+    fn too_many_peers_disconnects() {
+        let p2p = WireError::P2p(P2PStreamError::Disconnected(DisconnectReason::TooManyPeers));
+        assert_eq!(p2p.cause(), FailureCause::TooManyPeers);
+        let hello = WireError::P2p(P2PStreamError::HandshakeError(
+            P2PHandshakeError::Disconnected(DisconnectReason::TooManyPeers),
+        ));
+        assert_eq!(hello.cause(), FailureCause::TooManyPeers);
+    }
+
+    #[test]
+    fn useless_peer_disconnects() {
+        let error = WireError::P2p(P2PStreamError::HandshakeError(
+            P2PHandshakeError::Disconnected(DisconnectReason::UselessPeer),
+        ));
+        assert_eq!(error.cause(), FailureCause::UselessPeer);
+        assert_eq!(
+            HandshakeFailure::classify(&error),
+            HandshakeFailure::Permanent
+        );
+    }
+
+    #[test]
+    fn protocol_breaches_during_the_eth_handshake() {
+        let error = WireError::Eth(EthStreamError::P2PStreamError(
+            P2PStreamError::Disconnected(DisconnectReason::ProtocolBreach),
+        ));
+        assert_eq!(error.cause(), FailureCause::ProtocolBreach);
+        assert_eq!(
+            HandshakeFailure::classify(&error),
+            HandshakeFailure::Permanent
+        );
+    }
+
+    #[test]
+    fn refused_connections_are_transient_io() {
+        let error = WireError::Io(io::Error::from(ErrorKind::ConnectionRefused));
+        assert_eq!(error.cause(), FailureCause::Io);
+        assert_eq!(
+            HandshakeFailure::classify(&error),
+            HandshakeFailure::Transient
+        );
+        let denied = WireError::Io(io::Error::from(ErrorKind::PermissionDenied));
+        assert_eq!(
+            HandshakeFailure::classify(&denied),
+            HandshakeFailure::Permanent
+        );
+    }
+
+    #[test]
+    fn stalled_streams_are_transient() {
+        let ecies = WireError::Ecies(ECIESError::from(ECIESErrorImpl::StreamTimeout));
+        assert_eq!(ecies.cause(), FailureCause::Other);
+        assert_eq!(
+            HandshakeFailure::classify(&ecies),
+            HandshakeFailure::Transient
+        );
+        let ping = WireError::P2p(P2PStreamError::PingTimeout);
+        assert_eq!(
+            HandshakeFailure::classify(&ping),
+            HandshakeFailure::Transient
+        );
+    }
+
+    #[test]
+    fn mismatched_statuses_are_another_network() {
+        let errors = [
+            EthHandshakeError::MismatchedGenesis {
+                expected: MAINNET_GENESIS,
+                got: H256::from_low_u64_be(5),
+            },
+            EthHandshakeError::MismatchedChain {
+                expected: Chain::mainnet(),
+                got: Chain::Id(5),
+            },
+            EthHandshakeError::MismatchedProtocolVersion {
+                expected: 68,
+                got: 66,
+            },
+        ];
+        for error in errors {
+            let error = eth_handshake_error(error);
+            assert_eq!(error.cause(), FailureCause::WrongNetwork);
+            assert_eq!(
+                HandshakeFailure::classify(&error),
+                HandshakeFailure::Permanent
+            );
+        }
+    }
+
+    #[test]
+    fn missing_statuses_are_timeouts() {
+        let error = eth_handshake_error(EthHandshakeError::NoResponse);
+        assert_eq!(error.cause(), FailureCause::Timeout);
+        assert_eq!(
+            HandshakeFailure::classify(&error),
+            HandshakeFailure::Transient
+        );
+    }
+
+    #[test]
+    fn other_eth_handshake_errors_dont_tell_the_network() {
+        let error = eth_handshake_error(EthHandshakeError::NonStatusMessageInHandshake);
+        assert_eq!(error.cause(), FailureCause::Other);
+        assert_eq!(
+            HandshakeFailure::classify(&error),
+            HandshakeFailure::Permanent
+        );
+    }
+}
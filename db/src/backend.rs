@@ -0,0 +1,64 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::{AwsPeerDB, InMemoryPeerDB, PeerDB, SqlPeerDB};
+
+/// Where the peers are stored.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// `peers_data.db` sqlite file, for local testing.
+    Sqlite,
+    /// DynamoDB tables.
+    #[default]
+    Aws,
+    /// Nothing is persisted, for tests and dry runs.
+    Memory,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 3] = [Backend::Sqlite, Backend::Aws, Backend::Memory];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Sqlite => "sqlite",
+            Backend::Aws => "aws",
+            Backend::Memory => "memory",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Backend::ALL
+            .into_iter()
+            .find(|backend| backend.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Backend::ALL.iter().map(Backend::as_str).collect();
+                format!(
+                    "unknown backend `{s}`, expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Open the peer db of `backend`.
+pub async fn build_peer_db(backend: Backend) -> Arc<dyn PeerDB> {
+    match backend {
+        Backend::Sqlite => Arc::new(SqlPeerDB::new().await),
+        Backend::Aws => Arc::new(AwsPeerDB::new().await),
+        Backend::Memory => Arc::new(InMemoryPeerDB::new()),
+    }
+}
@@ -1,21 +1,147 @@
-use crate::types::{AddItemError, PeerData, QueryItemError, ScanTableError};
+use crate::types::{
+    as_option_string, as_option_u64, decode_cursor, encode_cursor, fold_city, same_city,
+    AddItemError, ChainHead, DeleteItemError, NetworkSummary, PeerData, PeerFilter, QueryItemError,
+    ScanTableError, ScannedPeers,
+};
 use async_trait::async_trait;
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_dynamodb::types::AttributeValue;
-use aws_sdk_dynamodb::{config::Region, Client};
-use std::collections::HashMap;
+use aws_config::retry::RetryConfig;
+use aws_sdk_dynamodb::operation::scan::builders::ScanFluentBuilder;
+use aws_sdk_dynamodb::types::{
+    AttributeValue, DeleteRequest, KeysAndAttributes, PutRequest, WriteRequest,
+};
+use aws_sdk_dynamodb::{config::Region, error::SdkError, Client};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, OptionalExtension, Params, Row};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, RwLock};
 use tokio_rusqlite::Connection;
 use tokio_stream::StreamExt;
 
 #[async_trait]
 pub trait PeerDB: Send + Sync {
+    /// Upsert a peer, incrementing its `seen_count` and keeping its `first_seen`, and what [`PeerData::keep_saved`]
+    /// keeps.
     async fn add_peer(&self, peer_data: PeerData) -> Result<(), AddItemError>;
+    /// Add many peers at once as they are, returning how many were saved.
+    async fn add_peers(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError>;
+    /// Save many crawled peers at once like [`PeerDB::add_peer`] does, returning the peers that couldn't be saved.
+    async fn upsert_peers(&self, peers: Vec<PeerData>) -> Result<Vec<PeerData>, AddItemError>;
+    /// Only refresh the `last_seen` of an already saved peer.
+    async fn touch_peer(&self, id: String, last_seen: String) -> Result<(), AddItemError>;
+    /// Flag a saved peer as unreachable, until it's crawled successfully again.
+    async fn mark_unreachable(&self, id: String) -> Result<(), AddItemError>;
+    /// Record the category, the cause and the time of the last failed crawl of a saved peer, counting it in its
+    /// `handshake_failures`. Unknown peers are ignored.
+    async fn record_failure(
+        &self,
+        id: String,
+        failure: String,
+        error: String,
+        failed_at: String,
+    ) -> Result<(), AddItemError>;
+    /// Record why the last session with a saved peer was closed, unknown peers are ignored.
+    async fn record_disconnect(&self, id: String, reason: String) -> Result<(), AddItemError>;
+    /// Add `delta` to the score of a saved peer, unknown peers are ignored.
+    async fn adjust_score(&self, id: String, delta: i64) -> Result<(), AddItemError>;
+    /// Up to `limit` reachable peers with a `last_seen` older than the given one, the oldest first.
+    async fn stale_peers(
+        &self,
+        last_seen: String,
+        limit: usize,
+    ) -> Result<Vec<PeerData>, ScanTableError>;
     async fn all_peers(&self, page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError>;
+    /// Up to `max_items` peers, all of them without a cap, telling whether the table held more.
+    ///
+    /// Unlike [`PeerDB::all_peers`] it stops reading the table at the cap, so that a query can't scan it unbounded.
+    async fn scan_peers(&self, max_items: Option<usize>) -> Result<ScannedPeers, ScanTableError>;
+    /// Up to `max_items` of the peers with a `last_seen` at or after the given one, all of them without a cap.
+    async fn peers_seen_since(
+        &self,
+        last_seen: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError>;
+    /// A page of up to `limit` peers starting after `cursor`, along with the cursor of the next page if there's one.
+    ///
+    /// Unlike [`PeerDB::all_peers`] it doesn't read the whole table, so that it can be walked one page at a time.
+    /// A zero `limit` gives an empty page without a next one.
+    async fn all_peers_cursor(
+        &self,
+        limit: usize,
+        cursor: Option<String>,
+    ) -> Result<(Vec<PeerData>, Option<String>), ScanTableError>;
+    /// Up to `max_items` of the peers meeting all the criteria of `filter`, all of them without a cap.
+    async fn query_peers(
+        &self,
+        filter: PeerFilter,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError>;
     async fn node_by_id(&self, id: String) -> Result<Option<Vec<PeerData>>, QueryItemError>;
     async fn node_by_ip(&self, ip: String) -> Result<Option<Vec<PeerData>>, QueryItemError>;
+    /// Up to `max_items` of the peers located in `city`, compared case insensitively, all of them without a cap.
+    async fn peers_by_city(
+        &self,
+        city: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError>;
+    /// Uniformly sample up to `n` of the first `max_items` peers read, all of them without a cap.
+    async fn random_peers(
+        &self,
+        n: usize,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError>;
+    /// Delete up to `limit` of the peers with a `last_seen` older than the given one, all of them without a limit, and
+    /// return how many were (or would be, with `dry_run`) deleted.
+    async fn delete_peers_older_than(
+        &self,
+        last_seen: String,
+        limit: Option<usize>,
+        dry_run: bool,
+    ) -> Result<u64, DeleteItemError>;
+    /// Save the latest block known by the crawler.
+    async fn set_latest_block(&self, head: ChainHead) -> Result<(), AddItemError>;
+    /// The latest block known by the crawler, if it saved one.
+    async fn latest_block(&self) -> Result<Option<ChainHead>, QueryItemError>;
+    /// Append the [`PeerData::snapshot`]s of crawled peers to their history, each keyed by its id and its `last_seen`,
+    /// and return how many were appended.
+    async fn append_history(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError>;
+    /// The snapshots of the history of a peer, oldest first.
+    async fn peer_history(&self, id: String) -> Result<Vec<PeerData>, QueryItemError>;
+    /// Aggregated figures about up to `max_items` peers, all of them without a cap, along with whether the table held
+    /// more.
+    async fn summary(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<(NetworkSummary, bool), ScanTableError>;
 }
 
+/// Max number of requests that can be sent in a single DynamoDB `BatchWriteItem`.
+const DYNAMODB_BATCH_SIZE: usize = 25;
+/// Default number of attempts of each DynamoDB request, `AWS_MAX_ATTEMPTS` overrides it.
+const DYNAMODB_MAX_ATTEMPTS: u32 = 5;
+/// Key of the latest block known by the crawler, in the crawler state table.
+const LATEST_BLOCK_KEY: &str = "latest_block";
+/// Attributes that a sighting without them leaves as they're stored, e.g. the disconnect reason stamped on the
+/// peer before its session data is written, or the ENR of a peer found by discv5 and later seen in a session.
+const KEPT_WHEN_MISSING: [&str; 2] = ["last_disconnect_reason", "enr"];
+/// Attributes that a peer only known from discovery sets, the others are kept from its last handshake, if any.
+///
+/// Being discovered isn't a successful crawl, so neither `seen_count` nor `unreachable` are among them.
+const DISCOVERY_ATTRIBUTES: [&str; 11] = [
+    "peer-ip",
+    "enode_url",
+    "port",
+    "udp_port",
+    "last_seen",
+    "source_region",
+    "discovery_source",
+    "dns_tree",
+    "enr",
+    "last_disconnect_reason",
+    "off_network",
+];
+
 #[derive(Clone)]
 pub struct AwsPeerDB {
     client: Client,
@@ -25,7 +151,18 @@ impl AwsPeerDB {
     pub async fn new() -> Self {
         let region_provider =
             RegionProviderChain::default_provider().or_else(Region::new("us-west-2"));
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
+        let max_attempts = std::env::var("AWS_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|attempts| attempts.parse().ok())
+            .filter(|attempts| *attempts > 0)
+            .unwrap_or(DYNAMODB_MAX_ATTEMPTS);
+        // the standard mode retries the throttled and transient errors with jittered exponential backoff, and
+        // returns the other errors right away
+        let shared_config = aws_config::from_env()
+            .region(region_provider)
+            .retry_config(RetryConfig::standard().with_max_attempts(max_attempts))
+            .load()
+            .await;
         let client = Client::new(&shared_config);
 
         AwsPeerDB { client }
@@ -57,11 +194,9 @@ impl AwsPeerDB {
             Err(err) => Err(err.into()),
         }
     }
-}
 
-#[async_trait]
-impl PeerDB for AwsPeerDB {
-    async fn add_peer(&self, peer_data: PeerData) -> Result<(), AddItemError> {
+    /// Build the DynamoDB item of a peer.
+    fn peer_item(&self, peer_data: PeerData) -> HashMap<String, AttributeValue> {
         let capabilities = peer_data
             .capabilities
             .iter()
@@ -72,6 +207,7 @@ impl PeerDB for AwsPeerDB {
         let client_version = AttributeValue::S(peer_data.client_version);
         let enode_url = AttributeValue::S(peer_data.enode_url);
         let port = AttributeValue::N(peer_data.tcp_port.to_string()); // numbers are sent over the network as string
+        let udp_port = AttributeValue::N(peer_data.udp_port.to_string());
         let chain = AttributeValue::S(peer_data.chain);
         let genesis_hash = AttributeValue::S(peer_data.genesis_block_hash);
         let best_block = AttributeValue::S(peer_data.best_block);
@@ -88,36 +224,542 @@ impl PeerDB for AwsPeerDB {
             AttributeValue::Null(true)
         };
         let isp = AttributeValue::S(peer_data.isp);
+        let first_seen = AttributeValue::S(peer_data.first_seen);
+        let seen_count = AttributeValue::N(peer_data.seen_count.to_string());
+        let unreachable = AttributeValue::Bool(peer_data.unreachable);
+        let latency_ms = if let Some(latency_ms) = peer_data.latency_ms {
+            AttributeValue::N(latency_ms.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let fork_hash = AttributeValue::S(peer_data.fork_hash);
+        let fork_next = AttributeValue::N(peer_data.fork_next.to_string());
+        let latitude = if let Some(latitude) = peer_data.latitude {
+            AttributeValue::N(latitude.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let longitude = if let Some(longitude) = peer_data.longitude {
+            AttributeValue::N(longitude.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let last_failure = if let Some(last_failure) = peer_data.last_failure {
+            AttributeValue::S(last_failure)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let p2p_version = if let Some(p2p_version) = peer_data.p2p_version {
+            AttributeValue::N(p2p_version.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let asn = if let Some(asn) = peer_data.asn {
+            AttributeValue::N(asn.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let as_org = if let Some(as_org) = peer_data.as_org {
+            AttributeValue::S(as_org)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let geo_provider = if let Some(geo_provider) = peer_data.geo_provider {
+            AttributeValue::S(geo_provider)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let port_reachable = if let Some(port_reachable) = peer_data.port_reachable {
+            AttributeValue::Bool(port_reachable)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let discovery_source = if let Some(discovery_source) = peer_data.discovery_source {
+            AttributeValue::S(discovery_source)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let last_failure_at = if let Some(last_failure_at) = peer_data.last_failure_at {
+            AttributeValue::S(last_failure_at)
+        } else {
+            AttributeValue::Null(true)
+        };
+        // numbers have up to 38 digits of precision, enough for any u128
+        let total_difficulty_num =
+            if let Some(total_difficulty_num) = peer_data.total_difficulty_num {
+                AttributeValue::N(total_difficulty_num.to_string())
+            } else {
+                AttributeValue::Null(true)
+            };
+        let last_disconnect_reason =
+            if let Some(last_disconnect_reason) = peer_data.last_disconnect_reason {
+                AttributeValue::S(last_disconnect_reason)
+            } else {
+                AttributeValue::Null(true)
+            };
+        let on_canonical = if let Some(on_canonical) = peer_data.on_canonical {
+            AttributeValue::Bool(on_canonical)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let sync_lag_blocks = if let Some(sync_lag_blocks) = peer_data.sync_lag_blocks {
+            AttributeValue::N(sync_lag_blocks.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let sync_lag_seconds = if let Some(sync_lag_seconds) = peer_data.sync_lag_seconds {
+            AttributeValue::N(sync_lag_seconds.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let head_block_number = if let Some(head_block_number) = peer_data.head_block_number {
+            AttributeValue::N(head_block_number.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let crawler_head_number = if let Some(crawler_head_number) = peer_data.crawler_head_number {
+            AttributeValue::N(crawler_head_number.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+        let dns_tree = if let Some(dns_tree) = peer_data.dns_tree {
+            AttributeValue::S(dns_tree)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let handshake_completed = if let Some(handshake_completed) = peer_data.handshake_completed {
+            AttributeValue::Bool(handshake_completed)
+        } else {
+            AttributeValue::Null(true)
+        };
+        let score = AttributeValue::N(peer_data.score.to_string());
+        let last_handshake_error =
+            if let Some(last_handshake_error) = peer_data.last_handshake_error {
+                AttributeValue::S(last_handshake_error)
+            } else {
+                AttributeValue::Null(true)
+            };
+        let handshake_failures = AttributeValue::N(peer_data.handshake_failures.to_string());
+        let advertised_eth_versions = AttributeValue::L(
+            peer_data
+                .advertised_eth_versions
+                .iter()
+                .map(|eth_version| AttributeValue::N(eth_version.to_string()))
+                .collect(),
+        );
+        let enr = if let Some(enr) = peer_data.enr {
+            AttributeValue::S(enr.to_string())
+        } else {
+            AttributeValue::Null(true)
+        };
+
+        HashMap::from([
+            ("peer-id".to_string(), peer_id),
+            ("peer-ip".to_string(), peer_ip),
+            ("client_version".to_string(), client_version),
+            ("enode_url".to_string(), enode_url),
+            ("port".to_string(), port),
+            ("udp_port".to_string(), udp_port),
+            ("chain".to_string(), chain),
+            ("country".to_string(), country),
+            ("city".to_string(), city),
+            ("capabilities".to_string(), capabilities),
+            ("eth_version".to_string(), eth_version),
+            ("last_seen".to_string(), last_seen),
+            ("source_region".to_string(), region_source),
+            ("genesis_block_hash".to_string(), genesis_hash),
+            ("best_block".to_string(), best_block),
+            ("total_difficulty".to_string(), total_difficulty),
+            ("synced".to_string(), synced),
+            ("isp".to_string(), isp),
+            ("first_seen".to_string(), first_seen),
+            ("seen_count".to_string(), seen_count),
+            ("unreachable".to_string(), unreachable),
+            ("latency_ms".to_string(), latency_ms),
+            ("fork_hash".to_string(), fork_hash),
+            ("fork_next".to_string(), fork_next),
+            ("latitude".to_string(), latitude),
+            ("longitude".to_string(), longitude),
+            ("last_failure".to_string(), last_failure),
+            ("p2p_version".to_string(), p2p_version),
+            ("asn".to_string(), asn),
+            ("as_org".to_string(), as_org),
+            ("geo_provider".to_string(), geo_provider),
+            ("port_reachable".to_string(), port_reachable),
+            ("discovery_source".to_string(), discovery_source),
+            ("last_failure_at".to_string(), last_failure_at),
+            ("total_difficulty_num".to_string(), total_difficulty_num),
+            ("last_disconnect_reason".to_string(), last_disconnect_reason),
+            ("on_canonical".to_string(), on_canonical),
+            ("sync_lag_blocks".to_string(), sync_lag_blocks),
+            ("sync_lag_seconds".to_string(), sync_lag_seconds),
+            ("head_block_number".to_string(), head_block_number),
+            ("crawler_head_number".to_string(), crawler_head_number),
+            ("dns_tree".to_string(), dns_tree),
+            ("handshake_completed".to_string(), handshake_completed),
+            ("score".to_string(), score),
+            ("last_handshake_error".to_string(), last_handshake_error),
+            ("handshake_failures".to_string(), handshake_failures),
+            (
+                "advertised_eth_versions".to_string(),
+                advertised_eth_versions,
+            ),
+            ("enr".to_string(), enr),
+            (
+                "snap_supported".to_string(),
+                AttributeValue::Bool(peer_data.snap_supported),
+            ),
+            (
+                "les_supported".to_string(),
+                AttributeValue::Bool(peer_data.les_supported),
+            ),
+            (
+                "off_network".to_string(),
+                AttributeValue::Bool(peer_data.off_network),
+            ),
+        ])
+    }
+}
+
+#[async_trait]
+impl PeerDB for AwsPeerDB {
+    async fn add_peer(&self, peer_data: PeerData) -> Result<(), AddItemError> {
+        let discovered_only = peer_data.handshake_completed == Some(false);
+        let mut item = self.peer_item(peer_data);
+        let peer_id = item.remove("peer-id").expect("it's always set");
+        let first_seen = item.remove("first_seen").expect("it's always set");
+        item.remove("seen_count");
+        let score = item.remove("score").expect("it's always set");
+        for name in KEPT_WHEN_MISSING {
+            if matches!(item.get(name), Some(AttributeValue::Null(_))) {
+                item.remove(name);
+            }
+        }
 
-        match self
+        // attribute names are aliased since some of them aren't valid in expressions, e.g. `peer-ip`
+        let mut update = self
             .client
-            .put_item()
+            .update_item()
             .table_name("eth-peer-data")
-            .item("peer-id", peer_id)
-            .item("peer-ip", peer_ip)
-            .item("client_version", client_version)
-            .item("enode_url", enode_url)
-            .item("port", port)
-            .item("chain", chain)
-            .item("country", country)
-            .item("city", city)
-            .item("capabilities", capabilities)
-            .item("eth_version", eth_version)
-            .item("last_seen", last_seen)
-            .item("source_region", region_source)
-            .item("genesis_block_hash", genesis_hash)
-            .item("best_block", best_block)
-            .item("total_difficulty", total_difficulty)
-            .item("synced", synced)
-            .item("isp", isp)
+            .key("peer-id", peer_id)
+            .expression_attribute_names("#first_seen", "first_seen")
+            .expression_attribute_values(":first_seen", first_seen)
+            .expression_attribute_names("#seen_count", "seen_count")
+            .expression_attribute_names("#score", "score")
+            .expression_attribute_values(":score", score);
+        let mut assignments =
+            vec!["#first_seen = if_not_exists(#first_seen, :first_seen)".to_string()];
+        let mut additions = vec!["#score :score"];
+        if discovered_only {
+            update =
+                update.expression_attribute_values(":zero", AttributeValue::N("0".to_string()));
+            assignments.push("#seen_count = if_not_exists(#seen_count, :zero)".to_string());
+        } else {
+            update = update.expression_attribute_values(":one", AttributeValue::N("1".to_string()));
+            additions.push("#seen_count :one");
+        }
+        for (i, (name, value)) in item.into_iter().enumerate() {
+            if discovered_only && !DISCOVERY_ATTRIBUTES.contains(&name.as_str()) {
+                assignments.push(format!("#a{i} = if_not_exists(#a{i}, :v{i})"));
+            } else {
+                assignments.push(format!("#a{i} = :v{i}"));
+            }
+            update = update
+                .expression_attribute_names(format!("#a{i}"), name)
+                .expression_attribute_values(format!(":v{i}"), value);
+        }
+        update
+            .update_expression(format!(
+                "SET {} ADD {}",
+                assignments.join(", "),
+                additions.join(", ")
+            ))
             .send()
-            .await
-        {
+            .await?;
+        Ok(())
+    }
+
+    async fn add_peers(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        let mut saved = 0;
+        let mut requests: Vec<WriteRequest> = peers
+            .into_iter()
+            .map(|peer_data| {
+                WriteRequest::builder()
+                    .put_request(
+                        PutRequest::builder()
+                            .set_item(Some(self.peer_item(peer_data)))
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+        while !requests.is_empty() {
+            let batch: Vec<_> = requests
+                .drain(..requests.len().min(DYNAMODB_BATCH_SIZE))
+                .collect();
+            let batch_len = batch.len();
+            let output = self
+                .client
+                .batch_write_item()
+                .request_items("eth-peer-data", batch)
+                .send()
+                .await?;
+            // throttled items come back as unprocessed, and they're not retried here
+            let unprocessed = output
+                .unprocessed_items()
+                .and_then(|items| items.get("eth-peer-data"))
+                .map_or(0, |items| items.len());
+            saved += batch_len - unprocessed;
+        }
+        Ok(saved)
+    }
+
+    async fn upsert_peers(&self, peers: Vec<PeerData>) -> Result<Vec<PeerData>, AddItemError> {
+        let mut failed = vec![];
+        for batch in merge_sightings(peers).chunks(DYNAMODB_BATCH_SIZE) {
+            // `BatchWriteItem` can't update items, so the saved peers are read first and their counters and kept
+            // attributes are written back along with the rest of the peer, a concurrent writer could be overwritten
+            // in between
+            let keys = batch
+                .iter()
+                .map(|peer| {
+                    HashMap::from([("peer-id".to_string(), AttributeValue::S(peer.id.clone()))])
+                })
+                .collect();
+            let output = self
+                .client
+                .batch_get_item()
+                .request_items(
+                    "eth-peer-data",
+                    KeysAndAttributes::builder().set_keys(Some(keys)).build(),
+                )
+                .send()
+                .await?;
+            let saved: HashMap<String, PeerData> = output
+                .responses()
+                .and_then(|responses| responses.get("eth-peer-data"))
+                .map(|items| {
+                    items
+                        .iter()
+                        .map(|item| {
+                            let peer = PeerData::from(item);
+                            (peer.id.clone(), peer)
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            // throttled keys come back as unprocessed, those peers would look new and have their counters reset, so
+            // they're left for the caller to retry
+            let unread_ids: HashSet<&str> = output
+                .unprocessed_keys()
+                .and_then(|keys| keys.get("eth-peer-data"))
+                .and_then(|keys| keys.keys())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|key| key.get("peer-id"))
+                .filter_map(|id| id.as_s().ok())
+                .map(String::as_str)
+                .collect();
+            let (unread, batch): (Vec<_>, Vec<_>) = batch
+                .iter()
+                .cloned()
+                .partition(|peer| unread_ids.contains(peer.id.as_str()));
+            failed.extend(unread);
+            if batch.is_empty() {
+                continue;
+            }
+            let mut batch: HashMap<String, PeerData> = batch
+                .into_iter()
+                .map(|mut peer| {
+                    if let Some(saved) = saved.get(&peer.id) {
+                        peer.first_seen = saved.first_seen.clone();
+                        peer.seen_count += saved.seen_count;
+                        peer.score += saved.score;
+                        peer.keep_saved(saved);
+                    }
+                    (peer.id.clone(), peer)
+                })
+                .collect();
+            let requests = batch
+                .values()
+                .map(|peer_data| {
+                    WriteRequest::builder()
+                        .put_request(
+                            PutRequest::builder()
+                                .set_item(Some(self.peer_item(peer_data.clone())))
+                                .build(),
+                        )
+                        .build()
+                })
+                .collect();
+            let output = self
+                .client
+                .batch_write_item()
+                .request_items("eth-peer-data", requests)
+                .send()
+                .await?;
+            // throttled items come back as unprocessed, the caller retries them
+            let unprocessed = output
+                .unprocessed_items()
+                .and_then(|items| items.get("eth-peer-data"))
+                .cloned()
+                .unwrap_or_default();
+            for request in unprocessed {
+                let id = request
+                    .put_request()
+                    .and_then(|put| put.item())
+                    .and_then(|item| item.get("peer-id"))
+                    .and_then(|id| id.as_s().ok());
+                if let Some(peer_data) = id.and_then(|id| batch.remove(id)) {
+                    failed.push(peer_data);
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    async fn touch_peer(&self, id: String, last_seen: String) -> Result<(), AddItemError> {
+        self.client
+            .update_item()
+            .table_name("eth-peer-data")
+            .key("peer-id", AttributeValue::S(id))
+            // don't create a partial item if the peer was deleted in the meantime
+            .condition_expression("attribute_exists(last_seen)")
+            // a peer answering again is back online
+            .update_expression("SET last_seen = :last_seen, unreachable = :unreachable")
+            .expression_attribute_values(":last_seen", AttributeValue::S(last_seen))
+            .expression_attribute_values(":unreachable", AttributeValue::Bool(false))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_unreachable(&self, id: String) -> Result<(), AddItemError> {
+        self.client
+            .update_item()
+            .table_name("eth-peer-data")
+            .key("peer-id", AttributeValue::S(id))
+            .condition_expression("attribute_exists(last_seen)")
+            .update_expression("SET unreachable = :unreachable")
+            .expression_attribute_values(":unreachable", AttributeValue::Bool(true))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn record_failure(
+        &self,
+        id: String,
+        failure: String,
+        error: String,
+        failed_at: String,
+    ) -> Result<(), AddItemError> {
+        let result = self
+            .client
+            .update_item()
+            .table_name("eth-peer-data")
+            .key("peer-id", AttributeValue::S(id))
+            .condition_expression("attribute_exists(last_seen)")
+            .update_expression(
+                "SET last_failure = :last_failure, last_failure_at = :last_failure_at, last_handshake_error = :last_handshake_error ADD handshake_failures :one",
+            )
+            .expression_attribute_values(":last_failure", AttributeValue::S(failure))
+            .expression_attribute_values(":last_failure_at", AttributeValue::S(failed_at))
+            .expression_attribute_values(":last_handshake_error", AttributeValue::S(error))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            // most failing peers were never saved
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn record_disconnect(&self, id: String, reason: String) -> Result<(), AddItemError> {
+        let result = self
+            .client
+            .update_item()
+            .table_name("eth-peer-data")
+            .key("peer-id", AttributeValue::S(id))
+            .condition_expression("attribute_exists(last_seen)")
+            .update_expression("SET last_disconnect_reason = :last_disconnect_reason")
+            .expression_attribute_values(":last_disconnect_reason", AttributeValue::S(reason))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn adjust_score(&self, id: String, delta: i64) -> Result<(), AddItemError> {
+        let result = self
+            .client
+            .update_item()
+            .table_name("eth-peer-data")
+            .key("peer-id", AttributeValue::S(id))
+            .condition_expression("attribute_exists(last_seen)")
+            // `ADD` starts from 0 when the peer has no score yet
+            .update_expression("ADD score :delta")
+            .expression_attribute_values(":delta", AttributeValue::N(delta.to_string()))
+            .send()
+            .await;
+        match result {
             Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(())
+            }
             Err(e) => Err(e.into()),
         }
     }
 
+    async fn stale_peers(
+        &self,
+        last_seen: String,
+        limit: usize,
+    ) -> Result<Vec<PeerData>, ScanTableError> {
+        let results: Result<Vec<_>, _> = self
+            .client
+            .scan()
+            .table_name("eth-peer-data")
+            .filter_expression(
+                "last_seen < :last_seen_parameter AND (attribute_not_exists(unreachable) OR unreachable = :false)",
+            )
+            .expression_attribute_values(":last_seen_parameter", AttributeValue::S(last_seen))
+            .expression_attribute_values(":false", AttributeValue::Bool(false))
+            .into_paginator()
+            .items()
+            .send()
+            .collect()
+            .await;
+        // scans aren't ordered, so all the stale peers are sorted here
+        let mut peers: Vec<PeerData> = results?.iter().map(|peer| peer.into()).collect();
+        peers.sort_by(|a, b| a.last_seen.cmp(&b.last_seen));
+        peers.truncate(limit);
+        Ok(peers)
+    }
+
+    async fn peers_seen_since(
+        &self,
+        last_seen: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        let scan = self
+            .client
+            .scan()
+            .table_name("eth-peer-data")
+            .filter_expression("last_seen >= :last_seen_parameter")
+            .expression_attribute_values(":last_seen_parameter", AttributeValue::S(last_seen));
+        capped_scan(scan, max_items, |_| true).await
+    }
+
     async fn all_peers(&self, page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
         let page_size = page_size.unwrap_or(1000);
         let results: Result<Vec<_>, _> = self
@@ -137,6 +779,79 @@ impl PeerDB for AwsPeerDB {
         }
     }
 
+    async fn scan_peers(&self, max_items: Option<usize>) -> Result<ScannedPeers, ScanTableError> {
+        let scan = self.client.scan().table_name("eth-peer-data");
+        capped_scan(scan, max_items, |_| true).await
+    }
+
+    async fn all_peers_cursor(
+        &self,
+        limit: usize,
+        cursor: Option<String>,
+    ) -> Result<(Vec<PeerData>, Option<String>), ScanTableError> {
+        let start_key = cursor
+            .map(|cursor| decode_cursor(&cursor))
+            .transpose()?
+            .map(|last_id| HashMap::from([("peer-id".to_string(), AttributeValue::S(last_id))]));
+        // DynamoDB rejects a zero limit
+        if limit == 0 {
+            return Ok((vec![], None));
+        }
+        let results = self
+            .client
+            .scan()
+            .table_name("eth-peer-data")
+            .limit(limit.min(i32::MAX as usize) as i32)
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        let peers = results
+            .items()
+            .unwrap_or_default()
+            .iter()
+            .map(PeerData::from)
+            .collect();
+        // a scan walks the table in the order of its key hashes, which the last evaluated key resumes from
+        let next_cursor = results
+            .last_evaluated_key()
+            .and_then(|key| as_option_string(key.get("peer-id")))
+            .map(|last_id| encode_cursor(&last_id));
+        Ok((peers, next_cursor))
+    }
+
+    async fn query_peers(
+        &self,
+        filter: PeerFilter,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        let mut scan = self.client.scan().table_name("eth-peer-data");
+        // DynamoDB compares strings case sensitively, so the client and country are only filtered here
+        let mut conditions = vec![];
+        if let Some(synced) = filter.synced {
+            conditions.push("synced = :synced");
+            scan = scan.expression_attribute_values(":synced", AttributeValue::Bool(synced));
+        }
+        if let Some(eth_version) = filter.eth_version {
+            conditions.push("eth_version = :eth_version");
+            scan = scan.expression_attribute_values(
+                ":eth_version",
+                AttributeValue::N(eth_version.to_string()),
+            );
+        }
+        if let Some(capability) = &filter.capability {
+            // the capabilities are advertised in lower case
+            conditions.push("contains(capabilities, :capability)");
+            scan = scan.expression_attribute_values(
+                ":capability",
+                AttributeValue::S(capability.to_lowercase()),
+            );
+        }
+        if !conditions.is_empty() {
+            scan = scan.filter_expression(conditions.join(" AND "));
+        }
+        capped_scan(scan, max_items, |peer| filter.matches(peer)).await
+    }
+
     async fn node_by_id(&self, id: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
         let results = self
             .client
@@ -175,19 +890,250 @@ impl PeerDB for AwsPeerDB {
             Ok(None)
         }
     }
+
+    async fn peers_by_city(
+        &self,
+        city: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        // DynamoDB can't compare strings case insensitively, so the table is filtered here
+        let scan = self.client.scan().table_name("eth-peer-data");
+        capped_scan(scan, max_items, |peer| same_city(&peer.city, &city)).await
+    }
+
+    async fn random_peers(
+        &self,
+        n: usize,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        // DynamoDB can't sample, so the scanned peers are sampled here
+        let scanned = self.scan_peers(max_items).await?;
+        Ok(scanned.sample(n))
+    }
+
+    async fn delete_peers_older_than(
+        &self,
+        last_seen: String,
+        limit: Option<usize>,
+        dry_run: bool,
+    ) -> Result<u64, DeleteItemError> {
+        let mut items = self
+            .client
+            .scan()
+            .table_name("eth-peer-data")
+            .filter_expression("last_seen < :last_seen_parameter")
+            .expression_attribute_values(":last_seen_parameter", AttributeValue::S(last_seen))
+            .projection_expression("#id")
+            .expression_attribute_names("#id", "peer-id")
+            .into_paginator()
+            .items()
+            .send();
+        let mut peer_ids: Vec<AttributeValue> = vec![];
+        while let Some(item) = items.next().await {
+            if let Some(peer_id) = item?.remove("peer-id") {
+                peer_ids.push(peer_id);
+            }
+            if limit.map_or(false, |limit| peer_ids.len() >= limit) {
+                break;
+            }
+        }
+
+        if dry_run {
+            return Ok(peer_ids.len() as u64);
+        }
+
+        let mut deleted = 0;
+        for chunk in peer_ids.chunks(DYNAMODB_BATCH_SIZE) {
+            let requests = chunk
+                .iter()
+                .map(|peer_id| {
+                    WriteRequest::builder()
+                        .delete_request(
+                            DeleteRequest::builder()
+                                .key("peer-id", peer_id.clone())
+                                .build(),
+                        )
+                        .build()
+                })
+                .collect();
+            let output = self
+                .client
+                .batch_write_item()
+                .request_items("eth-peer-data", requests)
+                .send()
+                .await?;
+            // throttled deletes come back as unprocessed, those peers are left for the next prune
+            let unprocessed = output
+                .unprocessed_items()
+                .and_then(|items| items.get("eth-peer-data"))
+                .map_or(0, |items| items.len());
+            deleted += (chunk.len() - unprocessed) as u64;
+        }
+        Ok(deleted)
+    }
+
+    async fn append_history(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        let mut appended = 0;
+        for chunk in peers.chunks(25) {
+            let requests = chunk
+                .iter()
+                .map(|peer_data| {
+                    let mut item = self.peer_item(peer_data.snapshot());
+                    item.insert(
+                        "seen_at".to_string(),
+                        AttributeValue::S(peer_data.last_seen.clone()),
+                    );
+                    WriteRequest::builder()
+                        .put_request(PutRequest::builder().set_item(Some(item)).build())
+                        .build()
+                })
+                .collect();
+            let output = self
+                .client
+                .batch_write_item()
+                .request_items("eth-peer-history", requests)
+                .send()
+                .await?;
+            // the history is best effort, throttled snapshots are dropped rather than retried
+            let unprocessed = output
+                .unprocessed_items()
+                .and_then(|items| items.get("eth-peer-history"))
+                .map_or(0, |items| items.len());
+            appended += chunk.len() - unprocessed;
+        }
+        Ok(appended)
+    }
+
+    async fn peer_history(&self, id: String) -> Result<Vec<PeerData>, QueryItemError> {
+        // the sort key of the history is `seen_at`, so the query returns the snapshots in order
+        let results: Result<Vec<_>, _> = self
+            .client
+            .query()
+            .table_name("eth-peer-history")
+            .key_condition_expression("#id = :id")
+            .expression_attribute_names("#id", "peer-id")
+            .expression_attribute_values(":id", AttributeValue::S(id))
+            .into_paginator()
+            .items()
+            .send()
+            .collect()
+            .await;
+        Ok(results?.iter().map(|peer| peer.into()).collect())
+    }
+
+    async fn set_latest_block(&self, head: ChainHead) -> Result<(), AddItemError> {
+        self.client
+            .put_item()
+            .table_name("eth-crawler-state")
+            .item("state-id", AttributeValue::S(LATEST_BLOCK_KEY.to_string()))
+            .item("block_number", AttributeValue::N(head.number.to_string()))
+            .item("block_hash", AttributeValue::S(head.hash))
+            .item("updated_at", AttributeValue::S(head.updated_at))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn latest_block(&self) -> Result<Option<ChainHead>, QueryItemError> {
+        let result = self
+            .client
+            .get_item()
+            .table_name("eth-crawler-state")
+            .key("state-id", AttributeValue::S(LATEST_BLOCK_KEY.to_string()))
+            .send()
+            .await?;
+        // the hash and time are missing from the items saved before they were added
+        Ok(result.item().and_then(|item| {
+            Some(ChainHead {
+                number: as_option_u64(item.get("block_number"))?,
+                hash: as_option_string(item.get("block_hash")).unwrap_or_default(),
+                updated_at: as_option_string(item.get("updated_at")).unwrap_or_default(),
+            })
+        }))
+    }
+
+    async fn summary(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<(NetworkSummary, bool), ScanTableError> {
+        let scanned = self.scan_peers(max_items).await?;
+        let latest_block = self.latest_block().await?.map(|head| head.number);
+        Ok((
+            NetworkSummary::from_peers(&scanned.peers, latest_block),
+            scanned.truncated,
+        ))
+    }
+}
+
+/// Read up to `max_items` peers with `scan`, all of them without a cap, keeping those `keep` accepts and telling
+/// whether the table held more.
+async fn capped_scan(
+    scan: ScanFluentBuilder,
+    max_items: Option<usize>,
+    keep: impl Fn(&PeerData) -> bool,
+) -> Result<ScannedPeers, ScanTableError> {
+    // one more peer than the cap tells whether the scan is truncated
+    let read_items = max_items.map(|max_items| max_items.saturating_add(1));
+    // the pages don't read much past the cap, each read item is paid for
+    let page_size = read_items.map_or(1000, |read_items| read_items.min(1000)) as i32;
+    let mut items = scan.limit(page_size).into_paginator().items().send();
+    let mut read = 0;
+    let mut peers = vec![];
+    while let Some(item) = items.next().await {
+        let item = item?;
+        if max_items.map_or(false, |max_items| read >= max_items) {
+            return Ok(ScannedPeers {
+                peers,
+                truncated: true,
+            });
+        }
+        read += 1;
+        let peer = PeerData::from(&item);
+        if keep(&peer) {
+            peers.push(peer);
+        }
+    }
+    Ok(ScannedPeers {
+        peers,
+        truncated: false,
+    })
 }
 
 #[derive(Clone)]
 pub struct InMemoryPeerDB {
     db: Arc<RwLock<HashMap<String, PeerData>>>,
+    latest_block: Arc<RwLock<Option<ChainHead>>>,
+    history: Arc<RwLock<HashMap<String, Vec<PeerData>>>>,
 }
 #[allow(clippy::new_without_default)]
 impl InMemoryPeerDB {
     pub fn new() -> Self {
         Self {
             db: Arc::new(RwLock::new(HashMap::new())),
+            latest_block: Arc::new(RwLock::new(None)),
+            history: Arc::new(RwLock::new(HashMap::new())),
         }
     }
+
+    /// Up to `max_items` of the peers `keep` accepts, all of them without a cap, telling whether there were more.
+    fn scan_where(
+        &self,
+        max_items: Option<usize>,
+        keep: impl Fn(&PeerData) -> bool,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        let db = self
+            .db
+            .read()
+            .map_err(|_| ScanTableError::InMemoryDbScanError())?;
+        // one more peer than the cap tells whether the scan is truncated
+        let peers = db
+            .values()
+            .filter(|peer_data| keep(peer_data))
+            .take(max_items.map_or(usize::MAX, |max_items| max_items.saturating_add(1)))
+            .cloned()
+            .collect();
+        Ok(ScannedPeers::capped(peers, max_items))
+    }
 }
 
 #[async_trait]
@@ -197,13 +1143,136 @@ impl PeerDB for InMemoryPeerDB {
             .db
             .write()
             .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        let mut peer_data = peer_data;
+        // a peer only known from discovery wasn't crawled
+        let crawled = u64::from(peer_data.handshake_completed != Some(false));
+        match db.get(&peer_data.id) {
+            Some(saved) => {
+                peer_data.first_seen = saved.first_seen.clone();
+                peer_data.seen_count = saved.seen_count + crawled;
+                peer_data.score += saved.score;
+                peer_data.keep_saved(saved);
+            }
+            None => peer_data.seen_count = crawled,
+        }
         db.insert(peer_data.id.clone(), peer_data);
         Ok(())
     }
 
-    async fn all_peers(&self, page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
-        let page_size = page_size.unwrap_or(50);
-        let db = self
+    async fn add_peers(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        let saved = peers.len();
+        for peer_data in peers {
+            db.insert(peer_data.id.clone(), peer_data);
+        }
+        Ok(saved)
+    }
+
+    async fn upsert_peers(&self, peers: Vec<PeerData>) -> Result<Vec<PeerData>, AddItemError> {
+        for peer_data in peers {
+            self.add_peer(peer_data).await?;
+        }
+        Ok(vec![])
+    }
+
+    async fn touch_peer(&self, id: String, last_seen: String) -> Result<(), AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        if let Some(peer_data) = db.get_mut(&id) {
+            peer_data.last_seen = last_seen;
+            peer_data.unreachable = false;
+        }
+        Ok(())
+    }
+
+    async fn mark_unreachable(&self, id: String) -> Result<(), AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        if let Some(peer_data) = db.get_mut(&id) {
+            peer_data.unreachable = true;
+        }
+        Ok(())
+    }
+
+    async fn record_failure(
+        &self,
+        id: String,
+        failure: String,
+        error: String,
+        failed_at: String,
+    ) -> Result<(), AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        if let Some(peer_data) = db.get_mut(&id) {
+            peer_data.last_failure = Some(failure);
+            peer_data.last_failure_at = Some(failed_at);
+            peer_data.last_handshake_error = Some(error);
+            peer_data.handshake_failures += 1;
+        }
+        Ok(())
+    }
+
+    async fn record_disconnect(&self, id: String, reason: String) -> Result<(), AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        if let Some(peer_data) = db.get_mut(&id) {
+            peer_data.last_disconnect_reason = Some(reason);
+        }
+        Ok(())
+    }
+
+    async fn adjust_score(&self, id: String, delta: i64) -> Result<(), AddItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        if let Some(peer_data) = db.get_mut(&id) {
+            peer_data.score += delta;
+        }
+        Ok(())
+    }
+
+    async fn stale_peers(
+        &self,
+        last_seen: String,
+        limit: usize,
+    ) -> Result<Vec<PeerData>, ScanTableError> {
+        let db = self
+            .db
+            .read()
+            .map_err(|_| ScanTableError::InMemoryDbScanError())?;
+        let mut peers: Vec<PeerData> = db
+            .values()
+            .filter(|peer| !peer.unreachable && peer.last_seen < last_seen)
+            .cloned()
+            .collect();
+        peers.sort_by(|a, b| a.last_seen.cmp(&b.last_seen));
+        peers.truncate(limit);
+        Ok(peers)
+    }
+
+    async fn peers_seen_since(
+        &self,
+        last_seen: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_where(max_items, |peer| peer.last_seen >= last_seen)
+    }
+
+    async fn all_peers(&self, page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
+        let page_size = page_size.unwrap_or(50);
+        let db = self
             .db
             .read()
             .map_err(|_| ScanTableError::InMemoryDbScanError())?;
@@ -214,6 +1283,46 @@ impl PeerDB for InMemoryPeerDB {
             .collect())
     }
 
+    async fn scan_peers(&self, max_items: Option<usize>) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_where(max_items, |_| true)
+    }
+
+    async fn all_peers_cursor(
+        &self,
+        limit: usize,
+        cursor: Option<String>,
+    ) -> Result<(Vec<PeerData>, Option<String>), ScanTableError> {
+        let last_id = cursor.map(|cursor| decode_cursor(&cursor)).transpose()?;
+        if limit == 0 {
+            return Ok((vec![], None));
+        }
+        let db = self
+            .db
+            .read()
+            .map_err(|_| ScanTableError::InMemoryDbScanError())?;
+        let mut peers: Vec<PeerData> = db
+            .values()
+            .filter(|peer_data| {
+                last_id
+                    .as_ref()
+                    .map_or(true, |last_id| peer_data.id > *last_id)
+            })
+            .cloned()
+            .collect();
+        peers.sort_by(|a, b| a.id.cmp(&b.id));
+        let next_cursor = (peers.len() > limit).then(|| encode_cursor(&peers[limit - 1].id));
+        peers.truncate(limit);
+        Ok((peers, next_cursor))
+    }
+
+    async fn query_peers(
+        &self,
+        filter: PeerFilter,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_where(max_items, |peer_data| filter.matches(peer_data))
+    }
+
     async fn node_by_id(&self, id: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
         let db = self
             .db
@@ -239,20 +1348,160 @@ impl PeerDB for InMemoryPeerDB {
                 .collect(),
         ))
     }
+
+    async fn peers_by_city(
+        &self,
+        city: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_where(max_items, |peer_data| same_city(&peer_data.city, &city))
+    }
+
+    async fn random_peers(
+        &self,
+        n: usize,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        Ok(self.scan_peers(max_items).await?.sample(n))
+    }
+
+    async fn delete_peers_older_than(
+        &self,
+        last_seen: String,
+        limit: Option<usize>,
+        dry_run: bool,
+    ) -> Result<u64, DeleteItemError> {
+        let mut db = self
+            .db
+            .write()
+            .map_err(|_| DeleteItemError::InMemoryDbDeleteItemError())?;
+        let stale: Vec<String> = db
+            .iter()
+            .filter(|(_, peer_data)| peer_data.last_seen < last_seen)
+            .map(|(id, _)| id.clone())
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        if !dry_run {
+            for id in &stale {
+                db.remove(id);
+            }
+        }
+        Ok(stale.len() as u64)
+    }
+
+    async fn set_latest_block(&self, head: ChainHead) -> Result<(), AddItemError> {
+        let mut latest_block = self
+            .latest_block
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        *latest_block = Some(head);
+        Ok(())
+    }
+
+    async fn latest_block(&self) -> Result<Option<ChainHead>, QueryItemError> {
+        let latest_block = self
+            .latest_block
+            .read()
+            .map_err(|_| QueryItemError::InMemoryDbQueryItemError())?;
+        Ok(latest_block.clone())
+    }
+
+    async fn append_history(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        let mut history = self
+            .history
+            .write()
+            .map_err(|_| AddItemError::InMemoryDbAddItemError())?;
+        for peer_data in &peers {
+            let snapshots = history.entry(peer_data.id.clone()).or_default();
+            // a snapshot replaces the one of the same peer seen at the same time, like the other dbs do with their key
+            snapshots.retain(|snapshot| snapshot.last_seen != peer_data.last_seen);
+            snapshots.push(peer_data.snapshot());
+            snapshots.sort_by(|a, b| a.last_seen.cmp(&b.last_seen));
+        }
+        Ok(peers.len())
+    }
+
+    async fn peer_history(&self, id: String) -> Result<Vec<PeerData>, QueryItemError> {
+        let history = self
+            .history
+            .read()
+            .map_err(|_| QueryItemError::InMemoryDbQueryItemError())?;
+        Ok(history.get(&id).cloned().unwrap_or_default())
+    }
+
+    async fn summary(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<(NetworkSummary, bool), ScanTableError> {
+        let latest_block = self.latest_block().await?.map(|head| head.number);
+        let scanned = self.scan_peers(max_items).await?;
+        Ok((
+            NetworkSummary::from_peers(&scanned.peers, latest_block),
+            scanned.truncated,
+        ))
+    }
 }
 
+/// Columns added to `eth_peer_data` after its first version, as `(name, type)`.
+///
+/// They're added with `ALTER TABLE` when opening the database, so existing databases get them too.
+const SQL_MIGRATIONS: &[(&str, &str)] = &[
+    ("udp_port", "INTEGER"),
+    ("first_seen", "TEXT"),
+    ("seen_count", "INTEGER"),
+    ("unreachable", "BOOLEAN"),
+    ("latency_ms", "INTEGER"),
+    ("fork_hash", "TEXT"),
+    ("fork_next", "INTEGER"),
+    ("latitude", "REAL"),
+    ("longitude", "REAL"),
+    ("last_failure", "TEXT"),
+    ("p2p_version", "INTEGER"),
+    ("asn", "INTEGER"),
+    ("as_org", "TEXT"),
+    ("geo_provider", "TEXT"),
+    ("port_reachable", "BOOLEAN"),
+    ("discovery_source", "TEXT"),
+    ("last_failure_at", "TEXT"),
+    // sqlite integers are 64 bits, so the total difficulty is only approximated, for range queries
+    ("total_difficulty_num", "REAL"),
+    ("last_disconnect_reason", "TEXT"),
+    ("on_canonical", "BOOLEAN"),
+    ("sync_lag_blocks", "INTEGER"),
+    ("sync_lag_seconds", "INTEGER"),
+    ("head_block_number", "INTEGER"),
+    ("crawler_head_number", "INTEGER"),
+    ("dns_tree", "TEXT"),
+    ("handshake_completed", "BOOLEAN"),
+    ("score", "INTEGER"),
+    ("last_handshake_error", "TEXT"),
+    ("handshake_failures", "INTEGER"),
+    // comma separated, like the capabilities
+    ("advertised_eth_versions", "TEXT"),
+    // a JSON object
+    ("enr", "TEXT"),
+    ("snap_supported", "BOOLEAN"),
+    ("les_supported", "BOOLEAN"),
+    ("off_network", "BOOLEAN"),
+    // `city` as `fold_city` folds it, sqlite's own case folding only covers ascii letters
+    ("city_folded", "TEXT"),
+];
+
 pub struct SqlPeerDB {
     db: Connection,
 }
 
 impl SqlPeerDB {
     pub async fn new() -> Self {
-        let db = Connection::open("peers_data.db").await.unwrap();
+        Self::open(Connection::open("peers_data.db").await.unwrap()).await
+    }
+
+    /// Create the tables missing from `db`.
+    async fn open(db: Connection) -> Self {
         // create `eth_peer_data` table if not exists
-        let _ = db
-            .call(|conn| {
-                conn.execute(
-                    "CREATE TABLE IF NOT EXISTS eth_peer_data (
+        db.call(|conn| {
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS eth_peer_data (
                 id TEXT PRIMARY KEY,
                 ip TEXT NOT NULL,
                 client_version TEXT NOT NULL,
@@ -270,40 +1519,389 @@ impl SqlPeerDB {
                 synced BOOLEAN,
                 isp TEXT
             );",
+                [],
+            )?;
+            // the latest block known by the crawler, in a single row
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS chain_head (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                number INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            );",
+                [],
+            )?;
+            // append-only snapshots of the crawled peers, as the JSON of their `PeerData`
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS peer_history (
+                id TEXT NOT NULL,
+                seen_at TEXT NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (id, seen_at)
+            );",
+                [],
+            )?;
+            for (column, column_type) in SQL_MIGRATIONS {
+                // sqlite has no `ADD COLUMN IF NOT EXISTS`, so this fails harmlessly if the column is already there
+                let _ = conn.execute(
+                    &format!("ALTER TABLE eth_peer_data ADD COLUMN {column} {column_type}"),
                     [],
-                )
+                );
+            }
+            // the rows written before `city_folded` was added
+            let unfolded: Vec<(String, String)> = conn
+                .prepare(
+                    "SELECT id, city FROM eth_peer_data WHERE city_folded IS NULL AND city IS NOT NULL",
+                )?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            for (id, city) in unfolded {
+                conn.execute(
+                    "UPDATE eth_peer_data SET city_folded = ?1 WHERE id = ?2",
+                    params![fold_city(&city), id],
+                )?;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap();
+        Self { db }
+    }
+
+    /// Query up to `max_items` of the peers matching `sql_where`, a `WHERE` clause with its `values`, all of them
+    /// without a cap, telling whether there were more.
+    async fn scan_peers_where(
+        &self,
+        sql_where: impl Into<String>,
+        mut values: Vec<Value>,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        // one more peer than the cap tells whether the scan is truncated, a negative limit means none in sqlite
+        values.push(Value::Integer(
+            max_items.map_or(-1, |max_items| max_items.saturating_add(1) as i64),
+        ));
+        let sql_where = format!("{} LIMIT ?{}", sql_where.into(), values.len());
+        let peers = self
+            .query_peers_where(sql_where, params_from_iter(values))
+            .await
+            .map_err(ScanTableError::SqlScanError)?;
+        Ok(ScannedPeers::capped(peers, max_items))
+    }
+
+    /// Query the peers matching `sql_where`, a `WHERE` clause with its parameters.
+    async fn query_peers_where<P>(
+        &self,
+        sql_where: impl Into<String>,
+        params: P,
+    ) -> Result<Vec<PeerData>, tokio_rusqlite::Error>
+    where
+        P: Params + Send + 'static,
+    {
+        let sql_where = sql_where.into();
+        self.db
+            .call(move |conn| {
+                let mut stmt = conn.prepare(&format!("SELECT * from eth_peer_data {sql_where}"))?;
+                let rows = stmt.query_map(params, peer_data_from_row)?;
+                let mut peers = vec![];
+                for peer_data in rows.flatten() {
+                    peers.push(peer_data);
+                }
+                Ok(peers)
             })
             .await
-            .unwrap();
-        Self { db }
     }
 }
 
+/// Build a [`PeerData`] from a row of `eth_peer_data`.
+fn peer_data_from_row(row: &Row<'_>) -> rusqlite::Result<PeerData> {
+    let tcp_port = row.get("port")?;
+    Ok(PeerData {
+        id: row.get("id")?,
+        address: row.get("ip")?,
+        client_version: row.get("client_version")?,
+        enode_url: row.get("enode_url")?,
+        tcp_port,
+        udp_port: row.get::<_, Option<u16>>("udp_port")?.unwrap_or(tcp_port),
+        chain: row.get("chain")?,
+        genesis_block_hash: row.get("genesis_hash")?,
+        best_block: row.get("best_block")?,
+        total_difficulty: row.get("total_difficulty")?,
+        country: row.get("country")?,
+        city: row.get("city")?,
+        last_seen: row.get("last_seen")?,
+        capabilities: row
+            .get::<_, String>("capabilities")?
+            .as_str()
+            .split(',')
+            .map(|s| s.to_string())
+            .collect(),
+        eth_version: row.get("eth_version")?,
+        synced: row.get("synced")?,
+        isp: row.get("isp")?,
+        // older rows were only seen once as far as we know
+        first_seen: row
+            .get::<_, Option<String>>("first_seen")?
+            .unwrap_or(row.get("last_seen")?),
+        seen_count: row.get::<_, Option<u64>>("seen_count")?.unwrap_or(1),
+        unreachable: row
+            .get::<_, Option<bool>>("unreachable")?
+            .unwrap_or_default(),
+        latency_ms: row.get("latency_ms")?,
+        fork_hash: row
+            .get::<_, Option<String>>("fork_hash")?
+            .unwrap_or_default(),
+        fork_next: row.get::<_, Option<u64>>("fork_next")?.unwrap_or_default(),
+        latitude: row.get("latitude")?,
+        longitude: row.get("longitude")?,
+        last_failure: row.get("last_failure")?,
+        p2p_version: row.get("p2p_version")?,
+        asn: row.get("asn")?,
+        as_org: row.get("as_org")?,
+        geo_provider: row.get("geo_provider")?,
+        port_reachable: row.get("port_reachable")?,
+        discovery_source: row.get("discovery_source")?,
+        last_failure_at: row.get("last_failure_at")?,
+        total_difficulty_num: row.get::<_, String>("total_difficulty")?.parse().ok(),
+        last_disconnect_reason: row.get("last_disconnect_reason")?,
+        on_canonical: row.get("on_canonical")?,
+        sync_lag_blocks: row.get("sync_lag_blocks")?,
+        sync_lag_seconds: row.get("sync_lag_seconds")?,
+        head_block_number: row.get("head_block_number")?,
+        crawler_head_number: row.get("crawler_head_number")?,
+        dns_tree: row.get("dns_tree")?,
+        handshake_completed: row.get("handshake_completed")?,
+        score: row.get::<_, Option<i64>>("score")?.unwrap_or_default(),
+        last_handshake_error: row.get("last_handshake_error")?,
+        handshake_failures: row
+            .get::<_, Option<u64>>("handshake_failures")?
+            .unwrap_or_default(),
+        advertised_eth_versions: row
+            .get::<_, Option<String>>("advertised_eth_versions")?
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|eth_version| eth_version.parse().ok())
+            .collect(),
+        enr: row
+            .get::<_, Option<String>>("enr")?
+            .and_then(|enr| serde_json::from_str(&enr).ok()),
+        snap_supported: row
+            .get::<_, Option<bool>>("snap_supported")?
+            .unwrap_or_default(),
+        les_supported: row
+            .get::<_, Option<bool>>("les_supported")?
+            .unwrap_or_default(),
+        off_network: row
+            .get::<_, Option<bool>>("off_network")?
+            .unwrap_or_default(),
+    })
+}
+
+/// Merge the sightings of the same peer, keeping its latest data and counting every sighting.
+///
+/// A DynamoDB batch can't contain the same key twice.
+fn merge_sightings(peers: Vec<PeerData>) -> Vec<PeerData> {
+    let mut merged: HashMap<String, PeerData> = HashMap::new();
+    for peer_data in peers {
+        match merged.entry(peer_data.id.clone()) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.get();
+                let mut peer_data = PeerData {
+                    first_seen: previous.first_seen.clone(),
+                    seen_count: previous.seen_count + peer_data.seen_count,
+                    score: previous.score + peer_data.score,
+                    ..peer_data
+                };
+                peer_data.keep_saved(previous);
+                entry.insert(peer_data);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(peer_data);
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+/// Join eth protocol versions with commas, as they're stored in sqlite.
+fn join_eth_versions(eth_versions: &[u8]) -> String {
+    eth_versions
+        .iter()
+        .map(|eth_version| eth_version.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Insert or replace a peer into `eth_peer_data`.
+fn insert_peer(conn: &rusqlite::Connection, peer_data: &PeerData) -> rusqlite::Result<usize> {
+    let advertised_eth_versions = join_eth_versions(&peer_data.advertised_eth_versions);
+    let enr = peer_data.enr.as_ref().map(|enr| enr.to_string());
+    conn.execute(
+        "INSERT OR REPLACE INTO eth_peer_data (id, ip, client_version, enode_url, port, chain, genesis_hash, best_block, total_difficulty, country, city, last_seen, capabilities, eth_version, synced, isp, udp_port, first_seen, seen_count, unreachable, latency_ms, fork_hash, fork_next, latitude, longitude, last_failure, p2p_version, asn, as_org, geo_provider, port_reachable, discovery_source, last_failure_at, total_difficulty_num, last_disconnect_reason, on_canonical, sync_lag_blocks, sync_lag_seconds, head_block_number, crawler_head_number, dns_tree, handshake_completed, score, last_handshake_error, handshake_failures, advertised_eth_versions, enr, snap_supported, les_supported, off_network, city_folded) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40, ?41, ?42, ?43, ?44, ?45, ?46, ?47, ?48, ?49, ?50, ?51)",
+        params![
+            &peer_data.id,
+            &peer_data.address,
+            &peer_data.client_version,
+            &peer_data.enode_url,
+            &peer_data.tcp_port,
+            &peer_data.chain,
+            &peer_data.genesis_block_hash,
+            &peer_data.best_block,
+            &peer_data.total_difficulty,
+            &peer_data.country,
+            &peer_data.city,
+            &peer_data.last_seen,
+            &peer_data.capabilities.join(","),
+            &peer_data.eth_version,
+            &peer_data.synced,
+            &peer_data.isp,
+            &peer_data.udp_port,
+            &peer_data.first_seen,
+            &peer_data.seen_count,
+            &peer_data.unreachable,
+            &peer_data.latency_ms,
+            &peer_data.fork_hash,
+            &peer_data.fork_next,
+            &peer_data.latitude,
+            &peer_data.longitude,
+            &peer_data.last_failure,
+            &peer_data.p2p_version,
+            &peer_data.asn,
+            &peer_data.as_org,
+            &peer_data.geo_provider,
+            &peer_data.port_reachable,
+            &peer_data.discovery_source,
+            &peer_data.last_failure_at,
+            &peer_data.total_difficulty_num.map(|td| td as f64),
+            &peer_data.last_disconnect_reason,
+            &peer_data.on_canonical,
+            &peer_data.sync_lag_blocks,
+            &peer_data.sync_lag_seconds,
+            &peer_data.head_block_number,
+            &peer_data.crawler_head_number,
+            &peer_data.dns_tree,
+            &peer_data.handshake_completed,
+            &peer_data.score,
+            &peer_data.last_handshake_error,
+            &peer_data.handshake_failures,
+            &advertised_eth_versions,
+            &enr,
+            &peer_data.snap_supported,
+            &peer_data.les_supported,
+            &peer_data.off_network,
+            fold_city(&peer_data.city),
+        ],
+    )
+}
+
+/// Upsert a peer into `eth_peer_data`, incrementing its `seen_count` and keeping its `first_seen`, and what
+/// [`PeerData::keep_saved`] keeps.
+fn upsert_peer(conn: &rusqlite::Connection, peer_data: &PeerData) -> rusqlite::Result<usize> {
+    let advertised_eth_versions = join_eth_versions(&peer_data.advertised_eth_versions);
+    let enr = peer_data.enr.as_ref().map(|enr| enr.to_string());
+    conn.execute(
+        "INSERT INTO eth_peer_data (id, ip, client_version, enode_url, port, chain, genesis_hash, best_block, total_difficulty, country, city, last_seen, capabilities, eth_version, synced, isp, udp_port, first_seen, seen_count, unreachable, latency_ms, fork_hash, fork_next, latitude, longitude, last_failure, p2p_version, asn, as_org, geo_provider, port_reachable, discovery_source, last_failure_at, total_difficulty_num, last_disconnect_reason, on_canonical, sync_lag_blocks, sync_lag_seconds, head_block_number, crawler_head_number, dns_tree, handshake_completed, score, last_handshake_error, handshake_failures, advertised_eth_versions, enr, snap_supported, les_supported, off_network, city_folded) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?12, CASE WHEN ?39 = 0 THEN 0 ELSE 1 END, 0, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33, ?34, ?35, ?36, ?37, ?38, ?39, ?40, ?41, ?42, ?43, ?44, ?45, ?46, ?47, ?48)
+        ON CONFLICT(id) DO UPDATE SET ip = excluded.ip, client_version = CASE WHEN excluded.handshake_completed = 0 THEN client_version ELSE excluded.client_version END, enode_url = excluded.enode_url, port = excluded.port, chain = CASE WHEN excluded.handshake_completed = 0 THEN chain ELSE excluded.chain END, genesis_hash = CASE WHEN excluded.handshake_completed = 0 THEN genesis_hash ELSE excluded.genesis_hash END, best_block = CASE WHEN excluded.handshake_completed = 0 THEN best_block ELSE excluded.best_block END, total_difficulty = CASE WHEN excluded.handshake_completed = 0 THEN total_difficulty ELSE excluded.total_difficulty END, country = CASE WHEN excluded.handshake_completed = 0 THEN country ELSE excluded.country END, city = CASE WHEN excluded.handshake_completed = 0 THEN city ELSE excluded.city END, last_seen = excluded.last_seen, capabilities = CASE WHEN excluded.handshake_completed = 0 THEN capabilities ELSE excluded.capabilities END, eth_version = CASE WHEN excluded.handshake_completed = 0 THEN eth_version ELSE excluded.eth_version END, synced = CASE WHEN excluded.handshake_completed = 0 THEN synced ELSE excluded.synced END, isp = CASE WHEN excluded.handshake_completed = 0 THEN isp ELSE excluded.isp END, udp_port = excluded.udp_port, first_seen = COALESCE(first_seen, excluded.first_seen), seen_count = CASE WHEN excluded.handshake_completed = 0 THEN seen_count ELSE COALESCE(seen_count, 0) + 1 END, unreachable = CASE WHEN excluded.handshake_completed = 0 THEN unreachable ELSE 0 END, latency_ms = CASE WHEN excluded.handshake_completed = 0 THEN latency_ms ELSE excluded.latency_ms END, fork_hash = CASE WHEN excluded.handshake_completed = 0 THEN fork_hash ELSE excluded.fork_hash END, fork_next = CASE WHEN excluded.handshake_completed = 0 THEN fork_next ELSE excluded.fork_next END, latitude = CASE WHEN excluded.handshake_completed = 0 THEN latitude ELSE excluded.latitude END, longitude = CASE WHEN excluded.handshake_completed = 0 THEN longitude ELSE excluded.longitude END, last_failure = CASE WHEN excluded.handshake_completed = 0 THEN last_failure ELSE excluded.last_failure END, p2p_version = CASE WHEN excluded.handshake_completed = 0 THEN p2p_version ELSE excluded.p2p_version END, asn = CASE WHEN excluded.handshake_completed = 0 THEN asn ELSE excluded.asn END, as_org = CASE WHEN excluded.handshake_completed = 0 THEN as_org ELSE excluded.as_org END, geo_provider = CASE WHEN excluded.handshake_completed = 0 THEN geo_provider ELSE excluded.geo_provider END, port_reachable = CASE WHEN excluded.handshake_completed = 0 THEN port_reachable ELSE excluded.port_reachable END, discovery_source = excluded.discovery_source, last_failure_at = CASE WHEN excluded.handshake_completed = 0 THEN last_failure_at ELSE excluded.last_failure_at END, total_difficulty_num = CASE WHEN excluded.handshake_completed = 0 THEN total_difficulty_num ELSE excluded.total_difficulty_num END, last_disconnect_reason = COALESCE(excluded.last_disconnect_reason, last_disconnect_reason), on_canonical = CASE WHEN excluded.handshake_completed = 0 THEN on_canonical ELSE excluded.on_canonical END, sync_lag_blocks = CASE WHEN excluded.handshake_completed = 0 THEN sync_lag_blocks ELSE excluded.sync_lag_blocks END, sync_lag_seconds = CASE WHEN excluded.handshake_completed = 0 THEN sync_lag_seconds ELSE excluded.sync_lag_seconds END, head_block_number = CASE WHEN excluded.handshake_completed = 0 THEN head_block_number ELSE excluded.head_block_number END, crawler_head_number = CASE WHEN excluded.handshake_completed = 0 THEN crawler_head_number ELSE excluded.crawler_head_number END, dns_tree = excluded.dns_tree, handshake_completed = CASE WHEN excluded.handshake_completed = 0 THEN handshake_completed ELSE excluded.handshake_completed END, score = COALESCE(score, 0) + excluded.score, last_handshake_error = CASE WHEN excluded.handshake_completed = 0 THEN last_handshake_error ELSE excluded.last_handshake_error END, handshake_failures = CASE WHEN excluded.handshake_completed = 0 THEN handshake_failures ELSE excluded.handshake_failures END, advertised_eth_versions = CASE WHEN excluded.handshake_completed = 0 THEN advertised_eth_versions ELSE excluded.advertised_eth_versions END, enr = COALESCE(excluded.enr, enr), snap_supported = CASE WHEN excluded.handshake_completed = 0 THEN snap_supported ELSE excluded.snap_supported END, les_supported = CASE WHEN excluded.handshake_completed = 0 THEN les_supported ELSE excluded.les_supported END, off_network = excluded.off_network, city_folded = CASE WHEN excluded.handshake_completed = 0 THEN city_folded ELSE excluded.city_folded END",
+        params![
+            &peer_data.id,
+            &peer_data.address,
+            &peer_data.client_version,
+            &peer_data.enode_url,
+            &peer_data.tcp_port,
+            &peer_data.chain,
+            &peer_data.genesis_block_hash,
+            &peer_data.best_block,
+            &peer_data.total_difficulty,
+            &peer_data.country,
+            &peer_data.city,
+            &peer_data.last_seen,
+            &peer_data.capabilities.join(","),
+            &peer_data.eth_version,
+            &peer_data.synced,
+            &peer_data.isp,
+            &peer_data.udp_port,
+            &peer_data.latency_ms,
+            &peer_data.fork_hash,
+            &peer_data.fork_next,
+            &peer_data.latitude,
+            &peer_data.longitude,
+            &peer_data.last_failure,
+            &peer_data.p2p_version,
+            &peer_data.asn,
+            &peer_data.as_org,
+            &peer_data.geo_provider,
+            &peer_data.port_reachable,
+            &peer_data.discovery_source,
+            &peer_data.last_failure_at,
+            &peer_data.total_difficulty_num.map(|td| td as f64),
+            &peer_data.last_disconnect_reason,
+            &peer_data.on_canonical,
+            &peer_data.sync_lag_blocks,
+            &peer_data.sync_lag_seconds,
+            &peer_data.head_block_number,
+            &peer_data.crawler_head_number,
+            &peer_data.dns_tree,
+            &peer_data.handshake_completed,
+            &peer_data.score,
+            &peer_data.last_handshake_error,
+            &peer_data.handshake_failures,
+            &advertised_eth_versions,
+            &enr,
+            &peer_data.snap_supported,
+            &peer_data.les_supported,
+            &peer_data.off_network,
+            fold_city(&peer_data.city),
+        ],
+    )
+}
+
 #[async_trait]
 impl PeerDB for SqlPeerDB {
     async fn add_peer(&self, peer_data: PeerData) -> Result<(), AddItemError> {
+        self.db
+            .call(move |conn| upsert_peer(conn, &peer_data))
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
+
+    async fn add_peers(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        let saved = self
+            .db
+            .call(move |conn| {
+                // a single transaction avoids syncing the db file for every peer
+                let tx = conn.transaction()?;
+                for peer_data in &peers {
+                    insert_peer(&tx, peer_data)?;
+                }
+                tx.commit()?;
+                Ok(peers.len())
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(saved)
+    }
+
+    async fn upsert_peers(&self, peers: Vec<PeerData>) -> Result<Vec<PeerData>, AddItemError> {
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for peer_data in &peers {
+                    upsert_peer(&tx, peer_data)?;
+                }
+                tx.commit()
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        // the transaction saves all the peers or none
+        Ok(vec![])
+    }
+
+    async fn touch_peer(&self, id: String, last_seen: String) -> Result<(), AddItemError> {
         self.db
             .call(move |conn| {
                 conn.execute(
-                    "INSERT OR REPLACE INTO eth_peer_data (id, ip, client_version, enode_url, port, chain, genesis_hash, best_block, total_difficulty, country, city, last_seen, capabilities, eth_version, synced, isp) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
-                    (
-                        &peer_data.id,
-                        &peer_data.address,
-                        &peer_data.client_version,
-                        &peer_data.enode_url,
-                        &peer_data.tcp_port,
-                        &peer_data.chain,
-                        &peer_data.genesis_block_hash,
-                        &peer_data.best_block,
-                        &peer_data.total_difficulty,
-                        &peer_data.country,
-                        &peer_data.city,
-                        &peer_data.last_seen,
-                        &peer_data.capabilities.join(","),
-                        &peer_data.eth_version,
-                        &peer_data.synced,
-                        &peer_data.isp,
-                    ),
+                    "UPDATE eth_peer_data SET last_seen = ?1, unreachable = 0 WHERE id = ?2",
+                    params![last_seen, id],
                 )
             })
             .await
@@ -311,84 +1909,173 @@ impl PeerDB for SqlPeerDB {
         Ok(())
     }
 
-    async fn all_peers(&self, _page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
-        let peers = self
-            .db
+    async fn mark_unreachable(&self, id: String) -> Result<(), AddItemError> {
+        self.db
             .call(move |conn| {
-                let mut stmt = conn.prepare("SELECT * from eth_peer_data")?;
-                let rows = stmt.query_map([], |row| {
-                    Ok(PeerData {
-                        id: row.get(0)?,
-                        address: row.get(1)?,
-                        client_version: row.get(2)?,
-                        enode_url: row.get(3)?,
-                        tcp_port: row.get(4)?,
-                        chain: row.get(5)?,
-                        genesis_block_hash: row.get(6)?,
-                        best_block: row.get(7)?,
-                        total_difficulty: row.get(8)?,
-                        country: row.get(9)?,
-                        city: row.get(10)?,
-                        last_seen: row.get(11)?,
-                        capabilities: row
-                            .get::<_, String>(12)?
-                            .as_str()
-                            .split(',')
-                            .map(|s| s.to_string())
-                            .collect(),
-                        eth_version: row.get(13)?,
-                        synced: row.get(14)?,
-                        isp: row.get(15)?,
-                    })
-                })?;
-                let mut peers = vec![];
-                for peer_data in rows.flatten() {
-                    peers.push(peer_data);
-                }
-                Ok(peers)
+                conn.execute(
+                    "UPDATE eth_peer_data SET unreachable = 1 WHERE id = ?1",
+                    params![id],
+                )
             })
             .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
+
+    async fn record_failure(
+        &self,
+        id: String,
+        failure: String,
+        error: String,
+        failed_at: String,
+    ) -> Result<(), AddItemError> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE eth_peer_data SET last_failure = ?2, last_handshake_error = ?3, last_failure_at = ?4, handshake_failures = COALESCE(handshake_failures, 0) + 1 WHERE id = ?1",
+                    params![id, failure, error, failed_at],
+                )
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
+
+    async fn record_disconnect(&self, id: String, reason: String) -> Result<(), AddItemError> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE eth_peer_data SET last_disconnect_reason = ?2 WHERE id = ?1",
+                    params![id, reason],
+                )
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
+
+    async fn adjust_score(&self, id: String, delta: i64) -> Result<(), AddItemError> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE eth_peer_data SET score = COALESCE(score, 0) + ?2 WHERE id = ?1",
+                    params![id, delta],
+                )
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
+
+    async fn stale_peers(
+        &self,
+        last_seen: String,
+        limit: usize,
+    ) -> Result<Vec<PeerData>, ScanTableError> {
+        self.query_peers_where(
+            "WHERE last_seen < ?1 AND NOT COALESCE(unreachable, 0) ORDER BY last_seen ASC LIMIT ?2",
+            params![last_seen, limit as i64],
+        )
+        .await
+        .map_err(ScanTableError::SqlScanError)
+    }
+
+    async fn peers_seen_since(
+        &self,
+        last_seen: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_peers_where(
+            "WHERE last_seen >= ?1",
+            vec![Value::Text(last_seen)],
+            max_items,
+        )
+        .await
+    }
+
+    async fn all_peers(&self, _page_size: Option<i32>) -> Result<Vec<PeerData>, ScanTableError> {
+        let peers = self
+            .query_peers_where("", [])
+            .await
             .map_err(ScanTableError::SqlScanError)?;
 
         Ok(peers)
     }
 
+    async fn scan_peers(&self, max_items: Option<usize>) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_peers_where("", vec![], max_items).await
+    }
+
+    async fn all_peers_cursor(
+        &self,
+        limit: usize,
+        cursor: Option<String>,
+    ) -> Result<(Vec<PeerData>, Option<String>), ScanTableError> {
+        let last_id = cursor
+            .map(|cursor| decode_cursor(&cursor))
+            .transpose()?
+            .unwrap_or_default();
+        if limit == 0 {
+            return Ok((vec![], None));
+        }
+        // one more peer than asked tells whether there's a next page
+        let mut peers = self
+            .query_peers_where(
+                "WHERE id > ?1 ORDER BY id ASC LIMIT ?2",
+                params![last_id, limit as i64 + 1],
+            )
+            .await
+            .map_err(ScanTableError::SqlScanError)?;
+        let next_cursor = (peers.len() > limit).then(|| encode_cursor(&peers[limit - 1].id));
+        peers.truncate(limit);
+        Ok((peers, next_cursor))
+    }
+
+    async fn query_peers(
+        &self,
+        filter: PeerFilter,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        let mut conditions = vec![];
+        let mut values = vec![];
+        if let Some(client) = filter.client {
+            values.push(Value::Text(client));
+            // the client name is what comes before the first `/` of the client version
+            conditions.push(format!(
+                "lower(substr(client_version, 1, instr(client_version || '/', '/') - 1)) = lower(?{})",
+                values.len()
+            ));
+        }
+        if let Some(country) = filter.country {
+            values.push(Value::Text(country));
+            conditions.push(format!("country = ?{} COLLATE NOCASE", values.len()));
+        }
+        if let Some(synced) = filter.synced {
+            values.push(Value::Integer(synced as i64));
+            conditions.push(format!("synced = ?{}", values.len()));
+        }
+        if let Some(eth_version) = filter.eth_version {
+            values.push(Value::Integer(eth_version as i64));
+            conditions.push(format!("eth_version = ?{}", values.len()));
+        }
+        if let Some(capability) = filter.capability {
+            values.push(Value::Text(capability));
+            // the capabilities are stored comma separated
+            conditions.push(format!(
+                "(',' || capabilities || ',') LIKE ('%,' || ?{} || ',%')",
+                values.len()
+            ));
+        }
+        let mut sql_where = String::new();
+        if !conditions.is_empty() {
+            sql_where = format!("WHERE {}", conditions.join(" AND "));
+        }
+        self.scan_peers_where(sql_where, values, max_items).await
+    }
+
     async fn node_by_id(&self, id: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
         let peers = self
-            .db
-            .call(move |conn| {
-                let mut stmt = conn.prepare("SELECT * from eth_peer_data WHERE id = ?1")?;
-                let rows = stmt.query_map([id], |row| {
-                    Ok(PeerData {
-                        id: row.get(0)?,
-                        address: row.get(1)?,
-                        client_version: row.get(2)?,
-                        enode_url: row.get(3)?,
-                        tcp_port: row.get(4)?,
-                        chain: row.get(5)?,
-                        genesis_block_hash: row.get(6)?,
-                        best_block: row.get(7)?,
-                        total_difficulty: row.get(8)?,
-                        country: row.get(9)?,
-                        city: row.get(10)?,
-                        last_seen: row.get(11)?,
-                        capabilities: row
-                            .get::<_, String>(12)?
-                            .as_str()
-                            .split(',')
-                            .map(|s| s.to_string())
-                            .collect(),
-                        eth_version: row.get(13)?,
-                        synced: row.get(14)?,
-                        isp: row.get(15)?,
-                    })
-                })?;
-                let mut peers = vec![];
-                for peer_data in rows.flatten() {
-                    peers.push(peer_data);
-                }
-                Ok(peers)
-            })
+            .query_peers_where("WHERE id = ?1", [id])
             .await
             .map_err(QueryItemError::SqlQueryItemError)?;
 
@@ -397,43 +2084,391 @@ impl PeerDB for SqlPeerDB {
 
     async fn node_by_ip(&self, ip: String) -> Result<Option<Vec<PeerData>>, QueryItemError> {
         let peers = self
+            .query_peers_where("WHERE ip = ?1", [ip])
+            .await
+            .map_err(QueryItemError::SqlQueryItemError)?;
+
+        Ok(Some(peers))
+    }
+
+    async fn peers_by_city(
+        &self,
+        city: String,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        self.scan_peers_where(
+            "WHERE city_folded = ?1",
+            vec![Value::Text(fold_city(&city))],
+            max_items,
+        )
+        .await
+    }
+
+    async fn random_peers(
+        &self,
+        n: usize,
+        max_items: Option<usize>,
+    ) -> Result<ScannedPeers, ScanTableError> {
+        // sampled like the other dbs do, rather than ordering the whole table randomly
+        Ok(self.scan_peers(max_items).await?.sample(n))
+    }
+
+    async fn delete_peers_older_than(
+        &self,
+        last_seen: String,
+        limit: Option<usize>,
+        dry_run: bool,
+    ) -> Result<u64, DeleteItemError> {
+        let deleted = self
             .db
             .call(move |conn| {
-                let mut stmt = conn.prepare("SELECT * from eth_peer_data WHERE ip = ?1")?;
-                let rows = stmt.query_map([ip], |row| {
-                    Ok(PeerData {
-                        id: row.get(0)?,
-                        address: row.get(1)?,
-                        client_version: row.get(2)?,
-                        enode_url: row.get(3)?,
-                        tcp_port: row.get(4)?,
-                        chain: row.get(5)?,
-                        genesis_block_hash: row.get(6)?,
-                        best_block: row.get(7)?,
-                        total_difficulty: row.get(8)?,
-                        country: row.get(9)?,
-                        city: row.get(10)?,
-                        last_seen: row.get(11)?,
-                        capabilities: row
-                            .get::<_, String>(12)?
-                            .as_str()
-                            .split(',')
-                            .map(|s| s.to_string())
-                            .collect(),
-                        eth_version: row.get(13)?,
-                        synced: row.get(14)?,
-                        isp: row.get(15)?,
-                    })
-                })?;
-                let mut peers = vec![];
-                for peer_data in rows.flatten() {
-                    peers.push(peer_data);
+                // a negative limit is no limit in sqlite
+                let limit = limit.map_or(-1, |limit| limit as i64);
+                if dry_run {
+                    conn.query_row(
+                        "SELECT COUNT(*) FROM (SELECT id FROM eth_peer_data WHERE last_seen < ?1 LIMIT ?2)",
+                        params![last_seen, limit],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .map(|count| count as u64)
+                } else {
+                    conn.execute(
+                        "DELETE FROM eth_peer_data WHERE id IN (SELECT id FROM eth_peer_data WHERE last_seen < ?1 LIMIT ?2)",
+                        params![last_seen, limit],
+                    )
+                    .map(|deleted| deleted as u64)
                 }
-                Ok(peers)
+            })
+            .await?;
+
+        Ok(deleted)
+    }
+
+    async fn set_latest_block(&self, head: ChainHead) -> Result<(), AddItemError> {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO chain_head (id, number, hash, updated_at) VALUES (0, ?1, ?2, ?3)",
+                    params![head.number, head.hash, head.updated_at],
+                )
             })
             .await
-            .map_err(QueryItemError::SqlQueryItemError)?;
+            .map_err(AddItemError::SqlAddItemError)?;
+        Ok(())
+    }
 
-        Ok(Some(peers))
+    async fn latest_block(&self) -> Result<Option<ChainHead>, QueryItemError> {
+        self.db
+            .call(|conn| {
+                conn.query_row(
+                    "SELECT number, hash, updated_at FROM chain_head WHERE id = 0",
+                    [],
+                    |row| {
+                        Ok(ChainHead {
+                            number: row.get(0)?,
+                            hash: row.get(1)?,
+                            updated_at: row.get(2)?,
+                        })
+                    },
+                )
+                .optional()
+            })
+            .await
+            .map_err(QueryItemError::SqlQueryItemError)
+    }
+
+    async fn append_history(&self, peers: Vec<PeerData>) -> Result<usize, AddItemError> {
+        self.db
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for peer_data in &peers {
+                    let data = serde_json::to_string(&peer_data.snapshot())
+                        .expect("PeerData is always serializable");
+                    tx.execute(
+                        "INSERT OR REPLACE INTO peer_history (id, seen_at, data) VALUES (?1, ?2, ?3)",
+                        params![peer_data.id, peer_data.last_seen, data],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(peers.len())
+            })
+            .await
+            .map_err(AddItemError::SqlAddItemError)
+    }
+
+    async fn peer_history(&self, id: String) -> Result<Vec<PeerData>, QueryItemError> {
+        self.db
+            .call(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT data FROM peer_history WHERE id = ?1 ORDER BY seen_at")?;
+                let rows = stmt.query_map([id], |row| row.get::<_, String>(0))?;
+                // snapshots written by an older `PeerData` that no longer parse are skipped
+                Ok(rows
+                    .flatten()
+                    .filter_map(|data| serde_json::from_str(&data).ok())
+                    .collect())
+            })
+            .await
+            .map_err(QueryItemError::SqlQueryItemError)
+    }
+
+    async fn summary(
+        &self,
+        max_items: Option<usize>,
+    ) -> Result<(NetworkSummary, bool), ScanTableError> {
+        let latest_block = self.latest_block().await?.map(|head| head.number);
+        // a negative limit means none in sqlite
+        let limit = max_items.map_or(-1, |max_items| max_items as i64);
+        self.db
+            .call(move |conn| {
+                let truncated = max_items.is_some()
+                    && conn.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM eth_peer_data LIMIT 1 OFFSET ?1)",
+                        [limit],
+                        |row| row.get(0),
+                    )?;
+                let summary = conn.query_row(
+                    "SELECT COUNT(*), COUNT(DISTINCT ip), COUNT(DISTINCT NULLIF(country, '')), COUNT(DISTINCT NULLIF(substr(client_version, 1, instr(client_version || '/', '/') - 1), '')), COUNT(CASE WHEN synced THEN 1 END), COUNT(CASE WHEN NOT COALESCE(unreachable, 0) THEN 1 END), COUNT(CASE WHEN snap_supported THEN 1 END), COUNT(CASE WHEN les_supported THEN 1 END) FROM (SELECT * FROM eth_peer_data LIMIT ?1)",
+                    [limit],
+                    |row| {
+                        Ok(NetworkSummary {
+                            total_peers: row.get(0)?,
+                            distinct_ips: row.get(1)?,
+                            distinct_countries: row.get(2)?,
+                            distinct_clients: row.get(3)?,
+                            synced_peers: row.get(4)?,
+                            online_peers: row.get(5)?,
+                            snap_peers: row.get(6)?,
+                            les_peers: row.get(7)?,
+                            latest_block,
+                        })
+                    },
+                )?;
+                Ok((summary, truncated))
+            })
+            .await
+            .map_err(ScanTableError::SqlScanError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// A handshaked peer with `id`, seen at `last_seen`.
+    fn peer(id: &str, last_seen: &str) -> PeerData {
+        serde_json::from_value(json!({
+            "enode_url": format!("enode://{id}@203.0.113.7:30303"),
+            "id": id,
+            "address": "203.0.113.7",
+            "tcp_port": 30303,
+            "udp_port": 30303,
+            "client_version": "Geth/v1.13.5-stable/linux-amd64/go1.21.4",
+            "eth_version": 68,
+            "capabilities": ["eth/68"],
+            "chain": "mainnet",
+            "total_difficulty": "0",
+            "best_block": "0x00",
+            "genesis_block_hash": "0x00",
+            "last_seen": last_seen,
+            "country": "",
+            "city": "",
+            "synced": true,
+            "isp": "",
+            "first_seen": last_seen,
+            "seen_count": 1,
+            "score": 5,
+            "handshake_completed": true,
+        }))
+        .expect("it's a valid peer")
+    }
+
+    async fn sqlite() -> SqlPeerDB {
+        SqlPeerDB::open(Connection::open_in_memory().await.unwrap()).await
+    }
+
+    /// Append two sightings of a peer out of order, then read its history back.
+    async fn assert_history_round_trips(db: &dyn PeerDB) {
+        let later = PeerData {
+            synced: Some(false),
+            ..peer("01", "2023-10-16 13:00:00 UTC")
+        };
+        let earlier = peer("01", "2023-10-16 12:00:00 UTC");
+
+        let appended = db
+            .append_history(vec![later, earlier, peer("02", "2023-10-16 12:00:00 UTC")])
+            .await
+            .unwrap();
+        let history = db.peer_history("01".to_string()).await.unwrap();
+
+        assert_eq!(appended, 3);
+        let seen_at: Vec<_> = history.iter().map(|peer| peer.last_seen.as_str()).collect();
+        assert_eq!(
+            seen_at,
+            ["2023-10-16 12:00:00 UTC", "2023-10-16 13:00:00 UTC"]
+        );
+        assert_eq!(history[0].synced, Some(true));
+        assert_eq!(history[1].synced, Some(false));
+        // a sighting doesn't know the counters of the saved record
+        assert!(history
+            .iter()
+            .all(|peer| peer.seen_count == 0 && peer.score == 0 && peer.first_seen.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn in_memory_history_round_trips() {
+        assert_history_round_trips(&InMemoryPeerDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_history_round_trips() {
+        assert_history_round_trips(&sqlite().await).await;
+    }
+
+    /// A sighting of the peer `id` from discovery only.
+    fn discovered(id: &str, last_seen: &str) -> PeerData {
+        PeerData {
+            client_version: String::new(),
+            capabilities: vec![],
+            synced: None,
+            seen_count: 0,
+            score: 0,
+            handshake_completed: Some(false),
+            ..peer(id, last_seen)
+        }
+    }
+
+    /// Crawl a peer, flag it unreachable, then discover it again.
+    async fn assert_discovery_isnt_a_crawl(db: &dyn PeerDB) {
+        db.add_peer(peer("01", "2023-10-16 12:00:00 UTC"))
+            .await
+            .unwrap();
+        db.mark_unreachable("01".to_string()).await.unwrap();
+
+        db.add_peer(discovered("01", "2023-10-16 13:00:00 UTC"))
+            .await
+            .unwrap();
+        db.upsert_peers(vec![discovered("01", "2023-10-16 14:00:00 UTC")])
+            .await
+            .unwrap();
+        db.add_peer(discovered("02", "2023-10-16 14:00:00 UTC"))
+            .await
+            .unwrap();
+
+        let saved = &db.node_by_id("01".to_string()).await.unwrap().unwrap()[0];
+        assert_eq!(saved.seen_count, 1);
+        assert!(saved.unreachable);
+        assert_eq!(saved.last_seen, "2023-10-16 14:00:00 UTC");
+        assert_eq!(
+            saved.client_version,
+            "Geth/v1.13.5-stable/linux-amd64/go1.21.4"
+        );
+        let saved = &db.node_by_id("02".to_string()).await.unwrap().unwrap()[0];
+        assert_eq!(saved.seen_count, 0);
+    }
+
+    #[tokio::test]
+    async fn in_memory_discovery_isnt_a_crawl() {
+        assert_discovery_isnt_a_crawl(&InMemoryPeerDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_discovery_isnt_a_crawl() {
+        assert_discovery_isnt_a_crawl(&sqlite().await).await;
+    }
+
+    /// Save three peers in Berlin, then read them through each of the capped scans.
+    async fn assert_scans_are_capped(db: &dyn PeerDB) {
+        for id in ["01", "02", "03"] {
+            let peer_data = PeerData {
+                city: "Berlin".to_string(),
+                ..peer(id, "2023-10-16 12:00:00 UTC")
+            };
+            db.add_peer(peer_data).await.unwrap();
+        }
+        let since = "2023-10-16 00:00:00 UTC".to_string();
+        let filter = PeerFilter {
+            synced: Some(true),
+            ..Default::default()
+        };
+
+        for (max_items, read, truncated) in
+            [(Some(2), 2, true), (Some(3), 3, false), (None, 3, false)]
+        {
+            let scans = [
+                db.scan_peers(max_items).await.unwrap(),
+                db.peers_by_city("berlin".to_string(), max_items)
+                    .await
+                    .unwrap(),
+                db.peers_seen_since(since.clone(), max_items).await.unwrap(),
+                db.query_peers(filter.clone(), max_items).await.unwrap(),
+                db.random_peers(10, max_items).await.unwrap(),
+            ];
+            for scanned in scans {
+                assert_eq!(scanned.peers.len(), read);
+                assert_eq!(scanned.truncated, truncated);
+            }
+            let (summary, summary_truncated) = db.summary(max_items).await.unwrap();
+            assert_eq!(summary.total_peers, read as u64);
+            assert_eq!(summary_truncated, truncated);
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_scans_are_capped() {
+        assert_scans_are_capped(&InMemoryPeerDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_scans_are_capped() {
+        assert_scans_are_capped(&sqlite().await).await;
+    }
+
+    /// Save a peer in a city spelled with non-ascii letters, then look it up in another case.
+    async fn assert_cities_fold_beyond_ascii(db: &dyn PeerDB) {
+        let peer_data = PeerData {
+            city: "München".to_string(),
+            ..peer("01", "2023-10-16 12:00:00 UTC")
+        };
+        db.add_peer(peer_data).await.unwrap();
+
+        let found = db.peers_by_city("MÜNCHEN".to_string(), None).await.unwrap();
+
+        let ids: Vec<_> = found.peers.iter().map(|peer| peer.id.as_str()).collect();
+        assert_eq!(ids, ["01"]);
+    }
+
+    #[tokio::test]
+    async fn in_memory_cities_fold_beyond_ascii() {
+        assert_cities_fold_beyond_ascii(&InMemoryPeerDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_cities_fold_beyond_ascii() {
+        assert_cities_fold_beyond_ascii(&sqlite().await).await;
+    }
+
+    /// Save a peer, then ask for an empty page of the table.
+    async fn assert_empty_pages_have_no_next_page(db: &dyn PeerDB) {
+        db.add_peer(peer("01", "2023-10-16 12:00:00 UTC"))
+            .await
+            .unwrap();
+
+        let (peers, next_cursor) = db.all_peers_cursor(0, None).await.unwrap();
+
+        assert!(peers.is_empty());
+        assert!(next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn in_memory_empty_pages_have_no_next_page() {
+        assert_empty_pages_have_no_next_page(&InMemoryPeerDB::new()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_empty_pages_have_no_next_page() {
+        assert_empty_pages_have_no_next_page(&sqlite().await).await;
     }
 }
@@ -1,10 +1,13 @@
+pub mod backend;
 pub mod db;
 pub mod types;
 
 use std::sync::Arc;
 use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tracing::warn;
 
 // Re-exports
+pub use backend::{build_peer_db, Backend};
 pub use db::{AwsPeerDB, InMemoryPeerDB, PeerDB, SqlPeerDB};
 pub use types::PeerData;
 
@@ -25,3 +28,14 @@ pub async fn append_to_file(peer_data: PeerData) -> eyre::Result<()> {
 pub async fn save_peer(peer_data: PeerData, db: Arc<dyn PeerDB>) {
     db.add_peer(peer_data).await.unwrap();
 }
+
+/// Helper function to save many peers at once, returning how many were saved.
+pub async fn save_peers(peers: Vec<PeerData>, db: Arc<dyn PeerDB>) -> usize {
+    match db.add_peers(peers).await {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!("Failed to save peers: {e}");
+            0
+        }
+    }
+}
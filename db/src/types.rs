@@ -1,10 +1,19 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{NaiveDateTime, Utc};
+use rand::seq::IteratorRandom;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use thiserror::Error;
 
 use aws_sdk_dynamodb::{
     error::SdkError,
-    operation::{put_item::PutItemError, query::QueryError, scan::ScanError},
+    operation::{
+        batch_get_item::BatchGetItemError, batch_write_item::BatchWriteItemError,
+        get_item::GetItemError, put_item::PutItemError, query::QueryError, scan::ScanError,
+        update_item::UpdateItemError,
+    },
     types::AttributeValue,
 };
 
@@ -14,20 +23,139 @@ pub struct PeerData {
     pub id: String,
     pub address: String,
     pub tcp_port: u16,
+    pub udp_port: u16,
     pub client_version: String,
+    /// The eth protocol version negotiated with the peer during the eth handshake.
     pub eth_version: u8,
+    /// All the capabilities advertised by the peer in its `Hello`, which may include eth versions other than the negotiated one.
     pub capabilities: Vec<String>,
     pub chain: String,
+    /// Total difficulty in decimal.
     pub total_difficulty: String,
+    /// Hash of the peer's best block as a lowercase `0x` prefixed hex string.
     pub best_block: String, // TODO: convert this to a blocknum with a lookup
+    /// Genesis hash as a lowercase `0x` prefixed hex string.
     pub genesis_block_hash: String,
     pub last_seen: String,
     pub country: String,
     pub city: String,
+    /// Whether the peer's best block is one of the latest blocks known by the crawler, unknown while it knew none.
     pub synced: Option<bool>,
     pub isp: String,
+    /// When the peer was crawled for the first time.
+    #[serde(default)]
+    pub first_seen: String,
+    /// How many times the peer was crawled successfully.
+    #[serde(default)]
+    pub seen_count: u64,
+    /// Whether the peer failed to answer repeated recrawls since it was last seen.
+    #[serde(default)]
+    pub unreachable: bool,
+    /// Time in milliseconds taken by the TCP connection and the p2p and eth handshakes, when we dialed the peer.
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Fork hash of the peer's fork id, as an `0x` prefixed hex string.
+    #[serde(default)]
+    pub fork_hash: String,
+    /// Block number or timestamp of the next fork scheduled by the peer, 0 if none.
+    #[serde(default)]
+    pub fork_next: u64,
+    /// Latitude of the peer's ip, when it could be geolocated.
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    /// Longitude of the peer's ip, when it could be geolocated.
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    /// Category of the last failed crawl of the peer since it was last seen, e.g. `transient` or `permanent`.
+    #[serde(default)]
+    pub last_failure: Option<String>,
+    /// RLPx p2p protocol version negotiated in the hello, unknown for the peers crawled from a network session.
+    #[serde(default)]
+    pub p2p_version: Option<u8>,
+    /// Number of the autonomous system announcing the peer's ip, stable unlike the isp names.
+    #[serde(default)]
+    pub asn: Option<u32>,
+    /// Organization owning the autonomous system.
+    #[serde(default)]
+    pub as_org: Option<String>,
+    /// Geolocation provider that located the peer, e.g. `ip-api` or `maxmind`.
+    #[serde(default)]
+    pub geo_provider: Option<String>,
+    /// Whether the peer accepted a tcp connection on its advertised port, unknown for the peers that weren't probed.
+    #[serde(default)]
+    pub port_reachable: Option<bool>,
+    /// How the peer was last surfaced: `discv4`, `dnsdisc`, `discv5`, `network`, `recrawler` or `admin`.
+    #[serde(default)]
+    pub discovery_source: Option<String>,
+    /// When the last failed crawl of the peer happened since it was last seen, see `last_failure`.
+    #[serde(default)]
+    pub last_failure_at: Option<String>,
+    /// `total_difficulty` as a number, when it fits in a `u128`.
+    #[serde(default)]
+    pub total_difficulty_num: Option<u128>,
+    /// Why the last session with the peer was closed, e.g. `Too many peers`.
+    #[serde(default)]
+    pub last_disconnect_reason: Option<String>,
+    /// Whether the peer's best block is the canonical block at its height, unknown when the block is too old or was
+    /// never seen by the crawler.
+    #[serde(default)]
+    pub on_canonical: Option<bool>,
+    /// How many blocks the peer's best block is behind the crawler's head, unknown when the peer didn't send its
+    /// header.
+    #[serde(default)]
+    pub sync_lag_blocks: Option<u64>,
+    /// Age in seconds of the peer's best block when the peer was crawled, unknown when the peer didn't send its
+    /// header.
+    #[serde(default)]
+    pub sync_lag_seconds: Option<u64>,
+    /// Number of the peer's best block, unknown when the peer didn't send its header and the block is too old or
+    /// was never seen by the crawler.
+    #[serde(default)]
+    pub head_block_number: Option<u64>,
+    /// Number of the crawler's head when the peer was crawled, unknown until the crawler knows a block.
+    #[serde(default)]
+    pub crawler_head_number: Option<u64>,
+    /// Domain of the DNS tree the peer was found in, e.g. `all.mainnet.ethdisco.net`, when dnsdisc surfaced it.
+    #[serde(default)]
+    pub dns_tree: Option<String>,
+    /// Whether the peer was handshaked, `false` for the peers only recorded from discovery, with empty client and
+    /// status fields.
+    #[serde(default)]
+    pub handshake_completed: Option<bool>,
+    /// Reputation of the peer, raised by successful crawls and lowered by failures and protocol breaches.
+    ///
+    /// Saving a crawled peer adds its score to the saved one, like a sighting adds to `seen_count`.
+    #[serde(default)]
+    pub score: i64,
+    /// Cause of the last failed handshake with the peer, e.g. `too_many_peers` or `wrong_network`.
+    #[serde(default)]
+    pub last_handshake_error: Option<String>,
+    /// How many handshakes with the peer failed since it was last crawled successfully.
+    #[serde(default)]
+    pub handshake_failures: u64,
+    /// The eth protocol versions the peer advertises in its capabilities, while `eth_version` is the one negotiated
+    /// with the crawler.
+    #[serde(default)]
+    pub advertised_eth_versions: Vec<u8>,
+    /// The key/value pairs of the peer's ENR, e.g. its `seq`, `eth` fork id or `snap` entry, when it was discovered
+    /// through one.
+    #[serde(default)]
+    pub enr: Option<serde_json::Value>,
+    /// Whether the peer advertises a `snap/*` capability, i.e. serves snap sync.
+    #[serde(default)]
+    pub snap_supported: bool,
+    /// Whether the peer advertises a `les/*` capability, i.e. serves light clients.
+    #[serde(default)]
+    pub les_supported: bool,
+    /// Whether the fork id in the peer's ENR belongs to another network, in which case it was never dialed.
+    #[serde(default)]
+    pub off_network: bool,
 }
 
+/// Interval in seconds at which a reachable peer is expected to be crawled again, it matches the crawler's default
+/// recrawl window.
+pub const AVAILABILITY_ROUND: i64 = 6 * 60 * 60;
+
 impl PeerData {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
@@ -35,6 +163,7 @@ impl PeerData {
         id: String,
         address: String,
         tcp_port: u16,
+        udp_port: u16,
         client_version: String,
         capabilities: Vec<String>,
         last_seen: String,
@@ -47,12 +176,46 @@ impl PeerData {
         eth_version: u8,
         synced: Option<bool>,
         isp: String,
+        first_seen: String,
+        seen_count: u64,
+        unreachable: bool,
+        latency_ms: Option<u64>,
+        fork_hash: String,
+        fork_next: u64,
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        last_failure: Option<String>,
+        p2p_version: Option<u8>,
+        asn: Option<u32>,
+        as_org: Option<String>,
+        geo_provider: Option<String>,
+        port_reachable: Option<bool>,
+        discovery_source: Option<String>,
+        last_failure_at: Option<String>,
+        total_difficulty_num: Option<u128>,
+        last_disconnect_reason: Option<String>,
+        on_canonical: Option<bool>,
+        sync_lag_blocks: Option<u64>,
+        sync_lag_seconds: Option<u64>,
+        head_block_number: Option<u64>,
+        crawler_head_number: Option<u64>,
+        dns_tree: Option<String>,
+        handshake_completed: Option<bool>,
+        score: i64,
+        last_handshake_error: Option<String>,
+        handshake_failures: u64,
+        advertised_eth_versions: Vec<u8>,
+        enr: Option<serde_json::Value>,
+        snap_supported: bool,
+        les_supported: bool,
+        off_network: bool,
     ) -> Self {
         Self {
             enode_url,
             id,
             address,
             tcp_port,
+            udp_port,
             client_version,
             capabilities,
             last_seen,
@@ -65,29 +228,173 @@ impl PeerData {
             genesis_block_hash,
             synced,
             isp,
+            first_seen,
+            seen_count,
+            unreachable,
+            latency_ms,
+            fork_hash,
+            fork_next,
+            latitude,
+            longitude,
+            last_failure,
+            p2p_version,
+            asn,
+            as_org,
+            geo_provider,
+            port_reachable,
+            discovery_source,
+            last_failure_at,
+            total_difficulty_num,
+            last_disconnect_reason,
+            on_canonical,
+            sync_lag_blocks,
+            sync_lag_seconds,
+            head_block_number,
+            crawler_head_number,
+            dns_tree,
+            handshake_completed,
+            score,
+            last_handshake_error,
+            handshake_failures,
+            advertised_eth_versions,
+            enr,
+            snap_supported,
+            les_supported,
+            off_network,
+        }
+    }
+
+    /// Share of the crawl rounds since the peer was first seen in which it was reachable, between 0 and 1.
+    ///
+    /// A round lasts [`AVAILABILITY_ROUND`] seconds, so a peer that stops answering sees its availability decay.
+    pub fn availability(&self) -> f64 {
+        let Some(first_seen) = parse_timestamp(&self.first_seen) else {
+            return 0.0;
+        };
+        let elapsed = (Utc::now().naive_utc() - first_seen).num_seconds().max(0);
+        let rounds = elapsed / AVAILABILITY_ROUND + 1;
+        (self.seen_count as f64 / rounds as f64).min(1.0)
+    }
+
+    /// How many blocks the peer's best block was behind the crawler's head when it was crawled, if both are known.
+    pub fn head_lag(&self) -> Option<u64> {
+        Some(
+            self.crawler_head_number?
+                .saturating_sub(self.head_block_number?),
+        )
+    }
+
+    /// This sighting as kept in the history of the peer.
+    ///
+    /// `first_seen`, `seen_count` and `score` only add up once merged into the saved record, a single sighting
+    /// doesn't know them, so they're left out.
+    pub fn snapshot(&self) -> PeerData {
+        PeerData {
+            first_seen: String::new(),
+            seen_count: 0,
+            score: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Keep what the `saved` record of the peer knows and this sighting doesn't: its ENR, why its last session was
+    /// closed and, when this sighting only comes from discovery, everything its last handshake told along with its
+    /// `seen_count` and whether it's unreachable.
+    pub fn keep_saved(&mut self, saved: &PeerData) {
+        if self.enr.is_none() {
+            self.enr = saved.enr.clone();
+        }
+        if self.last_disconnect_reason.is_none() {
+            self.last_disconnect_reason = saved.last_disconnect_reason.clone();
         }
+        if self.handshake_completed != Some(false) {
+            return;
+        }
+        let discovered = self.clone();
+        *self = PeerData {
+            enode_url: discovered.enode_url,
+            id: discovered.id,
+            address: discovered.address,
+            tcp_port: discovered.tcp_port,
+            udp_port: discovered.udp_port,
+            last_seen: discovered.last_seen,
+            first_seen: discovered.first_seen,
+            discovery_source: discovered.discovery_source,
+            dns_tree: discovered.dns_tree,
+            score: discovered.score,
+            enr: discovered.enr,
+            last_disconnect_reason: discovered.last_disconnect_reason,
+            off_network: discovered.off_network,
+            ..saved.clone()
+        };
     }
 }
 
+/// Parse a timestamp as stored in `last_seen`, e.g. `2023-10-16 12:34:56.789 UTC`.
+fn parse_timestamp(timestamp: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(timestamp.trim_end_matches(" UTC"), "%Y-%m-%d %H:%M:%S%.f").ok()
+}
+
 impl From<&HashMap<String, AttributeValue>> for PeerData {
     fn from(value: &HashMap<String, AttributeValue>) -> Self {
+        let tcp_port = as_u16(value.get("port"), 30303);
+        let last_seen = as_string(value.get("last_seen"), &"".to_string());
+        let total_difficulty = as_string(value.get("total_difficulty"), &"".to_string());
         let peer_data = PeerData::new(
             as_string(value.get("enode_url"), &"".to_string()),
             as_string(value.get("peer-id"), &"".to_string()),
             as_string(value.get("peer-ip"), &"".to_string()),
-            as_u16(value.get("port"), 30303),
+            tcp_port,
+            // older records don't have the udp port, which is usually the same as the tcp one
+            as_u16(value.get("udp_port"), tcp_port),
             as_string(value.get("client_version"), &"".to_string()),
             as_string_vec(value.get("capabilities")),
-            as_string(value.get("last_seen"), &"".to_string()),
+            last_seen.clone(),
             as_string(value.get("country"), &"".to_string()),
             as_string(value.get("city"), &"".to_string()),
             as_string(value.get("genesis_block_hash"), &"".to_string()),
             as_string(value.get("best_block"), &"".to_string()),
-            as_string(value.get("total_difficulty"), &"".to_string()),
+            total_difficulty.clone(),
             as_string(value.get("chain"), &"".to_string()),
             as_u8(value.get("eth_version"), 0),
             as_option_bool(value.get("synced"), None),
             as_string(value.get("isp"), &"".to_string()),
+            // older records were only seen once as far as we know
+            as_string(value.get("first_seen"), &last_seen),
+            as_u64(value.get("seen_count"), 1),
+            as_option_bool(value.get("unreachable"), None).unwrap_or_default(),
+            as_option_u64(value.get("latency_ms")),
+            as_string(value.get("fork_hash"), &"".to_string()),
+            as_u64(value.get("fork_next"), 0),
+            as_option_f64(value.get("latitude")),
+            as_option_f64(value.get("longitude")),
+            as_option_string(value.get("last_failure")),
+            as_option_u8(value.get("p2p_version")),
+            as_option_u32(value.get("asn")),
+            as_option_string(value.get("as_org")),
+            as_option_string(value.get("geo_provider")),
+            as_option_bool(value.get("port_reachable"), None),
+            as_option_string(value.get("discovery_source")),
+            as_option_string(value.get("last_failure_at")),
+            // older records only have the string
+            as_option_u128(value.get("total_difficulty_num"))
+                .or_else(|| total_difficulty.parse().ok()),
+            as_option_string(value.get("last_disconnect_reason")),
+            as_option_bool(value.get("on_canonical"), None),
+            as_option_u64(value.get("sync_lag_blocks")),
+            as_option_u64(value.get("sync_lag_seconds")),
+            as_option_u64(value.get("head_block_number")),
+            as_option_u64(value.get("crawler_head_number")),
+            as_option_string(value.get("dns_tree")),
+            as_option_bool(value.get("handshake_completed"), None),
+            as_i64(value.get("score"), 0),
+            as_option_string(value.get("last_handshake_error")),
+            as_u64(value.get("handshake_failures"), 0),
+            as_u8_vec(value.get("advertised_eth_versions")),
+            as_option_json(value.get("enr")),
+            as_option_bool(value.get("snap_supported"), None).unwrap_or_default(),
+            as_option_bool(value.get("les_supported"), None).unwrap_or_default(),
+            as_option_bool(value.get("off_network"), None).unwrap_or_default(),
         );
 
         peer_data
@@ -103,6 +410,20 @@ pub fn as_string(val: Option<&AttributeValue>, default: &String) -> String {
     default.to_owned()
 }
 
+pub fn as_option_string(val: Option<&AttributeValue>) -> Option<String> {
+    if let Some(v) = val {
+        if let Ok(s) = v.as_s() {
+            return Some(s.to_string());
+        }
+    }
+    None
+}
+
+/// A JSON value stored as a string, `None` if it's missing or not valid JSON.
+pub fn as_option_json(val: Option<&AttributeValue>) -> Option<serde_json::Value> {
+    serde_json::from_str(val?.as_s().ok()?).ok()
+}
+
 pub fn as_u16(val: Option<&AttributeValue>, default: u16) -> u16 {
     if let Some(v) = val {
         if let Ok(n) = v.as_n() {
@@ -114,6 +435,73 @@ pub fn as_u16(val: Option<&AttributeValue>, default: u16) -> u16 {
     default
 }
 
+pub fn as_u64(val: Option<&AttributeValue>, default: u64) -> u64 {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            if let Ok(n) = n.parse::<u64>() {
+                return n;
+            }
+        }
+    }
+    default
+}
+
+pub fn as_i64(val: Option<&AttributeValue>, default: i64) -> i64 {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            if let Ok(n) = n.parse::<i64>() {
+                return n;
+            }
+        }
+    }
+    default
+}
+
+pub fn as_option_u64(val: Option<&AttributeValue>) -> Option<u64> {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            return n.parse::<u64>().ok();
+        }
+    }
+    None
+}
+
+pub fn as_option_u128(val: Option<&AttributeValue>) -> Option<u128> {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            return n.parse::<u128>().ok();
+        }
+    }
+    None
+}
+
+pub fn as_option_u32(val: Option<&AttributeValue>) -> Option<u32> {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            return n.parse::<u32>().ok();
+        }
+    }
+    None
+}
+
+pub fn as_option_u8(val: Option<&AttributeValue>) -> Option<u8> {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            return n.parse::<u8>().ok();
+        }
+    }
+    None
+}
+
+pub fn as_option_f64(val: Option<&AttributeValue>) -> Option<f64> {
+    if let Some(v) = val {
+        if let Ok(n) = v.as_n() {
+            return n.parse::<f64>().ok();
+        }
+    }
+    None
+}
+
 pub fn as_u8(val: Option<&AttributeValue>, default: u8) -> u8 {
     if let Some(v) = val {
         if let Ok(n) = v.as_n() {
@@ -137,6 +525,18 @@ pub fn as_string_vec(val: Option<&AttributeValue>) -> Vec<String> {
     vec![]
 }
 
+pub fn as_u8_vec(val: Option<&AttributeValue>) -> Vec<u8> {
+    if let Some(val) = val {
+        if let Ok(val) = val.as_l() {
+            return val
+                .iter()
+                .filter_map(|v| v.as_n().ok()?.parse().ok())
+                .collect();
+        }
+    }
+    vec![]
+}
+
 pub fn as_option_bool(val: Option<&AttributeValue>, default: Option<bool>) -> Option<bool> {
     if let Some(v) = val {
         if let Ok(n) = v.as_bool() {
@@ -150,6 +550,12 @@ pub fn as_option_bool(val: Option<&AttributeValue>, default: Option<bool>) -> Op
 pub enum AddItemError {
     #[error("An error occurred adding a new item into the AWS database: {0}")]
     AwsAddItemError(#[from] SdkError<PutItemError>),
+    #[error("An error occurred adding new items into the AWS database: {0}")]
+    AwsBatchAddItemError(#[from] SdkError<BatchWriteItemError>),
+    #[error("An error occurred reading the items to update from the AWS database: {0}")]
+    AwsBatchGetItemError(#[from] SdkError<BatchGetItemError>),
+    #[error("An error occurred updating an item of the AWS database: {0}")]
+    AwsUpdateItemError(#[from] SdkError<UpdateItemError>),
     #[error("An error occurred adding a new item into the in memory db")]
     InMemoryDbAddItemError(),
     #[error("An error occurred adding a new item into the SQL database: {0}")]
@@ -164,12 +570,18 @@ pub enum ScanTableError {
     InMemoryDbScanError(),
     #[error("An error occurred while performing a scan of the SQL database: {0}")]
     SqlScanError(#[from] tokio_rusqlite::Error),
+    #[error("An error occurred while reading the latest block: {0}")]
+    LatestBlockQueryError(#[from] QueryItemError),
+    #[error("Invalid pagination cursor `{0}`")]
+    InvalidCursor(String),
 }
 
 #[derive(Debug, Error)]
 pub enum QueryItemError {
     #[error("An error occurred querying the AWS database: {0}")]
     AwsQueryItemError(#[from] SdkError<QueryError>),
+    #[error("An error occurred getting an item of the AWS database: {0}")]
+    AwsGetItemError(#[from] SdkError<GetItemError>),
     #[error("An error occurred querying the in memory database")]
     InMemoryDbQueryItemError(),
     #[error("An error occurred querying the SQL database: {0}")]
@@ -178,6 +590,12 @@ pub enum QueryItemError {
 
 #[derive(Debug, Error)]
 pub enum DeleteItemError {
+    #[error("An error occurred while scanning the AWS database for items to delete: {0}")]
+    AwsScanError(#[from] SdkError<ScanError>),
+    #[error("An error occurred deleting items from the AWS database: {0}")]
+    AwsDeleteItemError(#[from] SdkError<BatchWriteItemError>),
+    #[error("An error occurred deleting items from the in memory database")]
+    InMemoryDbDeleteItemError(),
     #[error("An error occurred deleting a new item into the SQL database: {0}")]
     SqlDeleteItemError(#[from] tokio_rusqlite::Error),
 }
@@ -186,3 +604,461 @@ pub enum DeleteItemError {
 pub struct ClientData {
     pub client_version: String,
 }
+
+/// Latest block ingested by the crawler, peers are `synced` when they're close enough to it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChainHead {
+    pub number: u64,
+    pub hash: String,
+    /// When the crawler ingested the block, stored like `last_seen`.
+    pub updated_at: String,
+}
+
+/// Criteria a peer must all meet, an empty filter matches every peer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerFilter {
+    /// Name of the client, e.g. `reth`, compared case insensitively.
+    pub client: Option<String>,
+    /// Country the peer is located in, compared case insensitively.
+    pub country: Option<String>,
+    pub synced: Option<bool>,
+    /// The eth protocol version negotiated with the peer.
+    pub eth_version: Option<u8>,
+    /// A capability the peer advertises, e.g. `snap/1`, compared case insensitively.
+    pub capability: Option<String>,
+}
+
+impl PeerFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether `peer` meets all the criteria.
+    pub fn matches(&self, peer: &PeerData) -> bool {
+        self.client.as_ref().map_or(true, |client| {
+            client_name(&peer.client_version).eq_ignore_ascii_case(client)
+        }) && self
+            .country
+            .as_ref()
+            .map_or(true, |country| peer.country.eq_ignore_ascii_case(country))
+            && self
+                .synced
+                .map_or(true, |synced| peer.synced == Some(synced))
+            && self
+                .eth_version
+                .map_or(true, |eth_version| peer.eth_version == eth_version)
+            && self.capability.as_ref().map_or(true, |capability| {
+                peer.capabilities
+                    .iter()
+                    .any(|advertised| advertised.eq_ignore_ascii_case(capability))
+            })
+    }
+}
+
+/// Peers read by a scan capped at a max number of items.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ScannedPeers {
+    pub peers: Vec<PeerData>,
+    /// Whether the scan stopped at the cap, leaving out some peers.
+    pub truncated: bool,
+}
+
+impl ScannedPeers {
+    /// Keep the first `max_items` of `peers`, read one past the cap to know whether the scan was truncated.
+    pub fn capped(mut peers: Vec<PeerData>, max_items: Option<usize>) -> Self {
+        let truncated = max_items.map_or(false, |max_items| peers.len() > max_items);
+        if let Some(max_items) = max_items {
+            peers.truncate(max_items);
+        }
+        Self { peers, truncated }
+    }
+
+    /// Uniformly sample up to `n` of the scanned peers.
+    pub fn sample(self, n: usize) -> Self {
+        Self {
+            peers: self
+                .peers
+                .into_iter()
+                .choose_multiple(&mut rand::thread_rng(), n),
+            truncated: self.truncated,
+        }
+    }
+}
+
+/// Aggregated figures about the crawled peers.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkSummary {
+    pub total_peers: u64,
+    pub distinct_ips: u64,
+    pub distinct_countries: u64,
+    /// Distinct client names, e.g. `Geth` or `Nethermind`, regardless of their version.
+    pub distinct_clients: u64,
+    pub synced_peers: u64,
+    /// Peers that weren't marked unreachable by the recrawler.
+    #[serde(default)]
+    pub online_peers: u64,
+    /// Peers serving snap sync.
+    #[serde(default)]
+    pub snap_peers: u64,
+    /// Peers serving light clients.
+    #[serde(default)]
+    pub les_peers: u64,
+    /// Latest block number known by the crawler.
+    pub latest_block: Option<u64>,
+}
+
+impl NetworkSummary {
+    /// Summarize `peers`, along with the latest block known by the crawler.
+    pub fn from_peers<'a>(
+        peers: impl IntoIterator<Item = &'a PeerData>,
+        latest_block: Option<u64>,
+    ) -> Self {
+        let mut summary = NetworkSummary {
+            latest_block,
+            ..Default::default()
+        };
+        let mut ips = HashSet::new();
+        let mut countries = HashSet::new();
+        let mut clients = HashSet::new();
+        for peer in peers {
+            summary.total_peers += 1;
+            if peer.synced == Some(true) {
+                summary.synced_peers += 1;
+            }
+            if !peer.unreachable {
+                summary.online_peers += 1;
+            }
+            if peer.snap_supported {
+                summary.snap_peers += 1;
+            }
+            if peer.les_supported {
+                summary.les_peers += 1;
+            }
+            ips.insert(peer.address.as_str());
+            if !peer.country.is_empty() {
+                countries.insert(peer.country.as_str());
+            }
+            let client = client_name(&peer.client_version);
+            if !client.is_empty() {
+                clients.insert(client);
+            }
+        }
+        summary.distinct_ips = ips.len() as u64;
+        summary.distinct_countries = countries.len() as u64;
+        summary.distinct_clients = clients.len() as u64;
+        summary
+    }
+}
+
+/// Name of the client in a client version, e.g. `Geth` in `Geth/v1.13.4-stable-3f907d6a/linux-amd64/go1.21.3`.
+pub fn client_name(client_version: &str) -> &str {
+    client_version.split('/').next().unwrap_or_default()
+}
+
+/// Opaque pagination cursor resuming a walk of the peers after the peer `last_id`, the peers are walked in the order
+/// of their ids.
+pub fn encode_cursor(last_id: &str) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::json!({ "id": last_id }).to_string())
+}
+
+/// The id of the last peer of the page a cursor was made after.
+pub fn decode_cursor(cursor: &str) -> Result<String, ScanTableError> {
+    let invalid = || ScanTableError::InvalidCursor(cursor.to_string());
+    let json = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let value: serde_json::Value = serde_json::from_slice(&json).map_err(|_| invalid())?;
+    value["id"].as_str().map(str::to_string).ok_or_else(invalid)
+}
+
+/// `ip` in the form peers are stored with: ipv4 addresses mapped into ipv6, like `::ffff:192.0.2.1`, are turned back
+/// into plain ipv4 ones.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Name and semantic version of the client in a client version, e.g. `("Geth", "1.13.5")` in
+/// `Geth/v1.13.5-stable-3f907d6a/linux-amd64/go1.21.3`.
+///
+/// Returns `None` when the client version doesn't carry a `major.minor[.patch]` version.
+pub fn parse_client_version(client_version: &str) -> Option<(&str, &str)> {
+    let mut parts = client_version.split('/');
+    let name = parts.next().filter(|name| !name.is_empty())?;
+    let version = parts.next()?;
+    let version = version.strip_prefix('v').unwrap_or(version);
+    // drop the build metadata, e.g. `-stable-3f907d6a` or `+bb9b72c0`
+    let end = version
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(version.len());
+    let version = version[..end].trim_end_matches('.');
+    if !version.contains('.') || version.split('.').any(str::is_empty) {
+        return None;
+    }
+    Some((name, version))
+}
+
+/// Granularity at which client versions are grouped.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientGrouping {
+    /// e.g. `Geth`.
+    #[default]
+    Name,
+    /// e.g. `Geth 1.13.5`.
+    NameVersion,
+}
+
+/// Number of peers running a client.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClientCount {
+    pub client: String,
+    pub count: u64,
+}
+
+/// Bucket of the client versions that can't be parsed.
+pub const UNKNOWN_CLIENT: &str = "Unknown";
+
+/// Count the peers by client, the most common first.
+pub fn group_clients<'a>(
+    peers: impl IntoIterator<Item = &'a PeerData>,
+    group_by: ClientGrouping,
+) -> Vec<ClientCount> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for peer in peers {
+        let client = match (group_by, parse_client_version(&peer.client_version)) {
+            (ClientGrouping::Name, _) if !client_name(&peer.client_version).is_empty() => {
+                client_name(&peer.client_version).to_string()
+            }
+            (ClientGrouping::NameVersion, Some((name, version))) => format!("{name} {version}"),
+            _ => UNKNOWN_CLIENT.to_string(),
+        };
+        *counts.entry(client).or_default() += 1;
+    }
+    let mut clients: Vec<ClientCount> = counts
+        .into_iter()
+        .map(|(client, count)| ClientCount { client, count })
+        .collect();
+    clients.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.client.cmp(&b.client)));
+    clients
+}
+
+/// Number of peers speaking a p2p protocol version, `None` for the peers whose version is unknown.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct P2pVersionCount {
+    pub p2p_version: Option<u8>,
+    pub count: u64,
+}
+
+/// Count the peers by p2p protocol version, the most common first.
+pub fn group_p2p_versions<'a>(
+    peers: impl IntoIterator<Item = &'a PeerData>,
+) -> Vec<P2pVersionCount> {
+    let mut counts: HashMap<Option<u8>, u64> = HashMap::new();
+    for peer in peers {
+        *counts.entry(peer.p2p_version).or_default() += 1;
+    }
+    let mut versions: Vec<P2pVersionCount> = counts
+        .into_iter()
+        .map(|(p2p_version, count)| P2pVersionCount { p2p_version, count })
+        .collect();
+    versions.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.p2p_version.cmp(&b.p2p_version))
+    });
+    versions
+}
+
+/// Number of peers that negotiated an eth protocol version with the crawler, and of peers advertising it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EthVersionCount {
+    pub eth_version: u8,
+    pub negotiated: u64,
+    pub advertised: u64,
+}
+
+/// Count the peers by negotiated and by advertised eth protocol version, the latest version first.
+///
+/// A peer advertises several versions, so the advertised counts add up to more than the peers.
+pub fn group_eth_versions<'a>(
+    peers: impl IntoIterator<Item = &'a PeerData>,
+) -> Vec<EthVersionCount> {
+    let mut counts: HashMap<u8, (u64, u64)> = HashMap::new();
+    for peer in peers {
+        // the peers only known from discovery negotiated nothing
+        if peer.eth_version > 0 {
+            counts.entry(peer.eth_version).or_default().0 += 1;
+        }
+        for eth_version in &peer.advertised_eth_versions {
+            counts.entry(*eth_version).or_default().1 += 1;
+        }
+    }
+    let mut versions: Vec<EthVersionCount> = counts
+        .into_iter()
+        .map(|(eth_version, (negotiated, advertised))| EthVersionCount {
+            eth_version,
+            negotiated,
+            advertised,
+        })
+        .collect();
+    versions.sort_by(|a, b| b.eth_version.cmp(&a.eth_version));
+    versions
+}
+
+/// Whether two cities are the same, regardless of their case.
+pub fn same_city(a: &str, b: &str) -> bool {
+    fold_city(a) == fold_city(b)
+}
+
+/// The form of `city` that [`same_city`] compares, e.g. `münchen` for `MÜNCHEN`.
+pub fn fold_city(city: &str) -> String {
+    city.to_lowercase()
+}
+
+/// Number of peers located in a city.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CityCount {
+    pub city: String,
+    pub count: u64,
+}
+
+/// Bucket of the peers that couldn't be located in a city.
+pub const UNKNOWN_CITY: &str = "Unknown";
+
+/// Count the peers by city, the most common first.
+pub fn group_cities<'a>(peers: impl IntoIterator<Item = &'a PeerData>) -> Vec<CityCount> {
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for peer in peers {
+        let city = match peer.city.trim() {
+            "" => UNKNOWN_CITY,
+            city => city,
+        };
+        *counts.entry(city.to_string()).or_default() += 1;
+    }
+    let mut cities: Vec<CityCount> = counts
+        .into_iter()
+        .map(|(city, count)| CityCount { city, count })
+        .collect();
+    cities.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.city.cmp(&b.city)));
+    cities
+}
+
+/// Number of peers last surfaced by a discovery source, `None` for the peers saved before the sources were recorded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SourceCount {
+    pub source: Option<String>,
+    pub count: u64,
+}
+
+/// Count the peers by discovery source, the most common first.
+pub fn group_sources<'a>(peers: impl IntoIterator<Item = &'a PeerData>) -> Vec<SourceCount> {
+    let mut counts: HashMap<Option<&str>, u64> = HashMap::new();
+    for peer in peers {
+        *counts.entry(peer.discovery_source.as_deref()).or_default() += 1;
+    }
+    let mut sources: Vec<SourceCount> = counts
+        .into_iter()
+        .map(|(source, count)| SourceCount {
+            source: source.map(str::to_string),
+            count,
+        })
+        .collect();
+    sources.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.source.cmp(&b.source)));
+    sources
+}
+
+/// Number of peers advertising a genesis hash.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GenesisCount {
+    pub genesis_block_hash: String,
+    pub count: u64,
+}
+
+/// Count the peers by genesis hash, the most common first.
+///
+/// Peers on another network are usually banned during the eth handshake, so the uncommon hashes are edge cases worth a
+/// look.
+pub fn group_genesis<'a>(peers: impl IntoIterator<Item = &'a PeerData>) -> Vec<GenesisCount> {
+    let mut counts: HashMap<&str, u64> = HashMap::new();
+    for peer in peers {
+        *counts.entry(peer.genesis_block_hash.as_str()).or_default() += 1;
+    }
+    let mut genesis: Vec<GenesisCount> = counts
+        .into_iter()
+        .map(|(genesis_block_hash, count)| GenesisCount {
+            genesis_block_hash: genesis_block_hash.to_string(),
+            count,
+        })
+        .collect();
+    genesis.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.genesis_block_hash.cmp(&b.genesis_block_hash))
+    });
+    genesis
+}
+
+/// Number of peers announced by an autonomous system, `None` for the peers whose asn is unknown.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AsnCount {
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+    pub count: u64,
+}
+
+/// Count the peers by autonomous system, the most common first.
+///
+/// A handful of systems announcing most of the peers tells how concentrated the network is.
+pub fn group_asns<'a>(peers: impl IntoIterator<Item = &'a PeerData>) -> Vec<AsnCount> {
+    let mut counts: HashMap<Option<u32>, (Option<&str>, u64)> = HashMap::new();
+    for peer in peers {
+        let (as_org, count) = counts.entry(peer.asn).or_default();
+        // the organization of a system is the same for all its peers, the first one known is kept
+        if as_org.is_none() {
+            *as_org = peer.as_org.as_deref();
+        }
+        *count += 1;
+    }
+    let mut asns: Vec<AsnCount> = counts
+        .into_iter()
+        .map(|(asn, (as_org, count))| AsnCount {
+            asn,
+            as_org: as_org.map(str::to_string),
+            count,
+        })
+        .collect();
+    asns.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.asn.cmp(&b.asn)));
+    asns
+}
+
+/// Share of the peers seen since a time that are synced.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SyncedRatio {
+    /// The peers were seen since this time, in RFC 3339.
+    pub since: String,
+    pub total_peers: u64,
+    pub synced_peers: u64,
+    /// `synced_peers / total_peers`, `None` without peers.
+    pub ratio: Option<f64>,
+}
+
+impl SyncedRatio {
+    /// The share of `peers`, seen since `since`, that are synced. The peers whose sync is unknown count as not synced.
+    pub fn from_peers<'a>(since: String, peers: impl IntoIterator<Item = &'a PeerData>) -> Self {
+        let mut total_peers = 0;
+        let mut synced_peers = 0;
+        for peer in peers {
+            total_peers += 1;
+            if peer.synced == Some(true) {
+                synced_peers += 1;
+            }
+        }
+        Self {
+            since,
+            total_peers,
+            synced_peers,
+            ratio: (total_peers > 0).then(|| synced_peers as f64 / total_peers as f64),
+        }
+    }
+}